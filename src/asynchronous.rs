@@ -0,0 +1,142 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Runtime-agnostic async API, enabled with the `async` cargo feature.
+//!
+//! Applying is offloaded to a thread pool as a whole, including opening
+//! or dropping trees, executing the transaction and flushing, so it doesn't
+//! stall the executor. The apply intent lives within the offloaded work,
+//! so dropping the future never leaves it half done. Iterators are exposed
+//! as streams that periodically yield back to the executor. Since they
+//! borrow the overlay, their reads can't be offloaded and are performed
+//! on the executor.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::Stream;
+use sled::{transaction::TransactionError, IVec};
+
+use crate::{
+    backend::{KvStore, KvTree},
+    ApplyOptions, ApplyOutcome, SledDbOverlay, SledDbOverlayStateDiff, SledTreeOverlay,
+    SledTreeOverlayIter,
+};
+
+/// Number of records a [`SledTreeOverlayStream`] produces
+/// before yielding back to the executor.
+const YIELD_EVERY: usize = 64;
+
+impl<S: KvStore> SledDbOverlay<S> {
+    /// Async version of [`SledDbOverlay::apply`], executing the
+    /// apply on a blocking thread pool.
+    pub async fn apply_async(&mut self) -> Result<(), TransactionError<sled::Error>> {
+        self.apply_with_async(ApplyOptions::default()).await?;
+        Ok(())
+    }
+
+    /// Async version of [`SledDbOverlay::apply_with`], executing the
+    /// apply on a blocking thread pool. If the future gets dropped while
+    /// the apply executes, the changes still get applied or rolled back
+    /// as a whole, but subscribers don't get notified and the post-apply
+    /// hooks are not executed. An attached publisher still publishes the
+    /// diff, since it gets recorded along with the changes.
+    pub async fn apply_with_async(
+        &mut self,
+        options: ApplyOptions,
+    ) -> Result<ApplyOutcome, TransactionError<sled::Error>> {
+        let (job, diff) = self.prepare_apply(options)?;
+        let applied = blocking::unblock(move || job.execute()).await?;
+        self.finish_apply(applied, diff)
+    }
+
+    /// Async version of [`SledDbOverlay::apply_diff`], executing the
+    /// apply on a blocking thread pool. If the future gets dropped while
+    /// the apply executes, the changes still get applied or rolled back
+    /// as a whole, but they don't get removed from our current state.
+    pub async fn apply_diff_async(
+        &mut self,
+        diff: &SledDbOverlayStateDiff,
+    ) -> Result<(), TransactionError<sled::Error>> {
+        let job = self.prepare_apply_diff(diff)?;
+        let applied = blocking::unblock(move || job.execute()).await?;
+        self.finish_apply_diff(applied, diff)
+    }
+
+    /// Retrieve an immutable stream from the overlay if the specified tree cache exists.
+    pub fn stream(
+        &self,
+        tree_key: &[u8],
+    ) -> Result<SledTreeOverlayStream<'_, S::Tree>, sled::Error> {
+        Ok(SledTreeOverlayStream::new(self.iter(tree_key)?))
+    }
+}
+
+impl SledDbOverlay<sled::Db> {
+    /// Asynchronously flush the underlying [`sled::Db`] to disk, using
+    /// [`sled::Tree::flush_async`]. Returns the number of bytes flushed.
+    pub async fn flush_async(&self) -> Result<usize, sled::Error> {
+        self.db.flush_async().await
+    }
+}
+
+impl<T: KvTree> SledTreeOverlay<T> {
+    /// Immutably stream through the tree overlay.
+    pub fn stream(&self) -> SledTreeOverlayStream<'_, T> {
+        SledTreeOverlayStream::new(self.iter())
+    }
+}
+
+/// Immutable stream of a [`SledTreeOverlay`], producing the same records as
+/// [`SledTreeOverlayIter`] while yielding back to the executor every
+/// `YIELD_EVERY` records. Records are read on the executor, so polling
+/// may block on reading the main tree.
+pub struct SledTreeOverlayStream<'a, T: KvTree = sled::Tree> {
+    /// The underlying tree overlay iterator.
+    iter: SledTreeOverlayIter<'a, T>,
+    /// Number of records produced since we last yielded.
+    produced: usize,
+}
+
+impl<'a, T: KvTree> SledTreeOverlayStream<'a, T> {
+    /// Instantiate a new [`SledTreeOverlayStream`] over provided iterator.
+    pub fn new(iter: SledTreeOverlayIter<'a, T>) -> Self {
+        Self { iter, produced: 0 }
+    }
+}
+
+// We never pin project into the iterator, so the stream can always be moved.
+impl<T: KvTree> Unpin for SledTreeOverlayStream<'_, T> {}
+
+impl<T: KvTree> Stream for SledTreeOverlayStream<'_, T> {
+    type Item = Result<(IVec, IVec), sled::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // Give other tasks a chance to run
+        if self.produced == YIELD_EVERY {
+            self.produced = 0;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        self.produced += 1;
+        Poll::Ready(self.iter.next())
+    }
+}
@@ -0,0 +1,488 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Pluggable storage backends the overlays can sit on top of.
+//!
+//! A backend is a [`KvStore`] holding named [`KvTree`] instances. [`sled`]
+//! is the default implementation, while [`MemoryStore`] keeps everything
+//! in memory, which is handy for tests and ephemeral states.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::{Bound, RangeBounds},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError, Transactional},
+    IVec,
+};
+
+/// A set of insertions and removals to apply over a [`KvTree`]
+/// atomically. Each key holds its new value, or `None` if it
+/// is removed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KvBatch {
+    /// The batch records.
+    pub records: BTreeMap<IVec, Option<IVec>>,
+}
+
+impl KvBatch {
+    /// Set a key to a new value.
+    pub fn insert<K: Into<IVec>, V: Into<IVec>>(&mut self, key: K, value: V) {
+        self.records.insert(key.into(), Some(value.into()));
+    }
+
+    /// Remove a key.
+    pub fn remove<K: Into<IVec>>(&mut self, key: K) {
+        self.records.insert(key.into(), None);
+    }
+}
+
+impl From<&KvBatch> for sled::Batch {
+    fn from(kv_batch: &KvBatch) -> Self {
+        let mut batch = sled::Batch::default();
+        for (key, value) in kv_batch.records.iter() {
+            match value {
+                Some(value) => batch.insert(key, value),
+                None => batch.remove(key),
+            }
+        }
+        batch
+    }
+}
+
+/// A single ordered key-value tree of a [`KvStore`].
+pub trait KvTree: fmt::Debug + Clone + Send + Sync + 'static {
+    /// Iterator over the tree records, in key order.
+    type Iter: Iterator<Item = Result<(IVec, IVec), sled::Error>>;
+
+    /// Returns the tree name.
+    fn name(&self) -> IVec;
+
+    /// Retrieve a value from the tree if it exists.
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>, sled::Error>;
+
+    /// Returns `true` if the tree contains a value for a specified key.
+    fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool, sled::Error> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Returns the record with the greatest key less than provided one.
+    fn get_lt<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<(IVec, IVec)>, sled::Error>;
+
+    /// Returns the record with the greatest key, or `None` if the tree is empty.
+    fn last(&self) -> Result<Option<(IVec, IVec)>, sled::Error>;
+
+    /// Returns the number of records in the tree.
+    fn len(&self) -> usize;
+
+    /// Returns `true` if the tree contains no records.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over all the tree records.
+    fn iter(&self) -> Self::Iter;
+
+    /// Iterate over the tree records within provided key range.
+    fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Self::Iter;
+
+    /// Atomically apply provided batch over the tree.
+    fn apply_batch(&self, batch: &KvBatch) -> Result<(), sled::Error>;
+
+    /// Atomically set a key to a new value, or remove it if `new` is
+    /// `None`, only if its current value matches `old`, where `None`
+    /// means the key doesn't exist. Returns `false` if it didn't match.
+    fn compare_and_swap<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, sled::Error>;
+}
+
+/// A key-value store holding multiple named [`KvTree`] instances.
+pub trait KvStore: fmt::Debug + Clone + Send + Sync + 'static {
+    /// The trees the store holds.
+    type Tree: KvTree;
+
+    /// Open the tree with provided name, creating it if it doesn't exist.
+    fn open_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<Self::Tree, sled::Error>;
+
+    /// Drop the tree with provided name, returning `true` if it existed.
+    fn drop_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<bool, sled::Error>;
+
+    /// Returns the names of all the trees in the store.
+    fn tree_names(&self) -> Vec<IVec>;
+
+    /// Perform an atomic transaction over provided trees,
+    /// applying their respective batches.
+    fn transaction(
+        &self,
+        trees: &[Self::Tree],
+        batches: &[KvBatch],
+    ) -> Result<(), TransactionError<sled::Error>>;
+
+    /// Perform an atomic transaction over provided trees, applying their
+    /// respective batches like [`KvStore::transaction`], and return the
+    /// previous values of each batch records, as read within the transaction.
+    fn transaction_with_previous(
+        &self,
+        trees: &[Self::Tree],
+        batches: &[KvBatch],
+    ) -> Result<Vec<BTreeMap<IVec, Option<IVec>>>, TransactionError<sled::Error>>;
+
+    /// Flush the store to durable storage, returning the number of bytes flushed.
+    fn flush(&self) -> Result<usize, sled::Error>;
+}
+
+/// Auxilliary function to convert a borrowed key bound to an owned one.
+pub(crate) fn owned_bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<IVec> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref().into()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref().into()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+impl KvTree for sled::Tree {
+    type Iter = sled::Iter;
+
+    fn name(&self) -> IVec {
+        sled::Tree::name(self)
+    }
+
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>, sled::Error> {
+        sled::Tree::get(self, key)
+    }
+
+    fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool, sled::Error> {
+        sled::Tree::contains_key(self, key)
+    }
+
+    fn get_lt<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<(IVec, IVec)>, sled::Error> {
+        sled::Tree::get_lt(self, key)
+    }
+
+    fn last(&self) -> Result<Option<(IVec, IVec)>, sled::Error> {
+        sled::Tree::last(self)
+    }
+
+    fn len(&self) -> usize {
+        sled::Tree::len(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        sled::Tree::is_empty(self)
+    }
+
+    fn iter(&self) -> Self::Iter {
+        sled::Tree::iter(self)
+    }
+
+    fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Self::Iter {
+        let start = owned_bound(range.start_bound());
+        let end = owned_bound(range.end_bound());
+        sled::Tree::range::<IVec, _>(self, (start, end))
+    }
+
+    fn apply_batch(&self, batch: &KvBatch) -> Result<(), sled::Error> {
+        sled::Tree::apply_batch(self, batch.into())
+    }
+
+    fn compare_and_swap<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, sled::Error> {
+        Ok(sled::Tree::compare_and_swap(self, key, old, new)?.is_ok())
+    }
+}
+
+impl KvStore for sled::Db {
+    type Tree = sled::Tree;
+
+    fn open_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<Self::Tree, sled::Error> {
+        sled::Db::open_tree(self, name)
+    }
+
+    fn drop_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<bool, sled::Error> {
+        sled::Db::drop_tree(self, name)
+    }
+
+    fn tree_names(&self) -> Vec<IVec> {
+        sled::Db::tree_names(self)
+    }
+
+    fn transaction(
+        &self,
+        trees: &[Self::Tree],
+        batches: &[KvBatch],
+    ) -> Result<(), TransactionError<sled::Error>> {
+        let batches: Vec<sled::Batch> = batches.iter().map(sled::Batch::from).collect();
+
+        trees.transaction(|trees| {
+            for (index, tree) in trees.iter().enumerate() {
+                tree.apply_batch(&batches[index])?;
+            }
+
+            Ok::<(), ConflictableTransactionError<sled::Error>>(())
+        })?;
+
+        Ok(())
+    }
+
+    fn transaction_with_previous(
+        &self,
+        trees: &[Self::Tree],
+        batches: &[KvBatch],
+    ) -> Result<Vec<BTreeMap<IVec, Option<IVec>>>, TransactionError<sled::Error>> {
+        trees.transaction(|trees| {
+            let mut previous = vec![];
+            for (index, tree) in trees.iter().enumerate() {
+                let mut tree_previous = BTreeMap::new();
+                for (key, value) in batches[index].records.iter() {
+                    let prev = match value {
+                        Some(value) => tree.insert(key, value)?,
+                        None => tree.remove(key)?,
+                    };
+                    tree_previous.insert(key.clone(), prev);
+                }
+                previous.push(tree_previous);
+            }
+
+            Ok::<_, ConflictableTransactionError<sled::Error>>(previous)
+        })
+    }
+
+    fn flush(&self) -> Result<usize, sled::Error> {
+        sled::Tree::flush(self)
+    }
+}
+
+/// Auxilliary function to acquire a read lock, panicking if it is poisoned.
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().expect("Memory store lock is poisoned")
+}
+
+/// Auxilliary function to acquire a write lock, panicking if it is poisoned.
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().expect("Memory store lock is poisoned")
+}
+
+/// A [`KvTree`] of a [`MemoryStore`]. Cloning it produces a new
+/// reference to the same tree.
+#[derive(Debug, Clone)]
+pub struct MemoryTree {
+    /// The tree name.
+    name: IVec,
+    /// The tree records.
+    records: Arc<RwLock<BTreeMap<IVec, IVec>>>,
+    /// Store-wide lock, held exclusively by transactions so
+    /// they are atomic with respect to all reads.
+    gate: Arc<RwLock<()>>,
+}
+
+impl MemoryTree {
+    /// Instantiate a new empty [`MemoryTree`] using provided store-wide lock.
+    fn new(name: IVec, gate: Arc<RwLock<()>>) -> Self {
+        Self {
+            name,
+            records: Arc::new(RwLock::new(BTreeMap::new())),
+            gate,
+        }
+    }
+
+    /// Execute provided function over the tree records, while holding
+    /// the store-wide lock.
+    fn with_records<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&BTreeMap<IVec, IVec>) -> R,
+    {
+        let _gate = read(&self.gate);
+        f(&read(&self.records))
+    }
+
+    /// Apply provided batch over the tree records, returning
+    /// the previous values of its records.
+    fn apply(&self, batch: &KvBatch) -> BTreeMap<IVec, Option<IVec>> {
+        let mut records = write(&self.records);
+        let mut previous = BTreeMap::new();
+        for (key, value) in batch.records.iter() {
+            let prev = match value {
+                Some(value) => records.insert(key.clone(), value.clone()),
+                None => records.remove(key),
+            };
+            previous.insert(key.clone(), prev);
+        }
+        previous
+    }
+}
+
+impl KvTree for MemoryTree {
+    type Iter = std::vec::IntoIter<Result<(IVec, IVec), sled::Error>>;
+
+    fn name(&self) -> IVec {
+        self.name.clone()
+    }
+
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>, sled::Error> {
+        Ok(self.with_records(|records| records.get(key.as_ref()).cloned()))
+    }
+
+    fn get_lt<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<(IVec, IVec)>, sled::Error> {
+        let key = IVec::from(key.as_ref());
+        Ok(self.with_records(|records| {
+            records
+                .range(..key)
+                .next_back()
+                .map(|(k, v)| (k.clone(), v.clone()))
+        }))
+    }
+
+    fn last(&self) -> Result<Option<(IVec, IVec)>, sled::Error> {
+        Ok(self.with_records(|records| {
+            records
+                .last_key_value()
+                .map(|(k, v)| (k.clone(), v.clone()))
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.with_records(|records| records.len())
+    }
+
+    /// Iterate over a snapshot of all the tree records.
+    fn iter(&self) -> Self::Iter {
+        self.range::<IVec, _>(..)
+    }
+
+    /// Iterate over a snapshot of the tree records within provided key range.
+    fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Self::Iter {
+        let start = owned_bound(range.start_bound());
+        let end = owned_bound(range.end_bound());
+        let snapshot: Vec<_> = self.with_records(|records| {
+            records
+                .range((start, end))
+                .map(|(k, v)| Ok((k.clone(), v.clone())))
+                .collect()
+        });
+        snapshot.into_iter()
+    }
+
+    fn apply_batch(&self, batch: &KvBatch) -> Result<(), sled::Error> {
+        let _gate = read(&self.gate);
+        self.apply(batch);
+        Ok(())
+    }
+
+    fn compare_and_swap<K: AsRef<[u8]>>(
+        &self,
+        key: K,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool, sled::Error> {
+        let _gate = read(&self.gate);
+        let mut records = write(&self.records);
+        if records.get(key.as_ref()).map(|value| value.as_ref()) != old {
+            return Ok(false);
+        }
+
+        match new {
+            Some(new) => records.insert(key.as_ref().into(), new.into()),
+            None => records.remove(key.as_ref()),
+        };
+
+        Ok(true)
+    }
+}
+
+/// A pure in-memory [`KvStore`], keeping its trees in [`BTreeMap`]
+/// instances. Cloning it produces a new reference to the same store.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    /// The store trees, by name.
+    trees: Arc<RwLock<BTreeMap<IVec, MemoryTree>>>,
+    /// Store-wide lock, held exclusively by transactions so
+    /// they are atomic with respect to all reads.
+    gate: Arc<RwLock<()>>,
+}
+
+impl MemoryStore {
+    /// Instantiate a new empty [`MemoryStore`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KvStore for MemoryStore {
+    type Tree = MemoryTree;
+
+    fn open_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<Self::Tree, sled::Error> {
+        let name = IVec::from(name.as_ref());
+        let tree = write(&self.trees)
+            .entry(name.clone())
+            .or_insert_with(|| MemoryTree::new(name, self.gate.clone()))
+            .clone();
+        Ok(tree)
+    }
+
+    fn drop_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<bool, sled::Error> {
+        Ok(write(&self.trees).remove(name.as_ref()).is_some())
+    }
+
+    fn tree_names(&self) -> Vec<IVec> {
+        read(&self.trees).keys().cloned().collect()
+    }
+
+    fn transaction(
+        &self,
+        trees: &[Self::Tree],
+        batches: &[KvBatch],
+    ) -> Result<(), TransactionError<sled::Error>> {
+        let _gate = write(&self.gate);
+        for (index, tree) in trees.iter().enumerate() {
+            tree.apply(&batches[index]);
+        }
+
+        Ok(())
+    }
+
+    fn transaction_with_previous(
+        &self,
+        trees: &[Self::Tree],
+        batches: &[KvBatch],
+    ) -> Result<Vec<BTreeMap<IVec, Option<IVec>>>, TransactionError<sled::Error>> {
+        let _gate = write(&self.gate);
+        let previous = trees
+            .iter()
+            .enumerate()
+            .map(|(index, tree)| tree.apply(&batches[index]))
+            .collect();
+
+        Ok(previous)
+    }
+
+    /// Memory stores have nothing to flush.
+    fn flush(&self) -> Result<usize, sled::Error> {
+        Ok(0)
+    }
+}
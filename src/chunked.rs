@@ -0,0 +1,889 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Chunked application of large overlay changes.
+//!
+//! Instead of writing all changes in a single transaction, the changes
+//! are written in bounded chunks, after recording them in a journal tree
+//! of the database. The journal holds a header describing the apply,
+//! claimed atomically so only one apply can be pending, and a record per
+//! operation, keyed by its index, so chunks are read back without loading
+//! the whole journal. Each chunk commits along with the journal progress
+//! marker, so an interrupted apply can be resumed or rolled back on
+//! restart. Dropped trees are dropped after all chunks got written, so
+//! once that happens, the apply can only be resumed. When a publisher is
+//! attached, the diff is recorded in the journal as well, and published
+//! once all operations got applied, even if the apply got resumed.
+
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use sled::{transaction::TransactionError, IVec};
+
+use crate::{
+    backend::{KvBatch, KvStore, KvTree},
+    replication::{outbox_batch, REPLICATION_TREE},
+    serial::{Decodable, Encodable, Sink, Source},
+    watch::EventKind,
+    SledDbOverlay, SledDbOverlayStateDiff, SledTreeOverlayStateDiff,
+};
+
+/// Tree holding the journal of a pending chunked apply.
+pub const APPLY_JOURNAL_TREE: &[u8] = b"__sled_overlay_apply_journal";
+
+/// Journal key of the pending apply header.
+const HEADER_KEY: &[u8] = b"header";
+
+/// Journal key prefix of the operations, followed by their index.
+const OP_PREFIX: &[u8] = b"op";
+
+/// Journal key of the number of operations that have been applied.
+/// It's only written once all the operations have been recorded.
+const PROGRESS_KEY: &[u8] = b"progress";
+
+/// Journal key of the encoded diff to publish, recorded when a publisher
+/// is attached to the applying overlay.
+const FRAME_KEY: &[u8] = b"frame";
+
+/// Journal key marking the diff got recorded in the replication tree,
+/// written in the same transaction.
+const PUBLISHED_KEY: &[u8] = b"published";
+
+/// Progress of a chunked apply, reported after each committed chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApplyProgress {
+    /// Number of operations that have been applied.
+    pub applied: usize,
+    /// Total number of operations to apply.
+    pub total: usize,
+}
+
+/// A single operation of a chunked apply.
+enum JournalOp {
+    /// Write a key, along with its previous value to roll it back.
+    Write {
+        tree: IVec,
+        key: IVec,
+        value: Option<IVec>,
+        previous: Option<IVec>,
+    },
+    /// Drop a tree, along with a flag indicating if the diff
+    /// drops it from its caches, or from its dropped trees.
+    Drop { tree: IVec, cached: bool },
+}
+
+impl Encodable for JournalOp {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        match self {
+            Self::Write {
+                tree,
+                key,
+                value,
+                previous,
+            } => {
+                0u8.encode(s);
+                tree.encode(s);
+                key.encode(s);
+                value.encode(s);
+                previous.encode(s);
+            }
+            Self::Drop { tree, cached } => {
+                1u8.encode(s);
+                tree.encode(s);
+                cached.encode(s);
+            }
+        }
+    }
+}
+
+impl Decodable for JournalOp {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        match u8::decode(s)? {
+            0 => Ok(Self::Write {
+                tree: IVec::decode(s)?,
+                key: IVec::decode(s)?,
+                value: Option::decode(s)?,
+                previous: Option::decode(s)?,
+            }),
+            1 => Ok(Self::Drop {
+                tree: IVec::decode(s)?,
+                cached: bool::decode(s)?,
+            }),
+            _ => Err(sled::Error::Unsupported("Invalid encoding".to_string())),
+        }
+    }
+}
+
+/// Description of a pending chunked apply, as recorded in the journal.
+struct JournalHeader {
+    /// The trees existing before the apply.
+    initial_tree_names: Vec<IVec>,
+    /// The trees the apply writes into.
+    written_trees: Vec<IVec>,
+    /// The written trees the diff restores.
+    restored_trees: Vec<IVec>,
+    /// Number of write operations.
+    writes: u64,
+    /// Total number of operations.
+    total: u64,
+}
+
+impl JournalHeader {
+    /// Instantiate a new empty [`JournalHeader`] over provided initial trees.
+    fn new(initial_tree_names: Vec<IVec>) -> Self {
+        Self {
+            initial_tree_names,
+            written_trees: vec![],
+            restored_trees: vec![],
+            writes: 0,
+            total: 0,
+        }
+    }
+
+    /// Returns the number of write operations.
+    fn writes(&self) -> usize {
+        self.writes as usize
+    }
+
+    /// Returns the total number of operations.
+    fn total(&self) -> usize {
+        self.total as usize
+    }
+
+    /// Returns the [`ApplyProgress`] of provided number of applied operations.
+    fn apply_progress(&self, applied: usize) -> ApplyProgress {
+        ApplyProgress {
+            applied,
+            total: self.total(),
+        }
+    }
+}
+
+impl Encodable for JournalHeader {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        self.initial_tree_names.encode(s);
+        self.written_trees.encode(s);
+        self.restored_trees.encode(s);
+        self.writes.encode(s);
+        self.total.encode(s);
+    }
+}
+
+impl Decodable for JournalHeader {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        let header = Self {
+            initial_tree_names: Vec::decode(s)?,
+            written_trees: Vec::decode(s)?,
+            restored_trees: Vec::decode(s)?,
+            writes: u64::decode(s)?,
+            total: u64::decode(s)?,
+        };
+
+        if header.writes > header.total || usize::try_from(header.total).is_err() {
+            return Err(invalid_journal());
+        }
+
+        Ok(header)
+    }
+}
+
+/// A pending journal tree, along with its header and progress.
+type PendingJournal<T> = (T, JournalHeader, Option<usize>);
+
+/// Auxilliary function to build the error of an invalid journal.
+fn invalid_journal() -> sled::Error {
+    sled::Error::Unsupported("Invalid apply journal".to_string())
+}
+
+/// Auxilliary function to build the journal key of provided operation index.
+fn op_key(index: usize) -> IVec {
+    let mut key = OP_PREFIX.to_vec();
+    key.extend_from_slice(&(index as u64).to_be_bytes());
+    key.into()
+}
+
+/// Auxilliary function to decode a journal record.
+fn decode_record<T: Decodable>(bytes: &[u8]) -> Result<T, sled::Error> {
+    let mut source = Source::new(bytes);
+    let record = T::decode(&mut source)?;
+    if !source.is_empty() {
+        return Err(sled::Error::Unsupported("Invalid encoding".to_string()));
+    }
+
+    Ok(record)
+}
+
+/// Auxilliary function to read the journal operations within provided
+/// index range.
+fn read_ops<T: KvTree>(
+    journal_tree: &T,
+    start: usize,
+    end: usize,
+) -> Result<Vec<JournalOp>, sled::Error> {
+    let mut ops = vec![];
+    for record in journal_tree.range(op_key(start)..op_key(end)) {
+        ops.push(decode_record(&record?.1)?);
+    }
+
+    if ops.len() != end - start {
+        return Err(invalid_journal());
+    }
+
+    Ok(ops)
+}
+
+/// Auxilliary function to build the journal batch setting provided progress.
+fn progress_batch(progress: usize) -> KvBatch {
+    let mut batch = KvBatch::default();
+    batch.insert(PROGRESS_KEY, IVec::from(&(progress as u64).to_be_bytes()));
+    batch
+}
+
+/// Auxilliary function to build the batches committing provided operations,
+/// writing either their values or their previous ones if we are rolling
+/// back, along with the journal batch setting provided progress.
+fn chunk_batches<T: KvTree>(
+    state_trees: &BTreeMap<IVec, T>,
+    journal_tree: &T,
+    ops: &[JournalOp],
+    rollback: bool,
+    progress: usize,
+) -> Result<(Vec<T>, Vec<KvBatch>), sled::Error> {
+    let mut chunk: BTreeMap<IVec, KvBatch> = BTreeMap::new();
+    for op in ops {
+        let JournalOp::Write {
+            tree,
+            key,
+            value,
+            previous,
+        } = op
+        else {
+            continue;
+        };
+        let value = if rollback { previous } else { value };
+        chunk
+            .entry(tree.clone())
+            .or_default()
+            .records
+            .insert(key.clone(), value.clone());
+    }
+
+    let mut trees = vec![];
+    let mut batches = vec![];
+    for (tree_key, batch) in chunk {
+        let Some(tree) = state_trees.get(&tree_key) else {
+            return Err(sled::Error::CollectionNotFound(tree_key));
+        };
+        trees.push(tree.clone());
+        batches.push(batch);
+    }
+
+    trees.push(journal_tree.clone());
+    batches.push(progress_batch(progress));
+
+    Ok((trees, batches))
+}
+
+/// Writer of the journal operations, recording them in batches.
+struct JournalWriter<'a, T: KvTree> {
+    /// The journal tree.
+    journal_tree: &'a T,
+    /// Maximum number of records of each batch.
+    max_ops: usize,
+    /// Operations that haven't been recorded yet.
+    batch: KvBatch,
+    /// Index of the next operation.
+    index: usize,
+}
+
+impl<'a, T: KvTree> JournalWriter<'a, T> {
+    /// Instantiate a new [`JournalWriter`] over provided journal tree.
+    fn new(journal_tree: &'a T, max_ops: usize) -> Self {
+        Self {
+            journal_tree,
+            max_ops,
+            batch: KvBatch::default(),
+            index: 0,
+        }
+    }
+
+    /// Append provided operation to the journal, recording the
+    /// pending batch once it's full.
+    fn push(&mut self, op: &JournalOp) -> Result<(), sled::Error> {
+        let mut bytes = vec![];
+        op.encode(&mut bytes);
+        self.batch.insert(op_key(self.index), bytes);
+        self.index += 1;
+        if self.batch.records.len() == self.max_ops {
+            self.journal_tree
+                .apply_batch(&std::mem::take(&mut self.batch))?;
+        }
+
+        Ok(())
+    }
+
+    /// Append the writes replacing the records of provided tree with
+    /// the ones of provided iterator, which must be in key order.
+    fn replace_records<U: KvTree>(
+        &mut self,
+        tree_key: &IVec,
+        tree: &U,
+        records: impl Iterator<Item = Result<(IVec, IVec), sled::Error>>,
+    ) -> Result<(), sled::Error> {
+        let mut previous = tree.iter().peekable();
+        let mut records = records.peekable();
+        loop {
+            let previous_key = match previous.peek() {
+                Some(Ok((key, _))) => Some(key.clone()),
+                Some(Err(e)) => return Err(e.clone()),
+                None => None,
+            };
+            let record_key = match records.peek() {
+                Some(Ok((key, _))) => Some(key.clone()),
+                Some(Err(e)) => return Err(e.clone()),
+                None => None,
+            };
+
+            let order = match (previous_key, record_key) {
+                (None, None) => return Ok(()),
+                (Some(previous_key), Some(record_key)) => previous_key.cmp(&record_key),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+            };
+
+            // Its safe to unwrap here since we peeked the records
+            let (key, value, prev) = match order {
+                Ordering::Equal => {
+                    let (_, prev) = previous.next().unwrap()?;
+                    let (key, value) = records.next().unwrap()?;
+                    (key, Some(value), Some(prev))
+                }
+                Ordering::Less => {
+                    let (key, prev) = previous.next().unwrap()?;
+                    (key, None, Some(prev))
+                }
+                Ordering::Greater => {
+                    let (key, value) = records.next().unwrap()?;
+                    (key, Some(value), None)
+                }
+            };
+
+            if value != prev {
+                self.push(&JournalOp::Write {
+                    tree: tree_key.clone(),
+                    key,
+                    value,
+                    previous: prev,
+                })?;
+            }
+        }
+    }
+}
+
+/// Auxilliary function to build the error of a chunk size of zero.
+fn invalid_chunk_size() -> TransactionError<sled::Error> {
+    TransactionError::Storage(sled::Error::Unsupported(
+        "Chunk size must be positive".to_string(),
+    ))
+}
+
+impl<S: KvStore> SledDbOverlay<S> {
+    /// Ensure provided trees are all opened, keeping their pointers.
+    fn open_trees<'a>(
+        &self,
+        tree_keys: impl Iterator<Item = &'a IVec>,
+    ) -> Result<BTreeMap<IVec, S::Tree>, sled::Error> {
+        let mut trees = BTreeMap::new();
+        for tree_key in tree_keys {
+            trees.insert(tree_key.clone(), self.db.open_tree(tree_key)?);
+        }
+
+        Ok(trees)
+    }
+
+    /// Retrieve the journal tree, if it exists in the database.
+    fn journal_tree(&self) -> Result<Option<S::Tree>, sled::Error> {
+        if !self.db.tree_names().contains(&APPLY_JOURNAL_TREE.into()) {
+            return Ok(None);
+        }
+
+        Ok(Some(self.db.open_tree(APPLY_JOURNAL_TREE)?))
+    }
+
+    /// Retrieve the pending chunked apply journal header, if any, along
+    /// with its progress, which is `None` if its operations haven't been
+    /// fully recorded.
+    fn read_journal(&self) -> Result<Option<PendingJournal<S::Tree>>, sled::Error> {
+        let Some(journal_tree) = self.journal_tree()? else {
+            return Ok(None);
+        };
+
+        let Some(header) = journal_tree.get(HEADER_KEY)? else {
+            return Ok(None);
+        };
+        let header: JournalHeader = decode_record(&header)?;
+
+        let progress = match journal_tree.get(PROGRESS_KEY)? {
+            Some(progress) => {
+                let bytes: [u8; 8] = progress
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| invalid_journal())?;
+                let progress =
+                    usize::try_from(u64::from_be_bytes(bytes)).map_err(|_| invalid_journal())?;
+                if progress > header.total() {
+                    return Err(invalid_journal());
+                }
+                Some(progress)
+            }
+            None => None,
+        };
+
+        Ok(Some((journal_tree, header, progress)))
+    }
+
+    /// Record the operations applying the overlay changes in the journal,
+    /// streaming each tree changes, including the spilled ones, in batches
+    /// of at most `max_ops` records. The journal is claimed first, atomically
+    /// failing if another apply is pending, and its final header is written
+    /// along with the progress marker and provided encoded diff to publish
+    /// last, marking the journal complete.
+    fn record_journal(
+        &self,
+        max_ops: usize,
+        frame: Option<Vec<u8>>,
+    ) -> Result<(S::Tree, JournalHeader), sled::Error> {
+        let journal_tree = self.db.open_tree(APPLY_JOURNAL_TREE)?;
+
+        // Claim the journal
+        let mut header = JournalHeader::new(self.state.initial_tree_names.clone());
+        let mut bytes = vec![];
+        header.encode(&mut bytes);
+        if !journal_tree.compare_and_swap(HEADER_KEY, None, Some(&bytes))? {
+            return Err(sled::Error::Unsupported(
+                "A chunked apply is pending".to_string(),
+            ));
+        }
+
+        // Record the writes of each tree
+        let mut writer = JournalWriter::new(&journal_tree, max_ops);
+        for (tree_key, cache) in self.state.caches.iter() {
+            header.written_trees.push(tree_key.clone());
+
+            // Copies replace all the tree records with their own
+            if self.state.tree_copies.contains_key(tree_key) {
+                writer.replace_records(tree_key, &cache.tree, cache.iter())?;
+                continue;
+            }
+
+            for record in cache.changes() {
+                let (key, value) = record?;
+                let previous = cache.tree.get(&key)?;
+                writer.push(&JournalOp::Write {
+                    tree: tree_key.clone(),
+                    key,
+                    value,
+                    previous,
+                })?;
+            }
+        }
+        header.writes = writer.index as u64;
+
+        // Record the drops
+        for tree_key in self.state.dropped_trees.keys() {
+            writer.push(&JournalOp::Drop {
+                tree: tree_key.clone(),
+                cached: false,
+            })?;
+        }
+        header.total = writer.index as u64;
+
+        // Mark the journal complete
+        let mut bytes = vec![];
+        header.encode(&mut bytes);
+        let mut batch = writer.batch;
+        batch.insert(HEADER_KEY, bytes);
+        batch.records.append(&mut progress_batch(0).records);
+        if let Some(frame) = frame {
+            batch.insert(FRAME_KEY, frame);
+        }
+        journal_tree.apply_batch(&batch)?;
+
+        Ok((journal_tree, header))
+    }
+
+    /// Build the last state diff of provided tree about to be dropped.
+    fn dropped_tree_diff(&self, tree_key: &[u8]) -> Result<SledTreeOverlayStateDiff, sled::Error> {
+        if !self.db.tree_names().contains(&tree_key.into()) {
+            return Ok(SledTreeOverlayStateDiff::default());
+        }
+
+        Ok(SledTreeOverlayStateDiff::new_dropped(
+            &self.db.open_tree(tree_key)?,
+        ))
+    }
+
+    /// Rebuild the diff recorded in provided journal, along with
+    /// provided last state diffs of the trees it drops.
+    fn rebuild_diff(
+        &self,
+        journal_tree: &S::Tree,
+        header: &JournalHeader,
+        mut dropped: BTreeMap<IVec, SledTreeOverlayStateDiff>,
+    ) -> Result<SledDbOverlayStateDiff, sled::Error> {
+        let mut diff = SledDbOverlayStateDiff {
+            initial_tree_names: header.initial_tree_names.clone(),
+            ..Default::default()
+        };
+        let mut restored = BTreeMap::new();
+        for tree_key in header.written_trees.iter() {
+            if header.restored_trees.contains(tree_key) {
+                restored.insert(tree_key.clone(), SledTreeOverlayStateDiff::default());
+            } else {
+                diff.caches.insert(
+                    tree_key.clone(),
+                    (SledTreeOverlayStateDiff::default(), false),
+                );
+            }
+        }
+
+        for record in journal_tree.range(op_key(0)..op_key(header.total())) {
+            match decode_record(&record?.1)? {
+                JournalOp::Write {
+                    tree,
+                    key,
+                    value,
+                    previous,
+                } => {
+                    let cache = match diff.caches.get_mut(&tree) {
+                        Some((cache, _)) => cache,
+                        None => restored.get_mut(&tree).ok_or_else(invalid_journal)?,
+                    };
+                    match value {
+                        Some(value) => {
+                            cache.cache.insert(key, (previous, value));
+                        }
+                        None => {
+                            cache.removed.insert(key, previous.unwrap_or_default());
+                        }
+                    }
+                }
+                JournalOp::Drop { tree, cached } => {
+                    let cache = dropped.remove(&tree).unwrap_or_default();
+                    match cached {
+                        true => diff.caches.insert(tree, (cache, true)),
+                        false => diff.dropped_trees.insert(tree, (cache, false)),
+                    };
+                }
+            }
+        }
+
+        for (tree_key, cache) in restored {
+            diff.dropped_trees.insert(tree_key, (cache, true));
+        }
+
+        Ok(diff)
+    }
+
+    /// Returns the [`ApplyProgress`] of a pending chunked apply found in
+    /// the database, which must be resumed or rolled back, if any.
+    pub fn pending_apply(&self) -> Result<Option<ApplyProgress>, sled::Error> {
+        Ok(self
+            .read_journal()?
+            .map(|(_, header, progress)| header.apply_progress(progress.unwrap_or_default())))
+    }
+
+    /// Apply all the overlay changes to the database like
+    /// [`SledDbOverlay::apply`], but in chunks of at most `max_ops`
+    /// record writes, each committed in its own transaction along with
+    /// the journal progress marker. If interrupted, the apply can be
+    /// continued with [`SledDbOverlay::resume_apply`] or reverted with
+    /// [`SledDbOverlay::rollback_apply`]. The apply is not atomic, so
+    /// readers of the database can observe partially applied changes.
+    /// This function **does not** perform a db flush. This should be done
+    /// externally, since then there is a choice to perform either blocking
+    /// or async IO. After execution is successful, caller should *NOT* use
+    /// the overlay again.
+    pub fn apply_chunked(&mut self, max_ops: usize) -> Result<(), TransactionError<sled::Error>> {
+        self.apply_chunked_with_progress(max_ops, |_| {})
+    }
+
+    /// Apply all the overlay changes to the database in chunks, like
+    /// [`SledDbOverlay::apply_chunked`], reporting the [`ApplyProgress`]
+    /// to provided callback after each committed chunk.
+    pub fn apply_chunked_with_progress<F: FnMut(ApplyProgress)>(
+        &mut self,
+        max_ops: usize,
+        progress: F,
+    ) -> Result<(), TransactionError<sled::Error>> {
+        if max_ops == 0 {
+            return Err(invalid_chunk_size());
+        }
+
+        // Validate the changes through the pre-apply hooks
+        let diff = self.hooked_diff()?;
+        if let Some(diff) = &diff {
+            self.hooks.pre_apply(diff)?;
+        }
+
+        // Record the changes in the journal. A pending apply must be
+        // handled first, since it may have partially written keys we
+        // are going to change, so recording fails if one exists.
+        let frame = diff
+            .as_ref()
+            .and_then(|diff| self.published(diff))
+            .map(|(_, frame)| frame);
+        let (journal_tree, header) = self.record_journal(max_ops, frame)?;
+
+        self.execute_journal(&journal_tree, &header, 0, max_ops, progress, diff.as_ref())
+    }
+
+    /// Continue a pending chunked apply found in the database, in chunks
+    /// of at most `max_ops` record writes. Returns `false` if no apply
+    /// was pending. The post-apply hooks receive the diff rebuilt from the
+    /// journal, where the trees dropped before the interruption have an
+    /// empty last state.
+    pub fn resume_apply(&mut self, max_ops: usize) -> Result<bool, TransactionError<sled::Error>> {
+        self.resume_apply_with_progress(max_ops, |_| {})
+    }
+
+    /// Continue a pending chunked apply found in the database, like
+    /// [`SledDbOverlay::resume_apply`], reporting the [`ApplyProgress`]
+    /// to provided callback after each committed chunk.
+    pub fn resume_apply_with_progress<F: FnMut(ApplyProgress)>(
+        &mut self,
+        max_ops: usize,
+        progress: F,
+    ) -> Result<bool, TransactionError<sled::Error>> {
+        if max_ops == 0 {
+            return Err(invalid_chunk_size());
+        }
+
+        let Some((journal_tree, header, applied)) = self.read_journal()? else {
+            return Ok(false);
+        };
+
+        // An apply interrupted while recording it hasn't written anything
+        let Some(applied) = applied else {
+            return Err(TransactionError::Storage(sled::Error::Unsupported(
+                "Chunked apply journal is incomplete and must be rolled back".to_string(),
+            )));
+        };
+
+        self.execute_journal(&journal_tree, &header, applied, max_ops, progress, None)?;
+
+        Ok(true)
+    }
+
+    /// Write the remaining operations of provided journal, starting from
+    /// provided number of applied ones, then remove it. The diff being
+    /// applied is rebuilt from the journal if it's not provided.
+    fn execute_journal<F: FnMut(ApplyProgress)>(
+        &mut self,
+        journal_tree: &S::Tree,
+        header: &JournalHeader,
+        mut applied: usize,
+        max_ops: usize,
+        mut progress: F,
+        diff: Option<&SledDbOverlayStateDiff>,
+    ) -> Result<(), TransactionError<sled::Error>> {
+        // Ensure written trees exist
+        let state_trees = self.open_trees(header.written_trees.iter())?;
+
+        // Write the records in chunks
+        while applied < header.writes() {
+            let end = header.writes().min(applied + max_ops);
+
+            let ops = read_ops(journal_tree, applied, end)?;
+            let (mut trees, mut batches) =
+                chunk_batches(&state_trees, journal_tree, &ops, false, end)?;
+
+            // Commit the chunk along with the journal progress marker
+            self.db.transaction(&trees, &batches)?;
+            trees.pop();
+            batches.pop();
+
+            self.on_applied(&trees, &batches, std::iter::empty());
+            applied = end;
+            progress(header.apply_progress(applied));
+        }
+
+        // Drop removed trees, keeping their last state if we
+        // have to rebuild the diff for the post-apply hooks
+        let rebuild = diff.is_none() && !self.hooks.is_empty();
+        let mut dropped = BTreeMap::new();
+        while applied < header.total() {
+            let Some(JournalOp::Drop { tree, .. }) =
+                read_ops(journal_tree, applied, applied + 1)?.pop()
+            else {
+                return Err(TransactionError::Storage(invalid_journal()));
+            };
+
+            let last_state = match diff.and_then(|diff| {
+                diff.dropped_tree_diffs()
+                    .find(|(tree_key, _)| **tree_key == tree)
+            }) {
+                Some((_, last_state)) => last_state.clone(),
+                None if rebuild || self.watchers.watches(&tree) => self.dropped_tree_diff(&tree)?,
+                None => SledTreeOverlayStateDiff::default(),
+            };
+
+            self.db.drop_tree(&tree)?;
+            applied += 1;
+            journal_tree.apply_batch(&progress_batch(applied))?;
+            self.on_applied(&[], &[], std::iter::once((&tree, &last_state)));
+            progress(header.apply_progress(applied));
+
+            if rebuild {
+                dropped.insert(tree, last_state);
+            }
+        }
+
+        // Record the diff to publish, so it can't get lost once
+        // the journal is removed, and send it
+        self.publish_journal(journal_tree)?;
+
+        // Remove the journal and execute the post-apply hooks
+        match diff {
+            Some(diff) => {
+                self.db.drop_tree(APPLY_JOURNAL_TREE)?;
+                self.hooks.post_apply(diff);
+            }
+            None if rebuild => {
+                let diff = self.rebuild_diff(journal_tree, header, dropped)?;
+                self.db.drop_tree(APPLY_JOURNAL_TREE)?;
+                self.hooks.post_apply(&diff);
+            }
+            None => {
+                self.db.drop_tree(APPLY_JOURNAL_TREE)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record the encoded diff of provided journal in the replication tree
+    /// as the next published frame, in the same transaction as marking it
+    /// published in the journal, and send it through the attached publisher.
+    /// Without one, like when resuming the apply on a restarted leader, the
+    /// frame gets sent when it's resent from the replication tree.
+    fn publish_journal(&self, journal_tree: &S::Tree) -> Result<(), TransactionError<sled::Error>> {
+        let Some(diff) = journal_tree.get(FRAME_KEY)? else {
+            return Ok(());
+        };
+        if journal_tree.contains_key(PUBLISHED_KEY)? {
+            return Ok(());
+        }
+
+        let commit = |tree: S::Tree, batch: KvBatch| {
+            let mut published = KvBatch::default();
+            published.insert(PUBLISHED_KEY, IVec::default());
+            self.db
+                .transaction(&[tree, journal_tree.clone()], &[batch, published])
+        };
+
+        match &self.publisher {
+            Some(publisher) => {
+                let ((), _, sent) = publisher.commit(&self.db, &diff, commit)?;
+                publisher.keep_error(sent);
+            }
+            None => {
+                let tree = self.db.open_tree(REPLICATION_TREE)?;
+                let (batch, _, _) = outbox_batch(&tree, &diff, None)?;
+                commit(tree, batch)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Revert a pending chunked apply found in the database, restoring
+    /// the previous values of the written keys in chunks of at most
+    /// `max_ops` record writes, and dropping the trees it created. An
+    /// interrupted rollback can be continued by calling this again.
+    /// Returns `false` if no apply was pending. Once the apply has started
+    /// dropping trees, or its diff got published, it can't be rolled back
+    /// anymore, and must be resumed.
+    pub fn rollback_apply(
+        &mut self,
+        max_ops: usize,
+    ) -> Result<bool, TransactionError<sled::Error>> {
+        if max_ops == 0 {
+            return Err(invalid_chunk_size());
+        }
+
+        let Some((journal_tree, header, applied)) = self.read_journal()? else {
+            return Ok(false);
+        };
+
+        // An apply interrupted while recording it hasn't written anything
+        let Some(mut applied) = applied else {
+            self.db.drop_tree(APPLY_JOURNAL_TREE)?;
+            return Ok(true);
+        };
+
+        if applied > header.writes() {
+            return Err(TransactionError::Storage(sled::Error::Unsupported(
+                "Chunked apply can't be rolled back after dropping trees".to_string(),
+            )));
+        }
+        if journal_tree.contains_key(PUBLISHED_KEY)? {
+            return Err(TransactionError::Storage(sled::Error::Unsupported(
+                "Chunked apply can't be rolled back after publishing it".to_string(),
+            )));
+        }
+
+        // Restore the previous values in chunks, from the last written ones
+        let state_trees = self.open_trees(header.written_trees.iter())?;
+        while applied > 0 {
+            let start = applied.saturating_sub(max_ops);
+
+            let ops = read_ops(&journal_tree, start, applied)?;
+            let (mut trees, mut batches) =
+                chunk_batches(&state_trees, &journal_tree, &ops, true, start)?;
+
+            // Commit the chunk along with the journal progress marker
+            self.db.transaction(&trees, &batches)?;
+            trees.pop();
+            batches.pop();
+
+            for cache in self.state.caches.values() {
+                cache.invalidate_read_cache();
+            }
+            for (tree, batch) in trees.iter().zip(batches.iter()) {
+                let tree_key = tree.name();
+                for (key, value) in batch.records.iter() {
+                    self.watchers
+                        .notify(&tree_key, key, value.as_ref(), EventKind::Reverted);
+                }
+            }
+            applied = start;
+        }
+
+        // Drop the trees the apply created
+        for tree_key in header.written_trees.iter() {
+            if !header.initial_tree_names.contains(tree_key) {
+                self.db.drop_tree(tree_key)?;
+            }
+        }
+
+        // Remove the journal
+        self.db.drop_tree(APPLY_JOURNAL_TREE)?;
+
+        Ok(true)
+    }
+}
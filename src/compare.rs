@@ -0,0 +1,168 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Comparison of two databases, or two sets of trees, producing the
+//! [`SledDbOverlayStateDiff`] that turns the first into the second.
+//!
+//! Trees are matched by name. Trees only found in the first set are
+//! recorded as dropped trees, while trees only found in the second set
+//! are recorded as new trees, containing all their records. When comparing
+//! whole stores, the trees this crate reserves for its own bookkeeping, like
+//! the intent log, are skipped.
+
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use sled::IVec;
+
+use crate::{
+    backend::{KvStore, KvTree},
+    SledDbOverlayStateDiff, SledTreeOverlayStateDiff, APPLY_JOURNAL_TREE, INTENT_LOG_TREE,
+    REPLICATION_TREE,
+};
+
+/// Trees reserved for the crate bookkeeping, which are not compared.
+const RESERVED_TREES: [&[u8]; 3] = [INTENT_LOG_TREE, APPLY_JOURNAL_TREE, REPLICATION_TREE];
+
+/// Produce the [`SledDbOverlayStateDiff`] that turns the `from` store
+/// into the `to` one, when applied over it, skipping the trees reserved
+/// for the crate bookkeeping.
+pub fn diff_stores<A: KvStore, B: KvStore>(
+    from: &A,
+    to: &B,
+) -> Result<SledDbOverlayStateDiff, sled::Error> {
+    let from_trees = open_trees(from)?;
+    let to_trees = open_trees(to)?;
+    diff_trees(&from_trees, &to_trees)
+}
+
+/// Produce the [`SledDbOverlayStateDiff`] that turns the `from` set
+/// of trees into the `to` one, when applied over the store holding the
+/// `from` trees.
+pub fn diff_trees<A: KvTree, B: KvTree>(
+    from: &[A],
+    to: &[B],
+) -> Result<SledDbOverlayStateDiff, sled::Error> {
+    let from: BTreeMap<IVec, &A> = from.iter().map(|tree| (tree.name(), tree)).collect();
+    let to: BTreeMap<IVec, &B> = to.iter().map(|tree| (tree.name(), tree)).collect();
+
+    let mut diff = SledDbOverlayStateDiff {
+        initial_tree_names: from.keys().cloned().collect(),
+        ..Default::default()
+    };
+
+    for (tree_key, from_tree) in from.iter() {
+        match to.get(tree_key) {
+            Some(to_tree) => {
+                let tree_diff = diff_tree(*from_tree, *to_tree)?;
+                if !tree_diff.cache.is_empty() || !tree_diff.removed.is_empty() {
+                    diff.caches.insert(tree_key.clone(), (tree_diff, false));
+                }
+            }
+            None => {
+                let tree_diff = full_diff(*from_tree)?;
+                diff.dropped_trees
+                    .insert(tree_key.clone(), (tree_diff, false));
+            }
+        }
+    }
+
+    for (tree_key, to_tree) in to.iter() {
+        if from.contains_key(tree_key) {
+            continue;
+        }
+
+        let tree_diff = full_diff(*to_tree)?;
+        diff.caches.insert(tree_key.clone(), (tree_diff, false));
+    }
+
+    Ok(diff)
+}
+
+/// Auxilliary function to open all the trees of provided store,
+/// except the ones reserved for the crate bookkeeping.
+fn open_trees<S: KvStore>(store: &S) -> Result<Vec<S::Tree>, sled::Error> {
+    store
+        .tree_names()
+        .iter()
+        .filter(|tree_key| !RESERVED_TREES.contains(&tree_key.as_ref()))
+        .map(|tree_key| store.open_tree(tree_key))
+        .collect()
+}
+
+/// Auxilliary function to produce the diff containing all the
+/// records of provided tree as inserts.
+fn full_diff<T: KvTree>(tree: &T) -> Result<SledTreeOverlayStateDiff, sled::Error> {
+    let mut diff = SledTreeOverlayStateDiff::default();
+    for record in tree.iter() {
+        let (key, value) = record?;
+        diff.cache.insert(key, (None, value));
+    }
+
+    Ok(diff)
+}
+
+/// Auxilliary function to produce the diff that turns the `from` tree
+/// into the `to` one, by walking both trees in key order.
+fn diff_tree<A: KvTree, B: KvTree>(
+    from: &A,
+    to: &B,
+) -> Result<SledTreeOverlayStateDiff, sled::Error> {
+    let mut diff = SledTreeOverlayStateDiff::default();
+    let mut from_iter = from.iter();
+    let mut to_iter = to.iter();
+    let mut from_next = from_iter.next().transpose()?;
+    let mut to_next = to_iter.next().transpose()?;
+
+    loop {
+        match (from_next.take(), to_next.take()) {
+            (None, None) => break,
+            // Keys only in the `from` tree were removed
+            (Some((key, previous)), None) => {
+                diff.removed.insert(key, previous);
+                from_next = from_iter.next().transpose()?;
+            }
+            // Keys only in the `to` tree were inserted
+            (None, Some((key, value))) => {
+                diff.cache.insert(key, (None, value));
+                to_next = to_iter.next().transpose()?;
+            }
+            (Some((from_key, previous)), Some((to_key, value))) => match from_key.cmp(&to_key) {
+                Ordering::Less => {
+                    diff.removed.insert(from_key, previous);
+                    from_next = from_iter.next().transpose()?;
+                    to_next = Some((to_key, value));
+                }
+                Ordering::Greater => {
+                    diff.cache.insert(to_key, (None, value));
+                    from_next = Some((from_key, previous));
+                    to_next = to_iter.next().transpose()?;
+                }
+                // Keys in both trees were updated if their values differ
+                Ordering::Equal => {
+                    if previous != value {
+                        diff.cache.insert(to_key, (Some(previous), value));
+                    }
+                    from_next = from_iter.next().transpose()?;
+                    to_next = to_iter.next().transpose()?;
+                }
+            },
+        }
+    }
+
+    Ok(diff)
+}
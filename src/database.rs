@@ -16,32 +16,94 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
 
-use sled::{
-    transaction::{ConflictableTransactionError, TransactionError},
-    IVec, Transactional,
+use sled::{transaction::TransactionError, IVec};
+
+use crate::{
+    backend::{KvBatch, KvStore, KvTree},
+    digest::{digest_with, StateDigest, StateHasher},
+    hooks::Hooks,
+    index::{IndexFn, Indexes, RecordWrite, SecondaryIndex},
+    intent::{is_reserved, recover},
+    layer::LayerIter,
+    replication::DiffPublisher,
+    spill::temporary_db,
+    watch::{EventKind, Subscriber, Watchers, DEFAULT_SUBSCRIBER_CAPACITY},
+    SledTreeOverlay, SledTreeOverlayIter, SledTreeOverlayStateDiff,
 };
 
-use crate::{SledTreeOverlay, SledTreeOverlayIter, SledTreeOverlayStateDiff};
+#[cfg(any(feature = "blake3", feature = "sha256"))]
+use crate::{digest::DefaultStateHasher, merkle::MerkleProof};
+
+/// Status of a tree from the point of view of a [`SledDbOverlay`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeStatus {
+    /// The tree existed in the database when the overlay was created.
+    Existing,
+    /// The tree has been opened in the overlay, but didn't exist before.
+    New,
+    /// The tree has been dropped in the overlay.
+    Dropped,
+    /// The tree has been dropped and then reopened or restored
+    /// through a diff in the overlay.
+    Restored,
+}
+
+/// Options of [`SledDbOverlay::apply_with`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ApplyOptions {
+    /// Flush the database after applying the changes.
+    pub flush: bool,
+    /// Return the diff of the applied changes, with the previous
+    /// values of their keys captured within the transaction.
+    pub return_diff: bool,
+}
+
+/// Outcome of [`SledDbOverlay::apply_with`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ApplyOutcome {
+    /// Number of bytes flushed, if flushing was requested.
+    pub flushed_bytes: Option<usize>,
+    /// The applied changes, if their diff was requested.
+    pub diff: Option<SledDbOverlayStateDiff>,
+}
+
+/// A tree replaced by a copy of another tree records in the database.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TreeCopy {
+    /// The tree whose database records get copied.
+    pub source: IVec,
+    /// Last state full diff of the replaced tree, if it existed
+    /// in the database, so the copy can be reverted.
+    pub replaced: Option<SledTreeOverlayStateDiff>,
+}
 
 /// Struct representing [`SledDbOverlay`] cache state
 #[derive(Debug, Clone)]
-pub struct SledDbOverlayState {
+pub struct SledDbOverlayState<T: KvTree = sled::Tree> {
     /// Existing trees in `db` at the time of instantiation, so we can track newly opened trees.
     pub initial_tree_names: Vec<IVec>,
     /// New trees that have been opened, but didn't exist in `db` before.
     pub new_tree_names: Vec<IVec>,
     /// Pointers to [`SledTreeOverlay`] instances that have been created.
-    pub caches: BTreeMap<IVec, SledTreeOverlay>,
+    pub caches: BTreeMap<IVec, SledTreeOverlay<T>>,
     /// Trees that were dropped, along with their last state full diff.
     pub dropped_trees: BTreeMap<IVec, SledTreeOverlayStateDiff>,
+    /// Dropped trees that have been reopened or restored by a diff.
+    pub(crate) restored_tree_names: Vec<IVec>,
+    /// Trees replaced by a copy of another tree database records,
+    /// whose caches hold their changes over the copied records.
+    pub tree_copies: BTreeMap<IVec, TreeCopy>,
     /// Protected trees, that we don't allow their removal,
     /// and don't drop their references if they become stale.
     pub protected_tree_names: Vec<IVec>,
 }
 
-impl SledDbOverlayState {
+impl<T: KvTree> SledDbOverlayState<T> {
     /// Instantiate a new [`SledDbOverlayState`].
     pub fn new(initial_tree_names: Vec<IVec>, protected_tree_names: Vec<IVec>) -> Self {
         Self {
@@ -49,15 +111,50 @@ impl SledDbOverlayState {
             new_tree_names: vec![],
             caches: BTreeMap::new(),
             dropped_trees: BTreeMap::new(),
+            restored_tree_names: vec![],
+            tree_copies: BTreeMap::new(),
             protected_tree_names,
         }
     }
 
-    /// Aggregate all the current overlay changes into [`sled::Batch`] instances and
-    /// return vectors of [`sled::Tree`] and their respective [`sled::Batch`] that can
-    /// be used for further operations. If there are no changes, both vectors will be empty.
-    fn aggregate(&self) -> Result<(Vec<sled::Tree>, Vec<sled::Batch>), sled::Error> {
-        let mut trees = vec![];
+    /// Returns the status of provided tree, or `None` if it's unknown.
+    pub fn tree_status(&self, tree_key: &[u8]) -> Option<TreeStatus> {
+        let tree_key = IVec::from(tree_key);
+        if self.restored_tree_names.contains(&tree_key) {
+            return Some(TreeStatus::Restored);
+        }
+
+        if self.dropped_trees.contains_key(&tree_key) {
+            return Some(TreeStatus::Dropped);
+        }
+
+        if self.new_tree_names.contains(&tree_key) {
+            return Some(TreeStatus::New);
+        }
+
+        if self.initial_tree_names.contains(&tree_key) || self.caches.contains_key(&tree_key) {
+            return Some(TreeStatus::Existing);
+        }
+
+        None
+    }
+
+    /// Returns all known trees along with their status, ordered by name.
+    pub fn tree_statuses(&self) -> BTreeMap<IVec, TreeStatus> {
+        self.initial_tree_names
+            .iter()
+            .chain(self.new_tree_names.iter())
+            .chain(self.restored_tree_names.iter())
+            .chain(self.caches.keys())
+            .chain(self.dropped_trees.keys())
+            .filter_map(|tree_key| Some((tree_key.clone(), self.tree_status(tree_key)?)))
+            .collect()
+    }
+
+    /// Aggregate all the current overlay changes into [`KvBatch`] instances and
+    /// return them along with their tree keys, that can be used for further
+    /// operations. If there are no changes, the vector will be empty.
+    pub(crate) fn batches(&self) -> Result<Vec<(IVec, KvBatch)>, sled::Error> {
         let mut batches = vec![];
 
         for (key, cache) in self.caches.iter() {
@@ -65,28 +162,69 @@ impl SledDbOverlayState {
                 return Err(sled::Error::CollectionNotFound(key.into()));
             }
 
-            if let Some(batch) = cache.aggregate() {
-                trees.push(cache.tree.clone());
-                batches.push(batch);
+            if let Some(batch) = cache.try_kv_batch()? {
+                batches.push((key.clone(), batch));
             }
         }
 
-        Ok((trees, batches))
+        Ok(batches)
     }
 
     /// Add provided `db` overlay state changes to our own.
-    pub fn add_diff(
+    pub fn add_diff<S: KvStore<Tree = T>>(
         &mut self,
-        db: &sled::Db,
+        db: &S,
         diff: &SledDbOverlayStateDiff,
     ) -> Result<(), sled::Error> {
         self.initial_tree_names
             .retain(|x| diff.initial_tree_names.contains(x));
 
+        for (k, (copy, reverted)) in diff.tree_copies.iter() {
+            // Reverted copies of new trees get dropped along with their caches
+            if *reverted {
+                self.tree_copies.remove(k);
+                let Some(replaced) = &copy.replaced else {
+                    continue;
+                };
+
+                // Restore the replaced tree records
+                let mut overlay = SledTreeOverlay::new(&db.open_tree(k)?);
+                overlay.clear()?;
+                overlay.add_diff(replaced);
+                if !self.restored_tree_names.contains(k) {
+                    self.restored_tree_names.push(k.clone());
+                }
+                self.caches.insert(k.clone(), overlay);
+                continue;
+            }
+
+            // Layer the copy changes over its source tree records
+            let mut overlay = SledTreeOverlay::new(&db.open_tree(k)?);
+            overlay.base = Some(db.open_tree(&copy.source)?);
+            if let Some((cache, _)) = diff.caches.get(k) {
+                overlay.add_diff(cache);
+            }
+            if !self.initial_tree_names.contains(k) && !self.new_tree_names.contains(k) {
+                self.new_tree_names.push(k.clone());
+            }
+            if copy.replaced.is_some() && !self.restored_tree_names.contains(k) {
+                self.restored_tree_names.push(k.clone());
+            }
+            self.dropped_trees.remove(k);
+            self.caches.insert(k.clone(), overlay);
+            self.tree_copies.insert(k.clone(), copy.clone());
+        }
+
         for (k, (cache, drop)) in diff.caches.iter() {
+            // Copies have been handled above
+            if diff.copies_tree(k) {
+                continue;
+            }
+
             if *drop {
                 assert!(!self.protected_tree_names.contains(k));
                 self.new_tree_names.retain(|x| x != k);
+                self.restored_tree_names.retain(|x| x != k);
                 self.caches.remove(k);
                 self.dropped_trees.insert(k.clone(), cache.clone());
                 continue;
@@ -113,6 +251,7 @@ impl SledDbOverlayState {
                     continue;
                 }
                 self.new_tree_names.retain(|x| x != k);
+                self.restored_tree_names.retain(|x| x != k);
                 self.caches.remove(k);
                 self.dropped_trees.insert(k.clone(), cache.clone());
                 continue;
@@ -124,6 +263,10 @@ impl SledDbOverlayState {
             if !self.new_tree_names.contains(k) {
                 self.new_tree_names.push(k.clone());
             }
+            if !self.restored_tree_names.contains(k) {
+                self.restored_tree_names.push(k.clone());
+            }
+            self.dropped_trees.remove(k);
 
             let mut overlay = SledTreeOverlay::new(&db.open_tree(k)?);
             overlay.add_diff(cache);
@@ -135,6 +278,17 @@ impl SledDbOverlayState {
 
     /// Remove provided `db` overlay state changes from our own.
     pub fn remove_diff(&mut self, diff: &SledDbOverlayStateDiff) {
+        // Applied copies now live in the database, while the
+        // records replaced by reverted ones got restored there.
+        for (k, (_, reverted)) in diff.tree_copies.iter() {
+            self.tree_copies.remove(k);
+            if !reverted {
+                if let Some(tree_overlay) = self.caches.get_mut(k) {
+                    tree_overlay.base = None;
+                }
+            }
+        }
+
         // We have some assertions here to catch catastrophic
         // logic bugs here, as all our fields are depending on each
         // other when checking for differences.
@@ -149,6 +303,18 @@ impl SledDbOverlayState {
                 self.initial_tree_names.push(k.clone());
             }
             self.new_tree_names.retain(|x| x != k);
+            self.restored_tree_names.retain(|x| x != k);
+
+            // Trees restored by a reverted copy are stale
+            if diff
+                .tree_copies
+                .get(k)
+                .is_some_and(|(_, reverted)| *reverted)
+                && !drop
+            {
+                self.caches.remove(k);
+                continue;
+            }
 
             // Check if tree is marked for drop
             if *drop {
@@ -171,11 +337,12 @@ impl SledDbOverlayState {
             };
 
             // If the state is unchanged, handle the stale tree
-            if tree_overlay.state == cache.into() {
+            if !tree_overlay.is_spilled() && tree_overlay.state == cache.into() {
                 // If tree is protected, we simply reset its cache
                 if self.protected_tree_names.contains(k) {
                     tree_overlay.state.cache = BTreeMap::new();
                     tree_overlay.state.removed = BTreeSet::new();
+                    tree_overlay.recompute_memory_usage();
                     tree_overlay.checkpoint();
                     continue;
                 }
@@ -191,13 +358,16 @@ impl SledDbOverlayState {
 
         // Now we handle the dropped trees
         for (k, (cache, restored)) in diff.dropped_trees.iter() {
-            // We must know the tree
+            // We must know the tree, unless the diff dropped it above
             assert!(
                 self.initial_tree_names.contains(k)
                     || self.new_tree_names.contains(k)
                     || self.dropped_trees.contains_key(k)
+                    || diff.caches.get(k).is_some_and(|(_, drop)| *drop)
             );
 
+            self.restored_tree_names.retain(|x| x != k);
+
             // Drop the trees that are not restored
             if !restored {
                 assert!(!self.protected_tree_names.contains(k));
@@ -220,11 +390,12 @@ impl SledDbOverlayState {
             };
 
             // If the state is unchanged, handle the stale tree
-            if tree_overlay.state == cache.into() {
+            if !tree_overlay.is_spilled() && tree_overlay.state == cache.into() {
                 // If tree is protected, we simply reset its cache
                 if self.protected_tree_names.contains(k) {
                     tree_overlay.state.cache = BTreeMap::new();
                     tree_overlay.state.removed = BTreeSet::new();
+                    tree_overlay.recompute_memory_usage();
                     tree_overlay.checkpoint();
                     continue;
                 }
@@ -240,7 +411,7 @@ impl SledDbOverlayState {
     }
 }
 
-impl Default for SledDbOverlayState {
+impl<T: KvTree> Default for SledDbOverlayState<T> {
     fn default() -> Self {
         Self::new(vec![], vec![])
     }
@@ -262,12 +433,18 @@ pub struct SledDbOverlayStateDiff {
     /// always set to false, and change to true when we inverse the diff, unless
     /// the tree is a new tree(not in our initial tree names).
     pub dropped_trees: BTreeMap<IVec, (SledTreeOverlayStateDiff, bool)>,
+    /// Trees replaced by a copy of another tree database records, along with a
+    /// boolean flag indicating if the copy should be reverted. Their cache diffs
+    /// hold their changes over the copied records. The revert flag is always set
+    /// to false, and change to true when we inverse the diff, in which case the
+    /// tree gets dropped, or its replaced records get restored.
+    pub tree_copies: BTreeMap<IVec, (TreeCopy, bool)>,
 }
 
 impl SledDbOverlayStateDiff {
     /// Instantiate a new [`SledDbOverlayStateDiff`], over the provided
     /// [`SledDbOverlayState`].
-    pub fn new(state: &SledDbOverlayState) -> Result<Self, sled::Error> {
+    pub fn new<T: KvTree>(state: &SledDbOverlayState<T>) -> Result<Self, sled::Error> {
         let mut caches = BTreeMap::new();
         let mut dropped_trees = BTreeMap::new();
 
@@ -278,6 +455,7 @@ impl SledDbOverlayStateDiff {
             if diff.cache.is_empty()
                 && diff.removed.is_empty()
                 && !state.new_tree_names.contains(key)
+                && !state.tree_copies.contains_key(key)
             {
                 continue;
             }
@@ -289,22 +467,40 @@ impl SledDbOverlayStateDiff {
             dropped_trees.insert(key.clone(), (cache.clone(), false));
         }
 
+        let tree_copies = state
+            .tree_copies
+            .iter()
+            .map(|(key, copy)| (key.clone(), (copy.clone(), false)))
+            .collect();
+
         Ok(Self {
             initial_tree_names: state.initial_tree_names.clone(),
             caches,
             dropped_trees,
+            tree_copies,
         })
     }
 
-    /// Aggregate all the overlay changes into [`sled::Batch`] instances and
-    /// return vectors of [`sled::Tree`] and their respective [`sled::Batch`] that can
-    /// be used for further operations. If there are no changes, both vectors will be empty.
-    /// Provided state trees must contain all the [`sled::Tree`] pointers the diff mutates.
-    fn aggregate(
-        &self,
-        state_trees: &BTreeMap<IVec, sled::Tree>,
-    ) -> Result<(Vec<sled::Tree>, Vec<sled::Batch>), sled::Error> {
-        let mut trees = vec![];
+    /// Returns `true` if provided tree gets replaced by a copy,
+    /// or gets its records restored by reverting one.
+    pub(crate) fn copies_tree(&self, tree_key: &[u8]) -> bool {
+        self.tree_copies
+            .get(tree_key)
+            .is_some_and(|(copy, reverted)| !reverted || copy.replaced.is_some())
+    }
+
+    /// Returns the applied copies, along with their source trees.
+    pub(crate) fn applied_copies(&self) -> impl Iterator<Item = (&IVec, &IVec)> {
+        self.tree_copies
+            .iter()
+            .filter(|(_, (_, reverted))| !reverted)
+            .map(|(tree_key, (copy, _))| (tree_key, &copy.source))
+    }
+
+    /// Aggregate all the overlay changes into [`KvBatch`] instances and
+    /// return them along with their tree keys, that can be used for further
+    /// operations. If there are no changes, the vector will be empty.
+    pub(crate) fn batches(&self) -> Vec<(IVec, KvBatch)> {
         let mut batches = vec![];
 
         for (key, (cache, drop)) in self.caches.iter() {
@@ -312,13 +508,13 @@ impl SledDbOverlayStateDiff {
                 continue;
             }
 
-            let Some(tree) = state_trees.get(key) else {
-                return Err(sled::Error::CollectionNotFound(key.into()));
+            // Reverted copies restore the records they replaced
+            let batch = match self.tree_copies.get(key) {
+                Some((copy, true)) => copy.replaced.as_ref().and_then(|r| r.kv_batch()),
+                _ => cache.kv_batch(),
             };
-
-            if let Some(batch) = cache.aggregate() {
-                trees.push(tree.clone());
-                batches.push(batch);
+            if let Some(batch) = batch {
+                batches.push((key.clone(), batch));
             }
         }
 
@@ -327,17 +523,12 @@ impl SledDbOverlayStateDiff {
                 continue;
             }
 
-            let Some(tree) = state_trees.get(key) else {
-                return Err(sled::Error::CollectionNotFound(key.into()));
-            };
-
-            if let Some(batch) = cache.aggregate() {
-                trees.push(tree.clone());
-                batches.push(batch);
+            if let Some(batch) = cache.kv_batch() {
+                batches.push((key.clone(), batch));
             }
         }
 
-        Ok((trees, batches))
+        batches
     }
 
     /// Produces a [`SledDbOverlayStateDiff`] containing the inverse
@@ -350,9 +541,12 @@ impl SledDbOverlayStateDiff {
 
         for (key, (cache, drop)) in self.caches.iter() {
             let inverse = cache.inverse();
-            // Flip its drop flag if its a new empty tree, otherwise
-            // check if its cache is empty and its a new tree.
-            let drop = if inverse.cache.is_empty()
+            // Reverted copies drop the tree, unless they replaced one. Otherwise,
+            // flip its drop flag if its a new empty tree, or check if its cache is
+            // empty and its a new tree.
+            let drop = if let Some((copy, reverted)) = self.tree_copies.get(key) {
+                !reverted && copy.replaced.is_none()
+            } else if inverse.cache.is_empty()
                 && inverse.removed.is_empty()
                 && !self.initial_tree_names.contains(key)
             {
@@ -371,6 +565,11 @@ impl SledDbOverlayStateDiff {
                 .insert(key.clone(), (cache.clone(), !restored));
         }
 
+        for (key, (copy, reverted)) in self.tree_copies.iter() {
+            diff.tree_copies
+                .insert(key.clone(), (copy.clone(), !reverted));
+        }
+
         diff
     }
 
@@ -383,6 +582,13 @@ impl SledDbOverlayStateDiff {
             assert!(self.initial_tree_names.contains(initial_tree_name));
         }
 
+        // Remove the copies we share
+        for (key, copy) in other.tree_copies.iter() {
+            if self.tree_copies.get(key) == Some(copy) {
+                self.tree_copies.remove(key);
+            }
+        }
+
         // First we remove each cache diff
         for (key, cache_pair) in other.caches.iter() {
             if !self.initial_tree_names.contains(key) {
@@ -433,30 +639,133 @@ impl SledDbOverlayStateDiff {
         }
     }
 
+    /// Returns the last state diffs of the trees the diff drops.
+    pub(crate) fn dropped_tree_diffs(
+        &self,
+    ) -> impl Iterator<Item = (&IVec, &SledTreeOverlayStateDiff)> {
+        let dropped_caches = self
+            .caches
+            .iter()
+            .filter(|(_, (_, drop))| *drop)
+            .map(|(tree_key, (diff, _))| (tree_key, diff));
+        let dropped_trees = self
+            .dropped_trees
+            .iter()
+            .filter(|(_, (_, restored))| !restored)
+            .map(|(tree_key, (diff, _))| (tree_key, diff));
+
+        dropped_caches.chain(dropped_trees)
+    }
+
     /// Auxilliary function to retrieve our newly opened trees.
     pub fn new_trees(&self) -> Vec<IVec> {
         let mut new_trees: Vec<IVec> = self.caches.keys().cloned().collect();
         new_trees.retain(|tree| !self.initial_tree_names.contains(tree));
         new_trees
     }
+
+    /// Produce a deterministic [`StateDigest`] of the diff, using the
+    /// hash function selected by the enabled cargo features.
+    #[cfg(any(feature = "blake3", feature = "sha256"))]
+    pub fn digest(&self) -> StateDigest {
+        self.digest_with::<DefaultStateHasher>()
+    }
+
+    /// Produce a deterministic [`StateDigest`] of the diff, using the
+    /// provided [`StateHasher`]. The initial tree names depend on unrelated
+    /// trees of the database, so they are not part of the digest.
+    pub fn digest_with<H: StateHasher>(&self) -> StateDigest {
+        digest_with::<H, _>(&(&self.caches, (&self.dropped_trees, &self.tree_copies)))
+    }
 }
 
-/// An overlay on top of an entire [`sled::Db`] which can span multiple trees
+/// A tree operation to perform on a [`sled::Db`] before applying batches.
+#[derive(Debug, Clone)]
+pub(crate) enum TreeOp {
+    /// Ensure the tree exists, opening it if we don't have its pointer yet.
+    Open(IVec),
+    /// Drop the tree, forgetting its pointer.
+    Drop(IVec),
+}
+
+/// Execute provided tree operations over the [`KvStore`] in order,
+/// keeping track of the tree pointers in provided map.
+pub(crate) fn execute_tree_ops<S: KvStore>(
+    db: &S,
+    ops: &[TreeOp],
+    trees: &mut BTreeMap<IVec, S::Tree>,
+) -> Result<(), sled::Error> {
+    for op in ops {
+        match op {
+            TreeOp::Open(tree_key) => {
+                if !trees.contains_key(tree_key) {
+                    trees.insert(tree_key.clone(), db.open_tree(tree_key)?);
+                }
+            }
+            TreeOp::Drop(tree_key) => {
+                trees.remove(tree_key);
+                db.drop_tree(tree_key)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// An overlay on top of an entire [`KvStore`] which can span multiple trees,
+/// which is a [`sled::Db`] by default.
 #[derive(Clone)]
-pub struct SledDbOverlay {
-    /// The [`sled::Db`] that is being overlayed.
-    db: sled::Db,
+pub struct SledDbOverlay<S: KvStore = sled::Db> {
+    /// The [`KvStore`] that is being overlayed.
+    pub(crate) db: S,
     /// Current overlay cache state
-    pub state: SledDbOverlayState,
+    pub state: SledDbOverlayState<S::Tree>,
     /// Checkpointed cache state to revert to
-    checkpoint: SledDbOverlayState,
+    pub(crate) checkpoint: SledDbOverlayState<S::Tree>,
+    /// Memory budget of the cache state, in bytes, after which
+    /// it gets spilled into a temporary [`sled::Db`].
+    memory_limit: Option<usize>,
+    /// Temporary [`sled::Db`] holding the spilled cache state.
+    spill_db: Option<sled::Db>,
+    /// Capacity of each tree overlay main tree values read cache.
+    read_cache_capacity: Option<usize>,
+    /// Subscribers of the overlay changes.
+    pub(crate) watchers: Watchers,
+    /// Hooks executed when applying the overlay changes.
+    pub(crate) hooks: Hooks,
+    /// Secondary indexes maintained by the overlay.
+    pub(crate) indexes: Indexes,
+    /// Publisher of the applied diffs, recording them along with the changes.
+    pub(crate) publisher: Option<DiffPublisher<S>>,
 }
 
-impl SledDbOverlay {
-    /// Instantiate a new [`SledDbOverlay`] on top of a given [`sled::Db`].
+impl<S: KvStore> SledDbOverlay<S> {
+    /// Instantiate a new [`SledDbOverlay`] on top of a given [`KvStore`].
     /// Note: Provided protected trees don't have to be opened as protected,
-    /// as they are setup as protected here.
-    pub fn new(db: &sled::Db, protected_tree_names: Vec<&[u8]>) -> Self {
+    /// as they are setup as protected here. Interrupted applies found in the
+    /// [`INTENT_LOG_TREE`](crate::INTENT_LOG_TREE) get recovered first. If
+    /// recovering fails, applying our changes retries it and returns its
+    /// error, while [`SledDbOverlay::try_new`] returns it right away.
+    pub fn new(db: &S, protected_tree_names: Vec<&[u8]>) -> Self {
+        // Recover interrupted applies. This is best effort, since
+        // they also get recovered before applying our changes.
+        let _ = recover(db);
+
+        Self::recovered(db, protected_tree_names)
+    }
+
+    /// Instantiate a new [`SledDbOverlay`] like [`SledDbOverlay::new`],
+    /// returning the error of recovering the interrupted applies, if any.
+    pub fn try_new(db: &S, protected_tree_names: Vec<&[u8]>) -> Result<Self, sled::Error> {
+        recover(db)?;
+
+        Ok(Self::recovered(db, protected_tree_names))
+    }
+
+    /// Auxilliary function to instantiate a new [`SledDbOverlay`] on top
+    /// of a given [`KvStore`], whose interrupted applies got recovered,
+    /// so its existing trees are final.
+    fn recovered(db: &S, protected_tree_names: Vec<&[u8]>) -> Self {
         let initial_tree_names = db.tree_names();
         let protected_tree_names: Vec<IVec> = protected_tree_names
             .into_iter()
@@ -469,9 +778,492 @@ impl SledDbOverlay {
                 protected_tree_names.clone(),
             ),
             checkpoint: SledDbOverlayState::new(initial_tree_names, protected_tree_names),
+            memory_limit: None,
+            spill_db: None,
+            read_cache_capacity: None,
+            watchers: Watchers::default(),
+            hooks: Hooks::default(),
+            indexes: Indexes::default(),
+            publisher: None,
         }
     }
 
+    /// Set the capacity of each tree overlay main tree values read cache,
+    /// in number of keys. Passing `None` disables the caches, which is the
+    /// default. The caches are invalidated when the overlay gets applied.
+    /// See [`SledTreeOverlay::set_read_cache_capacity`] for details.
+    pub fn set_read_cache_capacity(&mut self, capacity: Option<usize>) {
+        self.read_cache_capacity = capacity;
+        self.configure_read_caches();
+    }
+
+    /// Ensure all tree overlays read cache has our configured capacity.
+    fn configure_read_caches(&mut self) {
+        for cache in self.state.caches.values_mut() {
+            if cache.read_cache_capacity() != self.read_cache_capacity {
+                cache.set_read_cache_capacity(self.read_cache_capacity);
+            }
+        }
+    }
+
+    /// Subscribe to the changes of keys starting with provided prefix
+    /// in the specified tree. The returned [`Subscriber`] receives an
+    /// event whenever the overlay writes a matching key, and again when
+    /// the change gets applied, reverted to our checkpoint, or merged
+    /// through [`SledDbOverlay::add_diff`]. Clones of the overlay don't
+    /// inherit its subscribers. The subscriber buffers up to
+    /// [`DEFAULT_SUBSCRIBER_CAPACITY`] events, dropping new ones while
+    /// full, as described in [`Subscriber::missed`].
+    pub fn watch_prefix(&self, tree_key: &[u8], prefix: &[u8]) -> Subscriber {
+        self.watch_prefix_with_capacity(tree_key, prefix, DEFAULT_SUBSCRIBER_CAPACITY)
+    }
+
+    /// Subscribe to the changes of keys starting with provided prefix
+    /// in the specified tree, buffering up to provided number of events.
+    /// A zero capacity only delivers events while the subscriber is
+    /// blocked waiting for one. See [`SledDbOverlay::watch_prefix`] for
+    /// details.
+    pub fn watch_prefix_with_capacity(
+        &self,
+        tree_key: &[u8],
+        prefix: &[u8],
+        capacity: usize,
+    ) -> Subscriber {
+        self.watchers.subscribe(tree_key, prefix, capacity)
+    }
+
+    /// Register a hook executed before [`SledDbOverlay::apply`] and
+    /// [`SledDbOverlay::apply_diff`] touch the database. The hook receives
+    /// the changes about to be applied, and can veto them by returning an
+    /// error, which aborts the application as a [`TransactionError::Abort`].
+    /// Hooks are executed in registration order, and clones of the overlay
+    /// share them.
+    pub fn add_pre_apply_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&SledDbOverlayStateDiff) -> Result<(), sled::Error> + Send + Sync + 'static,
+    {
+        self.hooks.add_pre_apply(hook);
+    }
+
+    /// Register a hook executed after [`SledDbOverlay::apply`] and
+    /// [`SledDbOverlay::apply_diff`] have successfully applied the changes,
+    /// receiving the applied changes. Hooks are executed in registration
+    /// order, and clones of the overlay share them.
+    pub fn add_post_apply_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(&SledDbOverlayStateDiff) + Send + Sync + 'static,
+    {
+        self.hooks.add_post_apply(hook);
+    }
+
+    /// Remove all registered pre-apply and post-apply hooks.
+    pub fn clear_apply_hooks(&mut self) {
+        self.hooks.clear();
+    }
+
+    /// Declare a secondary index of the source tree into the index tree.
+    /// Provided function maps each source record key and value to its
+    /// index keys, and the index tree holds a record for each of them,
+    /// pointing back to the source record key. Index keys must be unique
+    /// across source records, so non-unique attributes should be suffixed
+    /// with the source record key.
+    /// From now on, every write, clear, [`SledDbOverlay::add_diff`] and
+    /// [`SledDbOverlay::try_remove_diff`] touching the source tree also updates
+    /// the index tree cache, so the index changes are checkpointed, reverted
+    /// and applied along with the source tree ones. Existing source records
+    /// are not indexed retroactively. Both trees must be already opened, and
+    /// clones of the overlay share the declared indexes.
+    pub fn add_index<F>(
+        &mut self,
+        source_tree: &[u8],
+        index_tree: &[u8],
+        index_keys: F,
+    ) -> Result<(), sled::Error>
+    where
+        F: Fn(&[u8], &[u8]) -> Vec<IVec> + Send + Sync + 'static,
+    {
+        self.get_cache(&source_tree.into())?;
+        self.get_cache(&index_tree.into())?;
+
+        let index_keys: IndexFn = Arc::new(index_keys);
+        self.indexes.add(SecondaryIndex {
+            source: source_tree.into(),
+            target: index_tree.into(),
+            index_keys,
+        })
+    }
+
+    /// Stop maintaining all the declared secondary indexes of the
+    /// source tree. Their index trees records are left intact.
+    pub fn remove_indexes(&mut self, source_tree: &[u8]) {
+        self.indexes.remove(source_tree);
+    }
+
+    /// Update the declared secondary indexes of the specified tree,
+    /// for a record changing from provided previous value to the new one.
+    /// Index records pointing to other source keys are left intact.
+    fn update_indexes(
+        &mut self,
+        tree_key: &[u8],
+        key: &[u8],
+        prev: Option<&[u8]>,
+        value: Option<&[u8]>,
+    ) -> Result<(), sled::Error> {
+        let mut writes = vec![];
+        self.plan_indexes(tree_key, key, prev, value, &mut writes)?;
+        self.write_records(&writes)?;
+        self.notify_pending(&writes);
+
+        Ok(())
+    }
+
+    /// Plan the writes updating the declared secondary indexes of the
+    /// specified tree, for a record changing from provided previous value
+    /// to the new one, without touching any tree cache. Index records
+    /// pointing to other source keys are left intact.
+    fn plan_indexes(
+        &self,
+        tree_key: &[u8],
+        key: &[u8],
+        prev: Option<&[u8]>,
+        value: Option<&[u8]>,
+        writes: &mut Vec<RecordWrite>,
+    ) -> Result<(), sled::Error> {
+        for index in self.indexes.of(tree_key) {
+            let old_keys = index.keys(key, prev);
+            let new_keys = index.keys(key, value);
+
+            // Remove stale index records, if they still point to the key
+            for index_key in old_keys.iter().filter(|k| !new_keys.contains(k)) {
+                if self
+                    .planned_get(&index.target, index_key, writes)?
+                    .as_deref()
+                    == Some(key)
+                {
+                    self.plan_write(&index.target, index_key, None, writes)?;
+                }
+            }
+
+            // Insert the new index records, if they don't exist already
+            for index_key in new_keys {
+                if self
+                    .planned_get(&index.target, &index_key, writes)?
+                    .as_deref()
+                    != Some(key)
+                {
+                    self.plan_write(&index.target, &index_key, Some(key), writes)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Plan a write of provided value into the specified tree key, along
+    /// with the secondary index writes it triggers, returning the value
+    /// it replaces. Any missing tree errors out before any cache changes.
+    fn plan_write(
+        &self,
+        tree_key: &[u8],
+        key: &[u8],
+        value: Option<&[u8]>,
+        writes: &mut Vec<RecordWrite>,
+    ) -> Result<Option<IVec>, sled::Error> {
+        let prev = self.planned_get(tree_key, key, writes)?;
+        writes.push((tree_key.into(), key.into(), value.map(IVec::from)));
+        self.plan_indexes(tree_key, key, prev.as_deref(), value, writes)?;
+
+        Ok(prev)
+    }
+
+    /// Retrieve the value of a key as it will be after provided planned writes.
+    fn planned_get(
+        &self,
+        tree_key: &[u8],
+        key: &[u8],
+        writes: &[RecordWrite],
+    ) -> Result<Option<IVec>, sled::Error> {
+        let planned = writes
+            .iter()
+            .rev()
+            .find(|(t, k, _)| t == tree_key && k == key);
+
+        match planned {
+            Some((_, _, value)) => Ok(value.clone()),
+            None => self.get(tree_key, key),
+        }
+    }
+
+    /// Execute provided planned writes in order on their tree caches.
+    fn write_records(&mut self, writes: &[RecordWrite]) -> Result<(), sled::Error> {
+        for (tree_key, key, value) in writes {
+            let cache = self.get_cache_mut(tree_key)?;
+            match value {
+                Some(value) => cache.insert(key, value)?,
+                None => cache.remove(key)?,
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Notify subscribers of provided executed writes.
+    fn notify_pending(&self, writes: &[RecordWrite]) {
+        for (tree_key, key, value) in writes {
+            self.watchers
+                .notify(tree_key, key, value.as_ref(), EventKind::Pending);
+        }
+    }
+
+    /// Retrieve the value of a key as seen through the overlay, even if
+    /// the specified tree is not opened in it, in which case we read it
+    /// from provided database trees, if it exists there.
+    fn visible_value(
+        &self,
+        tree_key: &IVec,
+        key: &[u8],
+        db_tree_names: &[IVec],
+    ) -> Result<Option<IVec>, sled::Error> {
+        if self.state.dropped_trees.contains_key(tree_key) {
+            return Ok(None);
+        }
+
+        if let Some(cache) = self.state.caches.get(tree_key) {
+            return cache.get(key);
+        }
+
+        if !db_tree_names.contains(tree_key) {
+            return Ok(None);
+        }
+
+        self.db.open_tree(tree_key)?.get(key)
+    }
+
+    /// Execute provided state mutation, updating the secondary indexes
+    /// for all the indexed keys provided diff changes whose value it
+    /// changed.
+    fn reconcile_indexes<F>(
+        &mut self,
+        diff: &SledDbOverlayStateDiff,
+        mutate: F,
+    ) -> Result<(), sled::Error>
+    where
+        F: FnOnce(&mut Self) -> Result<(), sled::Error>,
+    {
+        if self.indexes.is_empty() {
+            return mutate(self);
+        }
+
+        // Grab the indexed keys values before the mutation
+        let db_tree_names = self.db.tree_names();
+        let tree_diffs = diff
+            .caches
+            .iter()
+            .chain(diff.dropped_trees.iter())
+            .map(|(tree_key, (cache, _))| (tree_key, cache));
+        let mut records = vec![];
+        for (tree_key, cache) in tree_diffs {
+            if !self.indexes.is_source(tree_key) {
+                continue;
+            }
+
+            for key in cache.cache.keys().chain(cache.removed.keys()) {
+                let prev = self.visible_value(tree_key, key, &db_tree_names)?;
+                records.push((tree_key.clone(), key.clone(), prev));
+            }
+        }
+
+        mutate(self)?;
+
+        // Update the indexes of the keys that changed value
+        let db_tree_names = self.db.tree_names();
+        for (tree_key, key, prev) in records {
+            let value = self.visible_value(&tree_key, &key, &db_tree_names)?;
+            if prev != value {
+                self.update_indexes(&tree_key, &key, prev.as_deref(), value.as_deref())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle a successful write of provided batches into their trees,
+    /// along with the dropped trees last state diffs: the trees read
+    /// caches get invalidated and subscribers get notified.
+    pub(crate) fn on_applied<'a>(
+        &self,
+        trees: &[S::Tree],
+        batches: &[KvBatch],
+        dropped_trees: impl Iterator<Item = (&'a IVec, &'a SledTreeOverlayStateDiff)>,
+    ) {
+        for cache in self.state.caches.values() {
+            cache.invalidate_read_cache();
+        }
+
+        for (tree, batch) in trees.iter().zip(batches) {
+            let tree_key = tree.name();
+            if !self.watchers.watches(&tree_key) {
+                continue;
+            }
+
+            for (key, value) in batch.records.iter() {
+                self.watchers
+                    .notify(&tree_key, key, value.as_ref(), EventKind::Applied);
+            }
+        }
+
+        for (tree_key, diff) in dropped_trees {
+            if !self.watchers.watches(tree_key) {
+                continue;
+            }
+
+            for key in diff.cache.keys() {
+                self.watchers
+                    .notify(tree_key, key, None, EventKind::Applied);
+            }
+        }
+    }
+
+    /// Notify subscribers of the keys that changed value
+    /// by reverting provided state to our current one.
+    fn notify_reverted(&self, reverted: &SledDbOverlayState<S::Tree>) {
+        let tree_keys: BTreeSet<&IVec> = reverted
+            .caches
+            .keys()
+            .chain(self.state.caches.keys())
+            .collect();
+
+        for tree_key in tree_keys {
+            if !self.watchers.watches(tree_key) {
+                continue;
+            }
+
+            let before = reverted.caches.get(tree_key);
+            let after = self.state.caches.get(tree_key);
+
+            // Gather all keys changed in either state
+            let mut keys = BTreeSet::new();
+            for cache in [before, after].into_iter().flatten() {
+                let Ok(diff) = cache.diff(&[]) else {
+                    continue;
+                };
+                keys.extend(diff.cache.into_keys());
+                keys.extend(diff.removed.into_keys());
+            }
+
+            for key in keys {
+                // Missing tree overlays fall back to the main tree
+                let value = |cache: Option<&SledTreeOverlay<S::Tree>>| match cache {
+                    Some(cache) => cache.get(&key).ok().flatten(),
+                    None => [before, after]
+                        .into_iter()
+                        .flatten()
+                        .next()
+                        .and_then(|cache| cache.tree.get(&key).ok().flatten()),
+                };
+
+                let value_after = value(after);
+                if value(before) != value_after {
+                    self.watchers
+                        .notify(tree_key, &key, value_after.as_ref(), EventKind::Reverted);
+                }
+            }
+        }
+    }
+
+    /// Notify subscribers of the keys provided diff changes,
+    /// with the given event kind.
+    fn notify_diff(&self, diff: &SledDbOverlayStateDiff, kind: EventKind) {
+        for (tree_key, (cache, drop)) in diff.caches.iter() {
+            if !self.watchers.watches(tree_key) {
+                continue;
+            }
+
+            if *drop {
+                for key in cache.cache.keys() {
+                    self.watchers.notify(tree_key, key, None, kind);
+                }
+                continue;
+            }
+
+            for (key, (_, value)) in cache.cache.iter() {
+                self.watchers.notify(tree_key, key, Some(value), kind);
+            }
+
+            for key in cache.removed.keys() {
+                self.watchers.notify(tree_key, key, None, kind);
+            }
+        }
+
+        for (tree_key, (cache, restored)) in diff.dropped_trees.iter() {
+            if !self.watchers.watches(tree_key) {
+                continue;
+            }
+
+            for (key, (_, value)) in cache.cache.iter() {
+                let value = if *restored { Some(value) } else { None };
+                self.watchers.notify(tree_key, key, value, kind);
+            }
+        }
+    }
+
+    /// Set the memory budget of the overlay cache state, in bytes. Once it gets
+    /// exceeded, all tree overlays cache state is spilled into a temporary
+    /// [`sled::Db`], while all operations keep working transparently.
+    /// Passing `None` disables the budget, which is the default.
+    pub fn set_memory_limit(&mut self, memory_limit: Option<usize>) {
+        self.memory_limit = memory_limit;
+    }
+
+    /// Returns the approximate memory used by the overlay cache state, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.state
+            .caches
+            .values()
+            .map(|cache| cache.memory_usage())
+            .sum()
+    }
+
+    /// Returns the memory budget of the overlay cache state, in bytes, if set.
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory_limit
+    }
+
+    /// Spill all tree overlays cache state into our temporary [`sled::Db`],
+    /// if we have exceeded our memory budget.
+    pub(crate) fn enforce_memory_limit(&mut self) -> Result<(), sled::Error> {
+        let Some(memory_limit) = self.memory_limit else {
+            return Ok(());
+        };
+
+        if self.memory_usage() <= memory_limit {
+            return Ok(());
+        }
+
+        // Tracked usage is approximate, so we recompute it before spilling
+        for cache in self.state.caches.values_mut() {
+            cache.recompute_memory_usage();
+        }
+        if self.memory_usage() <= memory_limit {
+            return Ok(());
+        }
+
+        let spill_db = match &self.spill_db {
+            Some(spill_db) => spill_db.clone(),
+            None => {
+                let spill_db = temporary_db()?;
+                self.spill_db = Some(spill_db.clone());
+                spill_db
+            }
+        };
+
+        for cache in self.state.caches.values_mut() {
+            cache.spill(&spill_db)?;
+        }
+
+        Ok(())
+    }
+
     /// Create a new [`SledTreeOverlay`] on top of a given `tree_name`.
     /// This function will also open a new tree inside `db` regardless of if it has
     /// existed before, so for convenience, we also provide [`SledDbOverlay::purge_new_trees`]
@@ -489,10 +1281,12 @@ impl SledDbOverlay {
         // Open this tree in sled
         let tree = self.db.open_tree(&tree_key)?;
         let mut cache = SledTreeOverlay::new(&tree);
+        cache.set_read_cache_capacity(self.read_cache_capacity);
 
         // If we are reopenning a dropped tree, grab its cache
         if let Some(diff) = self.state.dropped_trees.remove(&tree_key) {
             cache.state = (&diff).into();
+            self.state.restored_tree_names.push(tree_key.clone());
         }
 
         // In case it hasn't existed before, we also need to track it
@@ -516,7 +1310,7 @@ impl SledDbOverlay {
         let tree_key: IVec = tree_name.into();
 
         // Check if tree is protected
-        if self.state.protected_tree_names.contains(&tree_key) {
+        if self.state.protected_tree_names.contains(&tree_key) || is_reserved(&tree_key) {
             return Err(sled::Error::Unsupported(
                 "Protected tree can't be dropped".to_string(),
             ));
@@ -527,6 +1321,16 @@ impl SledDbOverlay {
             return Err(sled::Error::CollectionNotFound(tree_key));
         }
 
+        // Dropping a copy restores the records it replaced, if any
+        if let Some(copy) = self.state.tree_copies.remove(&tree_key) {
+            if let Some(replaced) = copy.replaced {
+                self.state.caches.remove(&tree_key);
+                self.state.restored_tree_names.retain(|x| *x != tree_key);
+                self.state.dropped_trees.insert(tree_key, replaced);
+                return Ok(());
+            }
+        }
+
         // Check if its a new tree we created
         if self.state.new_tree_names.contains(&tree_key) {
             self.state.new_tree_names.retain(|x| *x != tree_key);
@@ -536,6 +1340,7 @@ impl SledDbOverlay {
             };
             let diff = SledTreeOverlayStateDiff::new_dropped(tree);
             self.state.caches.remove(&tree_key);
+            self.state.restored_tree_names.retain(|x| *x != tree_key);
             self.state.dropped_trees.insert(tree_key, diff);
 
             return Ok(());
@@ -552,11 +1357,115 @@ impl SledDbOverlay {
         };
         let diff = SledTreeOverlayStateDiff::new_dropped(tree);
         self.state.caches.remove(&tree_key);
+        self.state.restored_tree_names.retain(|x| *x != tree_key);
         self.state.dropped_trees.insert(tree_key, diff);
 
         Ok(())
     }
 
+    /// Copy all records of an opened tree into a new tree, which gets
+    /// opened in the overlay. The target tree must not exist, unless it
+    /// has been dropped in the overlay, in which case its records get
+    /// replaced. The copy is recorded as a tree operation: the target
+    /// tree cache starts as a copy of the source tree one, and reads
+    /// fall through to the source tree records in the database, so
+    /// this takes time linear to the source tree changes, instead of
+    /// its size. On apply, the source tree records get copied within
+    /// the transaction writing the rest of the changes.
+    pub fn copy_tree(&mut self, from: &[u8], to: &[u8]) -> Result<(), sled::Error> {
+        let to_key: IVec = to.into();
+        if from == to {
+            return Err(sled::Error::Unsupported(
+                "Tree can't be copied into itself".to_string(),
+            ));
+        }
+
+        // Check the target tree doesn't exist
+        if self.state.caches.contains_key(&to_key)
+            || (self.state.initial_tree_names.contains(&to_key)
+                && !self.state.dropped_trees.contains_key(&to_key))
+            || is_reserved(to)
+        {
+            return Err(sled::Error::Unsupported(
+                "Target tree already exists".to_string(),
+            ));
+        }
+
+        // Copies of copies read the records of their source tree
+        let source = self.get_cache(&from.into())?;
+        let copy = TreeCopy {
+            source: match self.state.tree_copies.get(from) {
+                Some(copy) => copy.source.clone(),
+                None => from.into(),
+            },
+            replaced: None,
+        };
+        let mut cache = source.clone();
+        cache.base = Some(source.base_tree().clone());
+        cache.tree = self.db.open_tree(&to_key)?;
+        cache.set_read_cache_capacity(self.read_cache_capacity);
+
+        // Keep the records of a replaced tree, so the copy can be reverted
+        let replaced = self.state.dropped_trees.remove(&to_key);
+        let copy = match self.state.initial_tree_names.contains(&to_key) {
+            true => {
+                self.state.restored_tree_names.push(to_key.clone());
+                TreeCopy { replaced, ..copy }
+            }
+            false => {
+                self.state.new_tree_names.push(to_key.clone());
+                copy
+            }
+        };
+
+        self.state.caches.insert(to_key.clone(), cache);
+        self.state.tree_copies.insert(to_key, copy);
+
+        self.enforce_memory_limit()
+    }
+
+    /// Rename an opened tree, by copying it into a new tree using
+    /// [`SledDbOverlay::copy_tree`] and dropping it afterwards. Like
+    /// the copy, the rename is recorded as tree operations, so a diff
+    /// containing it, and its inverse, express it as the new tree copy
+    /// and the old tree drop. Protected trees can't be renamed.
+    pub fn rename_tree(&mut self, from: &[u8], to: &[u8]) -> Result<(), sled::Error> {
+        // Check if tree is protected
+        if self.state.protected_tree_names.contains(&from.into()) {
+            return Err(sled::Error::Unsupported(
+                "Protected tree can't be renamed".to_string(),
+            ));
+        }
+
+        self.copy_tree(from, to)?;
+        self.drop_tree(from)
+    }
+
+    /// Returns the names of all trees existing from the point of view of
+    /// the overlay: the database trees it was created on and the trees it
+    /// opened, excluding the ones it dropped, ordered by name.
+    pub fn tree_names(&self) -> Vec<IVec> {
+        self.iter_trees()
+            .filter(|(_, status)| *status != TreeStatus::Dropped)
+            .map(|(tree_key, _)| tree_key)
+            .collect()
+    }
+
+    /// Returns `true` if provided tree exists from the
+    /// point of view of the overlay.
+    pub fn tree_exists(&self, tree_name: &[u8]) -> bool {
+        !matches!(
+            self.state.tree_status(tree_name),
+            None | Some(TreeStatus::Dropped)
+        )
+    }
+
+    /// Iterate over all trees known to the overlay, including the
+    /// dropped ones, along with their [`TreeStatus`], ordered by name.
+    pub fn iter_trees(&self) -> impl Iterator<Item = (IVec, TreeStatus)> {
+        self.state.tree_statuses().into_iter()
+    }
+
     /// Drop newly created trees from the sled database. This is a convenience
     /// function that should be used when we decide that we don't want to apply
     /// any cache changes, and we want to revert back to the initial state.
@@ -569,7 +1478,7 @@ impl SledDbOverlay {
     }
 
     /// Fetch the cache for a given tree.
-    fn get_cache(&self, tree_key: &IVec) -> Result<&SledTreeOverlay, sled::Error> {
+    fn get_cache(&self, tree_key: &IVec) -> Result<&SledTreeOverlay<S::Tree>, sled::Error> {
         if self.state.dropped_trees.contains_key(tree_key) {
             return Err(sled::Error::CollectionNotFound(tree_key.into()));
         }
@@ -582,7 +1491,10 @@ impl SledDbOverlay {
     }
 
     /// Fetch a mutable reference to the cache for a given tree.
-    fn get_cache_mut(&mut self, tree_key: &IVec) -> Result<&mut SledTreeOverlay, sled::Error> {
+    fn get_cache_mut(
+        &mut self,
+        tree_key: &IVec,
+    ) -> Result<&mut SledTreeOverlay<S::Tree>, sled::Error> {
         if self.state.dropped_trees.contains_key(tree_key) {
             return Err(sled::Error::CollectionNotFound(tree_key.into()));
         }
@@ -593,8 +1505,67 @@ impl SledDbOverlay {
         Err(sled::Error::CollectionNotFound(tree_key.clone()))
     }
 
-    /// Fetch all our caches current [`sled::Tree`] pointers.
-    pub fn get_state_trees(&self) -> BTreeMap<IVec, sled::Tree> {
+    /// Retrieve provided tree from the database,
+    /// without creating it if it doesn't exist.
+    fn existing_tree(&self, tree_key: &[u8]) -> Result<Option<S::Tree>, sled::Error> {
+        if !self.db.tree_names().contains(&IVec::from(tree_key)) {
+            return Ok(None);
+        }
+
+        Ok(Some(self.db.open_tree(tree_key)?))
+    }
+
+    /// Retrieve a value from the specified tree as if it was opened,
+    /// without opening it. Dropped trees are read with their cache
+    /// state restored, as reopening them does, while trees missing
+    /// from the database are read as empty.
+    pub(crate) fn get_unopened(
+        &self,
+        tree_key: &[u8],
+        key: &[u8],
+    ) -> Result<Option<IVec>, sled::Error> {
+        let Some(diff) = self.state.dropped_trees.get(tree_key) else {
+            if self.state.caches.contains_key(tree_key) {
+                return self.get(tree_key, key);
+            }
+
+            return match self.existing_tree(tree_key)? {
+                Some(tree) => tree.get(key),
+                None => Ok(None),
+            };
+        };
+
+        let tree = self.db.open_tree(tree_key)?;
+        let mut cache = SledTreeOverlay::new(&tree);
+        cache.state = diff.into();
+        cache.get(key)
+    }
+
+    /// Immutably iterate through the specified tree as if it was opened,
+    /// without opening it. See [`SledDbOverlay::get_unopened`] for details.
+    pub(crate) fn iter_unopened(&self, tree_key: &[u8]) -> Result<LayerIter<'_>, sled::Error> {
+        let Some(diff) = self.state.dropped_trees.get(tree_key) else {
+            if self.state.caches.contains_key(tree_key) {
+                return Ok(Box::new(self.iter(tree_key)?));
+            }
+
+            return match self.existing_tree(tree_key)? {
+                Some(tree) => Ok(Box::new(tree.iter())),
+                None => Ok(Box::new(std::iter::empty())),
+            };
+        };
+
+        // The restored cache only lives in this call,
+        // so we have to collect its records.
+        let tree = self.db.open_tree(tree_key)?;
+        let mut cache = SledTreeOverlay::new(&tree);
+        cache.state = diff.into();
+        let records = cache.iter().collect::<Vec<_>>();
+        Ok(Box::new(records.into_iter()))
+    }
+
+    /// Fetch all our caches current [`KvTree`] pointers.
+    pub fn get_state_trees(&self) -> BTreeMap<IVec, S::Tree> {
         // Grab our state tree pointers
         let mut state_trees = BTreeMap::new();
         for (key, cache) in self.state.caches.iter() {
@@ -630,73 +1601,194 @@ impl SledDbOverlay {
     }
 
     /// Insert a key to a new value in the specified tree cache, returning the last value
-    /// if it was set.
+    /// if it was set. The secondary index changes are computed before writing anything,
+    /// and subscribers get notified once both the record and its indexes are written.
     pub fn insert(
         &mut self,
         tree_key: &[u8],
         key: &[u8],
         value: &[u8],
     ) -> Result<Option<IVec>, sled::Error> {
-        let cache = self.get_cache_mut(&tree_key.into())?;
-        cache.insert(key, value)
+        let mut writes = vec![];
+        let prev = self.plan_write(tree_key, key, Some(value), &mut writes)?;
+        self.write_records(&writes)?;
+        self.notify_pending(&writes);
+        self.enforce_memory_limit()?;
+        Ok(prev)
     }
 
     /// Delete a value in the specified tree cache, returning the old value if it existed.
+    /// The secondary index changes are computed before writing anything, and subscribers
+    /// get notified once both the record and its indexes are written.
     pub fn remove(&mut self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error> {
-        let cache = self.get_cache_mut(&tree_key.into())?;
-        cache.remove(key)
+        let mut writes = vec![];
+        let prev = self.plan_write(tree_key, key, None, &mut writes)?;
+        self.write_records(&writes)?;
+        self.notify_pending(&writes);
+        self.enforce_memory_limit()?;
+        Ok(prev)
     }
 
     /// Removes all values from the specified tree cache and marks all
     /// its tree records as removed.
     pub fn clear(&mut self, tree_key: &[u8]) -> Result<(), sled::Error> {
+        // Grab the records getting removed, if they are watched or indexed
+        let records = match self.watchers.watches(tree_key) || self.indexes.is_source(tree_key) {
+            true => self
+                .iter(tree_key)?
+                .collect::<Result<Vec<(IVec, IVec)>, sled::Error>>()?,
+            false => vec![],
+        };
+
+        // Compute the index changes before clearing anything
+        let mut writes = vec![];
+        for (key, value) in records.iter() {
+            self.plan_indexes(tree_key, key, Some(value), None, &mut writes)?;
+        }
+
         let cache = self.get_cache_mut(&tree_key.into())?;
-        cache.clear()
-    }
+        cache.clear()?;
+        self.write_records(&writes)?;
+        for (key, _) in records {
+            self.watchers
+                .notify(tree_key, &key, None, EventKind::Pending);
+        }
+        self.notify_pending(&writes);
 
-    /// Aggregate all the current overlay changes into [`sled::Batch`] instances and
-    /// return vectors of [`sled::Tree`] and their respective [`sled::Batch`] that can
-    /// be used for further operations. If there are no changes, both vectors will be empty.
-    fn aggregate(&self) -> Result<(Vec<sled::Tree>, Vec<sled::Batch>), sled::Error> {
-        self.state.aggregate()
+        self.enforce_memory_limit()
     }
 
     /// Ensure all new trees that have been opened exist in sled by reopening them,
     /// atomically apply all batches on all trees as a transaction, and drop dropped
-    /// trees from sled.
+    /// trees from sled. Tree creations and drops are recorded in the intent log, so
+    /// an interrupted apply gets completed or rolled back as a whole.
+    /// Since the transaction holds all of its writes in memory, spilled changes
+    /// get loaded back in full; [`SledDbOverlay::apply_chunked`] streams them
+    /// in bounded chunks instead, at the cost of atomicity.
     /// This function **does not** perform a db flush. This should be done externally,
     /// since then there is a choice to perform either blocking or async IO.
     /// After execution is successful, caller should *NOT* use the overlay again.
     pub fn apply(&mut self) -> Result<(), TransactionError<sled::Error>> {
-        // Ensure new trees exist
-        let new_tree_names = self.state.new_tree_names.clone();
-        for tree_key in &new_tree_names {
-            let tree = self.db.open_tree(tree_key)?;
-            // Update cache tree pointer, it must exist
-            let cache = self.get_cache_mut(tree_key)?;
-            cache.tree = tree;
+        self.apply_with(ApplyOptions::default())?;
+        Ok(())
+    }
+
+    /// Apply all the overlay changes to the database like
+    /// [`SledDbOverlay::apply`], using provided [`ApplyOptions`] to
+    /// optionally flush the database afterwards, and retrieve the applied
+    /// diff. The diff previous values are read within the transaction, so
+    /// they are exactly the ones the changes replaced. If flushing fails,
+    /// the changes have been applied, but they might not be durable yet.
+    /// After execution is successful, caller should *NOT* use the overlay again.
+    pub fn apply_with(
+        &mut self,
+        options: ApplyOptions,
+    ) -> Result<ApplyOutcome, TransactionError<sled::Error>> {
+        let (job, diff) = self.prepare_apply(options)?;
+        let applied = job.execute()?;
+        self.finish_apply(applied, diff)
+    }
+
+    /// Build the [`SledDbOverlayStateDiff`] of provided batches that got
+    /// applied over their trees, using the previous values of their records
+    /// read within the transaction, along with our new and dropped trees.
+    pub(crate) fn applied_diff(
+        &self,
+        trees: &[S::Tree],
+        batches: &[KvBatch],
+        previous: &[BTreeMap<IVec, Option<IVec>>],
+    ) -> SledDbOverlayStateDiff {
+        let mut caches = BTreeMap::new();
+
+        // New trees are part of the diff, even if they have no changes
+        for tree_key in self.state.new_tree_names.iter() {
+            if self.state.caches.contains_key(tree_key) {
+                caches.insert(
+                    tree_key.clone(),
+                    (SledTreeOverlayStateDiff::default(), false),
+                );
+            }
         }
 
-        // Drop removed trees
-        for tree in self.state.dropped_trees.keys() {
-            self.db.drop_tree(tree)?;
+        for ((tree, batch), previous) in trees.iter().zip(batches).zip(previous) {
+            let mut diff = SledTreeOverlayStateDiff::default();
+            for (key, value) in batch.records.iter() {
+                let prev = previous.get(key).cloned().flatten();
+                match value {
+                    Some(value) => {
+                        diff.cache.insert(key.clone(), (prev, value.clone()));
+                    }
+                    None => {
+                        // Removals of missing keys changed nothing
+                        if let Some(prev) = prev {
+                            diff.removed.insert(key.clone(), prev);
+                        }
+                    }
+                }
+            }
+            caches.insert(tree.name(), (diff, false));
         }
 
-        // Aggregate batches
-        let (trees, batches) = self.aggregate()?;
-        if trees.is_empty() {
-            return Ok(());
+        let dropped_trees = self
+            .state
+            .dropped_trees
+            .iter()
+            .map(|(tree_key, diff)| (tree_key.clone(), (diff.clone(), false)))
+            .collect();
+
+        // Copied records got written in the batches, so we don't
+        // record the copies themselves.
+        SledDbOverlayStateDiff {
+            initial_tree_names: self.state.initial_tree_names.clone(),
+            caches,
+            dropped_trees,
+            tree_copies: BTreeMap::new(),
         }
+    }
 
-        // Perform an atomic transaction over all the collected trees and
-        // apply the batches.
-        trees.transaction(|trees| {
-            for (index, tree) in trees.iter().enumerate() {
-                tree.apply_batch(&batches[index])?;
-            }
+    /// Generate the diff of the current overlay state changes the hooks and
+    /// the attached publisher will receive on [`SledDbOverlay::apply`], if
+    /// any are registered.
+    pub(crate) fn hooked_diff(&self) -> Result<Option<SledDbOverlayStateDiff>, sled::Error> {
+        if self.hooks.is_empty() && self.publisher.is_none() {
+            return Ok(None);
+        }
 
-            Ok::<(), ConflictableTransactionError<sled::Error>>(())
-        })?;
+        Ok(Some(SledDbOverlayStateDiff::new(&self.state)?))
+    }
+
+    /// Generate the tree operations [`SledDbOverlay::apply`] must perform
+    /// before applying the batches: opening new trees, dropping removed
+    /// ones, and reopening the copies, so their records get cleared.
+    pub(crate) fn apply_tree_ops(&self) -> Vec<TreeOp> {
+        let mut ops = vec![];
+
+        for tree_key in &self.state.new_tree_names {
+            ops.push(TreeOp::Open(tree_key.clone()));
+        }
+
+        for tree_key in self.state.dropped_trees.keys() {
+            ops.push(TreeOp::Drop(tree_key.clone()));
+        }
+
+        for tree_key in self.state.tree_copies.keys() {
+            ops.push(TreeOp::Drop(tree_key.clone()));
+            ops.push(TreeOp::Open(tree_key.clone()));
+        }
+
+        ops
+    }
+
+    /// Update our caches [`KvTree`] pointers to provided ones.
+    pub(crate) fn update_tree_pointers(
+        &mut self,
+        trees: BTreeMap<IVec, S::Tree>,
+    ) -> Result<(), sled::Error> {
+        for (tree_key, tree) in trees {
+            // Update cache tree pointer, it must exist
+            let cache = self.get_cache_mut(&tree_key)?;
+            cache.tree = tree;
+        }
 
         Ok(())
     }
@@ -709,7 +1801,15 @@ impl SledDbOverlay {
     /// Revert to current cache state checkpoint. This function will
     /// not drop new trees from the `db`, so caller should handle it.
     pub fn revert_to_checkpoint(&mut self) {
-        self.state = self.checkpoint.clone();
+        self.restore_state(self.checkpoint.clone());
+    }
+
+    /// Replace current cache state with provided one, notifying
+    /// subscribers of the keys that changed value.
+    pub(crate) fn restore_state(&mut self, state: SledDbOverlayState<S::Tree>) {
+        let reverted = std::mem::replace(&mut self.state, state);
+        self.configure_read_caches();
+        self.notify_reverted(&reverted);
     }
 
     /// Calculate differences from provided overlay state changes
@@ -732,16 +1832,66 @@ impl SledDbOverlay {
         Ok(current)
     }
 
+    /// Produce a deterministic [`StateDigest`] of the current overlay
+    /// state changes, using the hash function selected by the enabled
+    /// cargo features.
+    #[cfg(any(feature = "blake3", feature = "sha256"))]
+    pub fn digest(&self) -> Result<StateDigest, sled::Error> {
+        Ok(self.diff(&[])?.digest())
+    }
+
+    /// Enable tracking of the specified tree cache state root.
+    /// See [`SledTreeOverlay::enable_state_root`] for details.
+    #[cfg(any(feature = "blake3", feature = "sha256"))]
+    pub fn enable_state_root(&mut self, tree_key: &[u8]) -> Result<(), sled::Error> {
+        let cache = self.get_cache_mut(&tree_key.into())?;
+        cache.enable_state_root()
+    }
+
+    /// Returns the specified tree cache state root, if its tracking is enabled.
+    #[cfg(any(feature = "blake3", feature = "sha256"))]
+    pub fn state_root(&self, tree_key: &[u8]) -> Result<Option<StateDigest>, sled::Error> {
+        let cache = self.get_cache(&tree_key.into())?;
+        Ok(cache.state_root())
+    }
+
+    /// Generate a [`MerkleProof`] of the provided key value, or its absence,
+    /// against the specified tree cache state root, if its tracking is enabled.
+    #[cfg(any(feature = "blake3", feature = "sha256"))]
+    pub fn prove(&self, tree_key: &[u8], key: &[u8]) -> Result<Option<MerkleProof>, sled::Error> {
+        let cache = self.get_cache(&tree_key.into())?;
+        Ok(cache.prove(key))
+    }
+
     /// Add provided `db` overlay state changes from our own.
     pub fn add_diff(&mut self, diff: &SledDbOverlayStateDiff) -> Result<(), sled::Error> {
-        self.state.add_diff(&self.db, diff)
+        self.reconcile_indexes(diff, |overlay| {
+            overlay.state.add_diff(&overlay.db, diff)?;
+            overlay.configure_read_caches();
+            overlay.notify_diff(diff, EventKind::Merged);
+            Ok(())
+        })?;
+        self.enforce_memory_limit()
     }
 
     /// Remove provided `db` overlay state changes from our own.
+    /// The secondary indexes are not updated, which is fine for diffs
+    /// produced by an overlay declaring the same indexes, since they
+    /// already contain the index changes. Otherwise,
+    /// [`SledDbOverlay::try_remove_diff`] should be used instead.
     pub fn remove_diff(&mut self, diff: &SledDbOverlayStateDiff) {
         self.state.remove_diff(diff)
     }
 
+    /// Remove provided `db` overlay state changes from our own,
+    /// updating the secondary indexes of the keys whose value changed.
+    pub fn try_remove_diff(&mut self, diff: &SledDbOverlayStateDiff) -> Result<(), sled::Error> {
+        self.reconcile_indexes(diff, |overlay| {
+            overlay.state.remove_diff(diff);
+            Ok(())
+        })
+    }
+
     /// For a provided `SledDbOverlayStateDiff`, ensure all trees exist in sled by
     /// reopening them, atomically apply all batches on all trees as a transaction,
     /// and drop dropped trees from sled. After that, remove the state changes from
@@ -753,24 +1903,46 @@ impl SledDbOverlay {
         &mut self,
         diff: &SledDbOverlayStateDiff,
     ) -> Result<(), TransactionError<sled::Error>> {
+        let job = self.prepare_apply_diff(diff)?;
+        let applied = job.execute()?;
+        self.finish_apply_diff(applied, diff)
+    }
+
+    /// Generate the tree operations [`SledDbOverlay::apply_diff`] must
+    /// perform for provided diff before applying its batches: opening
+    /// its trees, dropping removed ones, and reopening the trees its
+    /// copies replace or restore, so their records get cleared. Unknown
+    /// trees are tracked as new ones.
+    pub(crate) fn apply_diff_tree_ops(
+        &mut self,
+        diff: &SledDbOverlayStateDiff,
+    ) -> Result<Vec<TreeOp>, TransactionError<sled::Error>> {
         // We assert that the diff doesn't try to drop any of our protected trees
         for tree in diff.dropped_trees.keys() {
-            if self.state.protected_tree_names.contains(tree) {
+            if self.state.protected_tree_names.contains(tree) || is_reserved(tree) {
                 return Err(TransactionError::Storage(sled::Error::Unsupported(
                     "Protected tree can't be dropped".to_string(),
                 )));
             }
         }
         for (tree_key, (_, drop)) in diff.caches.iter() {
-            if *drop && self.state.protected_tree_names.contains(tree_key) {
+            if *drop
+                && (self.state.protected_tree_names.contains(tree_key) || is_reserved(tree_key))
+            {
                 return Err(TransactionError::Storage(sled::Error::Unsupported(
                     "Protected tree can't be dropped".to_string(),
                 )));
             }
         }
+        for tree_key in diff.tree_copies.keys() {
+            if self.state.protected_tree_names.contains(tree_key) || is_reserved(tree_key) {
+                return Err(TransactionError::Storage(sled::Error::Unsupported(
+                    "Protected tree can't be replaced".to_string(),
+                )));
+            }
+        }
 
-        // Grab current state trees
-        let mut state_trees = self.get_state_trees();
+        let mut ops = vec![];
 
         // Ensure diff trees exist
         for (tree_key, (_, drop)) in diff.caches.iter() {
@@ -783,21 +1955,17 @@ impl SledDbOverlay {
 
             // Check if it should be dropped
             if *drop {
-                self.db.drop_tree(tree_key)?;
+                ops.push(TreeOp::Drop(tree_key.clone()));
                 continue;
             }
 
-            if !state_trees.contains_key(tree_key) {
-                let tree = self.db.open_tree(tree_key)?;
-                state_trees.insert(tree_key.clone(), tree);
-            }
+            ops.push(TreeOp::Open(tree_key.clone()));
         }
 
         // Drop removed trees and ensure restored trees exist
         for (tree_key, (_, restored)) in diff.dropped_trees.iter() {
             if !restored {
-                state_trees.remove(tree_key);
-                self.db.drop_tree(tree_key)?;
+                ops.push(TreeOp::Drop(tree_key.clone()));
                 continue;
             }
 
@@ -808,37 +1976,22 @@ impl SledDbOverlay {
                 self.state.new_tree_names.push(tree_key.clone());
             }
 
-            if !state_trees.contains_key(tree_key) {
-                let tree = self.db.open_tree(tree_key)?;
-                state_trees.insert(tree_key.clone(), tree);
-            }
-        }
-
-        // Aggregate batches
-        let (trees, batches) = diff.aggregate(&state_trees)?;
-        if trees.is_empty() {
-            self.remove_diff(diff);
-            return Ok(());
+            ops.push(TreeOp::Open(tree_key.clone()));
         }
 
-        // Perform an atomic transaction over all the collected trees and
-        // apply the batches.
-        trees.transaction(|trees| {
-            for (index, tree) in trees.iter().enumerate() {
-                tree.apply_batch(&batches[index])?;
+        // Reopen the trees replaced or restored by copies
+        for tree_key in diff.tree_copies.keys() {
+            if diff.copies_tree(tree_key) {
+                ops.push(TreeOp::Drop(tree_key.clone()));
+                ops.push(TreeOp::Open(tree_key.clone()));
             }
+        }
 
-            Ok::<(), ConflictableTransactionError<sled::Error>>(())
-        })?;
-
-        // Remove changes from our current state
-        self.remove_diff(diff);
-
-        Ok(())
+        Ok(ops)
     }
 
     /// Retrieve an immutable itterator from the overlay if the specified tree cache exists.
-    pub fn iter(&self, tree_key: &[u8]) -> Result<SledTreeOverlayIter<'_>, sled::Error> {
+    pub fn iter(&self, tree_key: &[u8]) -> Result<SledTreeOverlayIter<'_, S::Tree>, sled::Error> {
         let cache = self.get_cache(&tree_key.into())?;
         Ok(cache.iter())
     }
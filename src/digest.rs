@@ -0,0 +1,104 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Deterministic hashing of overlay state diffs.
+//!
+//! The hash function is selected at compile time using cargo features:
+//! `blake3` (enabled by default) or `sha256`. Enabling both is rejected,
+//! since feature unification could otherwise silently change the digests
+//! between builds, so `sha256` requires disabling default features. When
+//! neither is enabled, the APIs relying on the default hash function, like
+//! state roots, are unavailable, while digests can still be produced using
+//! a custom [`StateHasher`].
+
+use crate::serial::{Encodable, Sink};
+
+#[cfg(all(feature = "blake3", feature = "sha256"))]
+compile_error!(
+    "features `blake3` and `sha256` are mutually exclusive, \
+     disable default features to use `sha256`"
+);
+
+/// Fixed-size hash produced by a [`StateHasher`].
+pub type StateDigest = [u8; 32];
+
+/// Hash function used to produce [`StateDigest`] values.
+pub trait StateHasher: Default {
+    /// Feed provided bytes into the hasher.
+    fn update(&mut self, bytes: &[u8]);
+
+    /// Consume the hasher and produce the final digest.
+    fn finalize(self) -> StateDigest;
+}
+
+/// [`StateHasher`] implementation using BLAKE3.
+#[cfg(feature = "blake3")]
+#[derive(Default)]
+pub struct Blake3Hasher(blake3::Hasher);
+
+#[cfg(feature = "blake3")]
+impl StateHasher for Blake3Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finalize(self) -> StateDigest {
+        *self.0.finalize().as_bytes()
+    }
+}
+
+/// [`StateHasher`] implementation using SHA-256.
+#[cfg(feature = "sha256")]
+#[derive(Default)]
+pub struct Sha256Hasher(sha2::Sha256);
+
+#[cfg(feature = "sha256")]
+impl StateHasher for Sha256Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        sha2::Digest::update(&mut self.0, bytes);
+    }
+
+    fn finalize(self) -> StateDigest {
+        sha2::Digest::finalize(self.0).into()
+    }
+}
+
+/// The [`StateHasher`] selected by the enabled cargo features.
+#[cfg(all(feature = "sha256", not(feature = "blake3")))]
+pub type DefaultStateHasher = Sha256Hasher;
+
+/// The [`StateHasher`] selected by the enabled cargo features.
+#[cfg(feature = "blake3")]
+pub type DefaultStateHasher = Blake3Hasher;
+
+/// Auxilliary struct to stream canonical encodings into a [`StateHasher`].
+struct HasherSink<H: StateHasher>(H);
+
+impl<H: StateHasher> Sink for HasherSink<H> {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+}
+
+/// Hash the canonical encoding of provided structure using the
+/// given [`StateHasher`].
+pub(crate) fn digest_with<H: StateHasher, T: Encodable + ?Sized>(value: &T) -> StateDigest {
+    let mut sink = HasherSink(H::default());
+    value.encode(&mut sink);
+    sink.0.finalize()
+}
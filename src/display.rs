@@ -0,0 +1,344 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Human-readable rendering of overlay states and their diffs.
+//!
+//! Each tree gets its own section, and each record a line:
+//! * `+ key = value` for a newly inserted key
+//! * `~ key: old -> new` for an updated key
+//! * `- key (was value)` for a removed key
+//!
+//! Copied trees sections render the changes over their source tree.
+//!
+//! Printable keys and values are rendered as quoted and escaped text,
+//! otherwise as hex, and long ones are truncated. Overlay states are
+//! rendered along with their spilled changes.
+
+use std::fmt;
+
+use crate::{
+    backend::KvTree, database::SledDbOverlayState, SledDbOverlayStateDiff, SledTreeOverlay,
+    SledTreeOverlayState, SledTreeOverlayStateDiff,
+};
+
+/// Default number of bytes rendered for each key or value.
+pub const DEFAULT_MAX_LEN: usize = 64;
+
+/// Pretty printer of overlay states and diffs, with configurable
+/// truncation of long keys and values.
+pub struct Pretty<'a, T: ?Sized> {
+    /// Structure being rendered.
+    inner: &'a T,
+    /// Maximum number of bytes rendered for each key or value,
+    /// or `None` to render them in full.
+    max_len: Option<usize>,
+}
+
+impl<'a, T: ?Sized> Pretty<'a, T> {
+    /// Instantiate a new [`Pretty`] printer over provided structure,
+    /// using the [`DEFAULT_MAX_LEN`] truncation.
+    pub fn new(inner: &'a T) -> Self {
+        Self {
+            inner,
+            max_len: Some(DEFAULT_MAX_LEN),
+        }
+    }
+
+    /// Truncate keys and values longer than provided number of bytes.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// Render keys and values in full, without truncation.
+    pub fn full(mut self) -> Self {
+        self.max_len = None;
+        self
+    }
+
+    /// Write provided bytes as quoted text if they are printable,
+    /// otherwise as hex, truncated to our configured length.
+    fn bytes(&self, f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+        if bytes.is_empty() {
+            return write!(f, "\"\"");
+        }
+
+        let shown = match self.max_len {
+            Some(max_len) if bytes.len() > max_len => &bytes[..max_len],
+            _ => bytes,
+        };
+
+        if bytes.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+            // Its safe to unwrap here since we checked all bytes are ASCII
+            write!(f, "{:?}", std::str::from_utf8(shown).unwrap())?;
+        } else {
+            write!(f, "0x")?;
+            for b in shown {
+                write!(f, "{b:02x}")?;
+            }
+        }
+
+        if shown.len() < bytes.len() {
+            write!(f, "...({} bytes)", bytes.len())?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a section header for provided tree name.
+    fn header(&self, f: &mut fmt::Formatter<'_>, tree_name: &[u8], tag: &str) -> fmt::Result {
+        write!(f, "[")?;
+        self.bytes(f, tree_name)?;
+        write!(f, "]")?;
+        if !tag.is_empty() {
+            write!(f, " ({tag})")?;
+        }
+        writeln!(f)
+    }
+
+    /// Write the records lines of a [`SledTreeOverlayStateDiff`].
+    fn tree_diff(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        diff: &SledTreeOverlayStateDiff,
+    ) -> fmt::Result {
+        for (key, (previous, value)) in diff.cache.iter() {
+            match previous {
+                Some(previous) => {
+                    write!(f, "~ ")?;
+                    self.bytes(f, key)?;
+                    write!(f, ": ")?;
+                    self.bytes(f, previous)?;
+                    write!(f, " -> ")?;
+                    self.bytes(f, value)?;
+                }
+                None => {
+                    write!(f, "+ ")?;
+                    self.bytes(f, key)?;
+                    write!(f, " = ")?;
+                    self.bytes(f, value)?;
+                }
+            }
+            writeln!(f)?;
+        }
+
+        for (key, value) in diff.removed.iter() {
+            write!(f, "- ")?;
+            self.bytes(f, key)?;
+            write!(f, " (was ")?;
+            self.bytes(f, value)?;
+            writeln!(f, ")")?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the records lines of a dropped tree [`SledTreeOverlayStateDiff`],
+    /// which contains its last state as inserts.
+    fn dropped_tree_diff(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        diff: &SledTreeOverlayStateDiff,
+        restored: bool,
+    ) -> fmt::Result {
+        for (key, (_, value)) in diff.cache.iter() {
+            if restored {
+                write!(f, "+ ")?;
+                self.bytes(f, key)?;
+                write!(f, " = ")?;
+                self.bytes(f, value)?;
+                writeln!(f)?;
+                continue;
+            }
+            write!(f, "- ")?;
+            self.bytes(f, key)?;
+            write!(f, " (was ")?;
+            self.bytes(f, value)?;
+            writeln!(f, ")")?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the records lines of a [`SledTreeOverlay`] cache state,
+    /// including its spilled changes.
+    fn tree_changes<U: KvTree>(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        overlay: &SledTreeOverlay<U>,
+    ) -> fmt::Result {
+        for record in overlay.changes() {
+            let (key, value) = record.map_err(|_| fmt::Error)?;
+            let Some(value) = value else {
+                continue;
+            };
+            write!(f, "+ ")?;
+            self.bytes(f, &key)?;
+            write!(f, " = ")?;
+            self.bytes(f, &value)?;
+            writeln!(f)?;
+        }
+
+        for record in overlay.changes() {
+            let (key, value) = record.map_err(|_| fmt::Error)?;
+            if value.is_none() {
+                write!(f, "- ")?;
+                self.bytes(f, &key)?;
+                writeln!(f)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the records lines of a [`SledTreeOverlayState`].
+    fn tree_state(&self, f: &mut fmt::Formatter<'_>, state: &SledTreeOverlayState) -> fmt::Result {
+        for (key, value) in state.cache.iter() {
+            write!(f, "+ ")?;
+            self.bytes(f, key)?;
+            write!(f, " = ")?;
+            self.bytes(f, value)?;
+            writeln!(f)?;
+        }
+
+        for key in state.removed.iter() {
+            write!(f, "- ")?;
+            self.bytes(f, key)?;
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Pretty<'_, SledTreeOverlayStateDiff> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.tree_diff(f, self.inner)
+    }
+}
+
+impl fmt::Display for Pretty<'_, SledTreeOverlayState> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.tree_state(f, self.inner)
+    }
+}
+
+impl fmt::Display for Pretty<'_, SledDbOverlayStateDiff> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (tree_name, (diff, drop)) in self.inner.caches.iter() {
+            let tag = if *drop {
+                "dropped"
+            } else if let Some((_, reverted)) = self.inner.tree_copies.get(tree_name) {
+                if *reverted {
+                    "restored"
+                } else {
+                    "copy"
+                }
+            } else if !self.inner.initial_tree_names.contains(tree_name) {
+                "new"
+            } else {
+                ""
+            };
+            self.header(f, tree_name, tag)?;
+            self.tree_diff(f, diff)?;
+        }
+
+        for (tree_name, (diff, restored)) in self.inner.dropped_trees.iter() {
+            let tag = if *restored { "restored" } else { "dropped" };
+            self.header(f, tree_name, tag)?;
+            self.dropped_tree_diff(f, diff, *restored)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: KvTree> fmt::Display for Pretty<'_, SledDbOverlayState<T>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (tree_name, cache) in self.inner.caches.iter() {
+            let tag = if self.inner.tree_copies.contains_key(tree_name) {
+                "copy"
+            } else if self.inner.new_tree_names.contains(tree_name) {
+                "new"
+            } else {
+                ""
+            };
+            self.header(f, tree_name, tag)?;
+            self.tree_changes(f, cache)?;
+        }
+
+        for (tree_name, diff) in self.inner.dropped_trees.iter() {
+            self.header(f, tree_name, "dropped")?;
+            self.dropped_tree_diff(f, diff, false)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl SledTreeOverlayStateDiff {
+    /// Returns a [`Pretty`] printer of the diff, to configure its rendering.
+    pub fn pretty(&self) -> Pretty<'_, Self> {
+        Pretty::new(self)
+    }
+}
+
+impl SledTreeOverlayState {
+    /// Returns a [`Pretty`] printer of the state, to configure its rendering.
+    pub fn pretty(&self) -> Pretty<'_, Self> {
+        Pretty::new(self)
+    }
+}
+
+impl SledDbOverlayStateDiff {
+    /// Returns a [`Pretty`] printer of the diff, to configure its rendering.
+    pub fn pretty(&self) -> Pretty<'_, Self> {
+        Pretty::new(self)
+    }
+}
+
+impl<T: KvTree> SledDbOverlayState<T> {
+    /// Returns a [`Pretty`] printer of the state, to configure its rendering.
+    pub fn pretty(&self) -> Pretty<'_, Self> {
+        Pretty::new(self)
+    }
+}
+
+impl fmt::Display for SledTreeOverlayStateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Pretty::new(self).fmt(f)
+    }
+}
+
+impl fmt::Display for SledTreeOverlayState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Pretty::new(self).fmt(f)
+    }
+}
+
+impl fmt::Display for SledDbOverlayStateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Pretty::new(self).fmt(f)
+    }
+}
+
+impl<T: KvTree> fmt::Display for SledDbOverlayState<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Pretty::new(self).fmt(f)
+    }
+}
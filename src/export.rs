@@ -0,0 +1,291 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Export of a pending overlay state, so it can be restored later.
+//!
+//! The export holds the overlay state along with its checkpoint. Each
+//! tree overlay is recorded as a diff over the database, containing the
+//! previous values of all changed keys, so we can verify the database
+//! hasn't diverged when we restore it.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+};
+
+use sled::IVec;
+
+use crate::{
+    backend::{KvStore, KvTree},
+    database::SledDbOverlayState,
+    serial::{Decodable, Encodable, Sink, Source},
+    SledDbOverlay, SledTreeOverlay, SledTreeOverlayStateDiff, TreeCopy,
+};
+
+/// Magic bytes prefixing an exported overlay.
+const MAGIC: &[u8] = b"sled-overlay";
+
+/// Version of the exported overlay encoding.
+const VERSION: u8 = 2;
+
+/// Auxilliary function to build the error of a diverged database.
+fn diverged() -> sled::Error {
+    sled::Error::Unsupported("Database diverged from the exported overlay".to_string())
+}
+
+/// Snapshot of a [`SledDbOverlayState`], with each tree overlay
+/// recorded as a diff over the database.
+struct StateSnapshot {
+    /// Existing trees in the database when the overlay was created.
+    initial_tree_names: Vec<IVec>,
+    /// New trees that have been opened.
+    new_tree_names: Vec<IVec>,
+    /// Dropped trees that have been reopened or restored.
+    restored_tree_names: Vec<IVec>,
+    /// Protected trees.
+    protected_tree_names: Vec<IVec>,
+    /// Diffs of all opened tree overlays.
+    caches: BTreeMap<IVec, SledTreeOverlayStateDiff>,
+    /// Dropped trees, along with their last state full diff.
+    dropped_trees: BTreeMap<IVec, SledTreeOverlayStateDiff>,
+    /// Copied trees, along with their source tree.
+    tree_copies: BTreeMap<IVec, TreeCopy>,
+}
+
+/// Auxilliary function to verify provided tree records in the
+/// database match the ones of its last state full diff.
+fn verify_records<S: KvStore>(
+    db: &S,
+    tree_names: &[IVec],
+    tree_key: &IVec,
+    diff: &SledTreeOverlayStateDiff,
+) -> Result<(), sled::Error> {
+    let records = match tree_names.contains(tree_key) {
+        true => db
+            .open_tree(tree_key)?
+            .iter()
+            .collect::<Result<Vec<(IVec, IVec)>, sled::Error>>()?,
+        false => vec![],
+    };
+    let expected: Vec<(IVec, IVec)> = diff
+        .cache
+        .iter()
+        .map(|(key, (_, value))| (key.clone(), value.clone()))
+        .collect();
+    if records != expected {
+        return Err(diverged());
+    }
+
+    Ok(())
+}
+
+impl StateSnapshot {
+    /// Instantiate a new [`StateSnapshot`] of provided state.
+    fn new<T: KvTree>(state: &SledDbOverlayState<T>) -> Result<Self, sled::Error> {
+        let mut caches = BTreeMap::new();
+        for (tree_key, cache) in state.caches.iter() {
+            caches.insert(tree_key.clone(), cache.diff(&[])?);
+        }
+
+        Ok(Self {
+            initial_tree_names: state.initial_tree_names.clone(),
+            new_tree_names: state.new_tree_names.clone(),
+            restored_tree_names: state.restored_tree_names.clone(),
+            protected_tree_names: state.protected_tree_names.clone(),
+            caches,
+            dropped_trees: state.dropped_trees.clone(),
+            tree_copies: state.tree_copies.clone(),
+        })
+    }
+
+    /// Verify the snapshot was taken over provided database state,
+    /// by checking all initial trees still exist, and the previous
+    /// values of all changed keys and dropped trees are unchanged.
+    /// Copied trees previous values are the ones of their source tree.
+    fn verify<S: KvStore>(&self, db: &S) -> Result<(), sled::Error> {
+        let tree_names = db.tree_names();
+        for tree_key in self.initial_tree_names.iter() {
+            if !tree_names.contains(tree_key) {
+                return Err(diverged());
+            }
+        }
+
+        for (tree_key, diff) in self.caches.iter() {
+            let tree_key = match self.tree_copies.get(tree_key) {
+                Some(copy) => &copy.source,
+                None => tree_key,
+            };
+
+            // New trees changes have no previous values to check,
+            // but restored trees may still have records in the database.
+            if !tree_names.contains(tree_key) {
+                continue;
+            }
+            let tree = db.open_tree(tree_key)?;
+
+            for (key, (previous, _)) in diff.cache.iter() {
+                if tree.get(key)? != *previous {
+                    return Err(diverged());
+                }
+            }
+
+            for (key, previous) in diff.removed.iter() {
+                let value = tree.get(key)?;
+                // Removed keys that didn't exist have an empty previous value
+                let unchanged = match &value {
+                    Some(value) => value == previous,
+                    None => previous.is_empty(),
+                };
+                if !unchanged {
+                    return Err(diverged());
+                }
+            }
+        }
+
+        for (tree_key, diff) in self.dropped_trees.iter() {
+            verify_records(db, &tree_names, tree_key, diff)?;
+        }
+
+        for (tree_key, copy) in self.tree_copies.iter() {
+            if let Some(replaced) = &copy.replaced {
+                verify_records(db, &tree_names, tree_key, replaced)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restore the snapshot state over provided database.
+    fn restore<S: KvStore>(&self, db: &S) -> Result<SledDbOverlayState<S::Tree>, sled::Error> {
+        let mut state = SledDbOverlayState::new(
+            self.initial_tree_names.clone(),
+            self.protected_tree_names.clone(),
+        );
+        state.new_tree_names = self.new_tree_names.clone();
+        state.restored_tree_names = self.restored_tree_names.clone();
+        state.dropped_trees = self.dropped_trees.clone();
+        state.tree_copies = self.tree_copies.clone();
+
+        for (tree_key, diff) in self.caches.iter() {
+            let mut cache = SledTreeOverlay::new(&db.open_tree(tree_key)?);
+            if let Some(copy) = self.tree_copies.get(tree_key) {
+                cache.base = Some(db.open_tree(&copy.source)?);
+            }
+            cache.add_diff(diff);
+            state.caches.insert(tree_key.clone(), cache);
+        }
+
+        Ok(state)
+    }
+}
+
+impl Encodable for StateSnapshot {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        self.initial_tree_names.encode(s);
+        self.new_tree_names.encode(s);
+        self.restored_tree_names.encode(s);
+        self.protected_tree_names.encode(s);
+        self.caches.encode(s);
+        self.dropped_trees.encode(s);
+        self.tree_copies.encode(s);
+    }
+}
+
+impl Decodable for StateSnapshot {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        Ok(Self {
+            initial_tree_names: Vec::decode(s)?,
+            new_tree_names: Vec::decode(s)?,
+            restored_tree_names: Vec::decode(s)?,
+            protected_tree_names: Vec::decode(s)?,
+            caches: BTreeMap::decode(s)?,
+            dropped_trees: BTreeMap::decode(s)?,
+            tree_copies: BTreeMap::decode(s)?,
+        })
+    }
+}
+
+impl<S: KvStore> SledDbOverlay<S> {
+    /// Export the current overlay state, along with its checkpoint,
+    /// so it can be restored later using [`SledDbOverlay::import`].
+    /// Overlay configuration, like its memory budget, read caches,
+    /// subscribers, hooks, indexes and state roots tracking, is not
+    /// exported.
+    pub fn export(&self) -> Result<Vec<u8>, sled::Error> {
+        let mut bytes = MAGIC.to_vec();
+        VERSION.encode(&mut bytes);
+        StateSnapshot::new(&self.state)?.encode(&mut bytes);
+        StateSnapshot::new(&self.checkpoint)?.encode(&mut bytes);
+        Ok(bytes)
+    }
+
+    /// Export the current overlay state into provided file.
+    /// The file is written atomically, by writing the export into a
+    /// temporary file next to it first, and then renaming it.
+    /// See [`SledDbOverlay::export`] for details.
+    pub fn export_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), sled::Error> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&self.export()?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+
+    /// Restore an overlay on top of provided database, from its state
+    /// exported using [`SledDbOverlay::export`]. Returns an error if the
+    /// export is invalid, or the database has diverged from the one it was
+    /// exported over: one of its initial trees doesn't exist anymore, or the
+    /// previous value of a changed key or a dropped tree records differ.
+    pub fn import(db: &S, bytes: &[u8]) -> Result<Self, sled::Error> {
+        let mut source = Source::new(bytes);
+        if source.read(MAGIC.len())? != MAGIC || u8::decode(&mut source)? != VERSION {
+            return Err(sled::Error::Unsupported(
+                "Unknown overlay export format".to_string(),
+            ));
+        }
+
+        let state = StateSnapshot::decode(&mut source)?;
+        let checkpoint = StateSnapshot::decode(&mut source)?;
+        if !source.is_empty() {
+            return Err(sled::Error::Unsupported("Invalid encoding".to_string()));
+        }
+
+        state.verify(db)?;
+        checkpoint.verify(db)?;
+
+        let mut overlay = Self::new(db, vec![]);
+        overlay.state = state.restore(db)?;
+        overlay.checkpoint = checkpoint.restore(db)?;
+
+        Ok(overlay)
+    }
+
+    /// Restore an overlay on top of provided database, from its state
+    /// exported into provided file using [`SledDbOverlay::export_to_file`].
+    /// See [`SledDbOverlay::import`] for details.
+    pub fn import_from_file<P: AsRef<Path>>(db: &S, path: P) -> Result<Self, sled::Error> {
+        Self::import(db, &fs::read(path)?)
+    }
+}
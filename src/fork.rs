@@ -0,0 +1,151 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Cheap forks of a [`SledDbOverlay`].
+//!
+//! A fork keeps its own cache state on top of an immutably borrowed
+//! overlay, so creating it doesn't copy any of the overlay state, and
+//! multiple forks of the same overlay can run independently of each
+//! other, even concurrently. The changes of a fork can be extracted as
+//! [`ForkChanges`] and committed into the overlay once its forks are done.
+
+use sled::IVec;
+
+use crate::{
+    backend::KvStore,
+    layer::{LayerIter, LayerState},
+    SledDbOverlay, StackableOverlay,
+};
+
+/// A logically independent fork of a [`SledDbOverlay`], sharing its
+/// state instead of copying it. Reads fall through the fork cache state
+/// to the overlay, while writes only affect the fork. Trees opened in
+/// the fork only get opened in the overlay once its changes get committed.
+pub struct SledDbOverlayFork<'a, S: KvStore = sled::Db> {
+    /// The overlay we forked.
+    parent: &'a SledDbOverlay<S>,
+    /// Current fork state.
+    state: LayerState,
+    /// Checkpointed fork state, which is empty until we checkpoint.
+    checkpoint: LayerState,
+}
+
+impl<S: KvStore> SledDbOverlay<S> {
+    /// Create a new [`SledDbOverlayFork`] of the overlay, in constant time.
+    pub fn fork(&self) -> SledDbOverlayFork<'_, S> {
+        SledDbOverlayFork::new(self)
+    }
+}
+
+impl<'a, S: KvStore> SledDbOverlayFork<'a, S> {
+    /// Instantiate a new [`SledDbOverlayFork`] of provided overlay.
+    pub fn new(parent: &'a SledDbOverlay<S>) -> Self {
+        Self {
+            parent,
+            state: LayerState::default(),
+            checkpoint: LayerState::default(),
+        }
+    }
+
+    /// Returns `true` if the fork has no changes.
+    pub fn is_clean(&self) -> bool {
+        self.state.is_clean()
+    }
+
+    /// Returns `true` if specified tree is empty.
+    pub fn is_empty(&self, tree_key: &[u8]) -> Result<bool, sled::Error> {
+        match self.iter(tree_key)?.next() {
+            Some(record) => record.map(|_| false),
+            None => Ok(true),
+        }
+    }
+
+    /// Extract the fork changes, consuming the fork, so they can be
+    /// committed into the overlay once its borrow ends.
+    pub fn into_changes(self) -> ForkChanges {
+        ForkChanges { state: self.state }
+    }
+}
+
+impl<S: KvStore> StackableOverlay for SledDbOverlayFork<'_, S> {
+    fn get(&self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        self.state.get(self.parent, tree_key, key)
+    }
+
+    fn iter(&self, tree_key: &[u8]) -> Result<LayerIter<'_>, sled::Error> {
+        self.state.iter(self.parent, tree_key)
+    }
+
+    fn get_unopened(&self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        self.state.get_unopened(self.parent, tree_key, key)
+    }
+
+    fn iter_unopened(&self, tree_key: &[u8]) -> Result<LayerIter<'_>, sled::Error> {
+        self.state.iter_unopened(self.parent, tree_key)
+    }
+
+    fn insert(
+        &mut self,
+        tree_key: &[u8],
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<IVec>, sled::Error> {
+        self.state.insert(self.parent, tree_key, key, value)
+    }
+
+    fn remove(&mut self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        self.state.remove(self.parent, tree_key, key)
+    }
+
+    fn open_tree(&mut self, tree_name: &[u8], protected: bool) -> Result<(), sled::Error> {
+        self.state.open_tree(self.parent, tree_name, protected)
+    }
+
+    fn drop_tree(&mut self, tree_name: &[u8]) -> Result<(), sled::Error> {
+        self.state.drop_tree(self.parent, tree_name)
+    }
+
+    fn is_protected(&self, tree_key: &[u8]) -> bool {
+        self.state.is_protected(self.parent, tree_key)
+    }
+
+    fn checkpoint(&mut self) {
+        self.checkpoint = self.state.clone();
+    }
+
+    fn revert_to_checkpoint(&mut self) {
+        self.state = self.checkpoint.clone();
+    }
+}
+
+/// The changes of a [`SledDbOverlayFork`], detached from the overlay.
+#[derive(Debug, Clone, Default)]
+pub struct ForkChanges {
+    /// The fork state.
+    state: LayerState,
+}
+
+impl ForkChanges {
+    /// Commit the changes into provided overlay, which should be the one
+    /// the fork was created from. Trees opened in the fork are opened
+    /// first, and then the changes of each tree are merged in place. If
+    /// an error occurs, the overlay is reverted to its last checkpoint.
+    pub fn commit<P: StackableOverlay>(self, parent: &mut P) -> Result<(), sled::Error> {
+        self.state.commit(parent)
+    }
+}
@@ -0,0 +1,96 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Hooks executed around the application of overlay changes.
+//!
+//! Pre-apply hooks receive the changes about to be applied and can veto
+//! them by returning an error, before anything touches the database.
+//! Post-apply hooks receive the changes once they have been applied.
+
+use std::sync::Arc;
+
+use sled::transaction::TransactionError;
+
+use crate::SledDbOverlayStateDiff;
+
+/// Validator executed before applying overlay changes.
+/// Returning an error aborts the application.
+pub type PreApplyHook =
+    Arc<dyn Fn(&SledDbOverlayStateDiff) -> Result<(), sled::Error> + Send + Sync>;
+
+/// Callback executed after overlay changes have been applied.
+pub type PostApplyHook = Arc<dyn Fn(&SledDbOverlayStateDiff) + Send + Sync>;
+
+/// Registry of the hooks executed when applying overlay changes.
+/// Clones share the registered hooks.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    /// Registered pre-apply hooks, in registration order.
+    pre_apply: Vec<PreApplyHook>,
+    /// Registered post-apply hooks, in registration order.
+    post_apply: Vec<PostApplyHook>,
+}
+
+impl Hooks {
+    /// Register a new pre-apply hook.
+    pub fn add_pre_apply<F>(&mut self, hook: F)
+    where
+        F: Fn(&SledDbOverlayStateDiff) -> Result<(), sled::Error> + Send + Sync + 'static,
+    {
+        self.pre_apply.push(Arc::new(hook));
+    }
+
+    /// Register a new post-apply hook.
+    pub fn add_post_apply<F>(&mut self, hook: F)
+    where
+        F: Fn(&SledDbOverlayStateDiff) + Send + Sync + 'static,
+    {
+        self.post_apply.push(Arc::new(hook));
+    }
+
+    /// Remove all registered hooks.
+    pub fn clear(&mut self) {
+        self.pre_apply.clear();
+        self.post_apply.clear();
+    }
+
+    /// Check if no hooks are registered.
+    pub fn is_empty(&self) -> bool {
+        self.pre_apply.is_empty() && self.post_apply.is_empty()
+    }
+
+    /// Execute all pre-apply hooks over provided diff, in registration
+    /// order. The first error is returned as a transaction abort.
+    pub fn pre_apply(
+        &self,
+        diff: &SledDbOverlayStateDiff,
+    ) -> Result<(), TransactionError<sled::Error>> {
+        for hook in &self.pre_apply {
+            hook(diff).map_err(TransactionError::Abort)?;
+        }
+
+        Ok(())
+    }
+
+    /// Execute all post-apply hooks over provided diff, in registration order.
+    pub fn post_apply(&self, diff: &SledDbOverlayStateDiff) {
+        for hook in &self.post_apply {
+            hook(diff);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Secondary indexes maintained by the overlay.
+//!
+//! An index maps each record of a source tree to a set of index keys,
+//! stored in a target tree pointing back to the record key. The overlay
+//! keeps the index tree cache in sync on every write of the source tree,
+//! so the index changes are checkpointed, reverted and applied along
+//! with the source tree ones.
+
+use std::sync::Arc;
+
+use sled::IVec;
+
+/// Function mapping a source tree record key and value to its index keys.
+pub type IndexFn = Arc<dyn Fn(&[u8], &[u8]) -> Vec<IVec> + Send + Sync>;
+
+/// A planned record write, holding its tree, key and new value,
+/// or `None` if the record gets removed.
+pub(crate) type RecordWrite = (IVec, IVec, Option<IVec>);
+
+/// A secondary index declared over a source tree.
+#[derive(Clone)]
+pub(crate) struct SecondaryIndex {
+    /// The tree being indexed.
+    pub source: IVec,
+    /// The tree holding the index records.
+    pub target: IVec,
+    /// Function mapping a source record to its index keys.
+    pub index_keys: IndexFn,
+}
+
+impl SecondaryIndex {
+    /// Compute the index keys of provided source record, if it exists.
+    pub fn keys(&self, key: &[u8], value: Option<&[u8]>) -> Vec<IVec> {
+        match value {
+            Some(value) => (self.index_keys)(key, value),
+            None => vec![],
+        }
+    }
+}
+
+/// Registry of the secondary indexes declared over an overlay.
+/// Clones share the declared index functions.
+#[derive(Clone, Default)]
+pub(crate) struct Indexes {
+    /// Declared indexes, in declaration order.
+    indexes: Vec<SecondaryIndex>,
+}
+
+impl Indexes {
+    /// Declare a new index. Returns an error if the index target tree
+    /// is the source tree itself, or indexes would cascade back into it.
+    pub fn add(&mut self, index: SecondaryIndex) -> Result<(), sled::Error> {
+        if index.source == index.target || self.reaches(&index.target, &index.source) {
+            return Err(sled::Error::Unsupported(
+                "Index can't cascade into its source tree".to_string(),
+            ));
+        }
+
+        self.indexes.push(index);
+        Ok(())
+    }
+
+    /// Check if writes to provided tree cascade into the target tree,
+    /// through declared indexes.
+    fn reaches(&self, tree_key: &IVec, target: &IVec) -> bool {
+        let mut pending = vec![tree_key];
+        let mut visited = vec![];
+        while let Some(tree_key) = pending.pop() {
+            if tree_key == target {
+                return true;
+            }
+            if visited.contains(&tree_key) {
+                continue;
+            }
+            visited.push(tree_key);
+            pending.extend(self.of(tree_key).map(|index| &index.target));
+        }
+
+        false
+    }
+
+    /// Remove all declared indexes of provided source tree.
+    pub fn remove(&mut self, source: &[u8]) {
+        self.indexes.retain(|index| index.source != source);
+    }
+
+    /// Check if no indexes are declared.
+    pub fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+
+    /// Check if provided tree is indexed.
+    pub fn is_source(&self, tree_key: &[u8]) -> bool {
+        self.indexes.iter().any(|index| index.source == tree_key)
+    }
+
+    /// Iterate over the declared indexes of provided source tree.
+    pub fn of<'a>(&'a self, tree_key: &'a [u8]) -> impl Iterator<Item = &'a SecondaryIndex> {
+        self.indexes
+            .iter()
+            .filter(move |index| index.source == tree_key)
+    }
+}
@@ -0,0 +1,336 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Intent log making [`SledDbOverlay::apply`] all-or-nothing across tree
+//! creations, drops and writes.
+//!
+//! Trees can't be created or dropped within a transaction, so before
+//! creating any trees, the apply logs its intent in [`INTENT_LOG_TREE`],
+//! and marks it as committed within the transaction writing its changes.
+//! Removed trees only get dropped after that, and then the intent gets
+//! removed. If the apply gets interrupted, the next [`SledDbOverlay::new`]
+//! rolls it back if it wasn't committed, by dropping the trees it created,
+//! or completes it otherwise, by dropping the trees it removed. Applies
+//! also recover interrupted ones before writing their changes, in case
+//! recovering failed when their overlay got instantiated.
+
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sled::IVec;
+
+use crate::{
+    backend::{KvBatch, KvStore, KvTree},
+    database::TreeOp,
+    serial::{Decodable, Encodable, Source},
+    SledDbOverlay, APPLY_JOURNAL_TREE,
+};
+
+/// Protected tree holding the intents of the applies in progress.
+pub const INTENT_LOG_TREE: &[u8] = b"__sled_overlay_intent_log";
+
+/// Phase of an intent whose changes haven't been written yet.
+const PREPARED: u8 = 0;
+
+/// Phase of an intent whose changes have been written.
+const COMMITTED: u8 = 1;
+
+/// Keys of the intents this process is executing, which must not be recovered.
+static IN_FLIGHT: Mutex<BTreeSet<IVec>> = Mutex::new(BTreeSet::new());
+
+/// Counter making the intent keys unique within this process.
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Auxilliary function to acquire the in-flight intents lock.
+fn in_flight() -> MutexGuard<'static, BTreeSet<IVec>> {
+    IN_FLIGHT.lock().expect("Intent log lock is poisoned")
+}
+
+/// Auxilliary function to generate a new unique intent key, ordered by time.
+fn intent_key() -> IVec {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or_default();
+    let mut key = nanos.to_be_bytes().to_vec();
+    key.extend_from_slice(&COUNTER.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    key.into()
+}
+
+/// Auxilliary function to encode an intent record.
+fn encode(phase: u8, created: &Vec<IVec>, dropped: &Vec<IVec>) -> Vec<u8> {
+    let mut bytes = vec![];
+    phase.encode(&mut bytes);
+    created.encode(&mut bytes);
+    dropped.encode(&mut bytes);
+    bytes
+}
+
+/// Auxilliary function to decode an intent record.
+fn decode(bytes: &[u8]) -> Result<(u8, Vec<IVec>, Vec<IVec>), sled::Error> {
+    let mut source = Source::new(bytes);
+    let phase = u8::decode(&mut source)?;
+    let created = Vec::decode(&mut source)?;
+    let dropped = Vec::decode(&mut source)?;
+    if !source.is_empty() || phase > COMMITTED {
+        return Err(sled::Error::Unsupported("Invalid encoding".to_string()));
+    }
+
+    Ok((phase, created, dropped))
+}
+
+/// Auxilliary function to remove provided intent from the log, dropping
+/// the log tree once it holds no intents, so it only exists while an
+/// apply is in progress, or got interrupted. Provided in-flight intents
+/// lock must be held, so no intent gets logged meanwhile. Intents are
+/// logged under that lock, so an empty log means none of them are in
+/// flight for this store, regardless of other stores' ones.
+fn release<S: KvStore>(
+    db: &S,
+    log: &S::Tree,
+    key: &IVec,
+    in_flight: &mut BTreeSet<IVec>,
+) -> Result<(), sled::Error> {
+    in_flight.remove(key);
+
+    let mut batch = KvBatch::default();
+    batch.remove(key.clone());
+    log.apply_batch(&batch)?;
+
+    if log.is_empty() {
+        db.drop_tree(INTENT_LOG_TREE)?;
+    }
+
+    Ok(())
+}
+
+/// Returns `true` if provided tree is reserved for the intent log
+/// or the chunked apply journal, so it must never be dropped.
+pub(crate) fn is_reserved(tree_key: &[u8]) -> bool {
+    tree_key == INTENT_LOG_TREE || tree_key == APPLY_JOURNAL_TREE
+}
+
+/// Complete or roll back all the interrupted intents found in the
+/// intent log of provided store. Returns `true` if any were found.
+pub(crate) fn recover<S: KvStore>(db: &S) -> Result<bool, sled::Error> {
+    if !db.tree_names().contains(&INTENT_LOG_TREE.into()) {
+        return Ok(false);
+    }
+
+    // We hold the lock so no intent gets logged while we recover
+    let mut in_flight = in_flight();
+    let log = db.open_tree(INTENT_LOG_TREE)?;
+    let mut recovered = false;
+    for record in log.iter() {
+        let (key, value) = record?;
+        if in_flight.contains(&key) {
+            continue;
+        }
+
+        // Committed intents get completed, otherwise rolled back
+        let (phase, created, dropped) = decode(&value)?;
+        let trees = if phase == COMMITTED { dropped } else { created };
+        for tree_key in trees {
+            db.drop_tree(&tree_key)?;
+        }
+
+        release(db, &log, &key, &mut in_flight)?;
+        recovered = true;
+    }
+
+    Ok(recovered)
+}
+
+/// The intent of an apply, splitting its tree operations into the ones
+/// executed before writing the changes, and the drops deferred after
+/// them. Trees that get dropped and then reopened are cleared within
+/// the transaction instead, so an interrupted apply doesn't lose them.
+/// Dropping it before it got committed rolls it back.
+pub(crate) struct Intent<S: KvStore> {
+    /// The store the intent applies to.
+    db: S,
+    /// Tree operations to execute before writing the changes.
+    ops: Vec<TreeOp>,
+    /// Trees that will be created.
+    created: Vec<IVec>,
+    /// Trees to drop after writing the changes.
+    dropped: Vec<IVec>,
+    /// Trees to clear while writing the changes.
+    cleared: Vec<IVec>,
+    /// The log tree and the intent key, if the intent has been logged.
+    log: Option<(S::Tree, IVec)>,
+    /// Flag indicating the changes have been written.
+    committed: bool,
+}
+
+impl<S: KvStore> Intent<S> {
+    /// Instantiate a new [`Intent`] executing provided tree operations.
+    /// Opened trees that are not in provided initial tree names, and hold
+    /// no records yet, are considered created by the intent.
+    pub fn new(db: &S, ops: Vec<TreeOp>, initial_tree_names: &[IVec]) -> Result<Self, sled::Error> {
+        let existing = db.tree_names();
+        let mut before = vec![];
+        let mut created = vec![];
+        let mut dropped = vec![];
+        let mut cleared = vec![];
+
+        for (index, op) in ops.iter().enumerate() {
+            match op {
+                TreeOp::Open(tree_key) => {
+                    if !initial_tree_names.contains(tree_key)
+                        && !created.contains(tree_key)
+                        && (!existing.contains(tree_key) || db.open_tree(tree_key)?.is_empty())
+                    {
+                        created.push(tree_key.clone());
+                    }
+                    before.push(op.clone());
+                }
+                TreeOp::Drop(tree_key) => {
+                    // Trees that get reopened must be cleared by their writes
+                    let reopened = ops[index + 1..]
+                        .iter()
+                        .any(|op| matches!(op, TreeOp::Open(k) if k == tree_key));
+                    if reopened {
+                        if !cleared.contains(tree_key) {
+                            cleared.push(tree_key.clone());
+                        }
+                    } else {
+                        dropped.push(tree_key.clone());
+                    }
+                }
+            }
+        }
+
+        // Trees dropped afterwards don't need to be opened
+        before.retain(|op| !matches!(op, TreeOp::Open(k) if dropped.contains(k)));
+        cleared.retain(|k| !dropped.contains(k));
+
+        Ok(Self {
+            db: db.clone(),
+            ops: before,
+            created,
+            dropped,
+            cleared,
+            log: None,
+            committed: false,
+        })
+    }
+
+    /// Tree operations to execute before writing the changes.
+    pub fn ops(&self) -> &[TreeOp] {
+        &self.ops
+    }
+
+    /// Trees to clear while writing the changes.
+    pub fn cleared(&self) -> &[IVec] {
+        &self.cleared
+    }
+
+    /// Log the intent, if it creates or drops any trees.
+    pub fn prepare(&mut self) -> Result<(), sled::Error> {
+        if self.created.is_empty() && self.dropped.is_empty() {
+            return Ok(());
+        }
+
+        // We hold the lock so the log tree doesn't get dropped meanwhile
+        let mut in_flight = in_flight();
+        let log = self.db.open_tree(INTENT_LOG_TREE)?;
+        let key = intent_key();
+        in_flight.insert(key.clone());
+        self.log = Some((log.clone(), key.clone()));
+
+        let mut batch = KvBatch::default();
+        batch.insert(key, encode(PREPARED, &self.created, &self.dropped));
+        log.apply_batch(&batch)
+    }
+
+    /// Add the log tree and the batch marking the intent as committed
+    /// to provided transaction trees and batches, if it was logged.
+    pub fn commit_batch(&self, trees: &mut Vec<S::Tree>, batches: &mut Vec<KvBatch>) -> bool {
+        let Some((log, key)) = &self.log else {
+            return false;
+        };
+
+        let mut batch = KvBatch::default();
+        batch.insert(key.clone(), encode(COMMITTED, &self.created, &self.dropped));
+        trees.push(log.clone());
+        batches.push(batch);
+        true
+    }
+
+    /// Mark the intent as committed, after its changes have been written.
+    pub fn committed(&mut self) {
+        self.committed = true;
+    }
+
+    /// Drop the removed trees and remove the intent from the log.
+    pub fn complete(mut self) -> Result<(), sled::Error> {
+        for tree_key in self.dropped.iter() {
+            self.db.drop_tree(tree_key)?;
+        }
+
+        let Some((log, key)) = self.log.take() else {
+            return Ok(());
+        };
+
+        let mut in_flight = in_flight();
+        let result = release(&self.db, &log, &key, &mut in_flight);
+        // Make sure the intent doesn't stay in flight if releasing failed
+        in_flight.remove(&key);
+        result
+    }
+}
+
+impl<S: KvStore> Drop for Intent<S> {
+    fn drop(&mut self) {
+        let Some((log, key)) = self.log.take() else {
+            return;
+        };
+
+        // Roll back uncommitted intents right away, while committed
+        // ones are left to be completed by the recovery.
+        let mut rolled_back = !self.committed;
+        if rolled_back {
+            for tree_key in self.created.iter() {
+                rolled_back &= self.db.drop_tree(tree_key).is_ok();
+            }
+        }
+
+        let mut in_flight = in_flight();
+        if rolled_back {
+            let _ = release(&self.db, &log, &key, &mut in_flight);
+        }
+        in_flight.remove(&key);
+    }
+}
+
+impl<S: KvStore> SledDbOverlay<S> {
+    /// Complete or roll back all the interrupted applies found in the
+    /// database intent log, which [`SledDbOverlay::new`] also attempts,
+    /// and every apply does before writing its changes. Returns `true`
+    /// if any were found.
+    pub fn recover(&self) -> Result<bool, sled::Error> {
+        recover(&self.db)
+    }
+}
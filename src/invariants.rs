@@ -0,0 +1,126 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Consistency checks of the overlay state invariants.
+
+use std::fmt;
+
+use sled::IVec;
+
+use crate::{
+    backend::{KvStore, KvTree},
+    database::SledDbOverlayState,
+    SledDbOverlay,
+};
+
+/// A violation of the [`SledDbOverlayState`] invariants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// A key is both inserted and removed in a tree overlay cache state.
+    CachedAndRemoved {
+        /// The tree of the key.
+        tree: IVec,
+        /// The key.
+        key: IVec,
+    },
+    /// A tree is tracked both as a new tree and as an initial tree.
+    NewAndInitial {
+        /// The tree.
+        tree: IVec,
+    },
+    /// A dropped tree still has a live cache.
+    DroppedWithCache {
+        /// The tree.
+        tree: IVec,
+    },
+    /// A protected tree is dropped.
+    ProtectedDropped {
+        /// The tree.
+        tree: IVec,
+    },
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CachedAndRemoved { tree, key } => {
+                write!(f, "Key {key:?} of tree {tree:?} is both cached and removed")
+            }
+            Self::NewAndInitial { tree } => {
+                write!(f, "Tree {tree:?} is both a new and an initial tree")
+            }
+            Self::DroppedWithCache { tree } => {
+                write!(f, "Dropped tree {tree:?} has a live cache")
+            }
+            Self::ProtectedDropped { tree } => {
+                write!(f, "Protected tree {tree:?} is dropped")
+            }
+        }
+    }
+}
+
+impl<T: KvTree> SledDbOverlayState<T> {
+    /// Verify the state is consistent, returning every violation of
+    /// its invariants found. An empty vector means the state is
+    /// consistent.
+    pub fn check_invariants(&self) -> Vec<InvariantViolation> {
+        let mut violations = vec![];
+
+        // Cache state inserted and removed keys must be disjoint
+        for (tree, cache) in self.caches.iter() {
+            for key in cache.state.removed.iter() {
+                if cache.state.cache.contains_key(key) {
+                    violations.push(InvariantViolation::CachedAndRemoved {
+                        tree: tree.clone(),
+                        key: key.clone(),
+                    });
+                }
+            }
+        }
+
+        // New trees must not be initial trees
+        for tree in self.new_tree_names.iter() {
+            if self.initial_tree_names.contains(tree) {
+                violations.push(InvariantViolation::NewAndInitial { tree: tree.clone() });
+            }
+        }
+
+        for tree in self.dropped_trees.keys() {
+            // Dropped trees must not have a live cache
+            if self.caches.contains_key(tree) {
+                violations.push(InvariantViolation::DroppedWithCache { tree: tree.clone() });
+            }
+
+            // Protected trees must never be dropped
+            if self.protected_tree_names.contains(tree) {
+                violations.push(InvariantViolation::ProtectedDropped { tree: tree.clone() });
+            }
+        }
+
+        violations
+    }
+}
+
+impl<S: KvStore> SledDbOverlay<S> {
+    /// Verify the current overlay state is consistent, returning every
+    /// violation of its invariants found. See
+    /// [`SledDbOverlayState::check_invariants`] for details.
+    pub fn check_invariants(&self) -> Vec<InvariantViolation> {
+        self.state.check_invariants()
+    }
+}
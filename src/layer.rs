@@ -0,0 +1,610 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Stackable overlays, allowing a child overlay layer to sit on top of a
+//! [`SledDbOverlay`] or another layer.
+//!
+//! Reads fall through the child layer to its parent, and eventually to the
+//! [`sled::Db`]. Committing a layer merges its state into its parent, while
+//! dropping it without committing discards its changes, so nested
+//! executions can be reverted independently of each other. Layers never
+//! touch their parent before getting committed, including the trees they
+//! open, and a failed commit reverts the parent to its last checkpoint.
+
+use std::{
+    collections::{btree_map, BTreeMap},
+    iter::Peekable,
+};
+
+use sled::IVec;
+
+use crate::{backend::KvStore, SledDbOverlay, SledTreeOverlayState};
+
+/// Boxed iterator over the records of an overlay tree.
+pub type LayerIter<'a> = Box<dyn Iterator<Item = Result<(IVec, IVec), sled::Error>> + 'a>;
+
+/// An overlay that can act as the parent of a [`SledDbOverlayLayer`].
+pub trait StackableOverlay {
+    /// Retrieve a value from the overlay if it exists in the specified tree.
+    fn get(&self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error>;
+
+    /// Returns `true` if the overlay contains a value for a specified key
+    /// in the specified tree.
+    fn contains_key(&self, tree_key: &[u8], key: &[u8]) -> Result<bool, sled::Error> {
+        Ok(self.get(tree_key, key)?.is_some())
+    }
+
+    /// Immutably iterate through the specified tree.
+    fn iter(&self, tree_key: &[u8]) -> Result<LayerIter<'_>, sled::Error>;
+
+    /// Retrieve a value from the specified tree as if it was opened,
+    /// without opening it, so child layers can read the trees they
+    /// opened before getting committed.
+    fn get_unopened(&self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error>;
+
+    /// Immutably iterate through the specified tree as if it was
+    /// opened, without opening it.
+    fn iter_unopened(&self, tree_key: &[u8]) -> Result<LayerIter<'_>, sled::Error>;
+
+    /// Insert a key to a new value in the specified tree, returning the
+    /// last value if it was set.
+    fn insert(
+        &mut self,
+        tree_key: &[u8],
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<IVec>, sled::Error>;
+
+    /// Delete a value in the specified tree, returning the old value if it existed.
+    fn remove(&mut self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error>;
+
+    /// Open the specified tree, marking it as protected if requested.
+    fn open_tree(&mut self, tree_name: &[u8], protected: bool) -> Result<(), sled::Error>;
+
+    /// Drop the specified tree.
+    fn drop_tree(&mut self, tree_name: &[u8]) -> Result<(), sled::Error>;
+
+    /// Returns `true` if the specified tree is protected.
+    fn is_protected(&self, tree_key: &[u8]) -> bool;
+
+    /// Checkpoint the current overlay state so we can revert to it, if needed.
+    fn checkpoint(&mut self);
+
+    /// Revert the overlay state to its last checkpoint.
+    fn revert_to_checkpoint(&mut self);
+
+    /// Create a new child [`SledDbOverlayLayer`] on top of the overlay.
+    fn layer(&mut self) -> SledDbOverlayLayer<'_, Self>
+    where
+        Self: Sized,
+    {
+        SledDbOverlayLayer::new(self)
+    }
+}
+
+impl<S: KvStore> StackableOverlay for SledDbOverlay<S> {
+    fn get(&self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        SledDbOverlay::get(self, tree_key, key)
+    }
+
+    fn iter(&self, tree_key: &[u8]) -> Result<LayerIter<'_>, sled::Error> {
+        Ok(Box::new(SledDbOverlay::iter(self, tree_key)?))
+    }
+
+    fn get_unopened(&self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        SledDbOverlay::get_unopened(self, tree_key, key)
+    }
+
+    fn iter_unopened(&self, tree_key: &[u8]) -> Result<LayerIter<'_>, sled::Error> {
+        SledDbOverlay::iter_unopened(self, tree_key)
+    }
+
+    fn insert(
+        &mut self,
+        tree_key: &[u8],
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<IVec>, sled::Error> {
+        SledDbOverlay::insert(self, tree_key, key, value)
+    }
+
+    fn remove(&mut self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        SledDbOverlay::remove(self, tree_key, key)
+    }
+
+    fn open_tree(&mut self, tree_name: &[u8], protected: bool) -> Result<(), sled::Error> {
+        SledDbOverlay::open_tree(self, tree_name, protected)
+    }
+
+    fn drop_tree(&mut self, tree_name: &[u8]) -> Result<(), sled::Error> {
+        SledDbOverlay::drop_tree(self, tree_name)
+    }
+
+    fn is_protected(&self, tree_key: &[u8]) -> bool {
+        self.state
+            .protected_tree_names
+            .contains(&IVec::from(tree_key))
+    }
+
+    fn checkpoint(&mut self) {
+        SledDbOverlay::checkpoint(self)
+    }
+
+    fn revert_to_checkpoint(&mut self) {
+        SledDbOverlay::revert_to_checkpoint(self)
+    }
+}
+
+/// Cache state of a child overlay on top of a parent [`StackableOverlay`],
+/// shared by [`SledDbOverlayLayer`] and [`crate::SledDbOverlayFork`].
+/// Its functions receive the parent they sit on top of.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LayerState {
+    /// Trees opened that the parent hasn't opened, or marked
+    /// as protected in it, along with their protected flag.
+    opened_trees: BTreeMap<IVec, bool>,
+    /// Current cache state of each changed tree.
+    caches: BTreeMap<IVec, SledTreeOverlayState>,
+    /// Dropped trees, along with their cache state,
+    /// so it can be restored if they get reopened.
+    dropped_trees: BTreeMap<IVec, SledTreeOverlayState>,
+}
+
+impl LayerState {
+    /// Ensure the specified tree hasn't been dropped.
+    fn check_tree(&self, tree_key: &[u8]) -> Result<(), sled::Error> {
+        if self.dropped_trees.contains_key(tree_key) {
+            return Err(sled::Error::CollectionNotFound(tree_key.into()));
+        }
+
+        Ok(())
+    }
+
+    /// Retrieve a value beneath our cache state, from the parent,
+    /// reading the tree as if it was opened if only we opened it.
+    fn base_get<P: StackableOverlay>(
+        &self,
+        parent: &P,
+        tree_key: &[u8],
+        key: &[u8],
+    ) -> Result<Option<IVec>, sled::Error> {
+        match self.opened_trees.contains_key(tree_key) {
+            true => parent.get_unopened(tree_key, key),
+            false => parent.get(tree_key, key),
+        }
+    }
+
+    /// Iterate over the records beneath our cache state, from the
+    /// parent, reading the tree as if it was opened if only we opened it.
+    fn base_iter<'a, P: StackableOverlay>(
+        &self,
+        parent: &'a P,
+        tree_key: &[u8],
+    ) -> Result<LayerIter<'a>, sled::Error> {
+        match self.opened_trees.contains_key(tree_key) {
+            true => parent.iter_unopened(tree_key),
+            false => parent.iter(tree_key),
+        }
+    }
+
+    /// Returns `true` if we have no changes.
+    pub fn is_clean(&self) -> bool {
+        self.opened_trees.is_empty()
+            && self.dropped_trees.is_empty()
+            && self
+                .caches
+                .values()
+                .all(|cache| cache.cache.is_empty() && cache.removed.is_empty())
+    }
+
+    /// Retrieve a value from the specified tree, reading
+    /// through our cache state to the parent.
+    pub fn get<P: StackableOverlay>(
+        &self,
+        parent: &P,
+        tree_key: &[u8],
+        key: &[u8],
+    ) -> Result<Option<IVec>, sled::Error> {
+        self.check_tree(tree_key)?;
+
+        if let Some(cache) = self.caches.get(tree_key) {
+            if cache.removed.contains(key) {
+                return Ok(None);
+            }
+
+            if let Some(value) = cache.cache.get(key) {
+                return Ok(Some(value.clone()));
+            }
+        }
+
+        self.base_get(parent, tree_key, key)
+    }
+
+    /// Immutably iterate through the specified tree, merging
+    /// our cache state over the parent records.
+    pub fn iter<'a, P: StackableOverlay>(
+        &'a self,
+        parent: &'a P,
+        tree_key: &[u8],
+    ) -> Result<LayerIter<'a>, sled::Error> {
+        self.check_tree(tree_key)?;
+
+        let base_iter = self.base_iter(parent, tree_key)?;
+        let Some(cache) = self.caches.get(tree_key) else {
+            return Ok(base_iter);
+        };
+
+        Ok(Box::new(SledDbOverlayLayerIter::new(base_iter, cache)))
+    }
+
+    /// Retrieve a value from the specified tree as if it was opened,
+    /// without opening it. Dropped trees are read with their cache restored.
+    pub fn get_unopened<P: StackableOverlay>(
+        &self,
+        parent: &P,
+        tree_key: &[u8],
+        key: &[u8],
+    ) -> Result<Option<IVec>, sled::Error> {
+        let cache = self
+            .caches
+            .get(tree_key)
+            .or_else(|| self.dropped_trees.get(tree_key));
+
+        if let Some(cache) = cache {
+            if cache.removed.contains(key) {
+                return Ok(None);
+            }
+
+            if let Some(value) = cache.cache.get(key) {
+                return Ok(Some(value.clone()));
+            }
+        }
+
+        parent.get_unopened(tree_key, key)
+    }
+
+    /// Immutably iterate through the specified tree as if it was opened,
+    /// without opening it. Dropped trees are read with their cache restored.
+    pub fn iter_unopened<'a, P: StackableOverlay>(
+        &'a self,
+        parent: &'a P,
+        tree_key: &[u8],
+    ) -> Result<LayerIter<'a>, sled::Error> {
+        let base_iter = parent.iter_unopened(tree_key)?;
+        let cache = self
+            .caches
+            .get(tree_key)
+            .or_else(|| self.dropped_trees.get(tree_key));
+        let Some(cache) = cache else {
+            return Ok(base_iter);
+        };
+
+        Ok(Box::new(SledDbOverlayLayerIter::new(base_iter, cache)))
+    }
+
+    /// Insert a key to a new value in the specified tree, returning the
+    /// last value if it was set.
+    pub fn insert<P: StackableOverlay>(
+        &mut self,
+        parent: &P,
+        tree_key: &[u8],
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<IVec>, sled::Error> {
+        let prev = self.get(parent, tree_key, key)?;
+
+        let cache = self.caches.entry(tree_key.into()).or_default();
+        cache.removed.remove(key);
+        cache.cache.insert(key.into(), value.into());
+
+        Ok(prev)
+    }
+
+    /// Delete a value in the specified tree, returning the old value if it existed.
+    pub fn remove<P: StackableOverlay>(
+        &mut self,
+        parent: &P,
+        tree_key: &[u8],
+        key: &[u8],
+    ) -> Result<Option<IVec>, sled::Error> {
+        // Previous value must exist
+        let Some(prev) = self.get(parent, tree_key, key)? else {
+            return Err(sled::Error::CollectionNotFound(key.into()));
+        };
+
+        // Only mark the key as removed if our parent contains it
+        let removed = self.base_get(parent, tree_key, key)?.is_some();
+
+        let cache = self.caches.entry(tree_key.into()).or_default();
+        cache.cache.remove(key);
+        if removed {
+            cache.removed.insert(key.into());
+        }
+
+        Ok(Some(prev))
+    }
+
+    /// Open the specified tree, marking it as protected if requested.
+    /// Trees our parent hasn't opened only get opened in it on commit.
+    pub fn open_tree<P: StackableOverlay>(
+        &mut self,
+        parent: &P,
+        tree_name: &[u8],
+        protected: bool,
+    ) -> Result<(), sled::Error> {
+        // If we are reopenning a dropped tree, restore its cache,
+        // otherwise stage opening it if our parent hasn't opened it
+        let staged = match self.dropped_trees.remove(tree_name) {
+            Some(cache) => {
+                self.caches.insert(tree_name.into(), cache);
+                false
+            }
+            None => self.opened_trees.contains_key(tree_name) || parent.iter(tree_name).is_err(),
+        };
+
+        // Mark tree as protected if requested
+        if staged || (protected && !self.is_protected(parent, tree_name)) {
+            let entry = self.opened_trees.entry(tree_name.into()).or_default();
+            *entry |= protected;
+        }
+
+        Ok(())
+    }
+
+    /// Drop the specified tree.
+    pub fn drop_tree<P: StackableOverlay>(
+        &mut self,
+        parent: &P,
+        tree_name: &[u8],
+    ) -> Result<(), sled::Error> {
+        // Check if tree is protected
+        if self.is_protected(parent, tree_name) {
+            return Err(sled::Error::Unsupported(
+                "Protected tree can't be dropped".to_string(),
+            ));
+        }
+
+        // Check if already removed
+        self.check_tree(tree_name)?;
+
+        // Make sure the tree exists beneath us
+        let _ = self.base_iter(parent, tree_name)?;
+
+        let cache = self.caches.remove(tree_name).unwrap_or_default();
+        self.dropped_trees.insert(tree_name.into(), cache);
+
+        Ok(())
+    }
+
+    /// Returns `true` if the specified tree is protected.
+    pub fn is_protected<P: StackableOverlay>(&self, parent: &P, tree_key: &[u8]) -> bool {
+        parent.is_protected(tree_key) || self.opened_trees.get(tree_key) == Some(&true)
+    }
+
+    /// Commit our changes into provided parent overlay. Opened trees get
+    /// opened first, and then the changes of each tree are merged in place.
+    /// If an error occurs, the parent gets reverted to its last checkpoint,
+    /// so it never ends up partly updated.
+    pub fn commit<P: StackableOverlay>(&self, parent: &mut P) -> Result<(), sled::Error> {
+        if let Err(e) = self.merge(parent) {
+            parent.revert_to_checkpoint();
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Merge our changes into provided parent overlay.
+    fn merge<P: StackableOverlay>(&self, parent: &mut P) -> Result<(), sled::Error> {
+        for (tree_key, protected) in self.opened_trees.iter() {
+            parent.open_tree(tree_key, *protected)?;
+        }
+
+        let trees = self
+            .caches
+            .iter()
+            .map(|(tree_key, cache)| (tree_key, cache, false))
+            .chain(
+                self.dropped_trees
+                    .iter()
+                    .map(|(tree_key, cache)| (tree_key, cache, true)),
+            );
+
+        for (tree_key, cache, drop) in trees {
+            for key in cache.removed.iter() {
+                parent.remove(tree_key, key)?;
+            }
+
+            for (key, value) in cache.cache.iter() {
+                parent.insert(tree_key, key, value)?;
+            }
+
+            // Changes of dropped trees are merged before dropping them,
+            // so they can be restored if the tree gets reopened.
+            if drop {
+                parent.drop_tree(tree_key)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A child overlay layer on top of a parent [`StackableOverlay`], keeping
+/// its own cache state per tree. All changes, including opening trees,
+/// only reach the parent once the layer gets committed.
+pub struct SledDbOverlayLayer<'a, P: StackableOverlay> {
+    /// The parent overlay.
+    parent: &'a mut P,
+    /// Current layer state.
+    state: LayerState,
+    /// Checkpointed layer state, which is empty until we checkpoint.
+    checkpoint: LayerState,
+}
+
+impl<'a, P: StackableOverlay> SledDbOverlayLayer<'a, P> {
+    /// Instantiate a new [`SledDbOverlayLayer`] on top of provided parent.
+    pub fn new(parent: &'a mut P) -> Self {
+        Self {
+            parent,
+            state: LayerState::default(),
+            checkpoint: LayerState::default(),
+        }
+    }
+
+    /// Returns `true` if the layer has no changes.
+    pub fn is_clean(&self) -> bool {
+        self.state.is_clean()
+    }
+
+    /// Returns `true` if specified tree is empty.
+    pub fn is_empty(&self, tree_key: &[u8]) -> Result<bool, sled::Error> {
+        match self.iter(tree_key)?.next() {
+            Some(record) => record.map(|_| false),
+            None => Ok(true),
+        }
+    }
+
+    /// Merge the layer changes into its parent, consuming the layer.
+    /// Trees opened in the layer are opened first, and then the changes
+    /// of each tree are merged in place. If an error occurs, the parent
+    /// is reverted to its last checkpoint, so callers should checkpoint
+    /// it before committing if it contains changes they want to keep.
+    pub fn commit(self) -> Result<(), sled::Error> {
+        self.state.commit(self.parent)
+    }
+
+    /// Discard the layer changes, consuming the layer.
+    /// This is the same as dropping it.
+    pub fn revert(self) {}
+}
+
+impl<P: StackableOverlay> StackableOverlay for SledDbOverlayLayer<'_, P> {
+    fn get(&self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        self.state.get(&*self.parent, tree_key, key)
+    }
+
+    fn iter(&self, tree_key: &[u8]) -> Result<LayerIter<'_>, sled::Error> {
+        self.state.iter(&*self.parent, tree_key)
+    }
+
+    fn get_unopened(&self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        self.state.get_unopened(&*self.parent, tree_key, key)
+    }
+
+    fn iter_unopened(&self, tree_key: &[u8]) -> Result<LayerIter<'_>, sled::Error> {
+        self.state.iter_unopened(&*self.parent, tree_key)
+    }
+
+    fn insert(
+        &mut self,
+        tree_key: &[u8],
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<IVec>, sled::Error> {
+        self.state.insert(&*self.parent, tree_key, key, value)
+    }
+
+    fn remove(&mut self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        self.state.remove(&*self.parent, tree_key, key)
+    }
+
+    fn open_tree(&mut self, tree_name: &[u8], protected: bool) -> Result<(), sled::Error> {
+        self.state.open_tree(&*self.parent, tree_name, protected)
+    }
+
+    fn drop_tree(&mut self, tree_name: &[u8]) -> Result<(), sled::Error> {
+        self.state.drop_tree(&*self.parent, tree_name)
+    }
+
+    fn is_protected(&self, tree_key: &[u8]) -> bool {
+        self.state.is_protected(&*self.parent, tree_key)
+    }
+
+    fn checkpoint(&mut self) {
+        self.checkpoint = self.state.clone();
+    }
+
+    fn revert_to_checkpoint(&mut self) {
+        self.state = self.checkpoint.clone();
+    }
+}
+
+/// Immutable iterator of a [`SledDbOverlayLayer`] tree, merging the
+/// layer cache state over its parent records.
+struct SledDbOverlayLayerIter<'a> {
+    /// Iterator over the parent tree records.
+    parent_iter: Peekable<LayerIter<'a>>,
+    /// Iterator over the layer cache records.
+    cache_iter: Peekable<btree_map::Iter<'a, IVec, IVec>>,
+    /// The layer cache state.
+    cache: &'a SledTreeOverlayState,
+}
+
+impl<'a> SledDbOverlayLayerIter<'a> {
+    /// Instantiate a new [`SledDbOverlayLayerIter`], merging provided
+    /// cache state over the parent records iterator.
+    fn new(parent_iter: LayerIter<'a>, cache: &'a SledTreeOverlayState) -> Self {
+        Self {
+            parent_iter: parent_iter.peekable(),
+            cache_iter: cache.cache.iter().peekable(),
+            cache,
+        }
+    }
+}
+
+impl Iterator for SledDbOverlayLayerIter<'_> {
+    type Item = Result<(IVec, IVec), sled::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Peek over the next parent key, checking if an error occured
+            let parent_key = match self.parent_iter.peek() {
+                Some(Err(e)) => return Some(Err(e.clone())),
+                Some(Ok((k, _))) => Some(k.clone()),
+                None => None,
+            };
+
+            let cache_key = self.cache_iter.peek().map(|(k, _)| (*k).clone());
+
+            // Cache records take precedence over parent records of the same key
+            match (parent_key, cache_key) {
+                (None, None) => return None,
+                (Some(parent_key), Some(cache_key)) if cache_key <= parent_key => {
+                    if cache_key == parent_key {
+                        self.parent_iter.next();
+                    }
+                    let (k, v) = self.cache_iter.next()?;
+                    return Some(Ok((k.clone(), v.clone())));
+                }
+                (None, Some(_)) => {
+                    let (k, v) = self.cache_iter.next()?;
+                    return Some(Ok((k.clone(), v.clone())));
+                }
+                (Some(parent_key), _) => {
+                    let record = self.parent_iter.next()?;
+                    // Skip keys removed in the layer
+                    if self.cache.removed.contains(&parent_key) {
+                        continue;
+                    }
+                    return Some(record);
+                }
+            }
+        }
+    }
+}
@@ -25,10 +25,88 @@
 
 pub use sled;
 
+pub mod backend;
+pub use backend::{KvBatch, KvStore, KvTree, MemoryStore, MemoryTree};
+
 pub mod tree;
 pub use tree::{
     SledTreeOverlay, SledTreeOverlayIter, SledTreeOverlayState, SledTreeOverlayStateDiff,
 };
 
 pub mod database;
-pub use database::{SledDbOverlay, SledDbOverlayStateDiff};
+pub use database::{
+    ApplyOptions, ApplyOutcome, SledDbOverlay, SledDbOverlayStateDiff, TreeCopy, TreeStatus,
+};
+
+pub mod chunked;
+pub use chunked::{ApplyProgress, APPLY_JOURNAL_TREE};
+
+pub mod compare;
+pub use compare::{diff_stores, diff_trees};
+
+pub mod digest;
+#[cfg(any(feature = "blake3", feature = "sha256"))]
+pub use digest::DefaultStateHasher;
+pub use digest::{StateDigest, StateHasher};
+
+pub mod display;
+pub use display::Pretty;
+
+pub mod export;
+
+pub mod fork;
+pub use fork::{ForkChanges, SledDbOverlayFork};
+
+pub mod hooks;
+pub use hooks::{PostApplyHook, PreApplyHook};
+
+pub mod index;
+pub use index::IndexFn;
+
+pub mod intent;
+pub use intent::INTENT_LOG_TREE;
+
+pub mod invariants;
+pub use invariants::InvariantViolation;
+
+pub mod layer;
+pub use layer::{SledDbOverlayLayer, StackableOverlay};
+
+#[cfg(any(feature = "blake3", feature = "sha256"))]
+pub mod merkle;
+#[cfg(any(feature = "blake3", feature = "sha256"))]
+pub use merkle::{MerkleProof, SparseMerkleTree};
+
+pub mod prefixed;
+pub use prefixed::{PrefixedView, PrefixedViewIter};
+
+pub mod replication;
+pub use replication::{
+    DiffApplier, DiffPublisher, DiffReceiver, DiffSender, ReplicatedDiff, ReplicationStatus,
+    DEFAULT_HISTORY_CAPACITY, REPLICATION_TREE,
+};
+
+pub mod sequence;
+pub use sequence::SEQUENCES_TREE;
+
+pub mod shared;
+pub use shared::SharedSledDbOverlay;
+
+pub mod stats;
+pub use stats::{SledDbOverlayStats, SledTreeOverlayStats};
+
+pub mod watch;
+pub use watch::{Event, EventKind, Subscriber, DEFAULT_SUBSCRIBER_CAPACITY};
+
+#[cfg(feature = "async")]
+pub mod asynchronous;
+#[cfg(feature = "async")]
+pub use asynchronous::SledTreeOverlayStream;
+
+mod pipeline;
+
+mod read_cache;
+
+mod serial;
+
+mod spill;
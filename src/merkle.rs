@@ -0,0 +1,333 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Sparse Merkle tree over the records of a tree overlay.
+//!
+//! Records are placed in a binary tree of depth 256, using the hash of
+//! their key as their path. Subtrees containing a single record are
+//! represented directly by its leaf hash, and empty subtrees by a zeroed
+//! hash, so only branching nodes have to be kept in memory:
+//! * leaf hash: `H(0x00 || H(key) || H(value))`
+//! * node hash: `H(0x01 || left || right)`
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+};
+
+use crate::digest::{DefaultStateHasher, StateDigest, StateHasher};
+
+/// Hash representing an empty subtree.
+pub const EMPTY_ROOT: StateDigest = [0u8; 32];
+
+/// Depth of the tree, equal to the bit length of a [`StateDigest`].
+const DEPTH: usize = 256;
+
+/// Hash provided bytes using the default [`StateHasher`].
+fn hash(bytes: &[u8]) -> StateDigest {
+    let mut hasher = DefaultStateHasher::default();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+/// Compute the leaf hash of a record, using its key and value hashes.
+fn leaf_hash(key_hash: &StateDigest, value_hash: &StateDigest) -> StateDigest {
+    let mut hasher = DefaultStateHasher::default();
+    hasher.update(&[0]);
+    hasher.update(key_hash);
+    hasher.update(value_hash);
+    hasher.finalize()
+}
+
+/// Compute the hash of a branching node.
+fn node_hash(left: &StateDigest, right: &StateDigest) -> StateDigest {
+    let mut hasher = DefaultStateHasher::default();
+    hasher.update(&[1]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize()
+}
+
+/// Returns the bit of provided path at given depth.
+fn bit(path: &StateDigest, depth: usize) -> bool {
+    (path[depth / 8] >> (7 - depth % 8)) & 1 == 1
+}
+
+/// Returns the number of leading bits two paths share.
+fn common_prefix(a: &StateDigest, b: &StateDigest) -> usize {
+    for (i, (x, y)) in a.iter().zip(b.iter()).enumerate() {
+        if x != y {
+            return i * 8 + (x ^ y).leading_zeros() as usize;
+        }
+    }
+    DEPTH
+}
+
+/// Returns the first and last paths under the node at given depth
+/// of provided path.
+fn node_bounds(path: &StateDigest, depth: usize) -> (StateDigest, StateDigest) {
+    let mut lower = *path;
+    let mut upper = *path;
+    for i in depth..DEPTH {
+        let mask = 1 << (7 - i % 8);
+        lower[i / 8] &= !mask;
+        upper[i / 8] |= mask;
+    }
+    (lower, upper)
+}
+
+/// A sparse Merkle tree, which can be updated incrementally.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SparseMerkleTree {
+    /// Value hashes and leaf hashes, keyed by their record key hash.
+    leaves: BTreeMap<StateDigest, (StateDigest, StateDigest)>,
+    /// Hashes of nodes containing at least two leaves,
+    /// keyed by their depth and first path.
+    nodes: HashMap<(usize, StateDigest), StateDigest>,
+}
+
+impl SparseMerkleTree {
+    /// Instantiate a new empty [`SparseMerkleTree`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build a [`SparseMerkleTree`] from provided records.
+    pub fn from_records<I, K, V>(records: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let mut tree = Self::new();
+        for (key, value) in records {
+            let key_hash = hash(key.as_ref());
+            let value_hash = hash(value.as_ref());
+            tree.leaves
+                .insert(key_hash, (value_hash, leaf_hash(&key_hash, &value_hash)));
+        }
+
+        // Build all branching nodes bottom up
+        let leaves: Vec<(StateDigest, StateDigest)> =
+            tree.leaves.iter().map(|(k, (_, v))| (*k, *v)).collect();
+        tree.build(0, &leaves);
+
+        tree
+    }
+
+    /// Recursively compute node hashes for provided sorted leaves slice,
+    /// starting from given depth.
+    fn build(&mut self, depth: usize, leaves: &[(StateDigest, StateDigest)]) -> StateDigest {
+        match leaves.len() {
+            0 => return EMPTY_ROOT,
+            1 => return leaves[0].1,
+            _ => {}
+        }
+
+        let split = leaves.partition_point(|(path, _)| !bit(path, depth));
+        let left = self.build(depth + 1, &leaves[..split]);
+        let right = self.build(depth + 1, &leaves[split..]);
+        let hash = node_hash(&left, &right);
+        let (lower, _) = node_bounds(&leaves[0].0, depth);
+        self.nodes.insert((depth, lower), hash);
+
+        hash
+    }
+
+    /// Returns the number of records in the tree.
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Returns `true` if the tree contains no records.
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// Returns the root hash of the tree.
+    pub fn root(&self) -> StateDigest {
+        self.node(0, &EMPTY_ROOT)
+    }
+
+    /// Set the value of a record.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        let key_hash = hash(key);
+        let value_hash = hash(value);
+        let leaf = leaf_hash(&key_hash, &value_hash);
+        self.update(key_hash, Some((value_hash, leaf)));
+    }
+
+    /// Remove a record, if it exists.
+    pub fn remove(&mut self, key: &[u8]) {
+        self.update(hash(key), None);
+    }
+
+    /// Update provided record path leaf, and recompute all the
+    /// branching nodes along its path.
+    fn update(&mut self, path: StateDigest, leaf: Option<(StateDigest, StateDigest)>) {
+        let before = self.branch_depth(&path);
+        match leaf {
+            Some(leaf) => self.leaves.insert(path, leaf),
+            None => self.leaves.remove(&path),
+        };
+        let after = self.branch_depth(&path);
+
+        // Remove nodes that no longer contain multiple leaves
+        if let Some(before) = before {
+            let start = after.map_or(0, |after| after + 1);
+            for depth in start..=before {
+                self.nodes.remove(&(depth, node_bounds(&path, depth).0));
+            }
+        }
+
+        // Recompute remaining nodes bottom up
+        let Some(after) = after else {
+            return;
+        };
+        for depth in (0..=after).rev() {
+            let (lower, _) = node_bounds(&path, depth);
+            let mut right = lower;
+            right[depth / 8] |= 1 << (7 - depth % 8);
+            let hash = node_hash(&self.node(depth + 1, &lower), &self.node(depth + 1, &right));
+            self.nodes.insert((depth, lower), hash);
+        }
+    }
+
+    /// Returns the deepest depth at which the node along provided
+    /// path contains at least two leaves, if any.
+    fn branch_depth(&self, path: &StateDigest) -> Option<usize> {
+        // Nodes contain contiguous leaves ranges, so we only
+        // need to check the path closest neighbours.
+        let mut neighbours: Vec<&StateDigest> = self
+            .leaves
+            .range(..*path)
+            .rev()
+            .take(2)
+            .map(|(k, _)| k)
+            .collect();
+        neighbours.reverse();
+        neighbours.extend(
+            self.leaves
+                .range((Bound::Included(*path), Bound::Unbounded))
+                .take(3)
+                .map(|(k, _)| k),
+        );
+
+        neighbours
+            .windows(2)
+            .map(|pair| common_prefix(path, pair[0]).min(common_prefix(path, pair[1])))
+            .max()
+    }
+
+    /// Returns the hash of the node at given depth of provided path.
+    fn node(&self, depth: usize, path: &StateDigest) -> StateDigest {
+        let (lower, upper) = node_bounds(path, depth);
+        if let Some(hash) = self.nodes.get(&(depth, lower)) {
+            return *hash;
+        }
+
+        // Node contains at most one leaf
+        match self.leaves.range(lower..=upper).next() {
+            Some((_, (_, leaf))) => *leaf,
+            None => EMPTY_ROOT,
+        }
+    }
+
+    /// Generate a [`MerkleProof`] of inclusion or exclusion for provided key.
+    pub fn prove(&self, key: &[u8]) -> MerkleProof {
+        let path = hash(key);
+        let mut siblings = vec![];
+
+        // Walk down the branching nodes along the path
+        let mut depth = 0;
+        while self
+            .nodes
+            .contains_key(&(depth, node_bounds(&path, depth).0))
+        {
+            let (mut sibling, _) = node_bounds(&path, depth + 1);
+            sibling[depth / 8] ^= 1 << (7 - depth % 8);
+            siblings.push(self.node(depth + 1, &sibling));
+            depth += 1;
+        }
+
+        // Grab the leaf occupying the path position, if any
+        let (lower, upper) = node_bounds(&path, depth);
+        let leaf = self
+            .leaves
+            .range(lower..=upper)
+            .next()
+            .map(|(k, (v, _))| (*k, *v));
+
+        MerkleProof { siblings, leaf }
+    }
+}
+
+/// Proof that a key has a specific value, or doesn't exist,
+/// in a [`SparseMerkleTree`] with a given root.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    /// Sibling hashes along the key path, starting from the root.
+    pub siblings: Vec<StateDigest>,
+    /// The key hash and value hash of the leaf occupying
+    /// the key path position, if any.
+    pub leaf: Option<(StateDigest, StateDigest)>,
+}
+
+impl MerkleProof {
+    /// Verify the proof against provided root. If a value is provided,
+    /// we verify the key contains it, otherwise we verify the key doesn't
+    /// exist.
+    pub fn verify(&self, root: &StateDigest, key: &[u8], value: Option<&[u8]>) -> bool {
+        let path = hash(key);
+        let depth = self.siblings.len();
+        if depth > DEPTH {
+            return false;
+        }
+
+        // Compute the hash at the key path position
+        let mut current = match (value, &self.leaf) {
+            (Some(value), Some((leaf_path, value_hash))) => {
+                if leaf_path != &path || value_hash != &hash(value) {
+                    return false;
+                }
+                leaf_hash(&path, value_hash)
+            }
+            (Some(_), None) => return false,
+            // Another leaf occupies the position, so it must share the path
+            // prefix. Its hash gets recomputed, so it can't be a branching node.
+            (None, Some((leaf_path, value_hash))) => {
+                if leaf_path == &path || common_prefix(leaf_path, &path) < depth {
+                    return false;
+                }
+                leaf_hash(leaf_path, value_hash)
+            }
+            (None, None) => EMPTY_ROOT,
+        };
+
+        // Fold the siblings up to the root
+        for (index, sibling) in self.siblings.iter().enumerate().rev() {
+            current = if bit(&path, index) {
+                node_hash(sibling, &current)
+            } else {
+                node_hash(&current, sibling)
+            };
+        }
+
+        &current == root
+    }
+}
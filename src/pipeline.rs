@@ -0,0 +1,339 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The apply pipeline shared by the blocking and async apply functions.
+//!
+//! Applying is split into three stages: preparing an [`ApplyJob`] from the
+//! overlay, executing it, and finishing the apply on the overlay. Execution
+//! performs all the blocking work without borrowing the overlay, so the async
+//! functions can move it into a blocking thread pool as a whole.
+
+use std::collections::BTreeMap;
+
+use sled::{transaction::TransactionError, IVec};
+
+use crate::{
+    backend::{KvBatch, KvStore, KvTree},
+    database::{execute_tree_ops, TreeOp},
+    intent::{recover, Intent},
+    replication::DiffPublisher,
+    serial::Encodable,
+    ApplyOptions, ApplyOutcome, SledDbOverlay, SledDbOverlayStateDiff,
+};
+
+/// The tree operations and batches of an apply, along with everything
+/// needed to execute them. The intent of the apply lives within its
+/// execution, so it always gets completed or rolled back as a whole.
+pub(crate) struct ApplyJob<S: KvStore> {
+    /// The store to apply the changes to.
+    db: S,
+    /// Tree names existing when the overlay was instantiated.
+    initial_tree_names: Vec<IVec>,
+    /// Tree operations to perform.
+    ops: Vec<TreeOp>,
+    /// Known tree pointers, before performing the tree operations.
+    trees: BTreeMap<IVec, S::Tree>,
+    /// Batches to apply, along with their tree keys.
+    batches: Vec<(IVec, KvBatch)>,
+    /// Copied trees, along with their source tree keys.
+    copies: Vec<(IVec, IVec)>,
+    /// The apply options.
+    options: ApplyOptions,
+    /// Publisher recording the applied diff, along with the encoded diff.
+    publisher: Option<(DiffPublisher<S>, Vec<u8>)>,
+}
+
+/// Outcome of an executed [`ApplyJob`].
+pub(crate) struct AppliedJob<S: KvStore> {
+    /// Tree pointers, after performing the tree operations.
+    trees: BTreeMap<IVec, S::Tree>,
+    /// The trees the batches got applied to.
+    applied_trees: Vec<S::Tree>,
+    /// The applied batches.
+    batches: Vec<KvBatch>,
+    /// Previous values of the applied batches records, if requested.
+    previous: Vec<BTreeMap<IVec, Option<IVec>>>,
+    /// Flag indicating the applied diff was requested.
+    return_diff: bool,
+    /// Outcome of flushing the store, if requested.
+    flushed: Option<Result<usize, sled::Error>>,
+}
+
+impl<S: KvStore> ApplyJob<S> {
+    /// Execute the job: recover interrupted applies, log our intent, perform
+    /// the tree operations, atomically apply all batches as a transaction,
+    /// along with clearing the reopened trees and recording the published
+    /// frame, send the frame, drop removed trees and optionally flush the
+    /// store.
+    pub fn execute(self) -> Result<AppliedJob<S>, TransactionError<sled::Error>> {
+        let Self {
+            db,
+            initial_tree_names,
+            ops,
+            mut trees,
+            mut batches,
+            copies,
+            options,
+            publisher,
+        } = self;
+
+        // Recover interrupted applies
+        recover(&db)?;
+
+        // Log our intent and ensure trees exist
+        let mut intent = Intent::new(&db, ops, &initial_tree_names)?;
+        intent.prepare()?;
+        execute_tree_ops(&db, intent.ops(), &mut trees)?;
+
+        // Copy the source trees records beneath the copies writes
+        let tree_names = db.tree_names();
+        for (tree_key, source) in copies.iter() {
+            if !tree_names.contains(source) {
+                continue;
+            }
+            let batch = tree_batch(&mut batches, tree_key);
+            for record in db.open_tree(source)?.iter() {
+                let (key, value) = record?;
+                batch.records.entry(key).or_insert(Some(value));
+            }
+        }
+
+        // Remove the records of reopened trees, along with their writes
+        for tree_key in intent.cleared() {
+            let Some(tree) = trees.get(tree_key) else {
+                return Err(TransactionError::Storage(sled::Error::CollectionNotFound(
+                    tree_key.clone(),
+                )));
+            };
+            let batch = tree_batch(&mut batches, tree_key);
+            for record in tree.iter() {
+                let (key, _) = record?;
+                batch.records.entry(key).or_insert(None);
+            }
+        }
+
+        // Grab the batches tree pointers
+        let mut applied_trees = Vec::with_capacity(batches.len());
+        let mut applied_batches = Vec::with_capacity(batches.len());
+        for (tree_key, batch) in batches {
+            let Some(tree) = trees.get(&tree_key) else {
+                return Err(TransactionError::Storage(sled::Error::CollectionNotFound(
+                    tree_key,
+                )));
+            };
+            applied_trees.push(tree.clone());
+            applied_batches.push(batch);
+        }
+
+        // Perform an atomic transaction over all the collected trees and
+        // apply the batches, capturing the previous values if needed.
+        let mut commit = |outbox: Option<(S::Tree, KvBatch)>| {
+            let outboxed = outbox.is_some();
+            if let Some((tree, batch)) = outbox {
+                applied_trees.push(tree);
+                applied_batches.push(batch);
+            }
+            let logged = intent.commit_batch(&mut applied_trees, &mut applied_batches);
+            let mut previous = vec![];
+            if !applied_trees.is_empty() {
+                if options.return_diff {
+                    previous = db.transaction_with_previous(&applied_trees, &applied_batches)?;
+                } else {
+                    db.transaction(&applied_trees, &applied_batches)?;
+                }
+            }
+            intent.committed();
+            for _ in 0..(logged as usize + outboxed as usize) {
+                applied_trees.pop();
+                applied_batches.pop();
+                previous.pop();
+            }
+            Ok::<_, TransactionError<sled::Error>>(previous)
+        };
+
+        // Record the published frame along with the batches, then send it
+        let previous = match &publisher {
+            Some((publisher, diff)) => {
+                let (previous, _, sent) =
+                    publisher.commit(&db, diff, |tree, batch| commit(Some((tree, batch))))?;
+                publisher.keep_error(sent);
+                previous
+            }
+            None => commit(None)?,
+        };
+
+        // Drop removed trees
+        intent.complete()?;
+
+        let flushed = options.flush.then(|| db.flush());
+
+        Ok(AppliedJob {
+            trees,
+            applied_trees,
+            batches: applied_batches,
+            previous,
+            return_diff: options.return_diff,
+            flushed,
+        })
+    }
+}
+
+/// Auxilliary function to retrieve the batch of provided tree key,
+/// creating it if it doesn't exist.
+fn tree_batch<'a>(batches: &'a mut Vec<(IVec, KvBatch)>, tree_key: &IVec) -> &'a mut KvBatch {
+    let index = match batches.iter().position(|(k, _)| k == tree_key) {
+        Some(index) => index,
+        None => {
+            batches.push((tree_key.clone(), KvBatch::default()));
+            batches.len() - 1
+        }
+    };
+    &mut batches[index].1
+}
+
+impl<S: KvStore> SledDbOverlay<S> {
+    /// Auxilliary function to retrieve the attached publisher, if any,
+    /// along with provided diff it has to publish, encoded.
+    pub(crate) fn published(
+        &self,
+        diff: &SledDbOverlayStateDiff,
+    ) -> Option<(DiffPublisher<S>, Vec<u8>)> {
+        let publisher = self.publisher.as_ref()?;
+        let mut bytes = vec![];
+        diff.encode(&mut bytes);
+        Some((publisher.clone(), bytes))
+    }
+
+    /// Validate the overlay changes through the pre-apply hooks, and prepare
+    /// the [`ApplyJob`] applying them. Returns the diff the hooks received,
+    /// if any are registered.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn prepare_apply(
+        &self,
+        options: ApplyOptions,
+    ) -> Result<(ApplyJob<S>, Option<SledDbOverlayStateDiff>), TransactionError<sled::Error>> {
+        // Validate the changes through the pre-apply hooks
+        let diff = self.hooked_diff()?;
+        if let Some(diff) = &diff {
+            self.hooks.pre_apply(diff)?;
+        }
+
+        // New trees get reopened, in case they got dropped meanwhile
+        let mut trees = self.get_state_trees();
+        for tree_key in self.state.new_tree_names.iter() {
+            trees.remove(tree_key);
+        }
+
+        let job = ApplyJob {
+            db: self.db.clone(),
+            initial_tree_names: self.state.initial_tree_names.clone(),
+            ops: self.apply_tree_ops(),
+            trees,
+            batches: self.state.batches()?,
+            copies: self
+                .state
+                .tree_copies
+                .iter()
+                .map(|(tree_key, copy)| (tree_key.clone(), copy.source.clone()))
+                .collect(),
+            options,
+            publisher: diff.as_ref().and_then(|diff| self.published(diff)),
+        };
+
+        Ok((job, diff))
+    }
+
+    /// Finish applying the overlay changes after their [`ApplyJob`] got
+    /// executed: update our tree pointers, notify subscribers and execute
+    /// the post-apply hooks with provided diff. Returns the flushing error,
+    /// if it failed, after the changes have been applied.
+    pub(crate) fn finish_apply(
+        &mut self,
+        applied: AppliedJob<S>,
+        diff: Option<SledDbOverlayStateDiff>,
+    ) -> Result<ApplyOutcome, TransactionError<sled::Error>> {
+        let AppliedJob {
+            trees,
+            applied_trees,
+            batches,
+            previous,
+            return_diff,
+            flushed,
+        } = applied;
+
+        self.update_tree_pointers(trees)?;
+
+        let applied_diff =
+            return_diff.then(|| self.applied_diff(&applied_trees, &batches, &previous));
+
+        self.on_applied(&applied_trees, &batches, self.state.dropped_trees.iter());
+        if let Some(diff) = &diff {
+            self.hooks.post_apply(diff);
+        }
+
+        Ok(ApplyOutcome {
+            flushed_bytes: flushed.transpose()?,
+            diff: applied_diff,
+        })
+    }
+
+    /// Validate provided diff through the pre-apply hooks, and prepare
+    /// the [`ApplyJob`] applying it.
+    pub(crate) fn prepare_apply_diff(
+        &mut self,
+        diff: &SledDbOverlayStateDiff,
+    ) -> Result<ApplyJob<S>, TransactionError<sled::Error>> {
+        // Validate the changes through the pre-apply hooks
+        self.hooks.pre_apply(diff)?;
+
+        Ok(ApplyJob {
+            db: self.db.clone(),
+            initial_tree_names: self.state.initial_tree_names.clone(),
+            ops: self.apply_diff_tree_ops(diff)?,
+            trees: self.get_state_trees(),
+            batches: diff.batches(),
+            copies: diff
+                .applied_copies()
+                .map(|(tree_key, source)| (tree_key.clone(), source.clone()))
+                .collect(),
+            options: ApplyOptions::default(),
+            publisher: self.published(diff),
+        })
+    }
+
+    /// Finish applying provided diff after its [`ApplyJob`] got executed:
+    /// notify subscribers, execute the post-apply hooks and remove the
+    /// changes from our current state.
+    pub(crate) fn finish_apply_diff(
+        &mut self,
+        applied: AppliedJob<S>,
+        diff: &SledDbOverlayStateDiff,
+    ) -> Result<(), TransactionError<sled::Error>> {
+        self.on_applied(
+            &applied.applied_trees,
+            &applied.batches,
+            diff.dropped_tree_diffs(),
+        );
+        self.hooks.post_apply(diff);
+
+        // Remove changes from our current state
+        self.try_remove_diff(diff)?;
+
+        Ok(())
+    }
+}
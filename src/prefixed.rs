@@ -0,0 +1,169 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Prefix-scoped views over a [`SledTreeOverlay`], emulating multiple
+//! logical tables inside a single tree.
+
+use std::iter::FusedIterator;
+
+use sled::IVec;
+
+use crate::{backend::KvTree, SledTreeOverlay, SledTreeOverlayIter};
+
+/// A view over a [`SledTreeOverlay`] scoped to the records with keys
+/// starting with a prefix. The prefix is transparently added to keys on
+/// writes, and stripped from them on reads and iteration.
+#[derive(Debug)]
+pub struct PrefixedView<'a, T: KvTree = sled::Tree> {
+    /// The tree overlay we are viewing.
+    overlay: &'a mut SledTreeOverlay<T>,
+    /// The prefix of the keys we are scoped to.
+    prefix: IVec,
+}
+
+impl<'a, T: KvTree> PrefixedView<'a, T> {
+    /// Instantiate a new [`PrefixedView`] over provided tree overlay,
+    /// scoped to provided prefix.
+    pub fn new(overlay: &'a mut SledTreeOverlay<T>, prefix: &[u8]) -> Self {
+        Self {
+            overlay,
+            prefix: prefix.into(),
+        }
+    }
+
+    /// Returns the prefix of the keys we are scoped to.
+    pub fn prefix(&self) -> &IVec {
+        &self.prefix
+    }
+
+    /// Auxilliary function to prepend our prefix to provided key.
+    fn prefixed(&self, key: &[u8]) -> Vec<u8> {
+        let mut prefixed = Vec::with_capacity(self.prefix.len() + key.len());
+        prefixed.extend_from_slice(&self.prefix);
+        prefixed.extend_from_slice(key);
+        prefixed
+    }
+
+    /// Returns `true` if the view contains a value for a specified key.
+    pub fn contains_key(&self, key: &[u8]) -> Result<bool, sled::Error> {
+        self.overlay.contains_key(&self.prefixed(key))
+    }
+
+    /// Retrieve a value from the view if it exists.
+    pub fn get(&self, key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        self.overlay.get(&self.prefixed(key))
+    }
+
+    /// Insert a key to a new value, returning the last value if it was set.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        let key = self.prefixed(key);
+        self.overlay.insert(&key, value)
+    }
+
+    /// Delete a value, if it exists, returning the old value.
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        let key = self.prefixed(key);
+        self.overlay.remove(&key)
+    }
+
+    /// Removes all values of the view, leaving the rest of the tree intact.
+    pub fn clear(&mut self) -> Result<(), sled::Error> {
+        self.overlay.clear_prefix(&self.prefix)
+    }
+
+    /// Returns the number of records in the view.
+    pub fn len(&self) -> Result<usize, sled::Error> {
+        let mut len = 0;
+        for record in self.overlay.scan_prefix(&self.prefix) {
+            record?;
+            len += 1;
+        }
+
+        Ok(len)
+    }
+
+    /// Returns `true` if the view contains no records.
+    pub fn is_empty(&self) -> Result<bool, sled::Error> {
+        match self.overlay.scan_prefix(&self.prefix).next() {
+            Some(record) => record.map(|_| false),
+            None => Ok(true),
+        }
+    }
+
+    /// Returns last key and value from the view or `None` if its empty,
+    /// based on the `Ord` implementation for `Vec<u8>`.
+    pub fn last(&self) -> Result<Option<(IVec, IVec)>, sled::Error> {
+        match self.overlay.scan_prefix(&self.prefix).last() {
+            Some(record) => {
+                let (key, value) = record?;
+                Ok(Some((strip_prefix(&key, self.prefix.len()), value)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Immutably iterate through the view, with keys stripped of our prefix.
+    pub fn iter(&self) -> PrefixedViewIter<'_, T> {
+        PrefixedViewIter {
+            iter: self.overlay.scan_prefix(&self.prefix),
+            prefix_len: self.prefix.len(),
+        }
+    }
+}
+
+impl<T: KvTree> SledTreeOverlay<T> {
+    /// Create a [`PrefixedView`] over the tree overlay, scoped to
+    /// provided prefix.
+    pub fn prefixed(&mut self, prefix: &[u8]) -> PrefixedView<'_, T> {
+        PrefixedView::new(self, prefix)
+    }
+}
+
+/// Auxilliary function to strip a prefix of provided length from a key.
+fn strip_prefix(key: &IVec, prefix_len: usize) -> IVec {
+    key.subslice(prefix_len, key.len() - prefix_len)
+}
+
+/// Immutable iterator of a [`PrefixedView`].
+pub struct PrefixedViewIter<'a, T: KvTree = sled::Tree> {
+    // Iterator over the tree overlay prefixed records.
+    iter: SledTreeOverlayIter<'a, T>,
+    // Length of the prefix to strip.
+    prefix_len: usize,
+}
+
+impl<T: KvTree> Iterator for PrefixedViewIter<'_, T> {
+    type Item = Result<(IVec, IVec), sled::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = self.iter.next()?;
+        Some(record.map(|(key, value)| (strip_prefix(&key, self.prefix_len), value)))
+    }
+}
+
+impl<T: KvTree> FusedIterator for PrefixedViewIter<'_, T> {}
+
+impl<'a, T: KvTree> IntoIterator for &'a PrefixedView<'_, T> {
+    type Item = Result<(IVec, IVec), sled::Error>;
+
+    type IntoIter = PrefixedViewIter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
@@ -0,0 +1,146 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Bounded read-through cache of the base tree values an overlay reads.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use sled::IVec;
+
+/// Cache state behind a [`ReadCache`].
+#[derive(Debug)]
+struct Inner {
+    /// Maximum number of cached keys.
+    capacity: usize,
+    /// Cached keys, along with their value, or `None` if they don't exist,
+    /// and their last access tick.
+    entries: HashMap<IVec, (Option<IVec>, u64)>,
+    /// Cached keys by their last access tick, least recent first.
+    order: BTreeMap<u64, IVec>,
+    /// Tick counter, increased on each access.
+    tick: u64,
+    /// Generation counter, increased on each invalidation.
+    generation: u64,
+}
+
+impl Inner {
+    /// Mark provided key as the most recently used one.
+    fn touch(&mut self, key: &IVec) {
+        self.tick += 1;
+        if let Some((_, tick)) = self.entries.get_mut(key) {
+            self.order.remove(tick);
+            *tick = self.tick;
+            self.order.insert(self.tick, key.clone());
+        }
+    }
+
+    /// Evict the least recently used keys, until we are within our capacity.
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&key);
+        }
+    }
+}
+
+/// A bounded least recently used cache of base tree values, including
+/// negative entries for keys that don't exist. Cloning it produces a new
+/// reference to the same cache, so all overlays over the same tree can
+/// share it.
+#[derive(Debug, Clone)]
+pub struct ReadCache {
+    /// The shared cache state.
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ReadCache {
+    /// Instantiate a new empty [`ReadCache`] holding up to provided number of keys.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+                generation: 0,
+            })),
+        }
+    }
+
+    /// Acquire the cache lock, panicking if it is poisoned.
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("Read cache lock is poisoned")
+    }
+
+    /// Returns the maximum number of cached keys.
+    pub fn capacity(&self) -> usize {
+        self.lock().capacity
+    }
+
+    /// Set the maximum number of cached keys, evicting keys if needed.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.lock();
+        inner.capacity = capacity;
+        inner.evict();
+    }
+
+    /// Returns the current generation, which must be provided when inserting
+    /// a value, so values read before an invalidation are not cached.
+    pub fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    /// Retrieve a cached key. Returns `None` if the key is not cached,
+    /// otherwise its value, or `None` if it doesn't exist.
+    pub fn get(&self, key: &IVec) -> Option<Option<IVec>> {
+        let mut inner = self.lock();
+        let value = inner.entries.get(key)?.0.clone();
+        inner.touch(key);
+        Some(value)
+    }
+
+    /// Cache a key value read at provided generation,
+    /// unless the cache got invalidated since.
+    pub fn insert(&self, key: IVec, value: Option<IVec>, generation: u64) {
+        let mut inner = self.lock();
+        if inner.generation != generation || inner.capacity == 0 {
+            return;
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some((_, previous)) = inner.entries.insert(key.clone(), (value, tick)) {
+            inner.order.remove(&previous);
+        }
+        inner.order.insert(tick, key);
+        inner.evict();
+    }
+
+    /// Remove all cached keys.
+    pub fn invalidate(&self) {
+        let mut inner = self.lock();
+        inner.entries.clear();
+        inner.order.clear();
+        inner.generation += 1;
+    }
+}
@@ -0,0 +1,567 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Replication of applied overlay changes to follower databases.
+//!
+//! On the leader, a [`DiffPublisher`] gets attached to an overlay, and
+//! sends each applied [`SledDbOverlayStateDiff`] as a sequence-numbered
+//! frame over a [`DiffSender`]. The frame and the next sequence number
+//! are stored in the [`REPLICATION_TREE`] of the leader, within the same
+//! transaction as the diff records, so a restarted leader continues the
+//! sequence, and keeps a bounded history of the published frames, so the
+//! missing ones can be resent. On the follower, a [`DiffApplier`] reads
+//! the frames from a [`DiffReceiver`] and applies them in order. The last
+//! applied sequence number is stored in the [`REPLICATION_TREE`] of the
+//! follower, within the same transaction as the diff records, so replayed
+//! frames are skipped and missing ones get detected. An in-process channel
+//! and a Unix socket transport are provided.
+
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Arc, Mutex, MutexGuard,
+};
+
+use sled::{transaction::TransactionError, IVec};
+
+use crate::{
+    backend::{KvBatch, KvStore, KvTree},
+    serial::{Decodable, Encodable, Source},
+    SledDbOverlay, SledDbOverlayStateDiff,
+};
+
+/// Tree holding the last applied sequence number on the follower, and
+/// the next sequence number along with the published frames on the leader.
+pub const REPLICATION_TREE: &[u8] = b"__sled_overlay_replication";
+
+/// Key of the last applied sequence number, stored as a big-endian `u64`.
+const SEQUENCE_KEY: &[u8] = b"sequence";
+
+/// Key of the next published sequence number, stored as a big-endian `u64`.
+const NEXT_SEQUENCE_KEY: &[u8] = b"next";
+
+/// Key prefix of the published frames, followed by their sequence number.
+const FRAME_PREFIX: &[u8] = b"frame";
+
+/// A sequence-numbered [`SledDbOverlayStateDiff`], as sent to followers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicatedDiff {
+    /// The diff sequence number.
+    pub sequence: u64,
+    /// The applied diff.
+    pub diff: SledDbOverlayStateDiff,
+}
+
+impl ReplicatedDiff {
+    /// Serialize the diff into a frame.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.sequence.encode(&mut bytes);
+        self.diff.encode(&mut bytes);
+        bytes
+    }
+
+    /// Deserialize a diff from provided frame.
+    pub fn decode(bytes: &[u8]) -> Result<Self, sled::Error> {
+        let mut source = Source::new(bytes);
+        let sequence = u64::decode(&mut source)?;
+        let diff = SledDbOverlayStateDiff::decode(&mut source)?;
+        if !source.is_empty() {
+            return Err(sled::Error::Unsupported("Invalid encoding".to_string()));
+        }
+
+        Ok(Self { sequence, diff })
+    }
+}
+
+/// Leader side of a replication transport.
+pub trait DiffSender: Send + Sync {
+    /// Send provided frame to the follower.
+    fn send(&self, frame: &[u8]) -> Result<(), sled::Error>;
+}
+
+/// Follower side of a replication transport.
+pub trait DiffReceiver {
+    /// Wait for the next frame, returning `None` once the leader is gone.
+    fn recv(&mut self) -> Result<Option<Vec<u8>>, sled::Error>;
+}
+
+/// Leader side of an in-process channel transport.
+pub struct ChannelSender {
+    /// The frames sender.
+    tx: Mutex<Sender<Vec<u8>>>,
+}
+
+impl DiffSender for ChannelSender {
+    fn send(&self, frame: &[u8]) -> Result<(), sled::Error> {
+        self.tx
+            .lock()
+            .expect("Channel sender lock is poisoned")
+            .send(frame.to_vec())
+            .map_err(|_| sled::Error::Unsupported("Replication channel is closed".to_string()))
+    }
+}
+
+/// Follower side of an in-process channel transport.
+pub struct ChannelReceiver {
+    /// The frames receiver.
+    rx: Receiver<Vec<u8>>,
+}
+
+impl DiffReceiver for ChannelReceiver {
+    fn recv(&mut self) -> Result<Option<Vec<u8>>, sled::Error> {
+        Ok(self.rx.recv().ok())
+    }
+}
+
+/// Create a new in-process channel transport.
+pub fn channel() -> (ChannelSender, ChannelReceiver) {
+    let (tx, rx) = mpsc::channel();
+    (ChannelSender { tx: Mutex::new(tx) }, ChannelReceiver { rx })
+}
+
+#[cfg(unix)]
+pub use unix::{UnixSocketReceiver, UnixSocketSender};
+
+/// Unix socket transport, sending each frame prefixed by
+/// its length as a little-endian `u64`.
+#[cfg(unix)]
+mod unix {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+        sync::Mutex,
+    };
+
+    use super::{DiffReceiver, DiffSender};
+
+    /// Leader side of a Unix socket transport.
+    pub struct UnixSocketSender {
+        /// The connected socket.
+        stream: Mutex<UnixStream>,
+    }
+
+    impl UnixSocketSender {
+        /// Instantiate a new [`UnixSocketSender`] over provided connected socket.
+        pub fn new(stream: UnixStream) -> Self {
+            Self {
+                stream: Mutex::new(stream),
+            }
+        }
+    }
+
+    impl DiffSender for UnixSocketSender {
+        fn send(&self, frame: &[u8]) -> Result<(), sled::Error> {
+            let mut stream = self.stream.lock().expect("Unix socket lock is poisoned");
+            stream.write_all(&(frame.len() as u64).to_le_bytes())?;
+            stream.write_all(frame)?;
+            stream.flush()?;
+            Ok(())
+        }
+    }
+
+    /// Follower side of a Unix socket transport.
+    pub struct UnixSocketReceiver {
+        /// The connected socket.
+        stream: UnixStream,
+    }
+
+    impl UnixSocketReceiver {
+        /// Instantiate a new [`UnixSocketReceiver`] over provided connected socket.
+        pub fn new(stream: UnixStream) -> Self {
+            Self { stream }
+        }
+    }
+
+    impl DiffReceiver for UnixSocketReceiver {
+        fn recv(&mut self) -> Result<Option<Vec<u8>>, sled::Error> {
+            // The leader is gone if the socket closes between frames
+            let mut len = [0u8; 8];
+            if self.stream.read(&mut len[..1])? == 0 {
+                return Ok(None);
+            }
+            self.stream.read_exact(&mut len[1..])?;
+            let len = u64::from_le_bytes(len);
+
+            // Read incrementally, so a corrupted length doesn't
+            // allocate more than what the leader actually sent.
+            let mut frame = vec![];
+            (&mut self.stream).take(len).read_to_end(&mut frame)?;
+            if frame.len() as u64 != len {
+                return Err(sled::Error::Unsupported(
+                    "Replication frame is truncated".to_string(),
+                ));
+            }
+
+            Ok(Some(frame))
+        }
+    }
+}
+
+/// Default number of published frames a [`DiffPublisher`] keeps,
+/// so they can be resent to followers that missed them.
+pub const DEFAULT_HISTORY_CAPACITY: usize = 64;
+
+/// Auxilliary function to build the leader key of the published frame
+/// of provided sequence number.
+fn frame_key(sequence: u64) -> IVec {
+    let mut key = FRAME_PREFIX.to_vec();
+    key.extend_from_slice(&sequence.to_be_bytes());
+    key.into()
+}
+
+/// Auxilliary function to decode a stored sequence number.
+fn decode_sequence(value: &[u8]) -> Result<u64, sled::Error> {
+    let bytes: [u8; 8] = value
+        .try_into()
+        .map_err(|_| sled::Error::Unsupported("Invalid sequence value".to_string()))?;
+
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Auxilliary function to retrieve the next sequence number the leader
+/// persisted in provided replication tree.
+fn persisted_next<T: KvTree>(tree: &T) -> Result<u64, sled::Error> {
+    match tree.get(NEXT_SEQUENCE_KEY)? {
+        Some(value) => decode_sequence(&value),
+        None => Ok(0),
+    }
+}
+
+/// Auxilliary function to build the batch of provided leader replication
+/// tree recording provided encoded diff as the frame of the next sequence
+/// number, keeping at most `capacity` frames, or all of them if it's not
+/// provided. Returns the batch along with the frame and its sequence number.
+pub(crate) fn outbox_batch<T: KvTree>(
+    tree: &T,
+    diff: &[u8],
+    capacity: Option<usize>,
+) -> Result<(KvBatch, u64, Vec<u8>), sled::Error> {
+    let sequence = persisted_next(tree)?;
+    let mut frame = vec![];
+    sequence.encode(&mut frame);
+    frame.extend_from_slice(diff);
+
+    let mut batch = KvBatch::default();
+    batch.insert(NEXT_SEQUENCE_KEY, IVec::from(&(sequence + 1).to_be_bytes()));
+    if capacity != Some(0) {
+        batch.insert(frame_key(sequence), frame.as_slice());
+    }
+
+    // Remove the frames no longer kept
+    if let Some(capacity) = capacity {
+        let oldest = (sequence + 1).saturating_sub(capacity as u64);
+        for record in tree.range(frame_key(0)..frame_key(oldest)) {
+            batch.remove(record?.0);
+        }
+    }
+
+    Ok((batch, sequence, frame))
+}
+
+/// Mutable state of a [`DiffPublisher`].
+struct PublisherState {
+    /// The last send error.
+    error: Option<sled::Error>,
+    /// Maximum number of frames kept in the history.
+    capacity: usize,
+}
+
+/// Shared state of a [`DiffPublisher`].
+struct Publisher<S: KvStore> {
+    /// The leader store.
+    db: S,
+    /// The transport frames are sent over.
+    sender: Box<dyn DiffSender>,
+    /// The publisher mutable state.
+    state: Mutex<PublisherState>,
+}
+
+/// Leader side publisher of the applied diffs. Clones share the same
+/// transport and history. The next sequence number and the history of
+/// the published frames are kept in the [`REPLICATION_TREE`] of the
+/// leader store, written in the same transaction as the published
+/// changes, so a restarted leader continues from where it stopped, and
+/// frames it failed to send, even if it crashed before sending them,
+/// can be resent.
+pub struct DiffPublisher<S: KvStore = sled::Db> {
+    /// The publisher shared state.
+    inner: Arc<Publisher<S>>,
+}
+
+impl<S: KvStore> Clone for DiffPublisher<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: KvStore> DiffPublisher<S> {
+    /// Instantiate a new [`DiffPublisher`] over provided transport,
+    /// publishing the diffs applied to provided leader store. The diffs
+    /// are numbered continuing from the sequence number persisted in the
+    /// store, so followers keep applying them after a leader restart.
+    pub fn new<T: DiffSender + 'static>(sender: T, db: &S) -> Self {
+        Self {
+            inner: Arc::new(Publisher {
+                db: db.clone(),
+                sender: Box::new(sender),
+                state: Mutex::new(PublisherState {
+                    error: None,
+                    capacity: DEFAULT_HISTORY_CAPACITY,
+                }),
+            }),
+        }
+    }
+
+    /// Keep up to provided number of published frames, instead of
+    /// [`DEFAULT_HISTORY_CAPACITY`], so they can be resent. Frames
+    /// exceeding it get removed on the next publish.
+    pub fn with_history_capacity(self, capacity: usize) -> Self {
+        self.state().capacity = capacity;
+        self
+    }
+
+    /// Auxilliary function to acquire the publisher state lock.
+    fn state(&self) -> MutexGuard<'_, PublisherState> {
+        self.inner.state.lock().expect("Publisher lock is poisoned")
+    }
+
+    /// Returns the sequence number of the next published diff.
+    pub fn next_sequence(&self) -> Result<u64, sled::Error> {
+        if !self
+            .inner
+            .db
+            .tree_names()
+            .contains(&REPLICATION_TREE.into())
+        {
+            return Ok(0);
+        }
+
+        persisted_next(&self.inner.db.open_tree(REPLICATION_TREE)?)
+    }
+
+    /// Record provided encoded diff as the next frame in the replication
+    /// tree of provided store, through provided function committing the
+    /// frame batch along with the diff changes, then send it. We hold the
+    /// lock until the frame got sent, so frames are sent in order. Returns
+    /// the commit result, along with the frame sequence number and the
+    /// sending result.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn commit<R, E, F>(
+        &self,
+        db: &S,
+        diff: &[u8],
+        commit: F,
+    ) -> Result<(R, u64, Result<(), sled::Error>), E>
+    where
+        E: From<sled::Error>,
+        F: FnOnce(S::Tree, KvBatch) -> Result<R, E>,
+    {
+        let state = self.state();
+        let tree = db.open_tree(REPLICATION_TREE)?;
+        let (batch, sequence, frame) = outbox_batch(&tree, diff, Some(state.capacity))?;
+        let committed = commit(tree, batch)?;
+
+        Ok((committed, sequence, self.inner.sender.send(&frame)))
+    }
+
+    /// Keep provided error of sending a frame published through an
+    /// attached overlay, so it can be retrieved with
+    /// [`DiffPublisher::take_error`].
+    pub(crate) fn keep_error(&self, result: Result<(), sled::Error>) {
+        if let Err(e) = result {
+            self.state().error = Some(e);
+        }
+    }
+
+    /// Publish provided diff, returning its sequence number. The sequence
+    /// number is consumed even if sending fails, so followers detect the
+    /// gap, and the frame is kept in the history so it can be resent.
+    pub fn publish(&self, diff: &SledDbOverlayStateDiff) -> Result<u64, sled::Error> {
+        let mut bytes = vec![];
+        diff.encode(&mut bytes);
+
+        let ((), sequence, sent) = self.commit(&self.inner.db, &bytes, |tree, batch| {
+            self.inner
+                .db
+                .transaction(&[tree], &[batch])
+                .map_err(|e| match e {
+                    TransactionError::Abort(e) | TransactionError::Storage(e) => e,
+                })
+        })?;
+
+        sent.map(|_| sequence)
+    }
+
+    /// Resend the published frames starting from provided sequence number,
+    /// for example the expected one of a [`ReplicationStatus::Gap`].
+    /// Returns the number of resent frames. Fails if any of them is no
+    /// longer kept in the history, in which case the follower must be
+    /// resynchronized from the leader database instead.
+    pub fn resend(&self, sequence: u64) -> Result<usize, sled::Error> {
+        // We hold the lock so frames are sent in order
+        let _state = self.state();
+        let next = self.next_sequence()?;
+        if sequence > next {
+            return Err(sled::Error::Unsupported(format!(
+                "Diff {sequence} has not been published yet"
+            )));
+        }
+        if sequence == next {
+            return Ok(0);
+        }
+
+        let tree = self.inner.db.open_tree(REPLICATION_TREE)?;
+        let mut frames = vec![];
+        for record in tree.range(frame_key(sequence)..frame_key(next)) {
+            frames.push(record?.1);
+        }
+        if frames.len() as u64 != next - sequence {
+            return Err(sled::Error::Unsupported(format!(
+                "Diff {sequence} is no longer kept in the history"
+            )));
+        }
+
+        for frame in frames.iter() {
+            self.inner.sender.send(frame)?;
+        }
+
+        Ok(frames.len())
+    }
+
+    /// Attach the publisher to provided overlay of the leader store,
+    /// replacing any previously attached one, so every diff it applies
+    /// gets recorded in the same transaction as its changes and then
+    /// published. Since applying succeeds once the changes are written,
+    /// send errors are kept and can be retrieved with
+    /// [`DiffPublisher::take_error`].
+    pub fn attach(&self, overlay: &mut SledDbOverlay<S>) {
+        overlay.publisher = Some(self.clone());
+    }
+
+    /// Retrieve and clear the last error of sending a diff
+    /// published through an attached overlay, if any.
+    pub fn take_error(&self) -> Option<sled::Error> {
+        self.state().error.take()
+    }
+}
+
+/// Outcome of applying a frame with [`DiffApplier::apply`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicationStatus {
+    /// The diff has been applied.
+    Applied(u64),
+    /// The diff had already been applied, so it was skipped.
+    Duplicate(u64),
+    /// The diff is ahead of the next expected one, so it was skipped.
+    /// The missing diffs can be resent with [`DiffPublisher::resend`],
+    /// while they are still kept in the leader history.
+    Gap {
+        /// The next expected sequence number.
+        expected: u64,
+        /// The received sequence number.
+        received: u64,
+    },
+}
+
+/// Follower side applier of the published diffs.
+pub struct DiffApplier<S: KvStore = sled::Db> {
+    /// The follower store.
+    db: S,
+}
+
+impl<S: KvStore> DiffApplier<S> {
+    /// Instantiate a new [`DiffApplier`] over provided follower store.
+    pub fn new(db: &S) -> Self {
+        Self { db: db.clone() }
+    }
+
+    /// Returns the last applied sequence number, or `None`
+    /// if the follower hasn't applied any diffs yet.
+    pub fn last_sequence(&self) -> Result<Option<u64>, sled::Error> {
+        if !self.db.tree_names().contains(&REPLICATION_TREE.into()) {
+            return Ok(None);
+        }
+
+        let Some(value) = self.db.open_tree(REPLICATION_TREE)?.get(SEQUENCE_KEY)? else {
+            return Ok(None);
+        };
+
+        Ok(Some(decode_sequence(&value)?))
+    }
+
+    /// Returns the sequence number of the next expected diff.
+    pub fn next_sequence(&self) -> Result<u64, sled::Error> {
+        Ok(self.last_sequence()?.map_or(0, |sequence| sequence + 1))
+    }
+
+    /// Apply provided frame, if it contains the next expected diff.
+    pub fn apply(&mut self, frame: &[u8]) -> Result<ReplicationStatus, sled::Error> {
+        let ReplicatedDiff { sequence, mut diff } = ReplicatedDiff::decode(frame)?;
+
+        let expected = self.next_sequence()?;
+        if sequence < expected {
+            return Ok(ReplicationStatus::Duplicate(sequence));
+        }
+        if sequence > expected {
+            return Ok(ReplicationStatus::Gap {
+                expected,
+                received: sequence,
+            });
+        }
+
+        // Record the sequence number along with the diff records,
+        // so they get written in the same transaction.
+        let previous = sequence
+            .checked_sub(1)
+            .map(|previous| IVec::from(&previous.to_be_bytes()));
+        diff.caches
+            .entry(REPLICATION_TREE.into())
+            .or_default()
+            .0
+            .cache
+            .insert(
+                SEQUENCE_KEY.into(),
+                (previous, IVec::from(&sequence.to_be_bytes())),
+            );
+        diff.dropped_trees.remove(REPLICATION_TREE);
+
+        let mut overlay = SledDbOverlay::new(&self.db, vec![]);
+        match overlay.apply_diff(&diff) {
+            Ok(()) => Ok(ReplicationStatus::Applied(sequence)),
+            Err(TransactionError::Abort(e)) | Err(TransactionError::Storage(e)) => Err(e),
+        }
+    }
+
+    /// Apply all the frames provided receiver produces, until the leader
+    /// is gone. Duplicates are skipped, while gaps produce an error, after
+    /// which the missing diffs can be requested from the leader through
+    /// [`DiffPublisher::resend`], starting from [`DiffApplier::next_sequence`].
+    pub fn run<R: DiffReceiver>(&mut self, receiver: &mut R) -> Result<(), sled::Error> {
+        while let Some(frame) = receiver.recv()? {
+            if let ReplicationStatus::Gap { expected, received } = self.apply(&frame)? {
+                return Err(sled::Error::Unsupported(format!(
+                    "Replication gap: expected diff {expected}, received {received}"
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Deterministic ID generation through the overlay.
+//!
+//! Unlike [`sled::Db::generate_id`], sequences live in the overlay cache
+//! until it gets applied, so they are reverted along with checkpoints,
+//! and replaying the same operations produces the same IDs.
+
+use sled::IVec;
+
+use crate::{
+    backend::{KvStore, KvTree},
+    SledDbOverlay,
+};
+
+/// Protected tree holding the overlay sequences, keyed by their name,
+/// with the next ID to generate as a big-endian `u64` value.
+pub const SEQUENCES_TREE: &[u8] = b"__sled_overlay_sequences";
+
+/// Auxilliary function to decode a stored sequence value.
+fn decode(value: &IVec) -> Result<u64, sled::Error> {
+    let bytes: [u8; 8] = value
+        .as_ref()
+        .try_into()
+        .map_err(|_| sled::Error::Unsupported("Invalid sequence value".to_string()))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+impl<S: KvStore> SledDbOverlay<S> {
+    /// Generate the next ID of provided sequence, starting from zero.
+    /// The sequence is stored in the protected [`SEQUENCES_TREE`], which
+    /// gets opened if needed, so generated IDs are reverted along with
+    /// checkpoints and only persisted once the overlay gets applied.
+    pub fn generate_id(&mut self, sequence: &[u8]) -> Result<u64, sled::Error> {
+        self.open_tree(SEQUENCES_TREE, true)?;
+
+        let id = match self.get(SEQUENCES_TREE, sequence)? {
+            Some(value) => decode(&value)?,
+            None => 0,
+        };
+        let next = id
+            .checked_add(1)
+            .ok_or_else(|| sled::Error::Unsupported("Sequence is exhausted".to_string()))?;
+        self.insert(SEQUENCES_TREE, sequence, &next.to_be_bytes())?;
+
+        Ok(id)
+    }
+
+    /// Returns the last ID generated by provided sequence, or `None`
+    /// if it hasn't generated any.
+    pub fn last_id(&self, sequence: &[u8]) -> Result<Option<u64>, sled::Error> {
+        let value = match self.get(SEQUENCES_TREE, sequence) {
+            Ok(value) => value,
+            // Fallback to the database if the tree is not opened yet
+            Err(sled::Error::CollectionNotFound(_)) => {
+                if !self.db.tree_names().contains(&SEQUENCES_TREE.into()) {
+                    return Ok(None);
+                }
+                self.db.open_tree(SEQUENCES_TREE)?.get(sequence)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        match value {
+            Some(value) => Ok(decode(&value)?.checked_sub(1)),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,300 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Canonical byte encoding of the overlay structures.
+//!
+//! All integers are written as fixed-width little-endian values, byte
+//! strings are prefixed with their length, and maps are written in their
+//! [`BTreeMap`] order, so the same structure always produces the same bytes
+//! regardless of the platform it was encoded on. Structures can be read
+//! back from their encoding through the [`Decodable`] trait.
+
+use std::collections::BTreeMap;
+
+use sled::IVec;
+
+use crate::{SledDbOverlayStateDiff, SledTreeOverlayStateDiff, TreeCopy};
+
+/// Destination of encoded bytes, so we can stream structures
+/// directly into a hasher without buffering them first.
+pub(crate) trait Sink {
+    /// Append provided bytes to the sink.
+    fn write(&mut self, bytes: &[u8]);
+}
+
+impl Sink for Vec<u8> {
+    fn write(&mut self, bytes: &[u8]) {
+        self.extend_from_slice(bytes);
+    }
+}
+
+/// Structures that can be written using the canonical encoding.
+pub(crate) trait Encodable {
+    /// Write the canonical encoding of `self` into provided sink.
+    fn encode<S: Sink>(&self, s: &mut S);
+}
+
+impl Encodable for u8 {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        s.write(&[*self]);
+    }
+}
+
+impl Encodable for u64 {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        s.write(&self.to_le_bytes());
+    }
+}
+
+impl Encodable for bool {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        (*self as u8).encode(s);
+    }
+}
+
+impl Encodable for [u8] {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        (self.len() as u64).encode(s);
+        s.write(self);
+    }
+}
+
+impl Encodable for IVec {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        self.as_ref().encode(s);
+    }
+}
+
+impl<T: Encodable + ?Sized> Encodable for &T {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        (**self).encode(s);
+    }
+}
+
+impl<T: Encodable> Encodable for Option<T> {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        match self {
+            Some(value) => {
+                1u8.encode(s);
+                value.encode(s);
+            }
+            None => 0u8.encode(s),
+        }
+    }
+}
+
+impl<A: Encodable, B: Encodable> Encodable for (A, B) {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        self.0.encode(s);
+        self.1.encode(s);
+    }
+}
+
+impl<K: Encodable, V: Encodable> Encodable for BTreeMap<K, V> {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        (self.len() as u64).encode(s);
+        for (key, value) in self.iter() {
+            key.encode(s);
+            value.encode(s);
+        }
+    }
+}
+
+impl Encodable for Vec<IVec> {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        (self.len() as u64).encode(s);
+        for item in self.iter() {
+            item.encode(s);
+        }
+    }
+}
+
+impl Encodable for SledTreeOverlayStateDiff {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        self.cache.encode(s);
+        self.removed.encode(s);
+    }
+}
+
+impl Encodable for TreeCopy {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        self.source.encode(s);
+        self.replaced.encode(s);
+    }
+}
+
+impl Encodable for SledDbOverlayStateDiff {
+    fn encode<S: Sink>(&self, s: &mut S) {
+        // Tree names order depends on how sled returned them,
+        // so we sort them to get a canonical representation.
+        let mut initial_tree_names = self.initial_tree_names.clone();
+        initial_tree_names.sort();
+        (initial_tree_names.len() as u64).encode(s);
+        for tree_name in initial_tree_names.iter() {
+            tree_name.encode(s);
+        }
+
+        self.caches.encode(s);
+        self.dropped_trees.encode(s);
+        self.tree_copies.encode(s);
+    }
+}
+
+/// Auxilliary function to build the error of an invalid encoding.
+fn invalid_encoding() -> sled::Error {
+    sled::Error::Unsupported("Invalid encoding".to_string())
+}
+
+/// Source of encoded bytes being decoded.
+pub(crate) struct Source<'a> {
+    /// The bytes that haven't been read yet.
+    bytes: &'a [u8],
+}
+
+impl<'a> Source<'a> {
+    /// Instantiate a new [`Source`] over provided bytes.
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    /// Read the next provided number of bytes.
+    pub fn read(&mut self, len: usize) -> Result<&'a [u8], sled::Error> {
+        if len > self.bytes.len() {
+            return Err(invalid_encoding());
+        }
+
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    /// Returns `true` if all bytes have been read.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+/// Structures that can be read from their canonical encoding.
+pub(crate) trait Decodable: Sized {
+    /// Read `Self` from its canonical encoding in provided source.
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error>;
+}
+
+impl Decodable for u8 {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        Ok(s.read(1)?[0])
+    }
+}
+
+impl Decodable for u64 {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        let bytes = s.read(8)?.try_into().map_err(|_| invalid_encoding())?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+impl Decodable for bool {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        match u8::decode(s)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_encoding()),
+        }
+    }
+}
+
+/// Auxilliary function to decode a length prefix.
+fn decode_len(s: &mut Source<'_>) -> Result<usize, sled::Error> {
+    usize::try_from(u64::decode(s)?).map_err(|_| invalid_encoding())
+}
+
+impl Decodable for IVec {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        let len = decode_len(s)?;
+        Ok(s.read(len)?.into())
+    }
+}
+
+impl<T: Decodable> Decodable for Option<T> {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        match u8::decode(s)? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(s)?)),
+            _ => Err(invalid_encoding()),
+        }
+    }
+}
+
+impl<A: Decodable, B: Decodable> Decodable for (A, B) {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        Ok((A::decode(s)?, B::decode(s)?))
+    }
+}
+
+impl<K: Decodable + Ord, V: Decodable> Decodable for BTreeMap<K, V> {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        let len = decode_len(s)?;
+        let mut map = BTreeMap::new();
+        for _ in 0..len {
+            let key = K::decode(s)?;
+            let value = V::decode(s)?;
+            map.insert(key, value);
+        }
+        Ok(map)
+    }
+}
+
+impl Decodable for Vec<IVec> {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        let len = decode_len(s)?;
+        let mut items = vec![];
+        for _ in 0..len {
+            items.push(IVec::decode(s)?);
+        }
+        Ok(items)
+    }
+}
+
+impl Decodable for SledTreeOverlayStateDiff {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        Ok(Self {
+            cache: BTreeMap::decode(s)?,
+            removed: BTreeMap::decode(s)?,
+        })
+    }
+}
+
+impl Decodable for TreeCopy {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        Ok(Self {
+            source: IVec::decode(s)?,
+            replaced: Option::decode(s)?,
+        })
+    }
+}
+
+impl Decodable for SledDbOverlayStateDiff {
+    fn decode(s: &mut Source<'_>) -> Result<Self, sled::Error> {
+        Ok(Self {
+            initial_tree_names: Vec::decode(s)?,
+            caches: BTreeMap::decode(s)?,
+            dropped_trees: BTreeMap::decode(s)?,
+            tree_copies: BTreeMap::decode(s)?,
+        })
+    }
+}
@@ -0,0 +1,403 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Thread-safe handle of a [`SledDbOverlay`], shareable across threads.
+//!
+//! Each tree overlay is kept behind its own lock, so reads and writes
+//! over different trees don't block each other, while reads over the
+//! same tree can happen concurrently. Operations affecting the overlay
+//! as a whole, like opening and dropping trees, applying or
+//! checkpointing, lock the entire overlay, so they are atomic with
+//! respect to all other operations.
+
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use sled::{transaction::TransactionError, IVec};
+
+use crate::{
+    backend::KvStore,
+    watch::{EventKind, Subscriber, Watchers},
+    ApplyOptions, ApplyOutcome, SledDbOverlay, SledDbOverlayStateDiff, SledTreeOverlay,
+    SledTreeOverlayIter,
+};
+
+/// The state behind a [`SharedSledDbOverlay`].
+struct SharedState<S: KvStore> {
+    /// The overlay, with its tree overlays moved out into `trees`.
+    overlay: SledDbOverlay<S>,
+    /// The overlay tree overlays, each behind its own lock.
+    trees: BTreeMap<IVec, RwLock<SledTreeOverlay<S::Tree>>>,
+}
+
+impl<S: KvStore> SharedState<S> {
+    /// Instantiate a new [`SharedState`], moving provided
+    /// overlay tree overlays behind their own locks.
+    fn new(mut overlay: SledDbOverlay<S>) -> Self {
+        let trees = mem::take(&mut overlay.state.caches)
+            .into_iter()
+            .map(|(tree_key, cache)| (tree_key, RwLock::new(cache)))
+            .collect();
+        Self { overlay, trees }
+    }
+
+    /// Move all tree overlays back into the overlay.
+    fn restore(&mut self) {
+        self.overlay.state.caches = mem::take(&mut self.trees)
+            .into_iter()
+            .map(|(tree_key, cache)| (tree_key, into_inner(cache)))
+            .collect();
+    }
+
+    /// Move all overlay tree overlays behind their own locks.
+    fn release(&mut self) {
+        self.trees = mem::take(&mut self.overlay.state.caches)
+            .into_iter()
+            .map(|(tree_key, cache)| (tree_key, RwLock::new(cache)))
+            .collect();
+    }
+
+    /// Fetch the lock of a given tree overlay.
+    fn get_cache(&self, tree_key: &[u8]) -> Result<&RwLock<SledTreeOverlay<S::Tree>>, sled::Error> {
+        self.trees
+            .get(tree_key)
+            .ok_or_else(|| sled::Error::CollectionNotFound(tree_key.into()))
+    }
+}
+
+/// Auxilliary function to acquire a read lock, panicking if it is poisoned.
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().expect("Shared overlay lock is poisoned")
+}
+
+/// Auxilliary function to acquire a write lock, panicking if it is poisoned.
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().expect("Shared overlay lock is poisoned")
+}
+
+/// Auxilliary function to consume a lock, panicking if it is poisoned.
+fn into_inner<T>(lock: RwLock<T>) -> T {
+    lock.into_inner().expect("Shared overlay lock is poisoned")
+}
+
+/// A thread-safe handle of a [`SledDbOverlay`]. Cloning the handle
+/// produces a new reference to the same overlay.
+/// Note: Writes to indexed trees always lock the entire overlay, to
+/// update their index trees, while writes exceeding the overlay memory
+/// budget lock it to spill its cache state.
+pub struct SharedSledDbOverlay<S: KvStore = sled::Db> {
+    /// The shared overlay state.
+    inner: Arc<RwLock<SharedState<S>>>,
+}
+
+impl<S: KvStore> Clone for SharedSledDbOverlay<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: KvStore> SharedSledDbOverlay<S> {
+    /// Instantiate a new [`SharedSledDbOverlay`] handle of provided overlay.
+    pub fn new(overlay: SledDbOverlay<S>) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(SharedState::new(overlay))),
+        }
+    }
+
+    /// Consume the handle and retrieve the overlay, if there are
+    /// no other handles referencing it.
+    pub fn into_inner(self) -> Option<SledDbOverlay<S>> {
+        let mut state = into_inner(Arc::into_inner(self.inner)?);
+        state.restore();
+        Some(state.overlay)
+    }
+
+    /// Lock the entire overlay and execute provided function over it.
+    /// All other operations will wait until it finishes.
+    pub fn with_overlay<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut SledDbOverlay<S>) -> R,
+    {
+        let mut state = write(&self.inner);
+        state.restore();
+        let result = f(&mut state.overlay);
+        state.release();
+        result
+    }
+
+    /// Execute provided function over the specified tree overlay, while
+    /// holding its read lock.
+    fn read_cache<F, R>(&self, tree_key: &[u8], f: F) -> Result<R, sled::Error>
+    where
+        F: FnOnce(&SledTreeOverlay<S::Tree>) -> Result<R, sled::Error>,
+    {
+        let state = read(&self.inner);
+        let cache = read(state.get_cache(tree_key)?);
+        f(&cache)
+    }
+
+    /// Execute provided function over the specified tree overlay, while
+    /// holding its write lock, along with the overlay subscribers. If the
+    /// tree is indexed, provided overlay function is executed instead while
+    /// locking the entire overlay, so its index trees get updated. Since
+    /// indexes are registered while locking the entire overlay, this is
+    /// checked under our lock. Afterwards, the overlay memory budget
+    /// is enforced.
+    fn write_cache<F, G, R>(&self, tree_key: &[u8], f: F, g: G) -> Result<R, sled::Error>
+    where
+        F: FnOnce(&mut SledTreeOverlay<S::Tree>, &Watchers) -> Result<R, sled::Error>,
+        G: FnOnce(&mut SledDbOverlay<S>) -> Result<R, sled::Error>,
+    {
+        let result = {
+            let state = read(&self.inner);
+            if state.overlay.indexes.is_source(tree_key) {
+                drop(state);
+                return self.with_overlay(g);
+            }
+
+            let mut cache = write(state.get_cache(tree_key)?);
+            f(&mut cache, &state.overlay.watchers)?
+        };
+
+        self.enforce_memory_limit()?;
+
+        Ok(result)
+    }
+
+    /// Spill the overlay cache state, locking the entire overlay,
+    /// if we have exceeded its memory budget.
+    fn enforce_memory_limit(&self) -> Result<(), sled::Error> {
+        {
+            let state = read(&self.inner);
+            let Some(memory_limit) = state.overlay.memory_limit() else {
+                return Ok(());
+            };
+
+            // Tree locks are acquired one at a time, so we never
+            // wait for one while holding another.
+            let memory_usage: usize = state
+                .trees
+                .values()
+                .map(|cache| read(cache).memory_usage())
+                .sum();
+            if memory_usage <= memory_limit {
+                return Ok(());
+            }
+        }
+
+        self.with_overlay(|overlay| overlay.enforce_memory_limit())
+    }
+
+    /// Subscribe to the changes of keys starting with provided prefix in
+    /// the specified tree. See [`SledDbOverlay::watch_prefix`] for details.
+    pub fn watch_prefix(&self, tree_key: &[u8], prefix: &[u8]) -> Subscriber {
+        read(&self.inner).overlay.watch_prefix(tree_key, prefix)
+    }
+
+    /// Subscribe to the changes of keys starting with provided prefix in
+    /// the specified tree, buffering up to provided number of events.
+    /// See [`SledDbOverlay::watch_prefix_with_capacity`] for details.
+    pub fn watch_prefix_with_capacity(
+        &self,
+        tree_key: &[u8],
+        prefix: &[u8],
+        capacity: usize,
+    ) -> Subscriber {
+        read(&self.inner)
+            .overlay
+            .watch_prefix_with_capacity(tree_key, prefix, capacity)
+    }
+
+    /// Returns `true` if the overlay contains a value for a specified key in the specified
+    /// tree cache.
+    pub fn contains_key(&self, tree_key: &[u8], key: &[u8]) -> Result<bool, sled::Error> {
+        self.read_cache(tree_key, |cache| cache.contains_key(key))
+    }
+
+    /// Retrieve a value from the overlay if it exists in the specified tree cache.
+    pub fn get(&self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        self.read_cache(tree_key, |cache| cache.get(key))
+    }
+
+    /// Returns `true` if specified tree cache is empty.
+    pub fn is_empty(&self, tree_key: &[u8]) -> Result<bool, sled::Error> {
+        self.read_cache(tree_key, |cache| cache.is_empty())
+    }
+
+    /// Returns last value from the overlay if the specified tree cache is not empty.
+    pub fn last(&self, tree_key: &[u8]) -> Result<Option<(IVec, IVec)>, sled::Error> {
+        self.read_cache(tree_key, |cache| cache.last())
+    }
+
+    /// Execute provided function over an immutable iterator of the specified
+    /// tree cache, while holding its read lock.
+    pub fn with_iter<F, R>(&self, tree_key: &[u8], f: F) -> Result<R, sled::Error>
+    where
+        F: FnOnce(SledTreeOverlayIter<'_, S::Tree>) -> R,
+    {
+        self.read_cache(tree_key, |cache| Ok(f(cache.iter())))
+    }
+
+    /// Insert a key to a new value in the specified tree cache, returning the last value
+    /// if it was set.
+    pub fn insert(
+        &self,
+        tree_key: &[u8],
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<IVec>, sled::Error> {
+        self.write_cache(
+            tree_key,
+            |cache, watchers| {
+                let prev = cache.insert(key, value)?;
+                watchers.notify(tree_key, key, Some(&value.into()), EventKind::Pending);
+                Ok(prev)
+            },
+            |overlay| overlay.insert(tree_key, key, value),
+        )
+    }
+
+    /// Delete a value in the specified tree cache, returning the old value if it existed.
+    pub fn remove(&self, tree_key: &[u8], key: &[u8]) -> Result<Option<IVec>, sled::Error> {
+        self.write_cache(
+            tree_key,
+            |cache, watchers| {
+                let prev = cache.remove(key)?;
+                watchers.notify(tree_key, key, None, EventKind::Pending);
+                Ok(prev)
+            },
+            |overlay| overlay.remove(tree_key, key),
+        )
+    }
+
+    /// Removes all values from the specified tree cache and marks all
+    /// its tree records as removed.
+    pub fn clear(&self, tree_key: &[u8]) -> Result<(), sled::Error> {
+        self.write_cache(
+            tree_key,
+            |cache, watchers| {
+                // Grab the keys getting removed, if they are watched
+                let keys = match watchers.watches(tree_key) {
+                    true => cache
+                        .iter()
+                        .map(|record| record.map(|(key, _)| key))
+                        .collect::<Result<Vec<IVec>, sled::Error>>()?,
+                    false => vec![],
+                };
+
+                cache.clear()?;
+                for key in keys {
+                    watchers.notify(tree_key, &key, None, EventKind::Pending);
+                }
+
+                Ok(())
+            },
+            |overlay| overlay.clear(tree_key),
+        )
+    }
+
+    /// Atomically execute [`SledDbOverlay::open_tree`].
+    pub fn open_tree(&self, tree_name: &[u8], protected: bool) -> Result<(), sled::Error> {
+        self.with_overlay(|overlay| overlay.open_tree(tree_name, protected))
+    }
+
+    /// Atomically execute [`SledDbOverlay::drop_tree`].
+    pub fn drop_tree(&self, tree_name: &[u8]) -> Result<(), sled::Error> {
+        self.with_overlay(|overlay| overlay.drop_tree(tree_name))
+    }
+
+    /// Atomically execute [`SledDbOverlay::tree_names`].
+    pub fn tree_names(&self) -> Vec<IVec> {
+        read(&self.inner).overlay.tree_names()
+    }
+
+    /// Atomically execute [`SledDbOverlay::tree_exists`].
+    pub fn tree_exists(&self, tree_name: &[u8]) -> bool {
+        read(&self.inner).overlay.tree_exists(tree_name)
+    }
+
+    /// Atomically execute [`SledDbOverlay::generate_id`].
+    pub fn generate_id(&self, sequence: &[u8]) -> Result<u64, sled::Error> {
+        self.with_overlay(|overlay| overlay.generate_id(sequence))
+    }
+
+    /// Atomically execute [`SledDbOverlay::copy_tree`].
+    pub fn copy_tree(&self, from: &[u8], to: &[u8]) -> Result<(), sled::Error> {
+        self.with_overlay(|overlay| overlay.copy_tree(from, to))
+    }
+
+    /// Atomically execute [`SledDbOverlay::rename_tree`].
+    pub fn rename_tree(&self, from: &[u8], to: &[u8]) -> Result<(), sled::Error> {
+        self.with_overlay(|overlay| overlay.rename_tree(from, to))
+    }
+
+    /// Atomically execute [`SledDbOverlay::apply`].
+    pub fn apply(&self) -> Result<(), TransactionError<sled::Error>> {
+        self.with_overlay(|overlay| overlay.apply())
+    }
+
+    /// Atomically execute [`SledDbOverlay::apply_with`].
+    pub fn apply_with(
+        &self,
+        options: ApplyOptions,
+    ) -> Result<ApplyOutcome, TransactionError<sled::Error>> {
+        self.with_overlay(|overlay| overlay.apply_with(options))
+    }
+
+    /// Atomically execute [`SledDbOverlay::checkpoint`].
+    pub fn checkpoint(&self) {
+        self.with_overlay(|overlay| overlay.checkpoint())
+    }
+
+    /// Atomically execute [`SledDbOverlay::revert_to_checkpoint`].
+    pub fn revert_to_checkpoint(&self) {
+        self.with_overlay(|overlay| overlay.revert_to_checkpoint())
+    }
+
+    /// Atomically execute [`SledDbOverlay::diff`].
+    pub fn diff(
+        &self,
+        sequence: &[SledDbOverlayStateDiff],
+    ) -> Result<SledDbOverlayStateDiff, sled::Error> {
+        self.with_overlay(|overlay| overlay.diff(sequence))
+    }
+
+    /// Atomically execute [`SledDbOverlay::add_diff`].
+    pub fn add_diff(&self, diff: &SledDbOverlayStateDiff) -> Result<(), sled::Error> {
+        self.with_overlay(|overlay| overlay.add_diff(diff))
+    }
+
+    /// Atomically execute [`SledDbOverlay::apply_diff`].
+    pub fn apply_diff(
+        &self,
+        diff: &SledDbOverlayStateDiff,
+    ) -> Result<(), TransactionError<sled::Error>> {
+        self.with_overlay(|overlay| overlay.apply_diff(diff))
+    }
+}
+
+impl<S: KvStore> From<SledDbOverlay<S>> for SharedSledDbOverlay<S> {
+    fn from(overlay: SledDbOverlay<S>) -> Self {
+        Self::new(overlay)
+    }
+}
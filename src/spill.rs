@@ -0,0 +1,264 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Temporary on-disk storage of overlay changes that were
+//! spilled out of memory.
+
+use std::{collections::BTreeMap, ops::Bound, sync::Arc};
+
+use sled::IVec;
+
+use crate::{SledTreeOverlayState, SledTreeOverlayStateDiff};
+
+/// Spilled record marker of an inserted key, followed by its value.
+const INSERTED: u8 = 1;
+
+/// Spilled record marker of a removed key.
+const REMOVED: u8 = 0;
+
+/// Open a new temporary [`sled::Db`] to spill overlay changes into.
+pub fn temporary_db() -> Result<sled::Db, sled::Error> {
+    sled::Config::new().temporary(true).open()
+}
+
+/// A [`sled::Tree`] inside a temporary [`sled::Db`], holding tree overlay
+/// changes that were spilled out of memory. Each record value is prefixed
+/// with a marker indicating whether the key was inserted or removed.
+/// The tree is dropped from the temporary database once we are dropped.
+#[derive(Debug)]
+struct SpillTree {
+    /// The temporary [`sled::Db`] holding the tree.
+    db: sled::Db,
+    /// The [`sled::Tree`] holding the spilled records.
+    tree: sled::Tree,
+}
+
+impl SpillTree {
+    /// Instantiate a new [`SpillTree`] inside provided temporary [`sled::Db`].
+    fn new(db: &sled::Db) -> Result<Self, sled::Error> {
+        let tree_name = db.generate_id()?.to_be_bytes();
+        Ok(Self {
+            db: db.clone(),
+            tree: db.open_tree(tree_name)?,
+        })
+    }
+
+    /// Copy all spilled records into a new tree of the same
+    /// temporary [`sled::Db`].
+    fn duplicate(&self) -> Result<Self, sled::Error> {
+        let spill = Self::new(&self.db)?;
+        for record in self.tree.iter() {
+            let (key, value) = record?;
+            spill.tree.insert(key, value)?;
+        }
+        Ok(spill)
+    }
+}
+
+impl Drop for SpillTree {
+    fn drop(&mut self) {
+        let _ = self.db.drop_tree(self.tree.name());
+    }
+}
+
+/// Spilled record values whose changes have been removed from the
+/// overlay, so the records must be ignored.
+#[derive(Debug, Clone)]
+enum Retired {
+    /// The record is ignored, whatever its value is.
+    Any,
+    /// The record is ignored if its value is one of these.
+    Matching(Vec<Option<IVec>>),
+}
+
+impl Retired {
+    /// Returns `true` if provided spilled record value is ignored.
+    fn matches(&self, value: &Option<IVec>) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Matching(values) => values.contains(value),
+        }
+    }
+}
+
+/// Tree overlay changes that were spilled out of memory. Clones share the
+/// underlying [`SpillTree`], which gets copied only when a shared one has
+/// to be written. Removing changes doesn't write the tree, but retires its
+/// records in memory, until they get removed by the next spill.
+#[derive(Debug, Clone)]
+pub struct SpilledState {
+    /// The tree holding the spilled records.
+    tree: Arc<SpillTree>,
+    /// Spilled records that must be ignored, since their changes got removed.
+    retired: BTreeMap<IVec, Retired>,
+}
+
+impl SpilledState {
+    /// Instantiate a new [`SpilledState`] inside provided temporary [`sled::Db`].
+    pub fn new(db: &sled::Db) -> Result<Self, sled::Error> {
+        Ok(Self {
+            tree: Arc::new(SpillTree::new(db)?),
+            retired: BTreeMap::new(),
+        })
+    }
+
+    /// Move provided tree overlay state changes into the tree, overwriting
+    /// previously spilled records of the same keys, and removing the
+    /// retired ones.
+    pub fn spill(&mut self, state: &SledTreeOverlayState) -> Result<(), sled::Error> {
+        // Clones may still read the tree, so we write our own copy of it
+        if Arc::get_mut(&mut self.tree).is_none() {
+            self.tree = Arc::new(self.tree.duplicate()?);
+        }
+
+        let mut batch = sled::Batch::default();
+
+        for (k, retired) in self.retired.iter() {
+            let Some(value) = self.tree.tree.get(k)? else {
+                continue;
+            };
+            if retired.matches(&decode(&value)) {
+                batch.remove(k);
+            }
+        }
+
+        for (k, v) in state.cache.iter() {
+            let mut value = Vec::with_capacity(v.len() + 1);
+            value.push(INSERTED);
+            value.extend_from_slice(v);
+            batch.insert(k, value);
+        }
+
+        for k in state.removed.iter() {
+            batch.insert(k, &[REMOVED]);
+        }
+
+        self.tree.tree.apply_batch(batch)?;
+        self.retired.clear();
+
+        Ok(())
+    }
+
+    /// Retire the spilled records of provided tree overlay state changes,
+    /// which are being removed from provided in-memory cache state. Records
+    /// shadowed by the in-memory cache state are older than the changes, so
+    /// they are always retired, while the rest only if they still hold the
+    /// changed values. Returns the memory the retired records use, in bytes.
+    pub fn retire(
+        &mut self,
+        state: &SledTreeOverlayState,
+        diff: &SledTreeOverlayStateDiff,
+    ) -> usize {
+        let changes = diff
+            .cache
+            .iter()
+            .map(|(k, v)| (k, Some(v.1.clone())))
+            .chain(diff.removed.keys().map(|k| (k, None)));
+
+        let mut memory_usage = 0;
+        for (k, value) in changes {
+            let shadowed = state.cache.contains_key(k) || state.removed.contains(k);
+            let retired = self.retired.entry(k.clone()).or_insert_with(|| {
+                memory_usage += k.len();
+                Retired::Matching(vec![])
+            });
+            match (shadowed, retired) {
+                (true, retired) => *retired = Retired::Any,
+                (false, Retired::Any) => {}
+                (false, Retired::Matching(values)) => {
+                    memory_usage += value.as_ref().map_or(0, |v| v.len());
+                    values.push(value);
+                }
+            }
+        }
+
+        memory_usage
+    }
+
+    /// Returns the approximate memory used by the retired records, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.retired
+            .iter()
+            .map(|(k, retired)| match retired {
+                Retired::Any => k.len(),
+                Retired::Matching(values) => {
+                    k.len() + values.iter().flatten().map(|v| v.len()).sum::<usize>()
+                }
+            })
+            .sum()
+    }
+
+    /// Returns `true` if provided spilled record must be ignored.
+    fn is_retired(&self, key: &[u8], value: &Option<IVec>) -> bool {
+        self.retired
+            .get(key)
+            .is_some_and(|retired| retired.matches(value))
+    }
+
+    /// Retrieve a spilled record. Returns `None` if the key was not spilled,
+    /// otherwise its value if it was inserted or `None` if it was removed.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Option<IVec>>, sled::Error> {
+        let Some(value) = self.tree.tree.get(key)? else {
+            return Ok(None);
+        };
+
+        let value = decode(&value);
+        if self.is_retired(key, &value) {
+            return Ok(None);
+        }
+
+        Ok(Some(value))
+    }
+
+    /// Retrieve the spilled record key with the greatest key less than provided
+    /// one, or the last spilled record key if no key is provided. The record
+    /// may be retired.
+    pub fn get_lt(&self, key: Option<&[u8]>) -> Result<Option<IVec>, sled::Error> {
+        let record = match key {
+            Some(key) => self.tree.tree.get_lt(key)?,
+            None => self.tree.tree.last()?,
+        };
+        Ok(record.map(|(key, _)| key))
+    }
+
+    /// Iterate over the spilled records keys within provided range.
+    /// The records may be retired.
+    pub fn range(&self, range: (Bound<IVec>, Bound<IVec>)) -> sled::Iter {
+        self.tree.tree.range(range)
+    }
+
+    /// Iterate over all the spilled records that are not retired.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(IVec, Option<IVec>), sled::Error>> + '_ {
+        self.tree.tree.iter().filter_map(|record| {
+            let (key, value) = match record {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e)),
+            };
+            let value = decode(&value);
+            (!self.is_retired(&key, &value)).then_some(Ok((key, value)))
+        })
+    }
+}
+
+/// Auxilliary function to decode a spilled record value.
+fn decode(value: &IVec) -> Option<IVec> {
+    match value.first() {
+        Some(&INSERTED) => Some(value.subslice(1, value.len() - 1)),
+        _ => None,
+    }
+}
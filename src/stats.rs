@@ -0,0 +1,194 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Statistics and size accounting of overlay states and their diffs.
+
+use std::{collections::BTreeMap, ops::AddAssign};
+
+use sled::IVec;
+
+use crate::{
+    backend::{KvStore, KvTree},
+    database::SledDbOverlayState,
+    SledDbOverlay, SledDbOverlayStateDiff, SledTreeOverlay, SledTreeOverlayState,
+    SledTreeOverlayStateDiff,
+};
+
+/// Statistics of the changes performed over a single tree.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SledTreeOverlayStats {
+    /// Number of keys that didn't exist before.
+    pub inserted: u64,
+    /// Number of existing keys whose value changed.
+    pub updated: u64,
+    /// Number of keys that were removed.
+    pub removed: u64,
+    /// Number of keys that were removed because their tree was dropped.
+    pub dropped: u64,
+    /// Number of keys that were inserted because their tree was restored.
+    pub restored: u64,
+    /// Total key and value bytes of the affected records, before the changes.
+    pub bytes_before: u64,
+    /// Total key and value bytes of the affected records, after the changes.
+    pub bytes_after: u64,
+}
+
+impl SledTreeOverlayStats {
+    /// Returns the size difference the changes produce, in bytes.
+    pub fn growth(&self) -> i64 {
+        self.bytes_after as i64 - self.bytes_before as i64
+    }
+
+    /// Returns the total number of keys the changes affect.
+    pub fn changed_keys(&self) -> u64 {
+        self.inserted + self.updated + self.removed + self.dropped + self.restored
+    }
+}
+
+impl AddAssign for SledTreeOverlayStats {
+    fn add_assign(&mut self, other: Self) {
+        self.inserted += other.inserted;
+        self.updated += other.updated;
+        self.removed += other.removed;
+        self.dropped += other.dropped;
+        self.restored += other.restored;
+        self.bytes_before += other.bytes_before;
+        self.bytes_after += other.bytes_after;
+    }
+}
+
+/// Statistics of the changes performed over a database, per tree.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SledDbOverlayStats {
+    /// Statistics of each affected tree.
+    pub trees: BTreeMap<IVec, SledTreeOverlayStats>,
+}
+
+impl SledDbOverlayStats {
+    /// Returns the accumulated statistics of all trees.
+    pub fn total(&self) -> SledTreeOverlayStats {
+        let mut total = SledTreeOverlayStats::default();
+        for stats in self.trees.values() {
+            total += *stats;
+        }
+        total
+    }
+}
+
+/// Auxilliary function to compute a record size.
+fn record_size(key: &[u8], value: &[u8]) -> u64 {
+    (key.len() + value.len()) as u64
+}
+
+impl SledTreeOverlayStateDiff {
+    /// Compute the [`SledTreeOverlayStats`] of the diff.
+    pub fn stats(&self) -> SledTreeOverlayStats {
+        let mut stats = SledTreeOverlayStats::default();
+
+        for (key, (previous, value)) in self.cache.iter() {
+            match previous {
+                Some(previous) => {
+                    stats.updated += 1;
+                    stats.bytes_before += record_size(key, previous);
+                }
+                None => stats.inserted += 1,
+            }
+            stats.bytes_after += record_size(key, value);
+        }
+
+        for (key, value) in self.removed.iter() {
+            stats.removed += 1;
+            stats.bytes_before += record_size(key, value);
+        }
+
+        stats
+    }
+
+    /// Compute the [`SledTreeOverlayStats`] of a dropped tree diff, which
+    /// contains its last state as inserts. If the restored flag is set, the
+    /// keys are counted as restored, otherwise as dropped.
+    fn dropped_stats(&self, restored: bool) -> SledTreeOverlayStats {
+        let mut stats = SledTreeOverlayStats::default();
+
+        for (key, (_, value)) in self.cache.iter() {
+            if restored {
+                stats.restored += 1;
+                stats.bytes_after += record_size(key, value);
+                continue;
+            }
+            stats.dropped += 1;
+            stats.bytes_before += record_size(key, value);
+        }
+
+        stats
+    }
+}
+
+impl SledTreeOverlayState {
+    /// Compute the [`SledTreeOverlayStats`] of the state, over the provided
+    /// [`KvTree`] that is being overlayed.
+    pub fn stats<T: KvTree>(&self, tree: &T) -> Result<SledTreeOverlayStats, sled::Error> {
+        Ok(SledTreeOverlayStateDiff::new(tree, self)?.stats())
+    }
+}
+
+impl<T: KvTree> SledTreeOverlay<T> {
+    /// Compute the [`SledTreeOverlayStats`] of the current overlay state.
+    pub fn stats(&self) -> Result<SledTreeOverlayStats, sled::Error> {
+        Ok(self.diff(&[])?.stats())
+    }
+}
+
+impl SledDbOverlayStateDiff {
+    /// Compute the [`SledDbOverlayStats`] of the diff.
+    pub fn stats(&self) -> SledDbOverlayStats {
+        let mut stats = SledDbOverlayStats::default();
+
+        for (tree_name, (diff, drop)) in self.caches.iter() {
+            let mut tree_stats = diff.stats();
+
+            // If the tree is dropped, its removed keys are dropped
+            if *drop {
+                tree_stats.dropped += tree_stats.removed;
+                tree_stats.removed = 0;
+            }
+
+            *stats.trees.entry(tree_name.clone()).or_default() += tree_stats;
+        }
+
+        for (tree_name, (diff, restored)) in self.dropped_trees.iter() {
+            *stats.trees.entry(tree_name.clone()).or_default() += diff.dropped_stats(*restored);
+        }
+
+        stats
+    }
+}
+
+impl<T: KvTree> SledDbOverlayState<T> {
+    /// Compute the [`SledDbOverlayStats`] of the state.
+    pub fn stats(&self) -> Result<SledDbOverlayStats, sled::Error> {
+        Ok(SledDbOverlayStateDiff::new(self)?.stats())
+    }
+}
+
+impl<S: KvStore> SledDbOverlay<S> {
+    /// Compute the [`SledDbOverlayStats`] of the current overlay state.
+    pub fn stats(&self) -> Result<SledDbOverlayStats, sled::Error> {
+        self.state.stats()
+    }
+}
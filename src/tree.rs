@@ -17,12 +17,26 @@
  */
 
 use std::{
-    cmp::Ordering,
-    collections::{btree_map::Keys, BTreeMap, BTreeSet},
+    borrow::Cow,
+    collections::{btree_map, btree_set, BTreeMap, BTreeSet},
     iter::{FusedIterator, Peekable},
+    ops::{Bound, RangeBounds},
 };
 
-use sled::{IVec, Iter};
+use sled::IVec;
+
+use crate::{
+    backend::{owned_bound, KvBatch, KvTree},
+    digest::{digest_with, StateDigest, StateHasher},
+    read_cache::ReadCache,
+    spill::SpilledState,
+};
+
+#[cfg(any(feature = "blake3", feature = "sha256"))]
+use crate::{
+    digest::DefaultStateHasher,
+    merkle::{MerkleProof, SparseMerkleTree},
+};
 
 /// Struct representing [`SledTreeOverlay`] cache state.
 #[derive(Debug, Default, Clone, PartialEq)]
@@ -46,16 +60,23 @@ impl SledTreeOverlayState {
     /// a [`sled::Batch`] ready for further operation.
     /// If there are no changes, return `None`.
     pub fn aggregate(&self) -> Option<sled::Batch> {
+        self.kv_batch().as_ref().map(sled::Batch::from)
+    }
+
+    /// Aggregate all the current tree overlay state changes into
+    /// a [`KvBatch`] ready for further operation.
+    /// If there are no changes, return `None`.
+    pub fn kv_batch(&self) -> Option<KvBatch> {
         if self.cache.is_empty() && self.removed.is_empty() {
             return None;
         }
 
-        let mut batch = sled::Batch::default();
+        let mut batch = KvBatch::default();
 
         // This kind of first-insert-then-remove operation should be fine
         // provided it's handled correctly in the above functions.
         for (k, v) in self.cache.iter() {
-            batch.insert(k, v);
+            batch.insert(k.clone(), v.clone());
         }
 
         for k in self.removed.iter() {
@@ -65,6 +86,14 @@ impl SledTreeOverlayState {
         Some(batch)
     }
 
+    /// Returns the approximate memory used by the state, in bytes,
+    /// counting the cached keys and values, along with removed keys.
+    pub fn memory_usage(&self) -> usize {
+        let cache: usize = self.cache.iter().map(|(k, v)| k.len() + v.len()).sum();
+        let removed: usize = self.removed.iter().map(|k| k.len()).sum();
+        cache + removed
+    }
+
     /// Add provided tree overlay state changes to our own.
     pub fn add_diff(&mut self, diff: &SledTreeOverlayStateDiff) {
         // Add all new keys into cache
@@ -133,8 +162,8 @@ pub struct SledTreeOverlayStateDiff {
 
 impl SledTreeOverlayStateDiff {
     /// Instantiate a new [`SledTreeOverlayStateDiff`], over the provided
-    /// [`sled::Tree`] that is being overlayed.
-    pub fn new(tree: &sled::Tree, state: &SledTreeOverlayState) -> Result<Self, sled::Error> {
+    /// [`KvTree`] that is being overlayed.
+    pub fn new<T: KvTree>(tree: &T, state: &SledTreeOverlayState) -> Result<Self, sled::Error> {
         let mut cache = BTreeMap::new();
         let mut removed = BTreeMap::new();
 
@@ -160,9 +189,9 @@ impl SledTreeOverlayStateDiff {
     }
 
     /// Instantiate a new [`SledTreeOverlayStateDiff`], over the provided
-    /// [`sled::Tree`] that is being dropped. The diff will contain all
+    /// [`KvTree`] that is being dropped. The diff will contain all
     /// existing tree keys in its cache as inserts, representing the last tree state.
-    pub fn new_dropped(tree: &sled::Tree) -> Self {
+    pub fn new_dropped<T: KvTree>(tree: &T) -> Self {
         let mut cache = BTreeMap::new();
 
         // Insert all tree keys
@@ -181,16 +210,23 @@ impl SledTreeOverlayStateDiff {
     /// a [`sled::Batch`] ready for further operation.
     /// If there are no changes, return `None`.
     pub fn aggregate(&self) -> Option<sled::Batch> {
+        self.kv_batch().as_ref().map(sled::Batch::from)
+    }
+
+    /// Aggregate all the current tree overlay state changes into
+    /// a [`KvBatch`] ready for further operation.
+    /// If there are no changes, return `None`.
+    pub fn kv_batch(&self) -> Option<KvBatch> {
         if self.cache.is_empty() && self.removed.is_empty() {
             return None;
         }
 
-        let mut batch = sled::Batch::default();
+        let mut batch = KvBatch::default();
 
         // This kind of first-insert-then-remove operation should be fine
         // provided it's handled correctly in the above functions.
         for (k, v) in self.cache.iter() {
-            batch.insert(k, v.1.clone());
+            batch.insert(k.clone(), v.1.clone());
         }
 
         for k in self.removed.keys() {
@@ -294,26 +330,143 @@ impl SledTreeOverlayStateDiff {
             self.cache.remove(k);
         }
     }
+
+    /// Produce a deterministic [`StateDigest`] of the diff, using the
+    /// hash function selected by the enabled cargo features.
+    #[cfg(any(feature = "blake3", feature = "sha256"))]
+    pub fn digest(&self) -> StateDigest {
+        self.digest_with::<DefaultStateHasher>()
+    }
+
+    /// Produce a deterministic [`StateDigest`] of the diff, using the
+    /// provided [`StateHasher`].
+    pub fn digest_with<H: StateHasher>(&self) -> StateDigest {
+        digest_with::<H, _>(self)
+    }
 }
 
-/// An overlay on top of a single [`sled::Tree`] instance.
+/// An overlay on top of a single [`KvTree`] instance,
+/// which is a [`sled::Tree`] by default.
 #[derive(Debug, Clone)]
-pub struct SledTreeOverlay {
-    /// The [`sled::Tree`] that is being overlayed.
-    pub tree: sled::Tree,
+pub struct SledTreeOverlay<T: KvTree = sled::Tree> {
+    /// The [`KvTree`] that is being overlayed.
+    pub tree: T,
+    /// The [`KvTree`] whose records we read beneath our cache state
+    /// instead of our own tree ones, if the overlay is a copy of it.
+    pub(crate) base: Option<T>,
     /// Current overlay cache state.
     pub state: SledTreeOverlayState,
     /// Checkpointed cache state to revert to.
     checkpoint: SledTreeOverlayState,
+    /// State root tracking of the overlayed records, if enabled.
+    #[cfg(any(feature = "blake3", feature = "sha256"))]
+    merkle: Option<SparseMerkleTree>,
+    /// Checkpointed state root tracking to revert to.
+    #[cfg(any(feature = "blake3", feature = "sha256"))]
+    checkpoint_merkle: Option<SparseMerkleTree>,
+    /// Cache state changes spilled out of memory, if any. The in-memory
+    /// cache state always takes precedence over the spilled one.
+    spill: Option<SpilledState>,
+    /// Checkpointed spilled cache state to revert to.
+    checkpoint_spill: Option<SpilledState>,
+    /// Approximate memory used by the in-memory cache state, along
+    /// with the retired spilled records, in bytes.
+    memory_usage: usize,
+    /// Bounded cache of the main tree values we have read, if enabled.
+    read_cache: Option<ReadCache>,
 }
 
-impl SledTreeOverlay {
-    /// Instantiate a new [`SledTreeOverlay`] on top of a given [`sled::Tree`].
-    pub fn new(tree: &sled::Tree) -> Self {
+impl<T: KvTree> SledTreeOverlay<T> {
+    /// Instantiate a new [`SledTreeOverlay`] on top of a given [`KvTree`].
+    pub fn new(tree: &T) -> Self {
         Self {
             tree: tree.clone(),
+            base: None,
             state: SledTreeOverlayState::new(),
             checkpoint: SledTreeOverlayState::new(),
+            #[cfg(any(feature = "blake3", feature = "sha256"))]
+            merkle: None,
+            #[cfg(any(feature = "blake3", feature = "sha256"))]
+            checkpoint_merkle: None,
+            spill: None,
+            checkpoint_spill: None,
+            memory_usage: 0,
+            read_cache: None,
+        }
+    }
+
+    /// Returns the [`KvTree`] whose records we read beneath our
+    /// cache state, which is our copied tree, if any, or our own.
+    pub(crate) fn base_tree(&self) -> &T {
+        self.base.as_ref().unwrap_or(&self.tree)
+    }
+
+    /// Retrieve a value beneath the in-memory cache state, from the
+    /// spilled cache state or the main tree.
+    fn base_get(&self, key: &IVec) -> Result<Option<IVec>, sled::Error> {
+        if let Some(spill) = &self.spill {
+            if let Some(value) = spill.get(key)? {
+                return Ok(value);
+            }
+        }
+
+        self.tree_get(key)
+    }
+
+    /// Retrieve a value from the main tree, going through
+    /// the read cache, if enabled.
+    fn tree_get(&self, key: &IVec) -> Result<Option<IVec>, sled::Error> {
+        let Some(read_cache) = &self.read_cache else {
+            return self.base_tree().get(key);
+        };
+
+        if let Some(value) = read_cache.get(key) {
+            return Ok(value);
+        }
+
+        let generation = read_cache.generation();
+        let value = self.base_tree().get(key)?;
+        read_cache.insert(key.clone(), value.clone(), generation);
+
+        Ok(value)
+    }
+
+    /// Returns `true` if the main tree contains a value for a specified key,
+    /// going through the read cache, if enabled.
+    fn tree_contains_key(&self, key: &IVec) -> Result<bool, sled::Error> {
+        if self.read_cache.is_none() {
+            return self.base_tree().contains_key(key);
+        }
+
+        Ok(self.tree_get(key)?.is_some())
+    }
+
+    /// Set the capacity of the main tree values read cache, in number of
+    /// keys, including the ones that don't exist. Passing `None` disables
+    /// the cache, which is the default. Clones of the overlay share the
+    /// cache, so they all see its invalidations.
+    /// Note: The cache must be invalidated using
+    /// [`SledTreeOverlay::invalidate_read_cache`] whenever the main tree
+    /// is written outside of the overlay apply functions.
+    pub fn set_read_cache_capacity(&mut self, capacity: Option<usize>) {
+        match (capacity, &self.read_cache) {
+            (None, _) => self.read_cache = None,
+            (Some(capacity), Some(read_cache)) => read_cache.set_capacity(capacity),
+            (Some(capacity), None) => self.read_cache = Some(ReadCache::new(capacity)),
+        }
+    }
+
+    /// Returns the capacity of the main tree values read cache, if enabled.
+    pub fn read_cache_capacity(&self) -> Option<usize> {
+        self.read_cache
+            .as_ref()
+            .map(|read_cache| read_cache.capacity())
+    }
+
+    /// Remove all main tree values from the read cache, if enabled.
+    pub fn invalidate_read_cache(&self) {
+        if let Some(read_cache) = &self.read_cache {
+            read_cache.invalidate();
         }
     }
 
@@ -325,25 +478,38 @@ impl SledTreeOverlay {
             return Ok(false);
         }
 
-        // Then check the cache and the main tree
-        if self.state.cache.contains_key(&key) || self.tree.contains_key(key)? {
+        // Then check the cache
+        if self.state.cache.contains_key(&key) {
             return Ok(true);
         }
 
-        Ok(false)
+        // Then the spilled cache state
+        if let Some(spill) = &self.spill {
+            if let Some(value) = spill.get(&key)? {
+                return Ok(value.is_some());
+            }
+        }
+
+        // And finally the main tree
+        self.tree_contains_key(&key)
     }
 
     /// Returns `true` if the overlay is empty.
     pub fn is_empty(&self) -> Result<bool, sled::Error> {
+        // If we have spilled changes, we have to check for any existing record
+        if self.spill.is_some() {
+            return Ok(self.iter().next().transpose()?.is_none());
+        }
+
         // Keep a counter of all elements
         let mut counter: i64 = 0;
 
         // Add existing keys
-        counter += self.tree.len() as i64;
+        counter += self.base_tree().len() as i64;
 
         // Add new keys
         for key in self.state.cache.keys() {
-            if !self.tree.contains_key(key)? {
+            if !self.tree_contains_key(key)? {
                 counter += 1;
             }
         }
@@ -357,13 +523,18 @@ impl SledTreeOverlay {
     /// Returns last key and value from the overlay or `None` if its empty,
     /// based on the `Ord` implementation for `Vec<u8>`.
     pub fn last(&self) -> Result<Option<(IVec, IVec)>, sled::Error> {
+        // If we have spilled changes, we have to merge them too
+        if self.spill.is_some() {
+            return self.spilled_last();
+        }
+
         // If both main tree and cache are empty, return None
-        if self.tree.is_empty() && self.state.cache.is_empty() {
+        if self.base_tree().is_empty() && self.state.cache.is_empty() {
             return Ok(None);
         }
 
         // Grab main tree last record
-        let tree_last = self.tree.last()?;
+        let tree_last = self.base_tree().last()?;
 
         // If cache has no records, main tree last exists
         if self.state.cache.is_empty() {
@@ -376,7 +547,7 @@ impl SledTreeOverlay {
             if self.state.removed.contains(&record.0) {
                 // Find last existing record
                 let mut key = record.0.clone();
-                while let Some(record) = self.tree.get_lt(key)? {
+                while let Some(record) = self.base_tree().get_lt(key)? {
                     // Check if record is removed
                     if self.state.removed.contains(&record.0) {
                         key = record.0;
@@ -407,7 +578,7 @@ impl SledTreeOverlay {
             }
 
             let mut key = tree_last.0.clone();
-            while let Some(record) = self.tree.get_lt(key)? {
+            while let Some(record) = self.base_tree().get_lt(key)? {
                 // Break if we reach the cache key position
                 if cache_last.0 >= &record.0 {
                     break;
@@ -426,6 +597,47 @@ impl SledTreeOverlay {
         Ok(Some((cache_last.0.clone(), cache_last.1.clone())))
     }
 
+    /// Returns last key and value from the overlay or `None` if its empty,
+    /// walking backwards over the main tree, spilled cache state and cache
+    /// keys, until we find one that is not removed.
+    fn spilled_last(&self) -> Result<Option<(IVec, IVec)>, sled::Error> {
+        let mut bound: Option<IVec> = None;
+        loop {
+            // Grab each source last key before our bound
+            let tree_last = match &bound {
+                Some(bound) => self.base_tree().get_lt(bound)?,
+                None => self.base_tree().last()?,
+            };
+            let cache_last = match &bound {
+                Some(bound) => self
+                    .state
+                    .cache
+                    .range::<IVec, _>(..bound.clone())
+                    .next_back(),
+                None => self.state.cache.last_key_value(),
+            };
+            let spill_last = match &self.spill {
+                Some(spill) => spill.get_lt(bound.as_deref())?,
+                None => None,
+            };
+
+            // Check if the greatest of them exists
+            let candidates: [Option<IVec>; 3] = [
+                tree_last.map(|record| record.0),
+                cache_last.map(|record| record.0.clone()),
+                spill_last,
+            ];
+            let Some(key) = candidates.into_iter().flatten().max() else {
+                return Ok(None);
+            };
+            if let Some(value) = self.get(&key)? {
+                return Ok(Some((key, value)));
+            }
+
+            bound = Some(key);
+        }
+    }
+
     /// Retrieve a value from the overlay if it exists.
     pub fn get(&self, key: &[u8]) -> Result<Option<IVec>, sled::Error> {
         // First check if the key was removed in the overlay
@@ -439,8 +651,8 @@ impl SledTreeOverlay {
             return Ok(Some(v.clone()));
         }
 
-        // And finally the main tree
-        self.tree.get(key)
+        // And finally the spilled cache state and the main tree
+        self.base_get(&key)
     }
 
     /// Insert a key to a new value, returning the last value if it was set.
@@ -449,19 +661,36 @@ impl SledTreeOverlay {
         // into `prev`.
         let mut prev: Option<IVec> = self.state.cache.insert(key.into(), value.into());
 
+        // Update our memory usage
+        self.memory_usage += key.len() + value.len();
+        if let Some(prev) = &prev {
+            self.memory_usage = self.memory_usage.saturating_sub(key.len() + prev.len());
+        }
+
         // In case this key was previously removed from the cache, we have to
         // delete it from the `removed` set.
         let key = IVec::from(key);
         if self.state.removed.contains(&key) {
             self.state.removed.remove(&key);
+            self.memory_usage = self.memory_usage.saturating_sub(key.len());
+            #[cfg(any(feature = "blake3", feature = "sha256"))]
+            if let Some(merkle) = &mut self.merkle {
+                merkle.insert(&key, value);
+            }
             // And in that case, a previous value isn't supposed to exist
             return Ok(None);
         }
 
         // If cache didn't contain this key previously, and it wasn't removed
-        // either, then check if it's in the main tree.
+        // either, then check if it's in the spilled cache state or the main tree.
         if prev.is_none() {
-            prev = self.tree.get(key)?;
+            prev = self.base_get(&key)?;
+        }
+
+        // Update the state root, if tracked
+        #[cfg(any(feature = "blake3", feature = "sha256"))]
+        if let Some(merkle) = &mut self.merkle {
+            merkle.insert(&key, value);
         }
 
         Ok(prev)
@@ -476,10 +705,14 @@ impl SledTreeOverlay {
         }
 
         // Attempt to remove from cache, and if it wasn't in the cache before,
-        // we have to get the previous value from the sled tree:
+        // we have to get the previous value from the spilled cache state or
+        // the sled tree:
         let mut prev: Option<IVec> = self.state.cache.remove(&key);
-        if prev.is_none() {
-            prev = self.tree.get(&key)?;
+        match &prev {
+            Some(prev) => {
+                self.memory_usage = self.memory_usage.saturating_sub(key.len() + prev.len());
+            }
+            None => prev = self.base_get(&key)?,
         }
 
         // Previous value must existed
@@ -487,43 +720,206 @@ impl SledTreeOverlay {
             return Err(sled::Error::CollectionNotFound(key));
         }
 
+        // Update the state root, if tracked
+        #[cfg(any(feature = "blake3", feature = "sha256"))]
+        if let Some(merkle) = &mut self.merkle {
+            merkle.remove(&key);
+        }
+
         // Mark the key as removed
+        self.memory_usage += key.len();
         self.state.removed.insert(key);
 
         Ok(prev)
     }
 
     /// Removes all values from the cache and marks all tree records as
-    /// removed.
+    /// removed. Use [`SledTreeOverlay::clear_prefix`] to only remove the
+    /// values of a key prefix.
     pub fn clear(&mut self) -> Result<(), sled::Error> {
         // Retrieve all db's keys to mark them as removed
         let removed_keys = self
-            .tree
+            .base_tree()
             .iter()
-            .keys()
+            .map(|record| record.map(|(key, _)| key))
             .collect::<Result<BTreeSet<IVec>, sled::Error>>()?;
 
         // Clear state
+        self.memory_usage = removed_keys.iter().map(|key| key.len()).sum();
         self.state.cache = BTreeMap::new();
         self.state.removed = removed_keys;
+        self.spill = None;
+
+        // Reset the state root, if tracked
+        #[cfg(any(feature = "blake3", feature = "sha256"))]
+        if self.merkle.is_some() {
+            self.merkle = Some(SparseMerkleTree::new());
+        }
 
         Ok(())
     }
 
     /// Aggregate all the current overlay changes into a [`sled::Batch`] ready for
     /// further operation. If there are no changes, return `None`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if reading the spilled changes fails. Use
+    /// [`SledTreeOverlay::try_aggregate`] for spilled overlays.
     pub fn aggregate(&self) -> Option<sled::Batch> {
-        self.state.aggregate()
+        if self.spill.is_none() {
+            return self.state.aggregate();
+        }
+
+        self.try_aggregate()
+            .expect("Failed to read spilled cache state")
+    }
+
+    /// Aggregate all the current overlay changes, including the spilled ones,
+    /// into a [`sled::Batch`] ready for further operation. If there are no
+    /// changes, return `None`.
+    pub fn try_aggregate(&self) -> Result<Option<sled::Batch>, sled::Error> {
+        Ok(self.try_kv_batch()?.as_ref().map(sled::Batch::from))
+    }
+
+    /// Aggregate all the current overlay changes, including the spilled ones,
+    /// into a [`KvBatch`] ready for further operation. If there are no
+    /// changes, return `None`.
+    pub fn try_kv_batch(&self) -> Result<Option<KvBatch>, sled::Error> {
+        let Some(spill) = &self.spill else {
+            return Ok(self.state.kv_batch());
+        };
+
+        let mut batch = KvBatch::default();
+
+        // Spilled changes go first, so the in-memory ones overwrite them
+        for record in spill.iter() {
+            match record? {
+                (k, Some(v)) => batch.insert(k, v),
+                (k, None) => batch.remove(k),
+            }
+        }
+
+        for (k, v) in self.state.cache.iter() {
+            batch.insert(k.clone(), v.clone());
+        }
+
+        for k in self.state.removed.iter() {
+            batch.remove(k.clone());
+        }
+
+        Ok(Some(batch))
+    }
+
+    /// Returns the current cache state merged with the spilled one.
+    fn full_state(&self) -> Result<Cow<'_, SledTreeOverlayState>, sled::Error> {
+        let Some(spill) = &self.spill else {
+            return Ok(Cow::Borrowed(&self.state));
+        };
+
+        let mut state = SledTreeOverlayState::new();
+        for record in spill.iter() {
+            match record? {
+                (k, Some(v)) => {
+                    state.cache.insert(k, v);
+                }
+                (k, None) => {
+                    state.removed.insert(k);
+                }
+            }
+        }
+
+        for (k, v) in self.state.cache.iter() {
+            state.removed.remove(k);
+            state.cache.insert(k.clone(), v.clone());
+        }
+
+        for k in self.state.removed.iter() {
+            state.cache.remove(k);
+            state.removed.insert(k.clone());
+        }
+
+        Ok(Cow::Owned(state))
+    }
+
+    /// Returns the approximate memory used by the in-memory cache state, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage
+    }
+
+    /// Recompute the memory used by the in-memory cache state, in case
+    /// it was mutated directly.
+    pub(crate) fn recompute_memory_usage(&mut self) {
+        self.memory_usage = self.state.memory_usage();
+        if let Some(spill) = &self.spill {
+            self.memory_usage += spill.memory_usage();
+        }
+    }
+
+    /// Returns the memory provided keys use in the in-memory cache state, in bytes.
+    fn keys_memory_usage<'a>(&self, keys: impl Iterator<Item = &'a IVec>) -> usize {
+        keys.map(|key| {
+            let cached = self.state.cache.get(key).map_or(0, |v| key.len() + v.len());
+            let removed = if self.state.removed.contains(key) {
+                key.len()
+            } else {
+                0
+            };
+            cached + removed
+        })
+        .sum()
+    }
+
+    /// Iterate over all the current cache state changes, including the
+    /// spilled ones, in key order, without loading the spilled ones in memory.
+    pub(crate) fn changes(&self) -> SledTreeOverlayChanges<'_> {
+        SledTreeOverlayChanges::new(&self.state, self.spill.as_ref())
+    }
+
+    /// Returns `true` if some of the cache state has been spilled out of memory.
+    pub fn is_spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    /// Move the in-memory cache state into a temporary tree inside
+    /// provided temporary [`sled::Db`], freeing its memory. All overlay
+    /// operations keep working transparently over the spilled state.
+    pub fn spill(&mut self, spill_db: &sled::Db) -> Result<(), sled::Error> {
+        if self.state.cache.is_empty() && self.state.removed.is_empty() {
+            return Ok(());
+        }
+
+        if self.spill.is_none() {
+            self.spill = Some(SpilledState::new(spill_db)?);
+        }
+
+        // We can safely unwrap here since we just created it
+        self.spill.as_mut().unwrap().spill(&self.state)?;
+        self.state = SledTreeOverlayState::new();
+        self.memory_usage = 0;
+
+        Ok(())
     }
 
     /// Checkpoint current cache state so we can revert to it, if needed.
     pub fn checkpoint(&mut self) {
         self.checkpoint = self.state.clone();
+        #[cfg(any(feature = "blake3", feature = "sha256"))]
+        {
+            self.checkpoint_merkle = self.merkle.clone();
+        }
+        self.checkpoint_spill = self.spill.clone();
     }
 
     /// Revert to current cache state checkpoint.
     pub fn revert_to_checkpoint(&mut self) {
         self.state = self.checkpoint.clone();
+        #[cfg(any(feature = "blake3", feature = "sha256"))]
+        {
+            self.merkle = self.checkpoint_merkle.clone();
+        }
+        self.spill = self.checkpoint_spill.clone();
+        self.recompute_memory_usage();
     }
 
     /// Calculate differences from provided overlay state changes
@@ -536,7 +932,7 @@ impl SledTreeOverlay {
         sequence: &[SledTreeOverlayStateDiff],
     ) -> Result<SledTreeOverlayStateDiff, sled::Error> {
         // Grab current state
-        let mut current = SledTreeOverlayStateDiff::new(&self.tree, &self.state)?;
+        let mut current = SledTreeOverlayStateDiff::new(self.base_tree(), &*self.full_state()?)?;
 
         // Remove provided diffs sequence
         for diff in sequence {
@@ -546,129 +942,309 @@ impl SledTreeOverlay {
         Ok(current)
     }
 
+    /// Produce a deterministic [`StateDigest`] of the current overlay
+    /// state changes, using the hash function selected by the enabled
+    /// cargo features.
+    #[cfg(any(feature = "blake3", feature = "sha256"))]
+    pub fn digest(&self) -> Result<StateDigest, sled::Error> {
+        Ok(self.diff(&[])?.digest())
+    }
+
     /// Add provided tree overlay state changes from our own.
     pub fn add_diff(&mut self, diff: &SledTreeOverlayStateDiff) {
-        self.state.add_diff(diff)
+        // Update our memory usage by the changed keys usage
+        let keys = || diff.cache.keys().chain(diff.removed.keys());
+        let previous = self.keys_memory_usage(keys());
+        self.state.add_diff(diff);
+        self.memory_usage =
+            (self.memory_usage + self.keys_memory_usage(keys())).saturating_sub(previous);
+
+        // Update the state root, if tracked
+        #[cfg(any(feature = "blake3", feature = "sha256"))]
+        if let Some(merkle) = &mut self.merkle {
+            for (k, v) in diff.cache.iter() {
+                merkle.insert(k, &v.1);
+            }
+            for k in diff.removed.keys() {
+                merkle.remove(k);
+            }
+        }
     }
 
-    /// Remove provided tree overlay state changes from our own.
+    /// Remove provided tree overlay state changes from our own,
+    /// including the spilled ones, which get retired until the next spill.
+    /// Since the changes are expected to have already been applied
+    /// to the tree, the state root, if tracked, remains unchanged.
     pub fn remove_diff(&mut self, diff: &SledTreeOverlayStateDiff) {
-        self.state.remove_diff(diff)
+        if let Some(spill) = &mut self.spill {
+            self.memory_usage += spill.retire(&self.state, diff);
+        }
+
+        // Update our memory usage by the changed keys usage
+        let keys = || diff.cache.keys().chain(diff.removed.keys());
+        let previous = self.keys_memory_usage(keys());
+        self.state.remove_diff(diff);
+        self.memory_usage =
+            (self.memory_usage + self.keys_memory_usage(keys())).saturating_sub(previous);
+    }
+
+    /// Enable tracking of the overlay state root, which is the root of a
+    /// [`SparseMerkleTree`] over all the records the overlay contains, as
+    /// in the main tree merged with the overlay changes. The tree is built
+    /// once here, and then updated incrementally on each overlay write.
+    #[cfg(any(feature = "blake3", feature = "sha256"))]
+    pub fn enable_state_root(&mut self) -> Result<(), sled::Error> {
+        if self.merkle.is_some() {
+            return Ok(());
+        }
+
+        let records = self.iter().collect::<Result<Vec<_>, sled::Error>>()?;
+        self.merkle = Some(SparseMerkleTree::from_records(records));
+
+        Ok(())
+    }
+
+    /// Returns the overlay state root, if its tracking is enabled.
+    #[cfg(any(feature = "blake3", feature = "sha256"))]
+    pub fn state_root(&self) -> Option<StateDigest> {
+        self.merkle.as_ref().map(|merkle| merkle.root())
+    }
+
+    /// Generate a [`MerkleProof`] of the provided key value, or its
+    /// absence, against the overlay state root, if its tracking is enabled.
+    #[cfg(any(feature = "blake3", feature = "sha256"))]
+    pub fn prove(&self, key: &[u8]) -> Option<MerkleProof> {
+        self.merkle.as_ref().map(|merkle| merkle.prove(key))
+    }
+
+    /// Removes all values with keys starting with provided prefix,
+    /// marking the corresponding tree records as removed.
+    pub fn clear_prefix(&mut self, prefix: &[u8]) -> Result<(), sled::Error> {
+        // Retrieve all prefixed keys to remove them
+        let keys = self
+            .scan_prefix(prefix)
+            .map(|record| record.map(|(key, _)| key))
+            .collect::<Result<Vec<IVec>, sled::Error>>()?;
+
+        for key in keys {
+            self.remove(&key)?;
+        }
+
+        Ok(())
     }
 
     /// Immutably iterate through the tree overlay.
-    pub fn iter(&self) -> SledTreeOverlayIter<'_> {
-        SledTreeOverlayIter::new(self)
+    pub fn iter(&self) -> SledTreeOverlayIter<'_, T> {
+        SledTreeOverlayIter::new(self, (Bound::Unbounded, Bound::Unbounded))
+    }
+
+    /// Immutably iterate through the tree overlay records
+    /// with keys inside provided range.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> SledTreeOverlayIter<'_, T> {
+        let start = owned_bound(range.start_bound());
+        let end = owned_bound(range.end_bound());
+        SledTreeOverlayIter::new(self, (start, end))
+    }
+
+    /// Immutably iterate through the tree overlay records
+    /// with keys starting with provided prefix.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> SledTreeOverlayIter<'_, T> {
+        let start = Bound::Included(IVec::from(prefix));
+        let end = match prefix_successor(prefix) {
+            Some(successor) => Bound::Excluded(successor),
+            None => Bound::Unbounded,
+        };
+        SledTreeOverlayIter::new(self, (start, end))
+    }
+}
+
+/// Auxilliary function to compute the smallest key greater than all
+/// keys starting with provided prefix. Returns `None` if no such key
+/// exists, which happens when the prefix is empty or all its bytes are
+/// `0xff`.
+pub(crate) fn prefix_successor(prefix: &[u8]) -> Option<IVec> {
+    let mut successor = prefix.to_vec();
+    while let Some(byte) = successor.pop() {
+        if byte < u8::MAX {
+            successor.push(byte + 1);
+            return Some(successor.into());
+        }
+    }
+
+    None
+}
+
+/// Iterator over the spilled records of a [`SledTreeOverlay`].
+type SpillIter<'a> = Box<dyn Iterator<Item = Result<(IVec, Option<IVec>), sled::Error>> + 'a>;
+
+/// Iterator over the cache state changes of a [`SledTreeOverlay`], merging
+/// the in-memory ones with the spilled ones. Each change is produced along
+/// with its value, or `None` if the key was removed.
+pub(crate) struct SledTreeOverlayChanges<'a> {
+    // Iterator over the in-memory cache records.
+    cache_iter: Peekable<btree_map::Iter<'a, IVec, IVec>>,
+    // Iterator over the in-memory removed keys.
+    removed_iter: Peekable<btree_set::Iter<'a, IVec>>,
+    // Iterator over the spilled records, if any.
+    spill_iter: Option<Peekable<SpillIter<'a>>>,
+}
+
+impl<'a> SledTreeOverlayChanges<'a> {
+    fn new(state: &'a SledTreeOverlayState, spill: Option<&'a SpilledState>) -> Self {
+        Self {
+            cache_iter: state.cache.iter().peekable(),
+            removed_iter: state.removed.iter().peekable(),
+            spill_iter: spill.map(|spill| (Box::new(spill.iter()) as SpillIter<'a>).peekable()),
+        }
+    }
+}
+
+impl Iterator for SledTreeOverlayChanges<'_> {
+    type Item = Result<(IVec, Option<IVec>), sled::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Peek over the next spilled record, checking if a sled error occured
+        let spill_key = match self.spill_iter.as_mut().and_then(|iter| iter.peek()) {
+            Some(Err(e)) => return Some(Err(e.clone())),
+            Some(Ok((k, _))) => Some(k.clone()),
+            None => None,
+        };
+        let cache_key = self.cache_iter.peek().map(|(k, _)| *k);
+        let removed_key = self.removed_iter.peek().copied();
+
+        // Find the next key, which is the smallest one
+        let next_key = [cache_key, removed_key, spill_key.as_ref()]
+            .into_iter()
+            .flatten()
+            .min()?
+            .clone();
+
+        // Spilled records are overwritten by the in-memory ones
+        let mut change = None;
+        if spill_key.as_ref() == Some(&next_key) {
+            if let Some(Some(Ok((_, value)))) = self.spill_iter.as_mut().map(|iter| iter.next()) {
+                change = Some(value);
+            }
+        }
+        if cache_key == Some(&next_key) {
+            change = self.cache_iter.next().map(|(_, v)| Some(v.clone()));
+        }
+        if removed_key == Some(&next_key) {
+            self.removed_iter.next();
+            change = Some(None);
+        }
+
+        change.map(|value| Ok((next_key, value)))
     }
 }
 
+impl FusedIterator for SledTreeOverlayChanges<'_> {}
+
 /// Immutable iterator of a [`SledTreeOverlay`].
-pub struct SledTreeOverlayIter<'a> {
+pub struct SledTreeOverlayIter<'a, T: KvTree = sled::Tree> {
     // Reference to the tree overlay.
-    overlay: &'a SledTreeOverlay,
-    // Iterator over [`sled::Tree`] keys that is being overlayed.
-    tree_iter: Peekable<Iter>,
+    overlay: &'a SledTreeOverlay<T>,
+    // Iterator over [`KvTree`] keys that is being overlayed.
+    tree_iter: Peekable<T::Iter>,
     // Iterator over the overlay's chache keys.
-    cache_iter: Peekable<Keys<'a, IVec, IVec>>,
+    cache_iter: Peekable<btree_map::Range<'a, IVec, IVec>>,
+    // Iterator over the overlay's spilled cache keys, if any.
+    spill_iter: Option<Peekable<sled::Iter>>,
 }
 
-impl<'a> SledTreeOverlayIter<'a> {
-    fn new(overlay: &'a SledTreeOverlay) -> Self {
+impl<'a, T: KvTree> SledTreeOverlayIter<'a, T> {
+    fn new(overlay: &'a SledTreeOverlay<T>, range: (Bound<IVec>, Bound<IVec>)) -> Self {
         Self {
             overlay,
-            tree_iter: overlay.tree.iter().peekable(),
-            cache_iter: overlay.state.cache.keys().peekable(),
+            tree_iter: overlay.base_tree().range(range.clone()).peekable(),
+            cache_iter: overlay.state.cache.range(range.clone()).peekable(),
+            spill_iter: overlay
+                .spill
+                .as_ref()
+                .map(|spill| spill.range(range).peekable()),
         }
     }
 }
 
-impl Iterator for SledTreeOverlayIter<'_> {
+impl<T: KvTree> Iterator for SledTreeOverlayIter<'_, T> {
     type Item = Result<(IVec, IVec), sled::Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // First we peek over the next tree key
-        let peek1 = self.tree_iter.peek();
-
-        // Check if a sled error occured
-        if let Some(Err(e)) = peek1 {
-            return Some(Err(e.clone()));
-        }
-
-        // Peek over the next cache key
-        let peek2 = self.cache_iter.peek();
-
-        // Find the next key we have to grab,
-        // and which iterator to advance.
-        let mut advance_iter: u8 = 0;
-        let next_key = match (peek1, peek2) {
-            (Some(k1), Some(&k2)) => {
-                // Its safe to unwrap here since we already checked for errors
-                let (k1, _) = k1.as_ref().unwrap();
-                match k1.cmp(k2) {
-                    Ordering::Equal => Some(k1.clone()),
-                    Ordering::Greater => {
-                        advance_iter = 2;
-                        Some(k2.clone())
-                    }
-                    Ordering::Less => {
-                        advance_iter = 1;
-                        Some(k1.clone())
-                    }
-                }
-            }
-            (Some(k1), None) => {
-                // Its safe to unwrap here since we already checked for errors
-                let (k1, _) = k1.as_ref().unwrap();
-                advance_iter = 1;
-                Some(k1.clone())
-            }
-            (None, Some(&k2)) => {
-                advance_iter = 2;
-                Some(k2.clone())
-            }
-            (None, None) => None,
-        };
+        loop {
+            // First we peek over the next tree record,
+            // checking if a sled error occured
+            let peek1 = match self.tree_iter.peek() {
+                Some(Err(e)) => return Some(Err(e.clone())),
+                Some(Ok(record)) => Some(record.clone()),
+                None => None,
+            };
+
+            // Peek over the next cache key
+            let peek2 = self.cache_iter.peek().map(|(k, _)| (*k).clone());
 
-        // Check if we have a key to grab
-        let next_key = next_key?;
+            // Peek over the next spilled cache key
+            let peek3 = match self.spill_iter.as_mut().and_then(|iter| iter.peek()) {
+                Some(Err(e)) => return Some(Err(e.clone())),
+                Some(Ok((k, _))) => Some(k.clone()),
+                None => None,
+            };
 
-        // Advance the corresponding iterator
-        match advance_iter {
-            1 => {
+            // Find the next key we have to grab, which is the smallest one.
+            // If there is none, we have reached the end.
+            let tree_key = peek1.as_ref().map(|(k, _)| k.clone());
+            let next_key = [&tree_key, &peek2, &peek3]
+                .into_iter()
+                .flatten()
+                .min()?
+                .clone();
+
+            // Advance the corresponding iterators
+            let mut tree_value = None;
+            if tree_key.as_ref() == Some(&next_key) {
                 self.tree_iter.next();
+                tree_value = peek1.map(|(_, v)| v);
             }
-            2 => {
+            if peek2.as_ref() == Some(&next_key) {
                 self.cache_iter.next();
             }
-            _ => {
-                self.tree_iter.next();
-                self.cache_iter.next();
+            if peek3.as_ref() == Some(&next_key) {
+                if let Some(iter) = self.spill_iter.as_mut() {
+                    iter.next();
+                }
+            }
+
+            // If the key only exists in the main tree, we already have its value
+            if let Some(value) = tree_value {
+                if peek2.as_ref() != Some(&next_key)
+                    && peek3.as_ref() != Some(&next_key)
+                    && !self.overlay.state.removed.contains(&next_key)
+                {
+                    return Some(Ok((next_key, value)));
+                }
             }
-        }
 
-        // Grab the next key value from the overlay
-        match self.overlay.get(&next_key) {
-            Ok(next_value) => match next_value {
-                Some(next_value) => Some(Ok((next_key, next_value))),
-                // If the value doesn't exist, it means it's in the
-                // removed set, so we advance the iterator.
-                None => self.next(),
-            },
-            Err(e) => Some(Err(e)),
+            // Grab the next key value from the overlay
+            match self.overlay.get(&next_key) {
+                Ok(Some(next_value)) => return Some(Ok((next_key, next_value))),
+                // If the value doesn't exist, it means it's removed,
+                // so we advance the iterator.
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
         }
     }
 }
 
-impl FusedIterator for SledTreeOverlayIter<'_> {}
+impl<T: KvTree> FusedIterator for SledTreeOverlayIter<'_, T> {}
 
 /// Define fusion iteration behavior, allowing
 /// us to use the [`SledTreeOverlayIter`] iterator in
 /// loops directly, without using .iter() method
 /// of [`SledTreeOverlay`].
-impl<'a> IntoIterator for &'a SledTreeOverlay {
+impl<'a, T: KvTree> IntoIterator for &'a SledTreeOverlay<T> {
     type Item = Result<(IVec, IVec), sled::Error>;
 
-    type IntoIter = SledTreeOverlayIter<'a>;
+    type IntoIter = SledTreeOverlayIter<'a, T>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.iter()
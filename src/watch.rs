@@ -0,0 +1,187 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Subscriptions to overlay changes over key prefixes.
+//!
+//! Subscribers receive an [`Event`] whenever the overlay writes a key
+//! matching their prefix, and again once that change is applied to the
+//! database, reverted to a checkpoint, or merged through a diff.
+//!
+//! Each subscriber buffers up to a bounded number of events. Writes never
+//! block on subscribers, so when a subscriber buffer is full, new events
+//! for it are dropped and counted, and it can check how many it missed
+//! through [`Subscriber::missed`].
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use sled::IVec;
+
+/// Default number of events a [`Subscriber`] buffers.
+pub const DEFAULT_SUBSCRIBER_CAPACITY: usize = 1024;
+
+/// The reason an [`Event`] was emitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// The key was written in the overlay.
+    Pending,
+    /// The key change was applied to the database.
+    Applied,
+    /// The key was reverted to its checkpointed value.
+    Reverted,
+    /// The key change was merged into the overlay through a diff.
+    Merged,
+}
+
+/// A change of a watched key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The tree the key belongs to.
+    pub tree: IVec,
+    /// The changed key.
+    pub key: IVec,
+    /// The key new value, or `None` if it was removed.
+    pub value: Option<IVec>,
+    /// The reason the event was emitted.
+    pub kind: EventKind,
+}
+
+/// Receiver of the [`Event`] instances matching a watched prefix.
+/// Iterating over it blocks until the next event arrives, or the
+/// overlay gets dropped.
+pub struct Subscriber {
+    /// The events receiver.
+    rx: Receiver<Event>,
+    /// Number of events dropped because the buffer was full.
+    missed: Arc<AtomicU64>,
+}
+
+impl Subscriber {
+    /// Returns the number of events dropped so far because the
+    /// subscriber buffer was full. A non zero value means the
+    /// received events no longer reflect all the key changes.
+    pub fn missed(&self) -> u64 {
+        self.missed.load(Ordering::Relaxed)
+    }
+
+    /// Retrieve the next event, if one is already available.
+    pub fn try_next(&self) -> Option<Event> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Wait for the next event up to provided timeout.
+    pub fn next_timeout(&self, timeout: Duration) -> Result<Event, RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
+}
+
+impl Iterator for Subscriber {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.recv().ok()
+    }
+}
+
+/// Sending side of a registered [`Subscriber`].
+#[derive(Debug)]
+struct Watcher {
+    /// The watched tree.
+    tree: IVec,
+    /// The watched key prefix.
+    prefix: IVec,
+    /// The events sender.
+    tx: SyncSender<Event>,
+    /// Number of events dropped because the buffer was full.
+    missed: Arc<AtomicU64>,
+}
+
+/// Registry of the subscribers of an overlay. Cloning it produces an
+/// empty registry, since clones represent a diverging overlay state.
+#[derive(Debug, Default)]
+pub(crate) struct Watchers {
+    /// Registered subscribers senders.
+    senders: Mutex<Vec<Watcher>>,
+}
+
+impl Watchers {
+    /// Register a new subscriber of provided tree key prefix,
+    /// buffering up to provided number of events.
+    pub fn subscribe(&self, tree: &[u8], prefix: &[u8], capacity: usize) -> Subscriber {
+        let (tx, rx) = sync_channel(capacity);
+        let missed = Arc::new(AtomicU64::new(0));
+        self.senders
+            .lock()
+            .expect("Watchers lock is poisoned")
+            .push(Watcher {
+                tree: tree.into(),
+                prefix: prefix.into(),
+                tx,
+                missed: missed.clone(),
+            });
+        Subscriber { rx, missed }
+    }
+
+    /// Returns `true` if any subscriber watches provided tree.
+    pub fn watches(&self, tree: &[u8]) -> bool {
+        self.senders
+            .lock()
+            .expect("Watchers lock is poisoned")
+            .iter()
+            .any(|watcher| watcher.tree == tree)
+    }
+
+    /// Send an event to all subscribers watching its key, without
+    /// blocking. Subscribers with a full buffer miss the event,
+    /// while the ones that are gone get dropped.
+    pub fn notify(&self, tree: &[u8], key: &[u8], value: Option<&IVec>, kind: EventKind) {
+        let mut senders = self.senders.lock().expect("Watchers lock is poisoned");
+        senders.retain(|watcher| {
+            if watcher.tree != tree || !key.starts_with(&watcher.prefix) {
+                return true;
+            }
+
+            let event = Event {
+                tree: tree.into(),
+                key: key.into(),
+                value: value.cloned(),
+                kind,
+            };
+            match watcher.tx.try_send(event) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    watcher.missed.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+impl Clone for Watchers {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
@@ -0,0 +1,235 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and apply its changes in chunks, interrupting
+//! the apply to verify it can be resumed or rolled back.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use sled::{Config, Db, IVec};
+
+use sled_overlay::{ApplyProgress, SledDbOverlay, APPLY_JOURNAL_TREE};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+
+/// Auxilliary function to initialize a database and an overlay with
+/// changes over it: updates and removals over an existing tree, records
+/// in a new tree and a dropped tree.
+fn setup(db: &Db) -> Result<SledDbOverlay, sled::Error> {
+    let tree_1 = db.open_tree(TREE_1)?;
+    for i in 0..5u8 {
+        tree_1.insert([i], &[i])?;
+    }
+    db.open_tree(TREE_3)?.insert(b"key", b"val")?;
+
+    let mut overlay = SledDbOverlay::new(db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_2, false)?;
+    overlay.open_tree(TREE_3, false)?;
+    for i in 0..3u8 {
+        overlay.insert(TREE_1, &[i], &[i + 10])?;
+    }
+    overlay.remove(TREE_1, &[3])?;
+    overlay.insert(TREE_1, &[5], &[5])?;
+    for i in 0..3u8 {
+        overlay.insert(TREE_2, &[i], &[i])?;
+    }
+    overlay.drop_tree(TREE_3)?;
+
+    Ok(overlay)
+}
+
+/// Auxilliary function to collect the records of a database tree.
+fn records(db: &Db, tree_key: &[u8]) -> Result<Vec<(IVec, IVec)>, sled::Error> {
+    db.open_tree(tree_key)?.iter().collect()
+}
+
+/// Auxilliary function to verify the database contains the applied changes.
+fn verify_applied(db: &Db) -> Result<(), sled::Error> {
+    let expected: Vec<(IVec, IVec)> = vec![
+        (IVec::from(&[0]), IVec::from(&[10])),
+        (IVec::from(&[1]), IVec::from(&[11])),
+        (IVec::from(&[2]), IVec::from(&[12])),
+        (IVec::from(&[4]), IVec::from(&[4])),
+        (IVec::from(&[5]), IVec::from(&[5])),
+    ];
+    assert_eq!(records(db, TREE_1)?, expected);
+    assert_eq!(records(db, TREE_2)?.len(), 3);
+    assert!(!db.tree_names().contains(&TREE_3.into()));
+    assert!(!db.tree_names().contains(&APPLY_JOURNAL_TREE.into()));
+
+    Ok(())
+}
+
+/// Auxilliary function to apply the overlay changes in chunks of two
+/// writes, interrupting the apply after the first chunk got committed.
+fn interrupted_apply(overlay: &mut SledDbOverlay) {
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        overlay.apply_chunked_with_progress(2, |progress| {
+            if progress.applied == 2 {
+                panic!("Interrupted apply");
+            }
+        })
+    }));
+    assert!(result.is_err());
+}
+
+#[test]
+fn sled_db_overlay_apply_chunked() -> Result<(), sled::Error> {
+    // Initialize database and overlay
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    let mut overlay = setup(&db)?;
+
+    // Chunk size must be positive
+    assert!(overlay.apply_chunked(0).is_err());
+
+    // Apply the changes in chunks of two writes, tracking the progress
+    let mut reported = vec![];
+    assert_eq!(
+        overlay.apply_chunked_with_progress(2, |progress| reported.push(progress)),
+        Ok(())
+    );
+    let applied: Vec<usize> = reported.iter().map(|p| p.applied).collect();
+    assert_eq!(applied, vec![2, 4, 6, 8, 9]);
+    assert!(reported.iter().all(|p| p.total == 9));
+
+    // Verify the database
+    verify_applied(&db)?;
+    assert_eq!(overlay.pending_apply()?, None);
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_apply_chunked_spilled() -> Result<(), sled::Error> {
+    // Initialize database and overlay, spilling its changes
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    let mut overlay = setup(&db)?;
+    overlay.set_memory_limit(Some(1));
+    overlay.insert(TREE_1, &[5], &[5])?;
+    assert!(overlay.state.caches.get(TREE_1).unwrap().is_spilled());
+
+    // The journal tree can't be dropped
+    assert!(matches!(
+        overlay.drop_tree(APPLY_JOURNAL_TREE),
+        Err(sled::Error::Unsupported(_))
+    ));
+
+    // Apply the spilled changes in chunks and verify the database
+    assert_eq!(overlay.apply_chunked(2), Ok(()));
+    verify_applied(&db)?;
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_apply_chunked_resume() -> Result<(), sled::Error> {
+    // Initialize database and overlay
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    let mut overlay = setup(&db)?;
+
+    // Interrupt the apply and verify the journal holds
+    // a record per operation, along with its header and progress
+    interrupted_apply(&mut overlay);
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    assert_eq!(
+        overlay.pending_apply()?,
+        Some(ApplyProgress {
+            applied: 2,
+            total: 9
+        })
+    );
+    assert_eq!(db.open_tree(APPLY_JOURNAL_TREE)?.len(), 11);
+
+    // New applies can't start while one is pending
+    overlay.open_tree(TREE_2, false)?;
+    overlay.insert(TREE_2, b"key", b"val")?;
+    assert!(overlay.apply_chunked(2).is_err());
+
+    // Resume the apply and verify the database,
+    // along with the diff the post-apply hooks received
+    let (tx, rx) = std::sync::mpsc::channel();
+    overlay.add_post_apply_hook(move |diff| tx.send(diff.clone()).unwrap());
+    assert_eq!(overlay.resume_apply(3), Ok(true));
+    let diff = rx.try_recv().unwrap();
+    assert_eq!(diff.caches[TREE_1].0.cache.len(), 4);
+    assert_eq!(diff.caches[TREE_2].0.cache.len(), 3);
+    assert_eq!(diff.dropped_trees[TREE_3].0.cache.len(), 1);
+    verify_applied(&db)?;
+    assert_eq!(overlay.resume_apply(3), Ok(false));
+    assert_eq!(overlay.rollback_apply(3), Ok(false));
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_apply_chunked_rollback() -> Result<(), sled::Error> {
+    // Initialize database and overlay
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    let initial_tree_1 = {
+        let mut overlay = setup(&db)?;
+        let initial_tree_1 = records(&db, TREE_1)?;
+        interrupted_apply(&mut overlay);
+        initial_tree_1
+    };
+    assert_ne!(records(&db, TREE_1)?, initial_tree_1);
+
+    // Rollback the apply and verify the database
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    assert_eq!(overlay.rollback_apply(1), Ok(true));
+    assert_eq!(records(&db, TREE_1)?, initial_tree_1);
+    assert!(!db.tree_names().contains(&TREE_2.into()));
+    assert_eq!(records(&db, TREE_3)?.len(), 1);
+    assert_eq!(overlay.pending_apply()?, None);
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_apply_chunked_rollback_new_keys() -> Result<(), sled::Error> {
+    // Initialize database and overlay
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    db.open_tree(TREE_1)?.insert([0], &[0])?;
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+
+    // Write a new key and remove it, so the diff
+    // removes it without a previous value
+    overlay.insert(TREE_1, &[1], &[1])?;
+    overlay.remove(TREE_1, &[1])?;
+    overlay.insert(TREE_1, &[2], &[2])?;
+    interrupted_apply(&mut overlay);
+
+    // Rollback the apply and verify the key doesn't exist
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    assert_eq!(overlay.rollback_apply(2), Ok(true));
+    assert_eq!(
+        records(&db, TREE_1)?,
+        vec![(IVec::from(&[0]), IVec::from(&[0]))]
+    );
+
+    Ok(())
+}
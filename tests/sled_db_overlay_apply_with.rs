@@ -0,0 +1,114 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and apply it with options, to verify the
+//! database gets flushed and the exact applied diff gets returned.
+
+use sled::{Config, IVec};
+
+use sled_overlay::{
+    ApplyOptions, ApplyOutcome, MemoryStore, SledDbOverlay, SledDbOverlayStateDiff,
+};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+
+#[test]
+fn sled_db_overlay_apply_with() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    let tree_1 = db.open_tree(TREE_1)?;
+    tree_1.insert(b"key_a", b"val_a")?;
+    tree_1.insert(b"key_b", b"val_b")?;
+
+    // Initialize overlay and make some changes
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_2, false)?;
+    overlay.insert(TREE_1, b"key_a", b"val_a2")?;
+    overlay.remove(TREE_1, b"key_b")?;
+    overlay.insert(TREE_2, b"key_c", b"val_c")?;
+    let expected = SledDbOverlayStateDiff::new(&overlay.state)?;
+
+    // Change the database beneath the overlay after its diff got captured
+    tree_1.insert(b"key_a", b"val_a1")?;
+
+    // Apply the overlay, flushing the database and retrieving the diff
+    let outcome = overlay
+        .apply_with(ApplyOptions {
+            flush: true,
+            return_diff: true,
+        })
+        .unwrap();
+    assert!(outcome.flushed_bytes.is_some());
+    let diff = outcome.diff.unwrap();
+
+    // The returned diff contains the value actually replaced
+    assert_ne!(diff, expected);
+    assert_eq!(
+        diff.caches[TREE_1].0.cache[b"key_a".as_slice()],
+        (Some(b"val_a1".into()), b"val_a2".into())
+    );
+    assert_eq!(
+        diff.caches[TREE_1].0.removed[b"key_b".as_slice()],
+        IVec::from(b"val_b")
+    );
+    assert_eq!(
+        diff.caches[TREE_2].0.cache[b"key_c".as_slice()],
+        (None, b"val_c".into())
+    );
+    assert_eq!(diff.caches.len(), 2);
+    assert!(diff.dropped_trees.is_empty());
+
+    // Undo the applied changes using the diff inverse
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    assert_eq!(overlay.apply_diff(&diff.inverse()), Ok(()));
+    assert_eq!(tree_1.get(b"key_a")?, Some(b"val_a1".into()));
+    assert_eq!(tree_1.get(b"key_b")?, Some(b"val_b".into()));
+    assert!(!db.tree_names().contains(&TREE_2.into()));
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_apply_with_defaults() -> Result<(), sled::Error> {
+    // Initialize a memory store and an overlay with some changes
+    let store = MemoryStore::new();
+    let mut overlay = SledDbOverlay::new(&store, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.insert(TREE_1, b"key_a", b"val_a")?;
+
+    // By default nothing is flushed or returned
+    assert_eq!(
+        overlay.apply_with(ApplyOptions::default()),
+        Ok(ApplyOutcome::default())
+    );
+
+    // Memory stores have nothing to flush
+    let outcome = overlay
+        .apply_with(ApplyOptions {
+            flush: true,
+            return_diff: false,
+        })
+        .unwrap();
+    assert_eq!(outcome.flushed_bytes, Some(0));
+
+    Ok(())
+}
@@ -0,0 +1,251 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and perform writes to verify overlay's async
+//! API functionality.
+
+#![cfg(feature = "async")]
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use sled::{transaction::TransactionError, Config, IVec};
+use smol::{future::poll_once, stream::StreamExt};
+
+use sled_overlay::{
+    ApplyOptions, KvBatch, KvStore, KvTree, MemoryStore, MemoryTree, SledDbOverlay, INTENT_LOG_TREE,
+};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+
+/// A [`MemoryStore`] whose transactions wait until they get released.
+#[derive(Debug, Clone, Default)]
+struct GatedStore {
+    /// The underlying store.
+    inner: MemoryStore,
+    /// Flag indicating a transaction is waiting.
+    entered: Arc<AtomicBool>,
+    /// Flag indicating transactions can proceed.
+    released: Arc<AtomicBool>,
+}
+
+impl GatedStore {
+    /// Auxilliary function to wait until transactions get released.
+    fn wait(&self) {
+        self.entered.store(true, Ordering::SeqCst);
+        while !self.released.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl KvStore for GatedStore {
+    type Tree = MemoryTree;
+
+    fn open_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<Self::Tree, sled::Error> {
+        self.inner.open_tree(name)
+    }
+
+    fn drop_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<bool, sled::Error> {
+        self.inner.drop_tree(name)
+    }
+
+    fn tree_names(&self) -> Vec<IVec> {
+        self.inner.tree_names()
+    }
+
+    fn transaction(
+        &self,
+        trees: &[Self::Tree],
+        batches: &[KvBatch],
+    ) -> Result<(), TransactionError<sled::Error>> {
+        self.wait();
+        self.inner.transaction(trees, batches)
+    }
+
+    fn transaction_with_previous(
+        &self,
+        trees: &[Self::Tree],
+        batches: &[KvBatch],
+    ) -> Result<Vec<BTreeMap<IVec, Option<IVec>>>, TransactionError<sled::Error>> {
+        self.wait();
+        self.inner.transaction_with_previous(trees, batches)
+    }
+
+    fn flush(&self) -> Result<usize, sled::Error> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn sled_db_overlay_async() -> Result<(), sled::Error> {
+    smol::block_on(async {
+        // Initialize database
+        let config = Config::new().temporary(true);
+        let db = config.open()?;
+
+        // Initialize overlay
+        let mut overlay = SledDbOverlay::new(&db, vec![]);
+        overlay.open_tree(TREE_1, false)?;
+        overlay.open_tree(TREE_2, false)?;
+
+        // Insert some values to the overlay
+        for i in 0..200u32 {
+            overlay.insert(TREE_1, &i.to_be_bytes(), b"val")?;
+        }
+        overlay.insert(TREE_2, b"key_a", b"val_a")?;
+        overlay.insert(TREE_2, b"key_b", b"val_b")?;
+
+        // Verify streams produce the same records as the iterators
+        let records = overlay.stream(TREE_1)?.collect::<Vec<_>>().await;
+        let expected = overlay.iter(TREE_1)?.collect::<Vec<_>>();
+        assert_eq!(records.len(), 200);
+        assert_eq!(records, expected);
+
+        // Grab the diff of the overlay changes
+        let diff = overlay.diff(&[])?;
+
+        // Apply the diff asynchronously
+        assert_eq!(overlay.apply_diff_async(&diff).await, Ok(()));
+        assert_eq!(db.open_tree(TREE_1)?.len(), 200);
+        assert_eq!(db.open_tree(TREE_2)?.get(b"key_a")?, Some(b"val_a".into()));
+
+        // Reopen the trees, since their applied caches got removed,
+        // and perform more changes, including a tree drop
+        overlay.open_tree(TREE_1, false)?;
+        overlay.open_tree(TREE_2, false)?;
+        overlay.insert(TREE_1, b"key_c", b"val_c")?;
+        overlay.remove(TREE_2, b"key_a")?;
+        overlay.drop_tree(TREE_2)?;
+
+        // Apply the overlay asynchronously
+        assert_eq!(overlay.apply_async().await, Ok(()));
+        assert_eq!(db.open_tree(TREE_1)?.get(b"key_c")?, Some(b"val_c".into()));
+        assert!(!db.tree_names().contains(&IVec::from(TREE_2)));
+
+        // Streaming over a missing tree fails
+        assert!(overlay.stream(TREE_2).is_err());
+
+        // Flush the database
+        overlay.flush_async().await?;
+
+        Ok(())
+    })
+}
+
+#[test]
+fn sled_db_overlay_apply_with_async() -> Result<(), sled::Error> {
+    smol::block_on(async {
+        let options = ApplyOptions {
+            flush: true,
+            return_diff: true,
+        };
+
+        // Perform the same changes over two databases
+        let mut outcomes = vec![];
+        for blocking in [true, false] {
+            let db = Config::new().temporary(true).open()?;
+            db.open_tree(TREE_2)?.insert(b"key_a", b"val_a")?;
+
+            let mut overlay = SledDbOverlay::new(&db, vec![]);
+            overlay.open_tree(TREE_1, false)?;
+            overlay.open_tree(TREE_2, false)?;
+            overlay.insert(TREE_1, b"key_b", b"val_b")?;
+            overlay.insert(TREE_2, b"key_a", b"val_aa")?;
+
+            // Apply them either blocking or asynchronously
+            let outcome = match blocking {
+                true => overlay.apply_with(options),
+                false => overlay.apply_with_async(options).await,
+            };
+            let Ok(outcome) = outcome else {
+                panic!("apply failed");
+            };
+            assert!(outcome.flushed_bytes.is_some());
+            assert_eq!(db.open_tree(TREE_1)?.get(b"key_b")?, Some(b"val_b".into()));
+            outcomes.push(outcome.diff.unwrap());
+        }
+
+        // Verify both produced the same applied diff
+        assert_eq!(outcomes[0], outcomes[1]);
+        let (tree_diff, _) = outcomes[1].caches.get(TREE_2).unwrap();
+        assert_eq!(
+            tree_diff.cache.get(b"key_a".as_slice()),
+            Some(&(Some(b"val_a".into()), b"val_aa".into()))
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn sled_db_overlay_apply_async_cancel() -> Result<(), sled::Error> {
+    smol::block_on(async {
+        // Initialize store and overlay changing a tree,
+        // dropping another one and creating a third one
+        let store = GatedStore::default();
+        let mut batch = KvBatch::default();
+        batch.insert(b"key_a", b"val_a");
+        store.open_tree(TREE_1)?.apply_batch(&batch)?;
+        store.open_tree(TREE_2)?.apply_batch(&batch)?;
+
+        let mut overlay = SledDbOverlay::new(&store, vec![]);
+        overlay.open_tree(TREE_1, false)?;
+        overlay.open_tree(TREE_3, false)?;
+        overlay.insert(TREE_1, b"key_a", b"val_a1")?;
+        overlay.insert(TREE_3, b"key_b", b"val_b")?;
+        overlay.drop_tree(TREE_2)?;
+
+        // Start applying, and drop the future while its transaction waits
+        {
+            let mut apply = Box::pin(overlay.apply_async());
+            while !store.entered.load(Ordering::SeqCst) {
+                assert!(poll_once(&mut apply).await.is_none());
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        store.released.store(true, Ordering::SeqCst);
+
+        // The apply still completes as a whole, once its intent is released
+        while store.tree_names().contains(&INTENT_LOG_TREE.into()) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(
+            store.open_tree(TREE_1)?.get(b"key_a")?,
+            Some(b"val_a1".into())
+        );
+        assert_eq!(
+            store.open_tree(TREE_3)?.get(b"key_b")?,
+            Some(b"val_b".into())
+        );
+        assert!(!store.tree_names().contains(&TREE_2.into()));
+        assert!(!overlay.recover()?);
+
+        Ok(())
+    })
+}
@@ -0,0 +1,154 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate two diverging [`sled::Db`] instances, and compare them to
+//! verify that applying their diff turns the first into the second.
+
+use sled::{Config, Db, IVec};
+
+use sled_overlay::{
+    diff_stores, diff_trees, MemoryStore, SledDbOverlay, APPLY_JOURNAL_TREE, INTENT_LOG_TREE,
+};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+
+/// Records of a database tree, along with its name.
+type TreeRecords = (IVec, Vec<(IVec, IVec)>);
+
+/// Auxilliary function to collect all the records of a database,
+/// along with their tree names.
+fn records(db: &Db) -> Result<Vec<TreeRecords>, sled::Error> {
+    let mut records = vec![];
+    let mut tree_names = db.tree_names();
+    tree_names.sort();
+    for tree_key in tree_names {
+        let tree = db.open_tree(&tree_key)?;
+        records.push((tree_key, tree.iter().collect::<Result<_, _>>()?));
+    }
+
+    Ok(records)
+}
+
+#[test]
+fn sled_db_overlay_compare() -> Result<(), sled::Error> {
+    // Initialize databases
+    let config = Config::new().temporary(true);
+    let db_1 = config.open()?;
+    let config = Config::new().temporary(true);
+    let db_2 = config.open()?;
+
+    // Both databases share a tree, with updated, removed and inserted keys
+    let tree_1 = db_1.open_tree(TREE_1)?;
+    tree_1.insert(b"key_a", b"val_a")?;
+    tree_1.insert(b"key_b", b"val_b")?;
+    tree_1.insert(b"key_c", b"val_c")?;
+    let tree_2 = db_2.open_tree(TREE_1)?;
+    tree_2.insert(b"key_a", b"val_a")?;
+    tree_2.insert(b"key_b", b"val_b2")?;
+    tree_2.insert(b"key_d", b"val_d")?;
+
+    // Each database has a tree the other one doesn't
+    db_1.open_tree(TREE_2)?.insert(b"key_e", b"val_e")?;
+    db_2.open_tree(TREE_3)?.insert(b"key_f", b"val_f")?;
+
+    // The trees reserved for the crate bookkeeping are not compared
+    db_1.open_tree(APPLY_JOURNAL_TREE)?.insert(b"key", b"val")?;
+    db_2.open_tree(INTENT_LOG_TREE)?.insert(b"key", b"val")?;
+
+    // Compare the databases
+    let diff = diff_stores(&db_1, &db_2)?;
+    let (tree_diff, drop) = &diff.caches[TREE_1];
+    assert!(!drop);
+    assert_eq!(tree_diff.cache.len(), 2);
+    assert_eq!(
+        tree_diff.cache[b"key_b".as_slice()],
+        (Some(b"val_b".into()), b"val_b2".into())
+    );
+    assert_eq!(
+        tree_diff.cache[b"key_d".as_slice()],
+        (None, b"val_d".into())
+    );
+    assert_eq!(tree_diff.removed[b"key_c".as_slice()], IVec::from(b"val_c"));
+    let (tree_diff, restored) = &diff.dropped_trees[TREE_2];
+    assert!(!restored);
+    assert_eq!(
+        tree_diff.cache[b"key_e".as_slice()],
+        (None, b"val_e".into())
+    );
+    assert_eq!(diff.new_trees(), vec![IVec::from(TREE_3)]);
+    assert_eq!(diff.dropped_trees.len(), 1);
+    db_1.drop_tree(APPLY_JOURNAL_TREE)?;
+    db_2.drop_tree(INTENT_LOG_TREE)?;
+
+    // Comparing a subset of trees only covers them
+    let subset = diff_trees(std::slice::from_ref(&tree_1), std::slice::from_ref(&tree_2))?;
+    assert_eq!(subset.caches.len(), 1);
+    assert_eq!(subset.caches[TREE_1], diff.caches[TREE_1]);
+    assert!(subset.dropped_trees.is_empty());
+
+    // Apply the diff and verify the databases match
+    let initial = records(&db_1)?;
+    let mut overlay = SledDbOverlay::new(&db_1, vec![]);
+    assert_eq!(overlay.apply_diff(&diff), Ok(()));
+    assert_eq!(records(&db_1)?, records(&db_2)?);
+    let diff = diff_stores(&db_1, &db_2)?;
+    assert!(diff.caches.is_empty());
+    assert!(diff.dropped_trees.is_empty());
+
+    // Revert the changes by comparing against a copy of the initial state
+    let snapshot = Config::new().temporary(true).open()?;
+    for (tree_key, tree_records) in initial.iter() {
+        let tree = snapshot.open_tree(tree_key)?;
+        for (key, value) in tree_records {
+            tree.insert(key, value)?;
+        }
+    }
+    let mut overlay = SledDbOverlay::new(&db_1, vec![]);
+    assert_eq!(overlay.apply_diff(&diff_stores(&db_1, &snapshot)?), Ok(()));
+    assert_eq!(records(&db_1)?, initial);
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_compare_backends() -> Result<(), sled::Error> {
+    // Initialize a database and an empty memory store
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    db.open_tree(TREE_1)?.insert(b"key_a", b"val_a")?;
+    let store = MemoryStore::new();
+
+    // The memory store lacks all the database trees
+    let diff = diff_stores(&db, &store)?;
+    assert!(diff.caches.is_empty());
+    assert_eq!(diff.dropped_trees.len(), 2);
+    assert_eq!(
+        diff.dropped_trees[TREE_1].0.cache[b"key_a".as_slice()],
+        (None, b"val_a".into())
+    );
+
+    // Applying the diff over the memory store recreates them
+    let mut overlay = SledDbOverlay::new(&store, vec![]);
+    assert_eq!(overlay.apply_diff(&diff_stores(&store, &db)?), Ok(()));
+    assert!(diff_stores(&db, &store)?.caches.is_empty());
+    assert!(diff_stores(&db, &store)?.dropped_trees.is_empty());
+
+    Ok(())
+}
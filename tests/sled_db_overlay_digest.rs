@@ -0,0 +1,131 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the same changes over two [`SledDbOverlay`] instances on top
+//! of different [`sled::Db`] instances, and verify their state diff
+//! digests match.
+
+#![cfg(any(feature = "blake3", feature = "sha256"))]
+
+use sled::Config;
+
+use sled_overlay::{SledDbOverlay, SledTreeOverlayStateDiff};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+
+/// Auxilliary function to initialize a database and perform
+/// the same set of changes over its overlay.
+fn setup(trees: &[&[u8]]) -> Result<(sled::Db, SledDbOverlay), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize trees with some values, in provided order
+    for tree in trees {
+        let tree = db.open_tree(tree)?;
+        tree.insert(b"key_a", b"val_a")?;
+        tree.insert(b"key_b", b"val_b")?;
+    }
+
+    // Initialize overlay and perform some changes
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_2, false)?;
+    overlay.open_tree(TREE_3, false)?;
+    overlay.insert(TREE_1, b"key_a", b"val_aa")?;
+    overlay.insert(TREE_1, b"key_c", b"val_c")?;
+    overlay.remove(TREE_2, b"key_b")?;
+    overlay.insert(TREE_3, b"key_d", b"val_d")?;
+
+    Ok((db, overlay))
+}
+
+#[test]
+fn sled_db_overlay_digest() -> Result<(), sled::Error> {
+    // Perform the same changes over two databases, where trees
+    // were created in different order
+    let (_db_1, mut overlay_1) = setup(&[TREE_1, TREE_2])?;
+    let (_db_2, mut overlay_2) = setup(&[TREE_2, TREE_1])?;
+
+    // Verify their digests match
+    let diff_1 = overlay_1.diff(&[])?;
+    let diff_2 = overlay_2.diff(&[])?;
+    assert_eq!(diff_1.digest(), diff_2.digest());
+    assert_eq!(overlay_1.digest()?, diff_1.digest());
+    assert_eq!(overlay_2.digest()?, diff_2.digest());
+
+    // Unrelated trees of the database must not affect the digest
+    let (_db_3, overlay_3) = setup(&[TREE_1, TREE_2, b"_unrelated"])?;
+    assert_ne!(
+        overlay_1.diff(&[])?.initial_tree_names.len(),
+        overlay_3.diff(&[])?.initial_tree_names.len()
+    );
+    assert_eq!(overlay_1.digest()?, overlay_3.digest()?);
+
+    // Digest must be stable across invocations
+    assert_eq!(diff_1.digest(), diff_1.clone().digest());
+
+    // Perform a different change in the second overlay
+    // and verify digests don't match anymore
+    overlay_2.insert(TREE_3, b"key_d", b"val_dd")?;
+    assert_ne!(overlay_1.digest()?, overlay_2.digest()?);
+
+    // Perform the same change in the first overlay
+    // and verify digests match again
+    overlay_1.insert(TREE_3, b"key_d", b"val_dd")?;
+    assert_eq!(overlay_1.digest()?, overlay_2.digest()?);
+
+    // Dropping a tree must also change the digest
+    overlay_1.drop_tree(TREE_2)?;
+    assert_ne!(overlay_1.digest()?, overlay_2.digest()?);
+    overlay_2.drop_tree(TREE_2)?;
+    assert_eq!(overlay_1.digest()?, overlay_2.digest()?);
+
+    // Inverse diff must produce a different digest
+    let diff = overlay_1.diff(&[])?;
+    assert_ne!(diff.digest(), diff.inverse().digest());
+
+    Ok(())
+}
+
+#[test]
+fn sled_tree_overlay_digest() {
+    // Value moving between fields must change the digest
+    let mut diff_1 = SledTreeOverlayStateDiff::default();
+    diff_1
+        .cache
+        .insert(b"key_a".into(), (None, b"val_a".into()));
+    let mut diff_2 = SledTreeOverlayStateDiff::default();
+    diff_2.removed.insert(b"key_a".into(), b"val_a".into());
+    assert_ne!(diff_1.digest(), diff_2.digest());
+
+    // Key and value boundaries must be unambiguous
+    let mut diff_3 = SledTreeOverlayStateDiff::default();
+    diff_3
+        .cache
+        .insert(b"key_".into(), (None, b"aval_a".into()));
+    assert_ne!(diff_1.digest(), diff_3.digest());
+
+    // Empty diffs must produce the same digest
+    assert_eq!(
+        SledTreeOverlayStateDiff::default().digest(),
+        SledTreeOverlayStateDiff::default().digest()
+    );
+}
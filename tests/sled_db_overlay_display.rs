@@ -0,0 +1,143 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and perform writes to verify the rendering
+//! of its state and diffs.
+
+use sled::Config;
+
+use sled_overlay::SledDbOverlay;
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+
+#[test]
+fn sled_db_overlay_display() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize trees with some values
+    let tree_1 = db.open_tree(TREE_1)?;
+    tree_1.insert(b"key_a", b"val_a")?;
+    tree_1.insert(b"key_b", b"val_b")?;
+    let tree_2 = db.open_tree(TREE_2)?;
+    tree_2.insert(b"key_c", b"val_c")?;
+
+    // Initialize overlay and perform some changes
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_3, false)?;
+    overlay.insert(TREE_1, b"key_a", b"val_aa")?;
+    overlay.insert(TREE_1, &[0x00, 0xff], &[0x01, 0x02])?;
+    overlay.remove(TREE_1, b"key_b")?;
+    overlay.insert(TREE_3, b"key_d", b"val_d")?;
+    overlay.drop_tree(TREE_2)?;
+
+    // Verify diff rendering
+    let diff = overlay.diff(&[])?;
+    let expected = "\
+[\"_tree1\"]
++ 0x00ff = 0x0102
+~ \"key_a\": \"val_a\" -> \"val_aa\"
+- \"key_b\" (was \"val_b\")
+[\"_tree3\"] (new)
++ \"key_d\" = \"val_d\"
+[\"_tree2\"] (dropped)
+- \"key_c\" (was \"val_c\")
+";
+    assert_eq!(diff.to_string(), expected);
+
+    // Verify inverse diff rendering
+    let expected = "\
+[\"_tree1\"]
+~ \"key_a\": \"val_aa\" -> \"val_a\"
++ \"key_b\" = \"val_b\"
+- 0x00ff (was 0x0102)
+[\"_tree3\"] (dropped)
+- \"key_d\" (was \"val_d\")
+[\"_tree2\"] (restored)
++ \"key_c\" = \"val_c\"
+";
+    assert_eq!(diff.inverse().to_string(), expected);
+
+    // Verify state rendering
+    let expected = "\
+[\"_tree1\"]
++ 0x00ff = 0x0102
++ \"key_a\" = \"val_aa\"
+- \"key_b\"
+[\"_tree3\"] (new)
++ \"key_d\" = \"val_d\"
+[\"_tree2\"] (dropped)
+- \"key_c\" (was \"val_c\")
+";
+    assert_eq!(overlay.state.to_string(), expected);
+
+    // Verify truncation
+    let tree_diff = &diff.caches.get(TREE_1).unwrap().0;
+    let expected = "\
++ 0x00ff = 0x0102
+~ \"key\"...(5 bytes): \"val\"...(5 bytes) -> \"val\"...(6 bytes)
+- \"key\"...(5 bytes) (was \"val\"...(5 bytes))
+";
+    assert_eq!(tree_diff.pretty().max_len(3).to_string(), expected);
+
+    // Verify full rendering of long values
+    overlay.insert(TREE_3, b"key_e", &[b'v'; 100])?;
+    let state = &overlay.state.caches.get(TREE_3).unwrap().state;
+    assert!(state.to_string().contains("...(100 bytes)"));
+    assert!(!state.pretty().full().to_string().contains("..."));
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_display_spilled() -> Result<(), sled::Error> {
+    // Initialize database and overlay
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+
+    // Keys containing separators are rendered unambiguously
+    overlay.insert(TREE_1, b"a = b", b"c -> \"d\"")?;
+    let expected = "\
+[\"_tree1\"] (new)
++ \"a = b\" = \"c -> \\\"d\\\"\"
+";
+    assert_eq!(overlay.state.to_string(), expected);
+
+    // Spill the changes and verify they are still rendered
+    overlay.set_memory_limit(Some(16));
+    overlay.insert(TREE_1, b"key_a", b"val_a")?;
+    overlay.remove(TREE_1, b"a = b")?;
+    overlay.insert(TREE_1, b"key_b", b"val_b")?;
+    assert!(overlay.state.caches.get(TREE_1).unwrap().is_spilled());
+    let expected = "\
+[\"_tree1\"] (new)
++ \"key_a\" = \"val_a\"
++ \"key_b\" = \"val_b\"
+- \"a = b\"
+";
+    assert_eq!(overlay.state.to_string(), expected);
+
+    Ok(())
+}
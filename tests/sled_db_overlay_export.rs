@@ -0,0 +1,95 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and export and restore it to verify overlay's
+//! state export functionality.
+
+use sled::Config;
+
+use sled_overlay::SledDbOverlay;
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+
+#[test]
+fn sled_db_overlay_export() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    let tree_1 = db.open_tree(TREE_1)?;
+    tree_1.insert(b"key_a", b"val_a")?;
+    tree_1.insert(b"key_b", b"val_b")?;
+    tree_1.insert(b"key_c", b"val_c")?;
+    db.open_tree(TREE_3)?.insert(b"key_d", b"val_d")?;
+
+    // Initialize overlay and perform some writes
+    let mut overlay = SledDbOverlay::new(&db, vec![TREE_1]);
+    overlay.open_tree(TREE_1, true)?;
+    overlay.insert(TREE_1, b"key_a", b"val_aa")?;
+    overlay.remove(TREE_1, b"key_b")?;
+    overlay.checkpoint();
+    overlay.open_tree(TREE_2, false)?;
+    overlay.insert(TREE_2, b"key_e", b"val_e")?;
+    overlay.drop_tree(TREE_3)?;
+
+    // Export the overlay into a file and restore it
+    let path = std::env::temp_dir().join(format!("sled-overlay-export-{}", std::process::id()));
+    overlay.export_to_file(&path)?;
+    let mut restored = SledDbOverlay::import_from_file(&db, &path)?;
+    std::fs::remove_file(&path)?;
+
+    // Verify the restored overlay state and checkpoint
+    assert_eq!(restored.diff(&[])?, overlay.diff(&[])?);
+    assert_eq!(restored.get(TREE_1, b"key_a")?, Some(b"val_aa".into()));
+    assert_eq!(restored.get(TREE_1, b"key_b")?, None);
+    assert_eq!(restored.get(TREE_2, b"key_e")?, Some(b"val_e".into()));
+    assert!(restored.get(TREE_3, b"key_d").is_err());
+    assert!(restored.drop_tree(TREE_1).is_err());
+    let export = restored.export()?;
+    assert_eq!(export, overlay.export()?);
+
+    let mut reverted = overlay.clone();
+    reverted.revert_to_checkpoint();
+    restored.revert_to_checkpoint();
+    assert_eq!(restored.diff(&[])?, reverted.diff(&[])?);
+
+    // Changing untouched keys doesn't affect the export
+    tree_1.insert(b"key_c", b"val_cc")?;
+    let mut restored = SledDbOverlay::import(&db, &export)?;
+
+    // Apply the restored overlay
+    assert_eq!(restored.apply(), Ok(()));
+    assert_eq!(tree_1.get(b"key_a")?, Some(b"val_aa".into()));
+    assert_eq!(tree_1.get(b"key_b")?, None);
+    assert_eq!(db.open_tree(TREE_2)?.get(b"key_e")?, Some(b"val_e".into()));
+    assert!(!db.tree_names().contains(&TREE_3.into()));
+
+    // Verify the export can't be restored over the changed database
+    assert!(SledDbOverlay::import(&db, &export).is_err());
+
+    // Verify invalid exports get rejected
+    assert!(SledDbOverlay::import(&db, b"").is_err());
+    assert!(SledDbOverlay::import(&db, &export[..export.len() - 1]).is_err());
+    let mut trailing = export.clone();
+    trailing.push(0);
+    assert!(SledDbOverlay::import(&db, &trailing).is_err());
+
+    Ok(())
+}
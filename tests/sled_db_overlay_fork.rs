@@ -0,0 +1,171 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and fork it to verify that forks diverge
+//! independently, and only the committed one affects the overlay.
+
+use sled::{Config, IVec};
+
+use sled_overlay::{SledDbOverlay, StackableOverlay};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+
+/// Auxilliary function to collect the records of a tree.
+fn records<O: StackableOverlay>(
+    overlay: &O,
+    tree_key: &[u8],
+) -> Result<Vec<(IVec, IVec)>, sled::Error> {
+    overlay.iter(tree_key)?.collect()
+}
+
+#[test]
+fn sled_db_overlay_fork() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Create a tree with some records in the database,
+    // and another one the overlay won't open
+    db.open_tree(TREE_1)?.insert(b"key_a", b"val_a")?;
+    db.open_tree(TREE_2)?.insert(b"key_b", b"val_b")?;
+
+    // Initialize overlay with some pending changes
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.insert(TREE_1, b"key_c", b"val_c")?;
+    let expected_parent = records(&overlay, TREE_1)?;
+
+    // Create two forks diverging from the same state
+    let mut fork_1 = overlay.fork();
+    let mut fork_2 = overlay.fork();
+    assert!(fork_1.is_clean());
+
+    // Forks read through to the overlay state
+    assert_eq!(fork_1.get(TREE_1, b"key_c")?, Some(b"val_c".into()));
+    assert_eq!(records(&fork_2, TREE_1)?, expected_parent);
+
+    // Diverge the forks
+    fork_1.insert(TREE_1, b"key_d", b"val_d")?;
+    fork_1.remove(TREE_1, b"key_a")?;
+    fork_1.open_tree(TREE_2, false)?;
+    fork_1.insert(TREE_2, b"key_e", b"val_e")?;
+    fork_1.open_tree(TREE_3, true)?;
+    fork_1.insert(TREE_3, b"key_f", b"val_f")?;
+    fork_2.remove(TREE_1, b"key_c")?;
+    fork_2.insert(TREE_1, b"key_a", b"val_a2")?;
+    fork_2.open_tree(TREE_2, false)?;
+    fork_2.drop_tree(TREE_2)?;
+    assert!(!fork_1.is_clean());
+    assert!(fork_1.drop_tree(TREE_3).is_err());
+
+    // Verify forks don't see each other changes
+    assert_eq!(
+        records(&fork_1, TREE_1)?,
+        vec![
+            (b"key_c".into(), b"val_c".into()),
+            (b"key_d".into(), b"val_d".into()),
+        ]
+    );
+    assert_eq!(
+        records(&fork_2, TREE_1)?,
+        vec![(b"key_a".into(), b"val_a2".into())]
+    );
+    assert_eq!(
+        records(&fork_1, TREE_2)?,
+        vec![
+            (b"key_b".into(), b"val_b".into()),
+            (b"key_e".into(), b"val_e".into()),
+        ]
+    );
+    assert!(fork_2.get(TREE_2, b"key_b").is_err());
+    assert!(fork_2.get(TREE_3, b"key_f").is_err());
+
+    // Verify the overlay and the database are unchanged
+    assert_eq!(records(&overlay, TREE_1)?, expected_parent);
+    assert!(overlay.get(TREE_2, b"key_b").is_err());
+    assert_eq!(db.open_tree(TREE_2)?.len(), 1);
+    assert!(!db.tree_names().contains(&IVec::from(TREE_3)));
+
+    // Forks can also run concurrently
+    std::thread::scope(|s| {
+        let fork = overlay.fork();
+        s.spawn(move || {
+            let mut fork = fork;
+            fork.insert(TREE_1, b"key_g", b"val_g").unwrap();
+            assert_eq!(fork.get(TREE_1, b"key_g").unwrap(), Some(b"val_g".into()));
+        });
+    });
+    assert!(overlay.get(TREE_1, b"key_g")?.is_none());
+
+    // Commit the winning fork into the overlay
+    let changes = fork_1.into_changes();
+    drop(fork_2);
+    changes.commit(&mut overlay)?;
+    assert!(db.tree_names().contains(&IVec::from(TREE_3)));
+    assert_eq!(
+        records(&overlay, TREE_1)?,
+        vec![
+            (b"key_c".into(), b"val_c".into()),
+            (b"key_d".into(), b"val_d".into()),
+        ]
+    );
+    assert_eq!(overlay.get(TREE_2, b"key_e")?, Some(b"val_e".into()));
+    assert_eq!(overlay.get(TREE_3, b"key_f")?, Some(b"val_f".into()));
+    assert!(overlay.is_protected(TREE_3));
+
+    // Apply overlay and verify the database
+    assert_eq!(overlay.apply(), Ok(()));
+    assert_eq!(db.open_tree(TREE_1)?.get(b"key_d")?, Some(b"val_d".into()));
+    assert!(db.open_tree(TREE_1)?.get(b"key_a")?.is_none());
+    assert_eq!(db.open_tree(TREE_2)?.get(b"key_e")?, Some(b"val_e".into()));
+    assert_eq!(db.open_tree(TREE_3)?.get(b"key_f")?, Some(b"val_f".into()));
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_fork_dropped_tree() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    db.open_tree(TREE_1)?.insert(b"key_a", b"val_a")?;
+
+    // Initialize overlay
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+
+    // Drop the tree in a fork, change it and reopen it
+    let mut fork = overlay.fork();
+    fork.insert(TREE_1, b"key_b", b"val_b")?;
+    fork.drop_tree(TREE_1)?;
+    assert!(fork.get(TREE_1, b"key_a").is_err());
+    fork.open_tree(TREE_1, false)?;
+    assert_eq!(fork.get(TREE_1, b"key_b")?, Some(b"val_b".into()));
+    fork.drop_tree(TREE_1)?;
+
+    // Commit the fork and verify the tree gets dropped
+    fork.into_changes().commit(&mut overlay)?;
+    assert!(overlay.get(TREE_1, b"key_a").is_err());
+    assert_eq!(overlay.apply(), Ok(()));
+    assert!(!db.tree_names().contains(&IVec::from(TREE_1)));
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and register apply hooks to verify overlay's
+//! invariant enforcement functionality.
+
+use std::sync::{Arc, Mutex};
+
+use sled::{transaction::TransactionError, Config, IVec};
+
+use sled_overlay::{SledDbOverlay, SledDbOverlayStateDiff};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+
+/// Auxilliary function to decode a balance value.
+fn balance(value: &IVec) -> i64 {
+    i64::from_le_bytes(value.as_ref().try_into().unwrap())
+}
+
+/// Auxilliary function to compute the balances supply change of
+/// provided diff over [`TREE_1`].
+fn supply_delta(diff: &SledDbOverlayStateDiff) -> i64 {
+    let Some((tree_diff, _)) = diff.caches.get(TREE_1) else {
+        return 0;
+    };
+
+    let mut delta = 0;
+    for (old, new) in tree_diff.cache.values() {
+        delta += balance(new) - old.as_ref().map(balance).unwrap_or(0);
+    }
+    for old in tree_diff.removed.values() {
+        delta -= balance(old);
+    }
+
+    delta
+}
+
+/// Auxilliary function to register a supply conservation hook.
+fn conserve_supply(overlay: &mut SledDbOverlay) {
+    overlay.add_pre_apply_hook(|diff| {
+        if supply_delta(diff) != 0 {
+            return Err(sled::Error::Unsupported(
+                "Supply is not conserved".to_string(),
+            ));
+        }
+        Ok(())
+    });
+}
+
+#[test]
+fn sled_db_overlay_hooks() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Mint the initial supply
+    let tree_1 = db.open_tree(TREE_1)?;
+    tree_1.insert(b"alice", &100_i64.to_le_bytes())?;
+    db.open_tree(TREE_2)?;
+
+    // Initialize overlay with a supply conservation hook
+    // and a hook recording the applied changes
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    conserve_supply(&mut overlay);
+    let applied = Arc::new(Mutex::new(vec![]));
+    let applied_ = applied.clone();
+    overlay.add_post_apply_hook(move |diff| applied_.lock().unwrap().push(diff.clone()));
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_2, false)?;
+
+    // Mint some more supply and drop a tree, which must get vetoed
+    // before anything touches the database
+    overlay.insert(TREE_1, b"bob", &10_i64.to_le_bytes())?;
+    overlay.drop_tree(TREE_2)?;
+    assert_eq!(
+        overlay.apply(),
+        Err(TransactionError::Abort(sled::Error::Unsupported(
+            "Supply is not conserved".to_string()
+        )))
+    );
+    assert!(tree_1.get(b"bob")?.is_none());
+    assert!(db.tree_names().contains(&TREE_2.into()));
+    assert!(applied.lock().unwrap().is_empty());
+
+    // Transfer some supply instead
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    conserve_supply(&mut overlay);
+    let applied_ = applied.clone();
+    overlay.add_post_apply_hook(move |diff| applied_.lock().unwrap().push(diff.clone()));
+    overlay.open_tree(TREE_1, false)?;
+    overlay.insert(TREE_1, b"alice", &70_i64.to_le_bytes())?;
+    overlay.insert(TREE_1, b"bob", &30_i64.to_le_bytes())?;
+    let transfer = overlay.diff(&[])?;
+
+    // Burn some supply on top of it, producing a vetoed diff
+    overlay.remove(TREE_1, b"bob")?;
+    let burn = overlay.diff(std::slice::from_ref(&transfer))?;
+
+    // Clones share the hooks
+    let mut clone = overlay.clone();
+    assert!(matches!(
+        clone.apply_diff(&burn),
+        Err(TransactionError::Abort(_))
+    ));
+    assert!(tree_1.get(b"bob")?.is_none());
+
+    // Apply the transfer diff
+    assert_eq!(overlay.apply_diff(&transfer), Ok(()));
+    assert_eq!(
+        tree_1.get(b"alice")?,
+        Some(IVec::from(&70_i64.to_le_bytes()))
+    );
+    assert_eq!(tree_1.get(b"bob")?, Some(IVec::from(&30_i64.to_le_bytes())));
+    assert_eq!(*applied.lock().unwrap(), vec![transfer]);
+
+    // The burn diff remains vetoed
+    assert!(matches!(
+        overlay.apply_diff(&burn),
+        Err(TransactionError::Abort(_))
+    ));
+    assert_eq!(tree_1.get(b"bob")?, Some(IVec::from(&30_i64.to_le_bytes())));
+
+    // Clearing the hooks allows it
+    overlay.clear_apply_hooks();
+    assert_eq!(overlay.apply_diff(&burn), Ok(()));
+    assert!(tree_1.get(b"bob")?.is_none());
+    assert_eq!(applied.lock().unwrap().len(), 1);
+
+    Ok(())
+}
@@ -0,0 +1,218 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and declare secondary indexes to verify
+//! overlay's index maintenance functionality.
+
+use sled::{Config, IVec};
+
+use sled_overlay::{SharedSledDbOverlay, SledDbOverlay};
+
+const USERS: &[u8] = b"_users";
+const EMAILS: &[u8] = b"_emails";
+const DOMAINS: &[u8] = b"_domains";
+
+/// Auxilliary function to index users by their email.
+fn email_index(_key: &[u8], value: &[u8]) -> Vec<IVec> {
+    vec![value.into()]
+}
+
+/// Auxilliary function to index emails by their domain,
+/// suffixed with the email, since domains are not unique.
+fn domain_index(key: &[u8], _value: &[u8]) -> Vec<IVec> {
+    let Some(at) = key.iter().position(|b| *b == b'@') else {
+        return vec![];
+    };
+    let mut index_key = key[at + 1..].to_vec();
+    index_key.push(b'/');
+    index_key.extend_from_slice(key);
+    vec![index_key.into()]
+}
+
+/// Auxilliary function to collect all records of a tree overlay.
+fn records(overlay: &SledDbOverlay, tree_key: &[u8]) -> Result<Vec<(IVec, IVec)>, sled::Error> {
+    overlay.iter(tree_key)?.collect()
+}
+
+/// Auxilliary function to build an expected record.
+fn record(key: &[u8], value: &[u8]) -> (IVec, IVec) {
+    (key.into(), value.into())
+}
+
+/// Auxilliary function to create an overlay with the indexes declared.
+fn indexed_overlay(db: &sled::Db) -> Result<SledDbOverlay, sled::Error> {
+    let mut overlay = SledDbOverlay::new(db, vec![]);
+    overlay.open_tree(USERS, false)?;
+    overlay.open_tree(EMAILS, false)?;
+    overlay.open_tree(DOMAINS, false)?;
+    overlay.add_index(USERS, EMAILS, email_index)?;
+    overlay.add_index(EMAILS, DOMAINS, domain_index)?;
+    Ok(overlay)
+}
+
+#[test]
+fn sled_db_overlay_index() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize overlay
+    let mut overlay = indexed_overlay(&db)?;
+
+    // Verify invalid indexes get rejected
+    assert!(overlay.add_index(USERS, USERS, email_index).is_err());
+    assert!(overlay.add_index(DOMAINS, USERS, email_index).is_err());
+    assert!(overlay.add_index(USERS, b"_unknown", email_index).is_err());
+
+    // Perform some writes, which cascade into both indexes
+    overlay.insert(USERS, b"1", b"alice@a.org")?;
+    overlay.insert(USERS, b"2", b"bob@b.org")?;
+    overlay.insert(USERS, b"2", b"bob@a.org")?;
+    overlay.insert(USERS, b"3", b"carol@b.org")?;
+    overlay.remove(USERS, b"3")?;
+    assert_eq!(
+        records(&overlay, EMAILS)?,
+        vec![record(b"alice@a.org", b"1"), record(b"bob@a.org", b"2")]
+    );
+    assert_eq!(
+        records(&overlay, DOMAINS)?,
+        vec![
+            record(b"a.org/alice@a.org", b"alice@a.org"),
+            record(b"a.org/bob@a.org", b"bob@a.org"),
+        ]
+    );
+
+    // Checkpoint, perform some more writes and revert them
+    overlay.checkpoint();
+    overlay.insert(USERS, b"1", b"alice@c.org")?;
+    overlay.insert(USERS, b"4", b"dave@d.org")?;
+    overlay.clear(USERS)?;
+    assert!(overlay.is_empty(EMAILS)?);
+    assert!(overlay.is_empty(DOMAINS)?);
+    overlay.revert_to_checkpoint();
+    assert_eq!(
+        records(&overlay, EMAILS)?,
+        vec![record(b"alice@a.org", b"1"), record(b"bob@a.org", b"2")]
+    );
+    assert_eq!(records(&overlay, DOMAINS)?.len(), 2);
+
+    // Apply the changes
+    let diff = overlay.diff(&[])?;
+    assert_eq!(overlay.apply_diff(&diff), Ok(()));
+    assert_eq!(db.open_tree(EMAILS)?.len(), 2);
+    assert_eq!(db.open_tree(DOMAINS)?.len(), 2);
+
+    // Merge a diff produced by an overlay without the indexes
+    let mut other = SledDbOverlay::new(&db, vec![]);
+    other.open_tree(USERS, false)?;
+    other.insert(USERS, b"1", b"alice@e.org")?;
+    other.remove(USERS, b"2")?;
+    let other_diff = other.diff(&[])?;
+    let mut overlay = indexed_overlay(&db)?;
+    overlay.add_diff(&other_diff)?;
+    assert_eq!(
+        records(&overlay, EMAILS)?,
+        vec![record(b"alice@e.org", b"1")]
+    );
+    assert_eq!(
+        records(&overlay, DOMAINS)?,
+        vec![record(b"e.org/alice@e.org", b"alice@e.org")]
+    );
+
+    // Removing it restores the indexes
+    overlay.try_remove_diff(&other_diff)?;
+    assert_eq!(
+        records(&overlay, EMAILS)?,
+        vec![record(b"alice@a.org", b"1"), record(b"bob@a.org", b"2")]
+    );
+    assert_eq!(records(&overlay, DOMAINS)?.len(), 2);
+
+    // Merge a diff produced by an overlay with the indexes
+    let mut other = indexed_overlay(&db)?;
+    other.insert(USERS, b"5", b"eve@a.org")?;
+    let other_diff = other.diff(&[])?;
+    overlay.add_diff(&other_diff)?;
+    assert_eq!(
+        records(&overlay, EMAILS)?,
+        vec![
+            record(b"alice@a.org", b"1"),
+            record(b"bob@a.org", b"2"),
+            record(b"eve@a.org", b"5"),
+        ]
+    );
+    assert_eq!(records(&overlay, DOMAINS)?.len(), 3);
+
+    // Removing it restores the indexes, since it contains their changes
+    overlay.remove_diff(&other_diff);
+    assert_eq!(
+        records(&overlay, EMAILS)?,
+        vec![record(b"alice@a.org", b"1"), record(b"bob@a.org", b"2")]
+    );
+    assert_eq!(records(&overlay, DOMAINS)?.len(), 2);
+    overlay.add_diff(&other_diff)?;
+    assert_eq!(records(&overlay, EMAILS)?.len(), 3);
+
+    // Index records pointing to other keys are left intact
+    overlay.remove_indexes(EMAILS);
+    overlay.insert(USERS, b"6", b"alice@a.org")?;
+    overlay.remove(USERS, b"1")?;
+    assert_eq!(overlay.get(EMAILS, b"alice@a.org")?, Some(b"6".into()));
+    assert_eq!(records(&overlay, DOMAINS)?.len(), 3);
+
+    // Verify the shared overlay writes update the indexes
+    let shared = SharedSledDbOverlay::new(overlay);
+    shared.insert(USERS, b"7", b"frank@f.org")?;
+    shared.remove(USERS, b"5")?;
+    assert_eq!(shared.get(EMAILS, b"frank@f.org")?, Some(b"7".into()));
+    assert_eq!(shared.get(EMAILS, b"eve@a.org")?, None);
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_index_failed_write() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize overlay, with a record in it
+    let mut overlay = indexed_overlay(&db)?;
+    overlay.insert(USERS, b"1", b"alice@a.org")?;
+    let subscriber = overlay.watch_prefix(USERS, b"");
+
+    // Drop the last index tree, so writes cascading into it fail
+    overlay.drop_tree(DOMAINS)?;
+    assert!(overlay.insert(USERS, b"2", b"bob@b.org").is_err());
+    assert!(overlay.insert(USERS, b"1", b"alice@c.org").is_err());
+    assert!(overlay.remove(USERS, b"1").is_err());
+    assert!(overlay.clear(USERS).is_err());
+
+    // Neither the source tree nor its index changed, and nothing got notified
+    assert_eq!(
+        records(&overlay, USERS)?,
+        vec![record(b"1", b"alice@a.org")]
+    );
+    assert_eq!(
+        records(&overlay, EMAILS)?,
+        vec![record(b"alice@a.org", b"1")]
+    );
+    assert!(subscriber.try_next().is_none());
+
+    Ok(())
+}
@@ -0,0 +1,265 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of a store that
+//! fails on demand, and interrupt its applies to verify the intent log
+//! completes or rolls them back as a whole.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use sled::{transaction::TransactionError, IVec};
+
+use sled_overlay::{
+    KvBatch, KvStore, KvTree, MemoryStore, MemoryTree, SledDbOverlay, SledDbOverlayStateDiff,
+    SledTreeOverlayStateDiff, INTENT_LOG_TREE,
+};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+
+/// A [`MemoryStore`] whose tree drops and transactions fail on demand.
+#[derive(Debug, Clone, Default)]
+struct FaultyStore {
+    /// The underlying store.
+    inner: MemoryStore,
+    /// Flag indicating tree drops fail.
+    fail_drops: Arc<AtomicBool>,
+    /// Flag indicating transactions fail.
+    fail_transactions: Arc<AtomicBool>,
+}
+
+impl FaultyStore {
+    /// Auxilliary function to build the error of a failure.
+    fn failure() -> sled::Error {
+        sled::Error::Unsupported("Simulated failure".to_string())
+    }
+}
+
+impl KvStore for FaultyStore {
+    type Tree = MemoryTree;
+
+    fn open_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<Self::Tree, sled::Error> {
+        self.inner.open_tree(name)
+    }
+
+    fn drop_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<bool, sled::Error> {
+        if self.fail_drops.load(Ordering::SeqCst) {
+            return Err(Self::failure());
+        }
+        self.inner.drop_tree(name)
+    }
+
+    fn tree_names(&self) -> Vec<IVec> {
+        self.inner.tree_names()
+    }
+
+    fn transaction(
+        &self,
+        trees: &[Self::Tree],
+        batches: &[KvBatch],
+    ) -> Result<(), TransactionError<sled::Error>> {
+        if self.fail_transactions.load(Ordering::SeqCst) {
+            return Err(TransactionError::Storage(Self::failure()));
+        }
+        self.inner.transaction(trees, batches)
+    }
+
+    fn transaction_with_previous(
+        &self,
+        trees: &[Self::Tree],
+        batches: &[KvBatch],
+    ) -> Result<Vec<BTreeMap<IVec, Option<IVec>>>, TransactionError<sled::Error>> {
+        if self.fail_transactions.load(Ordering::SeqCst) {
+            return Err(TransactionError::Storage(Self::failure()));
+        }
+        self.inner.transaction_with_previous(trees, batches)
+    }
+
+    fn flush(&self) -> Result<usize, sled::Error> {
+        self.inner.flush()
+    }
+}
+
+/// Auxilliary function to initialize a store with two trees, and an
+/// overlay changing the first one, dropping the second one and
+/// creating a third one.
+fn setup(store: &FaultyStore) -> Result<SledDbOverlay<FaultyStore>, sled::Error> {
+    let mut batch = KvBatch::default();
+    batch.insert(b"key_a", b"val_a");
+    store.open_tree(TREE_1)?.apply_batch(&batch)?;
+    store.open_tree(TREE_2)?.apply_batch(&batch)?;
+
+    let mut overlay = SledDbOverlay::new(store, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_3, false)?;
+    overlay.insert(TREE_1, b"key_a", b"val_a1")?;
+    overlay.insert(TREE_3, b"key_b", b"val_b")?;
+    overlay.drop_tree(TREE_2)?;
+
+    Ok(overlay)
+}
+
+/// Auxilliary function to check if the store contains provided tree.
+fn has_tree(store: &FaultyStore, tree_key: &[u8]) -> bool {
+    store.tree_names().contains(&tree_key.into())
+}
+
+#[test]
+fn sled_db_overlay_intent_complete() -> Result<(), sled::Error> {
+    // Initialize store and overlay
+    let store = FaultyStore::default();
+    let mut overlay = setup(&store)?;
+
+    // The intent log tree can't be dropped
+    assert!(overlay.drop_tree(INTENT_LOG_TREE).is_err());
+
+    // Interrupt the apply after its changes got written
+    store.fail_drops.store(true, Ordering::SeqCst);
+    assert!(overlay.apply().is_err());
+    let tree_1 = store.open_tree(TREE_1)?;
+    assert_eq!(tree_1.get(b"key_a")?, Some(b"val_a1".into()));
+    assert!(has_tree(&store, TREE_2));
+    assert!(has_tree(&store, INTENT_LOG_TREE));
+    assert!(overlay.recover().is_err());
+
+    // The next overlay fails to complete the apply, surfacing it
+    assert!(SledDbOverlay::try_new(&store, vec![]).is_err());
+    assert!(has_tree(&store, INTENT_LOG_TREE));
+
+    // The next overlay completes the apply
+    store.fail_drops.store(false, Ordering::SeqCst);
+    let overlay = SledDbOverlay::new(&store, vec![]);
+    assert!(!has_tree(&store, TREE_2));
+    assert!(!has_tree(&store, INTENT_LOG_TREE));
+    assert_eq!(
+        store.open_tree(TREE_3)?.get(b"key_b")?,
+        Some(b"val_b".into())
+    );
+    assert!(!overlay.recover()?);
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_intent_rollback() -> Result<(), sled::Error> {
+    // Initialize store and overlay
+    let store = FaultyStore::default();
+    let mut overlay = setup(&store)?;
+
+    // Interrupt the apply before its changes got written,
+    // failing its immediate rollback as well
+    store.fail_transactions.store(true, Ordering::SeqCst);
+    store.fail_drops.store(true, Ordering::SeqCst);
+    assert!(overlay.apply().is_err());
+    assert!(has_tree(&store, TREE_3));
+    assert!(has_tree(&store, INTENT_LOG_TREE));
+
+    // Recovering the apply rolls it back
+    store.fail_transactions.store(false, Ordering::SeqCst);
+    store.fail_drops.store(false, Ordering::SeqCst);
+    assert!(overlay.recover()?);
+    assert!(!has_tree(&store, TREE_3));
+    assert!(!has_tree(&store, INTENT_LOG_TREE));
+    let tree_1 = store.open_tree(TREE_1)?;
+    assert_eq!(tree_1.get(b"key_a")?, Some(b"val_a".into()));
+    assert_eq!(store.open_tree(TREE_2)?.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_intent_abort() -> Result<(), sled::Error> {
+    // Initialize store and overlay
+    let store = FaultyStore::default();
+    let mut overlay = setup(&store)?;
+
+    // A failed transaction gets rolled back right away
+    store.fail_transactions.store(true, Ordering::SeqCst);
+    assert!(overlay.apply().is_err());
+    assert!(!has_tree(&store, TREE_3));
+    assert!(!has_tree(&store, INTENT_LOG_TREE));
+    assert!(has_tree(&store, TREE_2));
+
+    // Applying again succeeds, recreating the new tree
+    store.fail_transactions.store(false, Ordering::SeqCst);
+    assert_eq!(overlay.apply(), Ok(()));
+    assert!(!has_tree(&store, TREE_2));
+    assert_eq!(
+        store.open_tree(TREE_3)?.get(b"key_b")?,
+        Some(b"val_b".into())
+    );
+    assert!(!has_tree(&store, INTENT_LOG_TREE));
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_intent_reopen() -> Result<(), sled::Error> {
+    // Initialize store with a tree holding some records
+    let store = FaultyStore::default();
+    let mut batch = KvBatch::default();
+    batch.insert(b"key_a", b"val_a");
+    batch.insert(b"key_b", b"val_b");
+    store.open_tree(TREE_1)?.apply_batch(&batch)?;
+    let mut overlay = SledDbOverlay::new(&store, vec![]);
+
+    // Build a diff dropping the tree and then restoring it
+    let mut restored = SledTreeOverlayStateDiff::default();
+    restored
+        .cache
+        .insert(b"key_c".into(), (None, b"val_c".into()));
+    let mut diff = SledDbOverlayStateDiff::default();
+    diff.caches
+        .insert(TREE_1.into(), (SledTreeOverlayStateDiff::default(), true));
+    diff.dropped_trees
+        .insert(TREE_1.into(), (restored.clone(), true));
+
+    // An apply failing after the tree operations keeps the tree intact
+    let mut failing = diff.clone();
+    failing.caches.insert(TREE_2.into(), (restored, false));
+    failing
+        .dropped_trees
+        .insert(TREE_2.into(), (SledTreeOverlayStateDiff::default(), false));
+    assert!(overlay.apply_diff(&failing).is_err());
+    let tree_1 = store.open_tree(TREE_1)?;
+    assert_eq!(tree_1.get(b"key_a")?, Some(b"val_a".into()));
+    assert_eq!(tree_1.get(b"key_b")?, Some(b"val_b".into()));
+    assert!(!has_tree(&store, INTENT_LOG_TREE));
+
+    // A failed transaction keeps the tree intact as well
+    store.fail_transactions.store(true, Ordering::SeqCst);
+    assert!(overlay.apply_diff(&diff).is_err());
+    store.fail_transactions.store(false, Ordering::SeqCst);
+    assert_eq!(tree_1.len(), 2);
+
+    // Applying the diff leaves only the restored records
+    assert_eq!(overlay.apply_diff(&diff), Ok(()));
+    let tree_1 = store.open_tree(TREE_1)?;
+    assert_eq!(tree_1.len(), 1);
+    assert_eq!(tree_1.get(b"key_c")?, Some(b"val_c".into()));
+    assert!(!has_tree(&store, INTENT_LOG_TREE));
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and corrupt its state to verify overlay's
+//! consistency checker functionality.
+
+use sled::{Config, IVec};
+
+use sled_overlay::{InvariantViolation, SledDbOverlay, SledTreeOverlayStateDiff};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+
+#[test]
+fn sled_db_overlay_invariants() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    db.open_tree(TREE_1)?.insert(b"key_a", b"val_a")?;
+
+    // Initialize overlay
+    let mut overlay = SledDbOverlay::new(&db, vec![TREE_1]);
+    overlay.open_tree(TREE_1, true)?;
+    overlay.open_tree(TREE_2, false)?;
+    assert!(overlay.check_invariants().is_empty());
+
+    // Perform a sequence of writes, diffs and applies
+    let mut sequence = vec![];
+    for i in 0..8_u8 {
+        overlay.insert(TREE_1, &[i], &[i])?;
+        overlay.insert(TREE_2, &[i], &[i])?;
+        if i > 0 {
+            overlay.remove(TREE_2, &[i - 1])?;
+        }
+        if i.is_multiple_of(3) {
+            overlay.open_tree(TREE_3, false)?;
+            overlay.drop_tree(TREE_3)?;
+        }
+
+        let diff = overlay.diff(&sequence)?;
+        let mut other = SledDbOverlay::new(&db, vec![TREE_1]);
+        other.add_diff(&diff)?;
+        assert!(other.check_invariants().is_empty());
+        sequence.push(diff);
+        assert!(overlay.check_invariants().is_empty());
+    }
+    for diff in &sequence {
+        assert_eq!(overlay.apply_diff(diff), Ok(()));
+        assert!(overlay.check_invariants().is_empty());
+    }
+
+    // Corrupt the state and verify all violations are found
+    let mut overlay = SledDbOverlay::new(&db, vec![TREE_1]);
+    overlay.open_tree(TREE_1, true)?;
+    overlay.open_tree(TREE_3, false)?;
+    overlay.insert(TREE_1, b"key_b", b"val_b")?;
+    let cache = overlay.state.caches.get_mut(TREE_1).unwrap();
+    cache.state.removed.insert(b"key_b".into());
+    overlay.state.initial_tree_names.push(TREE_3.into());
+    let tree_1 = db.open_tree(TREE_1)?;
+    overlay.state.dropped_trees.insert(
+        TREE_1.into(),
+        SledTreeOverlayStateDiff::new_dropped(&tree_1),
+    );
+
+    let tree_1 = IVec::from(TREE_1);
+    let violations = overlay.check_invariants();
+    assert_eq!(
+        violations,
+        vec![
+            InvariantViolation::CachedAndRemoved {
+                tree: tree_1.clone(),
+                key: b"key_b".into(),
+            },
+            InvariantViolation::NewAndInitial {
+                tree: TREE_3.into()
+            },
+            InvariantViolation::DroppedWithCache {
+                tree: tree_1.clone()
+            },
+            InvariantViolation::ProtectedDropped { tree: tree_1 },
+        ]
+    );
+    assert!(violations[3].to_string().contains("Protected tree"));
+
+    Ok(())
+}
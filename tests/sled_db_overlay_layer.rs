@@ -0,0 +1,239 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of nested [`SledDbOverlayLayer`] instances on top
+//! of a [`SledDbOverlay`], and perform writes to verify layers reads
+//! fall through, commits merge into parents and reverts discard changes.
+
+use sled::{Config, IVec};
+
+use sled_overlay::{SledDbOverlay, StackableOverlay};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+
+/// Auxilliary function to collect a tree records through provided overlay.
+fn records<O: StackableOverlay>(overlay: &O, tree_key: &[u8]) -> Vec<(IVec, IVec)> {
+    overlay
+        .iter(tree_key)
+        .unwrap()
+        .collect::<Result<Vec<(IVec, IVec)>, sled::Error>>()
+        .unwrap()
+}
+
+#[test]
+fn sled_db_overlay_layer() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Write some records directly to the database
+    db.open_tree(TREE_1)?.insert(b"key_a", b"val_a")?;
+    db.open_tree(TREE_2)?.insert(b"key_b", b"val_b")?;
+
+    // Initialize overlay
+    let mut overlay = SledDbOverlay::new(&db, vec![TREE_3]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_2, false)?;
+    overlay.insert(TREE_1, b"key_c", b"val_c")?;
+
+    // Block layer
+    let mut block = overlay.layer();
+    assert_eq!(block.get(TREE_1, b"key_a")?, Some(b"val_a".into()));
+    assert_eq!(block.get(TREE_1, b"key_c")?, Some(b"val_c".into()));
+    assert_eq!(block.insert(TREE_1, b"key_d", b"val_d")?, None);
+    assert_eq!(block.remove(TREE_1, b"key_a")?, Some(b"val_a".into()));
+    assert!(block.remove(TREE_1, b"key_a").is_err());
+
+    // Transaction layer, which gets committed
+    let mut tx = block.layer();
+    assert_eq!(tx.get(TREE_1, b"key_a")?, None);
+    assert_eq!(tx.get(TREE_1, b"key_d")?, Some(b"val_d".into()));
+    assert_eq!(
+        tx.insert(TREE_1, b"key_c", b"val_c2")?,
+        Some(b"val_c".into())
+    );
+
+    // Contract call layer, which gets reverted
+    let mut call = tx.layer();
+    call.insert(TREE_1, b"key_e", b"val_e")?;
+    call.remove(TREE_1, b"key_d")?;
+    call.drop_tree(TREE_2)?;
+    assert!(call.get(TREE_2, b"key_b").is_err());
+    assert!(call.drop_tree(TREE_3).is_err());
+    assert_eq!(
+        records(&call, TREE_1),
+        vec![
+            (b"key_c".into(), b"val_c2".into()),
+            (b"key_e".into(), b"val_e".into()),
+        ]
+    );
+    call.revert();
+
+    // Reverted changes are not visible
+    assert_eq!(
+        records(&tx, TREE_1),
+        vec![
+            (b"key_c".into(), b"val_c2".into()),
+            (b"key_d".into(), b"val_d".into()),
+        ]
+    );
+    assert_eq!(tx.get(TREE_2, b"key_b")?, Some(b"val_b".into()));
+
+    // Another contract call layer, which gets committed
+    let mut call = tx.layer();
+    call.drop_tree(TREE_2)?;
+    call.open_tree(TREE_3, true)?;
+    call.insert(TREE_3, b"key_f", b"val_f")?;
+    call.commit()?;
+    tx.commit()?;
+
+    // Block layer now contains the committed changes
+    assert!(block.get(TREE_2, b"key_b").is_err());
+    assert_eq!(block.get(TREE_3, b"key_f")?, Some(b"val_f".into()));
+    assert_eq!(
+        records(&block, TREE_1),
+        vec![
+            (b"key_c".into(), b"val_c2".into()),
+            (b"key_d".into(), b"val_d".into()),
+        ]
+    );
+
+    // Commit the block layer into the overlay
+    block.commit()?;
+
+    // Overlay now contains all committed changes
+    assert_eq!(overlay.get(TREE_1, b"key_a")?, None);
+    assert_eq!(overlay.get(TREE_1, b"key_c")?, Some(b"val_c2".into()));
+    assert_eq!(overlay.get(TREE_1, b"key_d")?, Some(b"val_d".into()));
+    assert_eq!(overlay.get(TREE_1, b"key_e")?, None);
+    assert_eq!(overlay.get(TREE_3, b"key_f")?, Some(b"val_f".into()));
+    assert!(overlay.get(TREE_2, b"key_b").is_err());
+
+    // Nothing reached the database
+    assert_eq!(db.open_tree(TREE_1)?.get(b"key_a")?, Some(b"val_a".into()));
+    assert!(db.open_tree(TREE_1)?.get(b"key_c")?.is_none());
+
+    // Apply the overlay
+    assert_eq!(overlay.apply(), Ok(()));
+    assert_eq!(db.open_tree(TREE_1)?.get(b"key_a")?, None);
+    assert_eq!(db.open_tree(TREE_1)?.get(b"key_d")?, Some(b"val_d".into()));
+    assert!(!db.tree_names().contains(&TREE_2.into()));
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_layer_staged_trees() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Write some records directly to the database
+    db.open_tree(TREE_1)?.insert(b"key_a", b"val_a")?;
+
+    // Initialize overlay, without opening any tree
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+
+    // Open trees in a layer, which gets reverted
+    let mut layer = overlay.layer();
+    layer.open_tree(TREE_1, false)?;
+    layer.open_tree(TREE_2, true)?;
+    assert!(!layer.is_clean());
+    assert!(layer.is_protected(TREE_2));
+    assert_eq!(layer.get(TREE_1, b"key_a")?, Some(b"val_a".into()));
+    assert!(layer.is_empty(TREE_2)?);
+
+    // Changes of trees opened in a nested layer are visible through it
+    let mut nested = layer.layer();
+    nested.remove(TREE_1, b"key_a")?;
+    nested.insert(TREE_2, b"key_b", b"val_b")?;
+    nested.commit()?;
+    assert_eq!(layer.get(TREE_1, b"key_a")?, None);
+    assert_eq!(
+        records(&layer, TREE_2),
+        vec![(b"key_b".into(), b"val_b".into())]
+    );
+    layer.revert();
+
+    // The overlay never opened the trees
+    assert!(!overlay.tree_exists(TREE_2));
+    assert!(overlay.get(TREE_1, b"key_a").is_err());
+    assert!(!db.tree_names().contains(&TREE_2.into()));
+
+    // Open the trees in another layer, which gets committed
+    let mut layer = overlay.layer();
+    layer.open_tree(TREE_1, false)?;
+    layer.open_tree(TREE_2, true)?;
+    layer.remove(TREE_1, b"key_a")?;
+    layer.insert(TREE_2, b"key_b", b"val_b")?;
+    layer.commit()?;
+
+    // The overlay now contains the trees along with their changes
+    assert!(overlay.tree_exists(TREE_2));
+    assert!(overlay.is_protected(TREE_2));
+    assert_eq!(overlay.get(TREE_1, b"key_a")?, None);
+    assert_eq!(overlay.get(TREE_2, b"key_b")?, Some(b"val_b".into()));
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_layer_failed_commit() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize two overlays, where only the first one contains a record
+    let mut overlay_1 = SledDbOverlay::new(&db, vec![]);
+    overlay_1.open_tree(TREE_1, false)?;
+    overlay_1.open_tree(TREE_2, false)?;
+    overlay_1.insert(TREE_2, b"key_a", b"val_a")?;
+    let mut overlay_2 = SledDbOverlay::new(&db, vec![]);
+    overlay_2.open_tree(TREE_1, false)?;
+    overlay_2.open_tree(TREE_2, false)?;
+    overlay_2.insert(TREE_1, b"key_c", b"val_c")?;
+    overlay_2.checkpoint();
+
+    // Perform changes on a fork of the first overlay
+    let mut fork = overlay_1.fork();
+    fork.open_tree(TREE_3, false)?;
+    fork.insert(TREE_1, b"key_b", b"val_b")?;
+    fork.remove(TREE_2, b"key_a")?;
+    let changes = fork.into_changes();
+
+    // Committing them into the second overlay fails on the missing
+    // record, after the other changes got merged in place, so it gets
+    // reverted to its checkpoint
+    assert!(changes.clone().commit(&mut overlay_2).is_err());
+    assert!(!overlay_2.tree_exists(TREE_3));
+    assert_eq!(
+        records(&overlay_2, TREE_1),
+        vec![(b"key_c".into(), b"val_c".into())]
+    );
+    assert!(overlay_2.is_empty(TREE_2)?);
+
+    // Committing them into the first overlay succeeds
+    changes.commit(&mut overlay_1)?;
+    assert!(overlay_1.tree_exists(TREE_3));
+    assert_eq!(overlay_1.get(TREE_1, b"key_b")?, Some(b"val_b".into()));
+    assert_eq!(overlay_1.get(TREE_2, b"key_a")?, None);
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an in-memory
+//! [`MemoryStore`] instance, and perform writes to verify overlay's
+//! pluggable storage backend functionality.
+
+use sled::IVec;
+
+use sled_overlay::{KvBatch, KvStore, KvTree, MemoryStore, SledDbOverlay, SledTreeOverlay};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+
+#[test]
+fn memory_store() -> Result<(), sled::Error> {
+    // Initialize store
+    let store = MemoryStore::new();
+    let tree = store.open_tree(TREE_1)?;
+    assert!(tree.is_empty());
+    assert_eq!(store.tree_names(), vec![IVec::from(TREE_1)]);
+
+    // Apply a batch
+    let mut batch = KvBatch::default();
+    batch.insert(b"key_a", b"val_a");
+    batch.insert(b"key_b", b"val_b");
+    batch.insert(b"key_c", b"val_c");
+    tree.apply_batch(&batch)?;
+
+    // Verify records
+    assert_eq!(tree.len(), 3);
+    assert_eq!(tree.get(b"key_a")?, Some(b"val_a".into()));
+    assert!(tree.contains_key(b"key_b")?);
+    assert_eq!(tree.last()?, Some((b"key_c".into(), b"val_c".into())));
+    assert_eq!(
+        tree.get_lt(b"key_c")?,
+        Some((b"key_b".into(), b"val_b".into()))
+    );
+    let keys: Vec<IVec> = tree
+        .range::<&[u8], _>(&b"key_b"[..]..)
+        .map(|record| record.unwrap().0)
+        .collect();
+    assert_eq!(keys, vec![IVec::from(b"key_b"), IVec::from(b"key_c")]);
+
+    // Trees handles share their records
+    assert_eq!(store.open_tree(TREE_1)?.len(), 3);
+
+    // Drop the tree
+    assert!(store.drop_tree(TREE_1)?);
+    assert!(!store.drop_tree(TREE_1)?);
+    assert!(store.tree_names().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn memory_tree_overlay() -> Result<(), sled::Error> {
+    // Initialize store and tree
+    let store = MemoryStore::new();
+    let tree = store.open_tree(TREE_1)?;
+    let mut batch = KvBatch::default();
+    batch.insert(b"key_a", b"val_a");
+    tree.apply_batch(&batch)?;
+
+    // Initialize overlay
+    let mut overlay = SledTreeOverlay::new(&tree);
+    overlay.insert(b"key_b", b"val_b")?;
+    overlay.remove(b"key_a")?;
+    assert_eq!(overlay.get(b"key_a")?, None);
+    assert_eq!(overlay.last()?, Some((b"key_b".into(), b"val_b".into())));
+
+    // Write overlay changes to the tree
+    tree.apply_batch(&overlay.try_kv_batch()?.unwrap())?;
+    assert_eq!(tree.get(b"key_a")?, None);
+    assert_eq!(tree.get(b"key_b")?, Some(b"val_b".into()));
+
+    Ok(())
+}
+
+#[test]
+fn memory_db_overlay() -> Result<(), sled::Error> {
+    // Initialize store
+    let store = MemoryStore::new();
+    store.open_tree(TREE_1)?;
+
+    // Initialize overlay
+    let mut overlay = SledDbOverlay::new(&store, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_2, false)?;
+
+    // Insert some values to the overlay
+    overlay.insert(TREE_1, b"key_a", b"val_a")?;
+    overlay.insert(TREE_1, b"key_b", b"val_b")?;
+    overlay.insert(TREE_2, b"key_c", b"val_c")?;
+    overlay.remove(TREE_1, b"key_b")?;
+
+    // Verify nothing reached the store
+    assert!(store.open_tree(TREE_1)?.is_empty());
+
+    // Apply the overlay
+    assert_eq!(overlay.apply(), Ok(()));
+    let tree_1 = store.open_tree(TREE_1)?;
+    let tree_2 = store.open_tree(TREE_2)?;
+    assert_eq!(tree_1.get(b"key_a")?, Some(b"val_a".into()));
+    assert_eq!(tree_1.get(b"key_b")?, None);
+    assert_eq!(tree_2.get(b"key_c")?, Some(b"val_c".into()));
+
+    // Apply a diff dropping a tree
+    let mut overlay = SledDbOverlay::new(&store, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.insert(TREE_1, b"key_d", b"val_d")?;
+    overlay.drop_tree(TREE_2)?;
+    let diff = overlay.diff(&[])?;
+    assert_eq!(overlay.apply_diff(&diff), Ok(()));
+    assert_eq!(tree_1.get(b"key_d")?, Some(b"val_d".into()));
+    assert_eq!(store.tree_names(), vec![IVec::from(TREE_1)]);
+
+    Ok(())
+}
@@ -0,0 +1,271 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and rename and copy trees to verify overlay's
+//! tree migration functionality.
+
+use sled::{Config, IVec};
+
+use sled_overlay::{ReplicatedDiff, SledDbOverlay};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+const TREE_4: &[u8] = b"_tree4";
+
+/// Auxilliary function to collect all records of a [`sled::Tree`].
+fn tree_records(db: &sled::Db, tree_key: &[u8]) -> Result<Vec<(IVec, IVec)>, sled::Error> {
+    db.open_tree(tree_key)?.iter().collect()
+}
+
+/// Auxilliary function to build an expected record.
+fn record(key: &[u8], value: &[u8]) -> (IVec, IVec) {
+    (key.into(), value.into())
+}
+
+#[test]
+fn sled_db_overlay_rename_tree() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Create trees in the database and insert some values
+    let tree_1 = db.open_tree(TREE_1)?;
+    tree_1.insert(b"key_a", b"val_a")?;
+    tree_1.insert(b"key_b", b"val_b")?;
+    let tree_4 = db.open_tree(TREE_4)?;
+    tree_4.insert(b"key_d", b"val_d")?;
+
+    // Initialize overlay
+    let mut overlay = SledDbOverlay::new(&db, vec![TREE_4]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_4, true)?;
+
+    // Perform some writes, so the source tree has pending changes
+    overlay.insert(TREE_1, b"key_c", b"val_c")?;
+    overlay.remove(TREE_1, b"key_a")?;
+
+    // Verify invalid operations get rejected
+    assert!(overlay.copy_tree(TREE_1, TREE_1).is_err());
+    assert!(overlay.copy_tree(TREE_1, TREE_4).is_err());
+    assert!(overlay.copy_tree(TREE_2, TREE_3).is_err());
+    assert!(overlay.rename_tree(TREE_4, TREE_3).is_err());
+
+    // Copy a tree and revert it
+    overlay.checkpoint();
+    overlay.copy_tree(TREE_4, TREE_3)?;
+    assert_eq!(overlay.get(TREE_3, b"key_d")?, Some(b"val_d".into()));
+    overlay.revert_to_checkpoint();
+    assert!(overlay.get(TREE_3, b"key_d").is_err());
+
+    // Rename a tree and copy it
+    overlay.rename_tree(TREE_1, TREE_2)?;
+    overlay.copy_tree(TREE_2, TREE_3)?;
+    assert!(overlay.get(TREE_1, b"key_b").is_err());
+    for tree_key in [TREE_2, TREE_3] {
+        assert_eq!(
+            overlay.iter(tree_key)?.collect::<Result<Vec<_>, _>>()?,
+            vec![record(b"key_b", b"val_b"), record(b"key_c", b"val_c")]
+        );
+    }
+
+    // Rename the copy back into the dropped tree, replacing its records
+    overlay.insert(TREE_3, b"key_e", b"val_e")?;
+    overlay.rename_tree(TREE_3, TREE_1)?;
+    assert_eq!(
+        overlay.iter(TREE_1)?.collect::<Result<Vec<_>, _>>()?,
+        vec![
+            record(b"key_b", b"val_b"),
+            record(b"key_c", b"val_c"),
+            record(b"key_e", b"val_e"),
+        ]
+    );
+    overlay.drop_tree(TREE_1)?;
+
+    // Apply the changes through their diff
+    let diff = overlay.diff(&[])?;
+    assert_eq!(overlay.apply_diff(&diff), Ok(()));
+    assert!(!db.tree_names().contains(&TREE_1.into()));
+    assert!(!db.tree_names().contains(&TREE_3.into()));
+    assert_eq!(
+        tree_records(&db, TREE_2)?,
+        vec![record(b"key_b", b"val_b"), record(b"key_c", b"val_c")]
+    );
+
+    // Apply the inverse diff and verify the original tree got restored
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    assert_eq!(overlay.apply_diff(&diff.inverse()), Ok(()));
+    assert!(!db.tree_names().contains(&TREE_2.into()));
+    assert_eq!(
+        tree_records(&db, TREE_1)?,
+        vec![record(b"key_a", b"val_a"), record(b"key_b", b"val_b")]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_rename_tree_inverse() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Create a tree in the database and insert some values
+    let tree_1 = db.open_tree(TREE_1)?;
+    tree_1.insert(b"key_a", b"val_a")?;
+    tree_1.insert(b"key_b", b"val_b")?;
+
+    // Rename the tree and copy it in an overlay
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.rename_tree(TREE_1, TREE_2)?;
+    overlay.copy_tree(TREE_2, TREE_3)?;
+
+    // The diff records them as copies of the original tree,
+    // along with the renamed tree drop
+    let diff = overlay.diff(&[])?;
+    assert_eq!(diff, diff.inverse().inverse());
+    assert!(diff.dropped_trees.contains_key(TREE_1));
+    for tree_key in [TREE_2, TREE_3] {
+        let (cache, drop) = &diff.caches[tree_key];
+        assert!(!drop);
+        assert!(cache.cache.is_empty());
+        let (copy, reverted) = &diff.tree_copies[tree_key];
+        assert!(!reverted);
+        assert_eq!(copy.source, TREE_1);
+        assert!(copy.replaced.is_none());
+    }
+
+    // The copies survive the diff encoding
+    let frame = ReplicatedDiff {
+        sequence: 0,
+        diff: diff.clone(),
+    };
+    assert_eq!(ReplicatedDiff::decode(&frame.encode())?, frame);
+
+    // Apply the diff
+    assert_eq!(overlay.apply_diff(&diff), Ok(()));
+    assert!(!db.tree_names().contains(&TREE_1.into()));
+    for tree_key in [TREE_2, TREE_3] {
+        assert_eq!(
+            tree_records(&db, tree_key)?,
+            vec![record(b"key_a", b"val_a"), record(b"key_b", b"val_b")]
+        );
+    }
+
+    // Apply its inverse and verify the original tree got restored
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    assert_eq!(overlay.apply_diff(&diff.inverse()), Ok(()));
+    assert!(!db.tree_names().contains(&TREE_2.into()));
+    assert!(!db.tree_names().contains(&TREE_3.into()));
+    assert_eq!(
+        tree_records(&db, TREE_1)?,
+        vec![record(b"key_a", b"val_a"), record(b"key_b", b"val_b")]
+    );
+
+    Ok(())
+}
+
+/// Auxilliary function to create the database trees, and an overlay
+/// renaming one of them and copying it over a dropped one.
+fn renamed_overlay(db: &sled::Db) -> Result<SledDbOverlay, sled::Error> {
+    let tree_1 = db.open_tree(TREE_1)?;
+    tree_1.insert(b"key_a", b"val_a")?;
+    tree_1.insert(b"key_b", b"val_b")?;
+    let tree_4 = db.open_tree(TREE_4)?;
+    tree_4.insert(b"key_d", b"val_d")?;
+
+    let mut overlay = SledDbOverlay::new(db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.insert(TREE_1, b"key_c", b"val_c")?;
+    overlay.drop_tree(TREE_4)?;
+    overlay.copy_tree(TREE_1, TREE_4)?;
+    overlay.insert(TREE_4, b"key_e", b"val_e")?;
+    overlay.rename_tree(TREE_1, TREE_2)?;
+
+    Ok(overlay)
+}
+
+/// Auxilliary function to verify the changes of [`renamed_overlay`]
+/// got applied to the database.
+fn verify_renamed(db: &sled::Db) -> Result<(), sled::Error> {
+    assert!(!db.tree_names().contains(&TREE_1.into()));
+    assert_eq!(
+        tree_records(db, TREE_2)?,
+        vec![
+            record(b"key_a", b"val_a"),
+            record(b"key_b", b"val_b"),
+            record(b"key_c", b"val_c"),
+        ]
+    );
+    assert_eq!(
+        tree_records(db, TREE_4)?,
+        vec![
+            record(b"key_a", b"val_a"),
+            record(b"key_b", b"val_b"),
+            record(b"key_c", b"val_c"),
+            record(b"key_e", b"val_e"),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_rename_tree_apply() -> Result<(), sled::Error> {
+    // Apply the changes in a single transaction
+    let db = Config::new().temporary(true).open()?;
+    let mut overlay = renamed_overlay(&db)?;
+
+    // The diff keeps the records the copy replaced
+    let diff = overlay.diff(&[])?;
+    let (copy, reverted) = &diff.tree_copies[TREE_4];
+    assert!(!reverted);
+    assert_eq!(copy.source, TREE_1);
+    assert_eq!(
+        copy.replaced.as_ref().map(|replaced| replaced.cache.len()),
+        Some(1)
+    );
+
+    // The copies survive an export
+    let overlay_2 = SledDbOverlay::import(&db, &overlay.export()?)?;
+    assert_eq!(overlay_2.get(TREE_4, b"key_b")?, Some(b"val_b".into()));
+    assert_eq!(overlay_2.get(TREE_4, b"key_d")?, None);
+
+    assert_eq!(overlay.apply(), Ok(()));
+    verify_renamed(&db)?;
+
+    // Apply the inverse diff and verify the replaced tree got restored
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    assert_eq!(overlay.apply_diff(&diff.inverse()), Ok(()));
+    assert!(!db.tree_names().contains(&TREE_2.into()));
+    assert_eq!(
+        tree_records(&db, TREE_1)?,
+        vec![record(b"key_a", b"val_a"), record(b"key_b", b"val_b")]
+    );
+    assert_eq!(tree_records(&db, TREE_4)?, vec![record(b"key_d", b"val_d")]);
+
+    // Apply the changes in chunks
+    let db = Config::new().temporary(true).open()?;
+    let mut overlay = renamed_overlay(&db)?;
+    assert_eq!(overlay.apply_chunked(1), Ok(()));
+    verify_renamed(&db)?;
+
+    Ok(())
+}
@@ -0,0 +1,350 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate a leader [`sled::Db`] replicating its applied overlays to a
+//! follower one, to verify the follower applies them in order, skipping
+//! duplicates and detecting gaps.
+
+use std::{
+    os::unix::net::UnixStream,
+    panic::{catch_unwind, AssertUnwindSafe},
+    thread,
+};
+
+use sled::{Config, Db, IVec};
+
+use sled_overlay::{
+    diff_stores, replication::channel, replication::UnixSocketReceiver,
+    replication::UnixSocketSender, DiffApplier, DiffPublisher, DiffReceiver, ReplicatedDiff,
+    ReplicationStatus, SledDbOverlay, SledDbOverlayStateDiff, REPLICATION_TREE,
+};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+
+/// Auxilliary function to verify the follower matches the leader,
+/// apart from its replication tree.
+fn verify_replica(leader: &Db, follower: &Db) -> Result<(), sled::Error> {
+    let diff = diff_stores(leader, follower)?;
+    assert!(diff.dropped_trees.is_empty());
+    assert!(diff.caches.is_empty());
+    assert!(follower.tree_names().contains(&REPLICATION_TREE.into()));
+
+    Ok(())
+}
+
+/// Auxilliary function to apply some changes through overlays of the
+/// leader, using both [`SledDbOverlay::apply`] and [`SledDbOverlay::apply_diff`].
+fn leader_changes(leader: &Db, publisher: &DiffPublisher) -> Result<(), sled::Error> {
+    // Create two trees
+    let mut overlay = SledDbOverlay::new(leader, vec![]);
+    publisher.attach(&mut overlay);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_2, false)?;
+    overlay.insert(TREE_1, b"key_a", b"val_a")?;
+    overlay.insert(TREE_1, b"key_b", b"val_b")?;
+    overlay.insert(TREE_2, b"key_c", b"val_c")?;
+    assert_eq!(overlay.apply(), Ok(()));
+
+    // Update the first tree and drop the second one through a diff
+    let mut overlay = SledDbOverlay::new(leader, vec![]);
+    publisher.attach(&mut overlay);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.insert(TREE_1, b"key_a", b"val_a2")?;
+    overlay.remove(TREE_1, b"key_b")?;
+    overlay.drop_tree(TREE_2)?;
+    let diff = SledDbOverlayStateDiff::new(&overlay.state)?;
+    assert_eq!(overlay.apply_diff(&diff), Ok(()));
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_replication_channel() -> Result<(), sled::Error> {
+    // Initialize databases
+    let leader = Config::new().temporary(true).open()?;
+    let follower = Config::new().temporary(true).open()?;
+
+    // Start the follower
+    let (sender, mut receiver) = channel();
+    let handle = {
+        let follower = follower.clone();
+        thread::spawn(move || DiffApplier::new(&follower).run(&mut receiver))
+    };
+
+    // Apply changes on the leader, and wait for the follower
+    // to finish once the publisher is gone
+    let publisher = DiffPublisher::new(sender, &leader);
+    leader_changes(&leader, &publisher)?;
+    assert_eq!(publisher.next_sequence()?, 2);
+    assert!(publisher.take_error().is_none());
+    drop(publisher);
+    handle.join().unwrap()?;
+
+    // Verify the follower
+    verify_replica(&leader, &follower)?;
+    assert_eq!(
+        follower.open_tree(TREE_1)?.get(b"key_a")?,
+        Some(b"val_a2".into())
+    );
+    assert!(!follower.tree_names().contains(&TREE_2.into()));
+    assert_eq!(DiffApplier::new(&follower).last_sequence()?, Some(1));
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_replication_unix_socket() -> Result<(), sled::Error> {
+    // Initialize databases
+    let leader = Config::new().temporary(true).open()?;
+    let follower = Config::new().temporary(true).open()?;
+
+    // Start the follower
+    let (leader_stream, follower_stream) = UnixStream::pair()?;
+    let handle = {
+        let follower = follower.clone();
+        let mut receiver = UnixSocketReceiver::new(follower_stream);
+        thread::spawn(move || DiffApplier::new(&follower).run(&mut receiver))
+    };
+
+    // Apply changes on the leader, and wait for the follower
+    // to finish once the socket gets closed
+    let publisher = DiffPublisher::new(UnixSocketSender::new(leader_stream), &leader);
+    leader_changes(&leader, &publisher)?;
+    assert!(publisher.take_error().is_none());
+    drop(publisher);
+    handle.join().unwrap()?;
+
+    // Verify the follower
+    verify_replica(&leader, &follower)?;
+    assert_eq!(DiffApplier::new(&follower).last_sequence()?, Some(1));
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_replication_ordering() -> Result<(), sled::Error> {
+    // Initialize follower database
+    let follower = Config::new().temporary(true).open()?;
+    let mut applier = DiffApplier::new(&follower);
+    assert_eq!(applier.last_sequence()?, None);
+
+    // Build the frames of three diffs inserting a key each
+    let frames: Vec<Vec<u8>> = (0..3u8)
+        .map(|i| {
+            let mut diff = SledDbOverlayStateDiff::default();
+            diff.caches
+                .entry(TREE_1.into())
+                .or_default()
+                .0
+                .cache
+                .insert(IVec::from(&[i]), (None, IVec::from(&[i])));
+            ReplicatedDiff {
+                sequence: i as u64,
+                diff,
+            }
+            .encode()
+        })
+        .collect();
+    assert_eq!(ReplicatedDiff::decode(&frames[1])?.sequence, 1);
+    assert!(ReplicatedDiff::decode(&frames[1][1..]).is_err());
+
+    // Apply them out of order
+    assert_eq!(applier.apply(&frames[0])?, ReplicationStatus::Applied(0));
+    assert_eq!(applier.apply(&frames[0])?, ReplicationStatus::Duplicate(0));
+    assert_eq!(
+        applier.apply(&frames[2])?,
+        ReplicationStatus::Gap {
+            expected: 1,
+            received: 2
+        }
+    );
+    assert_eq!(follower.open_tree(TREE_1)?.len(), 1);
+    assert_eq!(applier.apply(&frames[1])?, ReplicationStatus::Applied(1));
+    assert_eq!(applier.apply(&frames[2])?, ReplicationStatus::Applied(2));
+    assert_eq!(follower.open_tree(TREE_1)?.len(), 3);
+    assert_eq!(applier.next_sequence()?, 3);
+
+    // Running over a stream with a gap fails
+    let leader = Config::new().temporary(true).open()?;
+    let (sender, mut receiver) = channel();
+    let publisher = DiffPublisher::new(sender, &leader);
+    for _ in 0..5 {
+        publisher.publish(&SledDbOverlayStateDiff::default())?;
+    }
+    drop(publisher);
+    for _ in 0..4 {
+        receiver.recv()?.unwrap();
+    }
+    assert!(applier.run(&mut receiver).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_replication_send_error() -> Result<(), sled::Error> {
+    // Initialize leader database and a publisher without a follower
+    let leader = Config::new().temporary(true).open()?;
+    let (sender, receiver) = channel();
+    drop(receiver);
+    let publisher = DiffPublisher::new(sender, &leader);
+
+    // Applying succeeds, while the send error is kept
+    let mut overlay = SledDbOverlay::new(&leader, vec![]);
+    publisher.attach(&mut overlay);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.insert(TREE_1, b"key_a", b"val_a")?;
+    assert_eq!(overlay.apply(), Ok(()));
+    assert!(publisher.take_error().is_some());
+    assert!(publisher.take_error().is_none());
+
+    // The sequence number was consumed, so followers detect the gap,
+    // while resending still fails without a follower
+    assert_eq!(publisher.next_sequence()?, 1);
+    assert!(publisher.resend(0).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_replication_resend() -> Result<(), sled::Error> {
+    // Initialize databases and a publisher keeping two frames
+    let leader = Config::new().temporary(true).open()?;
+    let follower = Config::new().temporary(true).open()?;
+    let mut applier = DiffApplier::new(&follower);
+    let (sender, mut receiver) = channel();
+    let publisher = DiffPublisher::new(sender, &leader).with_history_capacity(2);
+
+    // Publish three diffs inserting a key each
+    for i in 0..3u8 {
+        let mut diff = SledDbOverlayStateDiff::default();
+        diff.caches
+            .entry(TREE_1.into())
+            .or_default()
+            .0
+            .cache
+            .insert(IVec::from(&[i]), (None, IVec::from(&[i])));
+        assert_eq!(publisher.publish(&diff)?, i as u64);
+    }
+
+    // The follower misses the second one
+    let frame = receiver.recv()?.unwrap();
+    assert_eq!(applier.apply(&frame)?, ReplicationStatus::Applied(0));
+    receiver.recv()?.unwrap();
+    let frame = receiver.recv()?.unwrap();
+    assert_eq!(
+        applier.apply(&frame)?,
+        ReplicationStatus::Gap {
+            expected: 1,
+            received: 2
+        }
+    );
+
+    // Frames no longer kept or not published yet can't be resent
+    assert!(publisher.resend(0).is_err());
+    assert!(publisher.resend(4).is_err());
+    assert_eq!(publisher.resend(3)?, 0);
+
+    // Request the missing diffs and apply them
+    assert_eq!(publisher.resend(applier.next_sequence()?)?, 2);
+    drop(publisher);
+    applier.run(&mut receiver)?;
+    assert_eq!(applier.last_sequence()?, Some(2));
+    assert_eq!(follower.open_tree(TREE_1)?.len(), 3);
+
+    // A restarted leader continues from its persisted sequence
+    let (sender, mut receiver) = channel();
+    let publisher = DiffPublisher::new(sender, &leader);
+    assert_eq!(publisher.next_sequence()?, 3);
+    assert_eq!(publisher.resend(1)?, 2);
+    assert!(publisher.resend(0).is_err());
+    let mut diff = SledDbOverlayStateDiff::default();
+    diff.caches
+        .entry(TREE_1.into())
+        .or_default()
+        .0
+        .cache
+        .insert(IVec::from(&[3]), (None, IVec::from(&[3])));
+    assert_eq!(publisher.publish(&diff)?, 3);
+    drop(publisher);
+    applier.run(&mut receiver)?;
+    assert_eq!(follower.open_tree(TREE_1)?.len(), 4);
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_replication_persisted() -> Result<(), sled::Error> {
+    // Initialize databases and a publisher failing to send,
+    // like a leader crashing right after applying
+    let leader = Config::new().temporary(true).open()?;
+    let follower = Config::new().temporary(true).open()?;
+    let mut applier = DiffApplier::new(&follower);
+    let (sender, receiver) = channel();
+    drop(receiver);
+    let publisher = DiffPublisher::new(sender, &leader);
+
+    // Apply some changes, which get recorded despite the send error
+    let mut overlay = SledDbOverlay::new(&leader, vec![]);
+    publisher.attach(&mut overlay);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.insert(TREE_1, b"key_a", b"val_a")?;
+    overlay.insert(TREE_1, b"key_b", b"val_b")?;
+    overlay.open_tree(TREE_2, false)?;
+    overlay.insert(TREE_2, b"key_c", b"val_c")?;
+    assert_eq!(overlay.apply(), Ok(()));
+    assert!(publisher.take_error().is_some());
+    drop(publisher);
+
+    // Apply more changes in chunks without a publisher, interrupting
+    // the apply, which was started by an overlay with one attached
+    let (sender, mut receiver) = channel();
+    let publisher = DiffPublisher::new(sender, &leader);
+    let mut overlay = SledDbOverlay::new(&leader, vec![]);
+    publisher.attach(&mut overlay);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.insert(TREE_1, b"key_a", b"val_a2")?;
+    overlay.remove(TREE_1, b"key_b")?;
+    overlay.insert(TREE_1, b"key_d", b"val_d")?;
+    overlay.drop_tree(TREE_2)?;
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        overlay.apply_chunked_with_progress(1, |progress| {
+            if progress.applied == 1 {
+                panic!("Interrupted apply");
+            }
+        })
+    }));
+    assert!(result.is_err());
+    drop(overlay);
+    drop(publisher);
+    let mut overlay = SledDbOverlay::new(&leader, vec![]);
+    assert_eq!(overlay.resume_apply(1), Ok(true));
+    assert!(receiver.recv()?.is_none());
+
+    // The restarted leader resends both diffs from its history
+    let (sender, mut receiver) = channel();
+    let publisher = DiffPublisher::new(sender, &leader);
+    assert_eq!(publisher.next_sequence()?, 2);
+    assert_eq!(publisher.resend(applier.next_sequence()?)?, 2);
+    drop(publisher);
+    applier.run(&mut receiver)?;
+    assert_eq!(applier.last_sequence()?, Some(1));
+    verify_replica(&leader, &follower)?;
+
+    Ok(())
+}
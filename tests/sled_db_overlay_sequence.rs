@@ -0,0 +1,86 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and generate IDs to verify overlay's
+//! sequences functionality.
+
+use sled::Config;
+
+use sled_overlay::{SledDbOverlay, SEQUENCES_TREE};
+
+const SEQUENCE_1: &[u8] = b"_sequence1";
+const SEQUENCE_2: &[u8] = b"_sequence2";
+
+/// Auxilliary function to generate some IDs on a fresh database.
+fn generate_ids() -> Result<Vec<u64>, sled::Error> {
+    let db = Config::new().temporary(true).open()?;
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+
+    let mut ids = vec![];
+    for sequence in [SEQUENCE_1, SEQUENCE_2, SEQUENCE_1, SEQUENCE_1] {
+        ids.push(overlay.generate_id(sequence)?);
+    }
+
+    Ok(ids)
+}
+
+#[test]
+fn sled_db_overlay_sequence() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize overlay
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    assert_eq!(overlay.last_id(SEQUENCE_1)?, None);
+    assert!(!db.tree_names().contains(&SEQUENCES_TREE.into()));
+
+    // Generate some IDs
+    assert_eq!(overlay.generate_id(SEQUENCE_1)?, 0);
+    assert_eq!(overlay.generate_id(SEQUENCE_1)?, 1);
+    assert_eq!(overlay.generate_id(SEQUENCE_2)?, 0);
+    assert_eq!(overlay.last_id(SEQUENCE_1)?, Some(1));
+    assert!(overlay.drop_tree(SEQUENCES_TREE).is_err());
+
+    // Checkpoint, generate some more IDs and revert them
+    overlay.checkpoint();
+    assert_eq!(overlay.generate_id(SEQUENCE_1)?, 2);
+    assert_eq!(overlay.generate_id(SEQUENCE_1)?, 3);
+    overlay.revert_to_checkpoint();
+    assert_eq!(overlay.last_id(SEQUENCE_1)?, Some(1));
+    assert_eq!(overlay.generate_id(SEQUENCE_1)?, 2);
+
+    // Verify the database is unaffected until we apply the overlay
+    let sequences = db.open_tree(SEQUENCES_TREE)?;
+    assert!(sequences.is_empty());
+    assert_eq!(overlay.apply(), Ok(()));
+    assert_eq!(sequences.len(), 2);
+
+    // Verify a new overlay continues the sequences
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    assert_eq!(overlay.last_id(SEQUENCE_1)?, Some(2));
+    assert_eq!(overlay.generate_id(SEQUENCE_1)?, 3);
+    assert_eq!(overlay.generate_id(SEQUENCE_2)?, 1);
+
+    // Verify the same operations produce the same IDs
+    assert_eq!(generate_ids()?, vec![0, 0, 1, 2]);
+    assert_eq!(generate_ids()?, generate_ids()?);
+
+    Ok(())
+}
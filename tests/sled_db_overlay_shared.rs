@@ -0,0 +1,162 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SharedSledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and perform concurrent reads and writes from
+//! multiple threads to verify shared overlay's functionality.
+
+use std::thread;
+
+use sled::{Config, IVec};
+
+use sled_overlay::{SharedSledDbOverlay, SledDbOverlay};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+
+#[test]
+fn sled_db_overlay_shared() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize the shared overlay
+    let overlay = SharedSledDbOverlay::new(SledDbOverlay::new(&db, vec![]));
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_2, false)?;
+
+    // Write to each tree from its own thread, while other threads read
+    thread::scope(|s| {
+        for tree_key in [TREE_1, TREE_2] {
+            let overlay = overlay.clone();
+            s.spawn(move || {
+                for i in 0..100u32 {
+                    overlay
+                        .insert(tree_key, &i.to_be_bytes(), tree_key)
+                        .unwrap();
+                }
+            });
+        }
+
+        for _ in 0..4 {
+            let overlay = overlay.clone();
+            s.spawn(move || {
+                for i in 0..100u32 {
+                    // Records are either missing or fully written
+                    if let Some(value) = overlay.get(TREE_1, &i.to_be_bytes()).unwrap() {
+                        assert_eq!(value, TREE_1);
+                    }
+
+                    // Iteration sees keys in order
+                    let keys = overlay
+                        .with_iter(TREE_2, |iter| {
+                            iter.map(|record| record.unwrap().0).collect::<Vec<IVec>>()
+                        })
+                        .unwrap();
+                    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+                }
+            });
+        }
+    });
+
+    // Verify all records got written
+    for tree_key in [TREE_1, TREE_2] {
+        let records = overlay.with_iter(tree_key, |iter| iter.count())?;
+        assert_eq!(records, 100);
+        assert_eq!(
+            overlay.last(tree_key)?,
+            Some((IVec::from(&99u32.to_be_bytes()), tree_key.into()))
+        );
+    }
+
+    // Checkpoint, perform some changes and revert them
+    overlay.checkpoint();
+    overlay.remove(TREE_1, &0u32.to_be_bytes())?;
+    overlay.drop_tree(TREE_2)?;
+    assert!(!overlay.contains_key(TREE_1, &0u32.to_be_bytes())?);
+    assert!(overlay.get(TREE_2, &0u32.to_be_bytes()).is_err());
+    overlay.revert_to_checkpoint();
+    assert!(overlay.contains_key(TREE_1, &0u32.to_be_bytes())?);
+    assert!(!overlay.is_empty(TREE_2)?);
+
+    // Apply the overlay while readers are running
+    thread::scope(|s| {
+        let reader = overlay.clone();
+        s.spawn(move || {
+            for _ in 0..100 {
+                assert_eq!(reader.with_iter(TREE_1, |iter| iter.count()).unwrap(), 100);
+            }
+        });
+        assert_eq!(overlay.apply(), Ok(()));
+    });
+
+    // Verify changes were written to the database
+    assert_eq!(db.open_tree(TREE_1)?.len(), 100);
+    assert_eq!(db.open_tree(TREE_2)?.len(), 100);
+
+    // Retrieve the overlay back
+    let overlay = overlay.into_inner().unwrap();
+    assert_eq!(
+        overlay.get(TREE_1, &0u32.to_be_bytes())?,
+        Some(TREE_1.into())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_shared_index_and_budget() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize the shared overlay with a small memory budget
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.set_memory_limit(Some(256));
+    let overlay = SharedSledDbOverlay::new(overlay);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_2, false)?;
+
+    // Write to the first tree, while an index of it gets declared
+    thread::scope(|s| {
+        let writer = overlay.clone();
+        s.spawn(move || {
+            for i in 0..200u32 {
+                writer.insert(TREE_1, &i.to_be_bytes(), b"value").unwrap();
+            }
+        });
+        overlay
+            .with_overlay(|overlay| overlay.add_index(TREE_1, TREE_2, |key, _| vec![key.into()]))
+            .unwrap();
+    });
+
+    // Once a write got indexed, all later ones must have been indexed too
+    let indexed: Vec<bool> = (0..200u32)
+        .map(|i| overlay.contains_key(TREE_2, &i.to_be_bytes()).unwrap())
+        .collect();
+    assert!(indexed.windows(2).all(|pair| pair[1] || !pair[0]));
+
+    // Verify the memory budget was enforced on the shared writes
+    overlay.with_overlay(|overlay| {
+        assert!(overlay.memory_usage() <= 256);
+        assert!(overlay.state.caches.get(TREE_1).unwrap().is_spilled());
+    });
+    assert_eq!(overlay.with_iter(TREE_1, |iter| iter.count())?, 200);
+
+    Ok(())
+}
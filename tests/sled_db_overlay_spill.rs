@@ -0,0 +1,203 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance with a memory budget, and perform writes
+//! exceeding it to verify overlay's cache spilling functionality.
+
+use std::collections::BTreeMap;
+
+use sled::{Config, IVec};
+
+use sled_overlay::SledDbOverlay;
+
+const TREE: &[u8] = b"_tree";
+
+/// Auxilliary function to verify the overlay tree view matches
+/// the expected records.
+fn verify(overlay: &SledDbOverlay, expected: &BTreeMap<IVec, IVec>) -> Result<(), sled::Error> {
+    let records = overlay
+        .iter(TREE)?
+        .collect::<Result<Vec<(IVec, IVec)>, sled::Error>>()?;
+    let expected_records: Vec<(IVec, IVec)> = expected
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    assert_eq!(records, expected_records);
+
+    for i in 0..64u32 {
+        let key = i.to_be_bytes();
+        assert_eq!(overlay.get(TREE, &key)?, expected.get(&key[..]).cloned());
+        assert_eq!(
+            overlay.contains_key(TREE, &key)?,
+            expected.contains_key(&key[..])
+        );
+    }
+
+    assert_eq!(
+        overlay.last(TREE)?,
+        expected
+            .last_key_value()
+            .map(|(k, v)| (k.clone(), v.clone()))
+    );
+    assert_eq!(overlay.is_empty(TREE)?, expected.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_spill() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize tree with some values
+    let tree = db.open_tree(TREE)?;
+    let mut expected = BTreeMap::new();
+    for i in (0..32u32).step_by(2) {
+        tree.insert(i.to_be_bytes(), b"base")?;
+        expected.insert(IVec::from(&i.to_be_bytes()), IVec::from(b"base"));
+    }
+
+    // Initialize overlay with a small memory budget
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.set_memory_limit(Some(64));
+    overlay.open_tree(TREE, false)?;
+
+    // Perform writes exceeding the budget
+    for i in 0..48u32 {
+        let key = i.to_be_bytes();
+        if i.is_multiple_of(4) && i < 32 {
+            overlay.remove(TREE, &key)?;
+            expected.remove(&key[..]);
+            continue;
+        }
+        overlay.insert(TREE, &key, b"overlay")?;
+        expected.insert(IVec::from(&key), IVec::from(b"overlay"));
+    }
+
+    // Verify the cache got spilled and the overlay view is unaffected
+    assert!(overlay.state.caches.get(TREE).unwrap().is_spilled());
+    assert!(overlay.memory_usage() <= 64);
+    verify(&overlay, &expected)?;
+
+    // Checkpoint, perform some more writes and revert
+    overlay.checkpoint();
+    let checkpoint_expected = expected.clone();
+    for i in 0..64u32 {
+        let key = i.to_be_bytes();
+        overlay.insert(TREE, &key, b"checkpoint")?;
+        expected.insert(IVec::from(&key), IVec::from(b"checkpoint"));
+    }
+    verify(&overlay, &expected)?;
+    overlay.revert_to_checkpoint();
+    expected = checkpoint_expected;
+    verify(&overlay, &expected)?;
+
+    // Writes on a cloned overlay must not affect the original
+    let mut overlay_clone = overlay.clone();
+    overlay_clone.insert(TREE, &1u32.to_be_bytes(), b"clone")?;
+    overlay_clone.remove(TREE, &2u32.to_be_bytes())?;
+    verify(&overlay, &expected)?;
+
+    // Verify the diff contains all the spilled changes
+    let diff = overlay.diff(&[])?;
+    let (tree_diff, _) = diff.caches.get(TREE).unwrap();
+    assert_eq!(tree_diff.removed.len(), 8);
+    assert_eq!(tree_diff.cache.len(), 40);
+
+    // Verify the aggregated batch contains all the spilled changes
+    let cache = overlay.state.caches.get(TREE).unwrap();
+    assert!(cache.try_aggregate()?.is_some());
+    assert_eq!(cache.try_kv_batch()?.unwrap().records.len(), 48);
+
+    // Now execute all tree batches in the overlay
+    assert_eq!(overlay.apply(), Ok(()));
+
+    // Verify sled contains the expected records
+    let records = tree
+        .iter()
+        .collect::<Result<BTreeMap<IVec, IVec>, sled::Error>>()?;
+    assert_eq!(records, expected);
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_spill_apply_diff() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    let tree = db.open_tree(TREE)?;
+
+    // Initialize overlay with a small memory budget
+    // and perform writes exceeding it
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.set_memory_limit(Some(64));
+    overlay.open_tree(TREE, false)?;
+    for i in 0..10u32 {
+        overlay.insert(TREE, &i.to_be_bytes(), b"valuevalue")?;
+    }
+    assert!(overlay.state.caches.get(TREE).unwrap().is_spilled());
+
+    // Grab the diff, and modify a key afterwards, spilling it again
+    let diff = overlay.diff(&[])?;
+    overlay.insert(TREE, &1u32.to_be_bytes(), b"modified")?;
+    for i in 10..16u32 {
+        overlay.insert(TREE, &i.to_be_bytes(), b"valuevalue")?;
+    }
+
+    // Apply the diff, and verify only the later changes remain pending
+    assert_eq!(overlay.apply_diff(&diff), Ok(()));
+    let (tree_diff, _) = overlay.diff(&[])?.caches.get(TREE).unwrap().clone();
+    assert_eq!(tree_diff.cache.len(), 7);
+    assert!(tree_diff
+        .cache
+        .contains_key(&IVec::from(&1u32.to_be_bytes())));
+
+    // Write the tree outside of the overlay, and verify
+    // the applied spilled records don't hide it
+    tree.insert(0u32.to_be_bytes(), b"external")?;
+    assert_eq!(
+        overlay.get(TREE, &0u32.to_be_bytes())?,
+        Some(b"external".into())
+    );
+    assert_eq!(
+        overlay.get(TREE, &1u32.to_be_bytes())?,
+        Some(b"modified".into())
+    );
+
+    // Perform more writes, spilling again, which removes
+    // the applied spilled records
+    for i in 16..24u32 {
+        overlay.insert(TREE, &i.to_be_bytes(), b"valuevalue")?;
+    }
+    assert!(overlay.memory_usage() <= 64);
+    assert_eq!(
+        overlay.get(TREE, &0u32.to_be_bytes())?,
+        Some(b"external".into())
+    );
+
+    // Apply the rest and verify the external write was not overwritten
+    assert_eq!(overlay.apply(), Ok(()));
+    assert_eq!(tree.get(0u32.to_be_bytes())?, Some(b"external".into()));
+    assert_eq!(tree.get(1u32.to_be_bytes())?, Some(b"modified".into()));
+    assert_eq!(tree.len(), 24);
+
+    Ok(())
+}
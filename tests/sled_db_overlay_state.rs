@@ -1910,3 +1910,34 @@ fn sled_db_overlay_protected_trees() -> Result<(), sled::Error> {
 
     Ok(())
 }
+
+#[test]
+fn sled_db_overlay_restored_trees() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize a tree with some values
+    let tree_1 = db.open_tree(TREE_1)?;
+    tree_1.insert(b"key_a", b"val_a")?;
+
+    // Produce a diff dropping the tree
+    let mut other = SledDbOverlay::new(&db, vec![]);
+    other.drop_tree(TREE_1)?;
+    let diff = other.diff(&[])?;
+
+    // Merge it, along with its inverse restoring the tree
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.add_diff(&diff)?;
+    assert!(overlay.get(TREE_1, b"key_a").is_err());
+    overlay.add_diff(&diff.inverse())?;
+
+    // The restored tree is no longer dropped
+    assert!(!overlay.state.dropped_trees.contains_key(TREE_1));
+    assert_eq!(overlay.get(TREE_1, b"key_a")?, Some(b"val_a".into()));
+    overlay.insert(TREE_1, b"key_b", b"val_b")?;
+    assert_eq!(overlay.apply(), Ok(()));
+    assert_eq!(db.open_tree(TREE_1)?.len(), 2);
+
+    Ok(())
+}
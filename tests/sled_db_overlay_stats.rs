@@ -0,0 +1,134 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and perform writes to verify its state
+//! and diffs statistics.
+
+use sled::Config;
+
+use sled_overlay::{SledDbOverlay, SledTreeOverlayStats};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+
+#[test]
+fn sled_db_overlay_stats() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize trees with some values
+    let tree_1 = db.open_tree(TREE_1)?;
+    tree_1.insert(b"key_a", b"val_a")?;
+    tree_1.insert(b"key_b", b"val_b")?;
+    let tree_2 = db.open_tree(TREE_2)?;
+    tree_2.insert(b"key_c", b"val_c")?;
+    tree_2.insert(b"key_d", b"val_d")?;
+
+    // Initialize overlay and perform some changes
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_3, false)?;
+    overlay.insert(TREE_1, b"key_a", b"val_aa")?;
+    overlay.insert(TREE_1, b"key_e", b"val_e")?;
+    overlay.remove(TREE_1, b"key_b")?;
+    overlay.insert(TREE_3, b"key_f", b"val_f")?;
+    overlay.drop_tree(TREE_2)?;
+
+    // Verify state statistics
+    let stats = overlay.stats()?;
+    assert_eq!(stats.trees.len(), 3);
+    assert_eq!(
+        stats.trees[TREE_1],
+        SledTreeOverlayStats {
+            inserted: 1,
+            updated: 1,
+            removed: 1,
+            dropped: 0,
+            restored: 0,
+            bytes_before: 20,
+            bytes_after: 21,
+        }
+    );
+    assert_eq!(
+        stats.trees[TREE_2],
+        SledTreeOverlayStats {
+            dropped: 2,
+            bytes_before: 20,
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        stats.trees[TREE_3],
+        SledTreeOverlayStats {
+            inserted: 1,
+            bytes_after: 10,
+            ..Default::default()
+        }
+    );
+    let total = stats.total();
+    assert_eq!(total.changed_keys(), 6);
+    assert_eq!(total.bytes_before, 40);
+    assert_eq!(total.bytes_after, 31);
+    assert_eq!(total.growth(), -9);
+
+    // Diff statistics must match the state ones
+    let diff = overlay.diff(&[])?;
+    assert_eq!(diff.stats(), stats);
+
+    // Verify inverse diff statistics
+    let stats = diff.inverse().stats();
+    assert_eq!(
+        stats.trees[TREE_1],
+        SledTreeOverlayStats {
+            inserted: 1,
+            updated: 1,
+            removed: 1,
+            dropped: 0,
+            restored: 0,
+            bytes_before: 21,
+            bytes_after: 20,
+        }
+    );
+    assert_eq!(
+        stats.trees[TREE_2],
+        SledTreeOverlayStats {
+            restored: 2,
+            bytes_after: 20,
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        stats.trees[TREE_3],
+        SledTreeOverlayStats {
+            dropped: 1,
+            bytes_before: 10,
+            ..Default::default()
+        }
+    );
+    assert_eq!(stats.total().growth(), 9);
+
+    // Verify tree level statistics
+    let cache = overlay.state.caches.get(TREE_1).unwrap();
+    assert_eq!(cache.stats()?, overlay.stats()?.trees[TREE_1]);
+    assert_eq!(cache.state.stats(&tree_1)?, cache.stats()?);
+
+    Ok(())
+}
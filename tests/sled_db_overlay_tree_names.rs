@@ -0,0 +1,111 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and open and drop trees to verify overlay's
+//! tree listing functionality.
+
+use sled::{Config, IVec};
+
+use sled_overlay::{SharedSledDbOverlay, SledDbOverlay, TreeStatus};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+const TREE_3: &[u8] = b"_tree3";
+const TREE_4: &[u8] = b"_tree4";
+
+/// Auxilliary function to collect the overlay trees, excluding sled's default one.
+fn trees(overlay: &SledDbOverlay) -> Vec<(IVec, TreeStatus)> {
+    overlay
+        .iter_trees()
+        .filter(|(tree_key, _)| tree_key != b"__sled__default")
+        .collect()
+}
+
+#[test]
+fn sled_db_overlay_tree_names() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Create trees in the database
+    db.open_tree(TREE_1)?;
+    db.open_tree(TREE_2)?;
+
+    // Initialize overlay
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    assert!(overlay.tree_exists(TREE_1));
+    assert!(overlay.tree_exists(TREE_2));
+    assert!(!overlay.tree_exists(TREE_3));
+    assert_eq!(
+        trees(&overlay),
+        vec![
+            (TREE_1.into(), TreeStatus::Existing),
+            (TREE_2.into(), TreeStatus::Existing),
+        ]
+    );
+
+    // Open a new tree and drop the existing ones
+    overlay.open_tree(TREE_3, false)?;
+    overlay.drop_tree(TREE_1)?;
+    overlay.drop_tree(TREE_2)?;
+    assert!(!overlay.tree_exists(TREE_1));
+    assert!(overlay.tree_exists(TREE_3));
+    assert_eq!(
+        trees(&overlay),
+        vec![
+            (TREE_1.into(), TreeStatus::Dropped),
+            (TREE_2.into(), TreeStatus::Dropped),
+            (TREE_3.into(), TreeStatus::New),
+        ]
+    );
+
+    // Reopen a dropped tree and drop the new one
+    overlay.open_tree(TREE_2, false)?;
+    overlay.drop_tree(TREE_3)?;
+    assert_eq!(
+        trees(&overlay),
+        vec![
+            (TREE_1.into(), TreeStatus::Dropped),
+            (TREE_2.into(), TreeStatus::Restored),
+            (TREE_3.into(), TreeStatus::Dropped),
+        ]
+    );
+    assert!(!overlay
+        .tree_names()
+        .iter()
+        .any(|tree_key| tree_key == TREE_1 || tree_key == TREE_3));
+
+    // Restore a dropped tree through an inverse diff
+    let mut other = SledDbOverlay::new(&db, vec![]);
+    other.drop_tree(TREE_1)?;
+    let diff = other.diff(&[])?;
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.add_diff(&diff)?;
+    assert_eq!(trees(&overlay)[0], (TREE_1.into(), TreeStatus::Dropped));
+    overlay.add_diff(&diff.inverse())?;
+    assert_eq!(trees(&overlay)[0], (TREE_1.into(), TreeStatus::Restored));
+
+    // Verify the shared overlay view
+    let shared = SharedSledDbOverlay::new(overlay);
+    shared.open_tree(TREE_4, false)?;
+    assert!(shared.tree_exists(TREE_4));
+    assert!(shared.tree_names().contains(&TREE_4.into()));
+
+    Ok(())
+}
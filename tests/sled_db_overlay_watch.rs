@@ -0,0 +1,204 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledDbOverlay`] on top of an entire
+//! [`sled::Db`] instance, and subscribe to key prefixes to verify
+//! overlay's change subscription functionality.
+
+use sled::{Config, IVec};
+
+use sled_overlay::{Event, EventKind, SledDbOverlay, Subscriber};
+
+const TREE_1: &[u8] = b"_tree1";
+const TREE_2: &[u8] = b"_tree2";
+
+/// Auxilliary function to build an [`Event`].
+fn event(tree: &[u8], key: &[u8], value: Option<&[u8]>, kind: EventKind) -> Event {
+    Event {
+        tree: tree.into(),
+        key: key.into(),
+        value: value.map(IVec::from),
+        kind,
+    }
+}
+
+/// Auxilliary function to drain all available subscriber events.
+fn drain(subscriber: &Subscriber) -> Vec<Event> {
+    let mut events = vec![];
+    while let Some(event) = subscriber.try_next() {
+        events.push(event);
+    }
+    events
+}
+
+#[test]
+fn sled_db_overlay_watch() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize overlay
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+    overlay.open_tree(TREE_2, false)?;
+
+    // Subscribe to some prefixes
+    let sub_a = overlay.watch_prefix(TREE_1, b"a");
+    let sub_all = overlay.watch_prefix(TREE_1, b"");
+    let sub_tree_2 = overlay.watch_prefix(TREE_2, b"a");
+
+    // Perform some writes
+    overlay.insert(TREE_1, b"a1", b"v1")?;
+    overlay.insert(TREE_1, b"b1", b"v2")?;
+    overlay.remove(TREE_1, b"a1")?;
+    overlay.insert(TREE_1, b"a2", b"v3")?;
+    assert_eq!(
+        drain(&sub_a),
+        vec![
+            event(TREE_1, b"a1", Some(b"v1"), EventKind::Pending),
+            event(TREE_1, b"a1", None, EventKind::Pending),
+            event(TREE_1, b"a2", Some(b"v3"), EventKind::Pending),
+        ]
+    );
+    assert_eq!(drain(&sub_all).len(), 4);
+    assert!(drain(&sub_tree_2).is_empty());
+
+    // Checkpoint, perform some more writes and revert them
+    overlay.checkpoint();
+    overlay.insert(TREE_1, b"a2", b"v4")?;
+    overlay.insert(TREE_1, b"a3", b"v5")?;
+    assert_eq!(drain(&sub_a).len(), 2);
+    overlay.revert_to_checkpoint();
+    assert_eq!(
+        drain(&sub_a),
+        vec![
+            event(TREE_1, b"a2", Some(b"v3"), EventKind::Reverted),
+            event(TREE_1, b"a3", None, EventKind::Reverted),
+        ]
+    );
+    assert_eq!(drain(&sub_all).len(), 4);
+
+    // Apply the changes
+    let diff = overlay.diff(&[])?;
+    assert_eq!(overlay.apply_diff(&diff), Ok(()));
+    assert_eq!(
+        drain(&sub_a),
+        vec![
+            event(TREE_1, b"a1", None, EventKind::Applied),
+            event(TREE_1, b"a2", Some(b"v3"), EventKind::Applied),
+        ]
+    );
+    assert_eq!(
+        drain(&sub_all),
+        vec![
+            event(TREE_1, b"a1", None, EventKind::Applied),
+            event(TREE_1, b"a2", Some(b"v3"), EventKind::Applied),
+            event(TREE_1, b"b1", Some(b"v2"), EventKind::Applied),
+        ]
+    );
+
+    // Merge a diff produced by another overlay
+    let mut other = SledDbOverlay::new(&db, vec![]);
+    other.open_tree(TREE_1, false)?;
+    other.open_tree(TREE_2, false)?;
+    other.remove(TREE_1, b"a2")?;
+    other.insert(TREE_2, b"a4", b"v6")?;
+    let diff = other.diff(&[])?;
+    overlay.add_diff(&diff)?;
+    assert_eq!(
+        drain(&sub_a),
+        vec![event(TREE_1, b"a2", None, EventKind::Merged)]
+    );
+    assert_eq!(
+        drain(&sub_tree_2),
+        vec![event(TREE_2, b"a4", Some(b"v6"), EventKind::Merged)]
+    );
+
+    // Apply the merged changes
+    assert_eq!(overlay.apply(), Ok(()));
+    assert_eq!(
+        drain(&sub_a),
+        vec![event(TREE_1, b"a2", None, EventKind::Applied)]
+    );
+    assert_eq!(
+        drain(&sub_tree_2),
+        vec![event(TREE_2, b"a4", Some(b"v6"), EventKind::Applied)]
+    );
+
+    // Drop a tree and apply the overlay
+    overlay.drop_tree(TREE_2)?;
+    assert_eq!(overlay.apply(), Ok(()));
+    assert_eq!(
+        drain(&sub_tree_2),
+        vec![event(TREE_2, b"a4", None, EventKind::Applied)]
+    );
+
+    // Clones don't inherit subscribers
+    drain(&sub_a);
+    let mut clone = overlay.clone();
+    clone.open_tree(TREE_1, false)?;
+    clone.insert(TREE_1, b"a5", b"v7")?;
+    assert!(drain(&sub_a).is_empty());
+
+    // Subscribers finish once the overlay is gone
+    drop(overlay);
+    let mut sub_all = sub_all;
+    drain(&sub_all);
+    assert_eq!(sub_all.next(), None);
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_watch_bounded() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize overlay
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.open_tree(TREE_1, false)?;
+
+    // Subscribe with a small buffer, and never read from it
+    let sub = overlay.watch_prefix_with_capacity(TREE_1, b"", 2);
+
+    // Writes don't block once the buffer is full
+    for i in 0..5u8 {
+        overlay.insert(TREE_1, &[i], b"v")?;
+    }
+    assert_eq!(sub.missed(), 3);
+
+    // Only the buffered events are received
+    assert_eq!(
+        drain(&sub),
+        vec![
+            event(TREE_1, &[0], Some(b"v"), EventKind::Pending),
+            event(TREE_1, &[1], Some(b"v"), EventKind::Pending),
+        ]
+    );
+
+    // New events are received once the buffer has room again
+    overlay.insert(TREE_1, &[5], b"v")?;
+    assert_eq!(
+        drain(&sub),
+        vec![event(TREE_1, &[5], Some(b"v"), EventKind::Pending)]
+    );
+    assert_eq!(sub.missed(), 3);
+
+    Ok(())
+}
@@ -0,0 +1,213 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledTreeOverlay`] on top of a
+//! [`sled::Tree`] instance with state root tracking enabled, and
+//! perform writes to verify its state root and proofs functionality.
+
+#![cfg(any(feature = "blake3", feature = "sha256"))]
+
+use sled::{Config, IVec};
+
+use sled_overlay::{
+    merkle::EMPTY_ROOT, DefaultStateHasher, SledDbOverlay, SledTreeOverlay, SparseMerkleTree,
+    StateHasher,
+};
+
+const TREE: &[u8] = b"_tree";
+
+/// Auxilliary function to compute the overlay state root from scratch.
+fn full_root(overlay: &SledTreeOverlay) -> Result<[u8; 32], sled::Error> {
+    let records = overlay
+        .iter()
+        .collect::<Result<Vec<(IVec, IVec)>, sled::Error>>()?;
+    Ok(SparseMerkleTree::from_records(records).root())
+}
+
+#[test]
+fn sled_tree_overlay_merkle() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize tree with some values and its overlay
+    let tree = db.open_tree(TREE)?;
+    tree.insert(b"key_a", b"val_a")?;
+    tree.insert(b"key_b", b"val_b")?;
+    let mut overlay = SledTreeOverlay::new(&tree);
+
+    // State root is not tracked by default
+    assert_eq!(overlay.state_root(), None);
+    assert_eq!(overlay.prove(b"key_a"), None);
+
+    // Perform some changes before enabling tracking
+    overlay.insert(b"key_c", b"val_c")?;
+    overlay.remove(b"key_a")?;
+    overlay.enable_state_root()?;
+    let root = overlay.state_root().unwrap();
+    assert_eq!(root, full_root(&overlay)?);
+
+    // Verify inclusion and exclusion proofs
+    let proof = overlay.prove(b"key_b").unwrap();
+    assert!(proof.verify(&root, b"key_b", Some(b"val_b")));
+    assert!(!proof.verify(&root, b"key_b", Some(b"val_bb")));
+    assert!(!proof.verify(&root, b"key_b", None));
+    let proof = overlay.prove(b"key_a").unwrap();
+    assert!(proof.verify(&root, b"key_a", None));
+    assert!(!proof.verify(&root, b"key_a", Some(b"val_a")));
+
+    // Checkpoint and perform some more changes
+    overlay.checkpoint();
+    overlay.insert(b"key_a", b"val_aa")?;
+    overlay.insert(b"key_b", b"val_bb")?;
+    overlay.remove(b"key_c")?;
+    assert_ne!(overlay.state_root().unwrap(), root);
+    assert_eq!(overlay.state_root().unwrap(), full_root(&overlay)?);
+
+    // Revert to checkpoint and verify root was restored
+    overlay.revert_to_checkpoint();
+    assert_eq!(overlay.state_root().unwrap(), root);
+
+    // Clear the overlay and verify root is empty
+    overlay.clear()?;
+    assert_eq!(overlay.state_root().unwrap(), EMPTY_ROOT);
+    let proof = overlay.prove(b"key_b").unwrap();
+    assert!(proof.verify(&EMPTY_ROOT, b"key_b", None));
+
+    Ok(())
+}
+
+#[test]
+fn sled_tree_overlay_merkle_incremental() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize tree with some values and its overlay
+    let tree = db.open_tree(TREE)?;
+    for i in 0..64u32 {
+        tree.insert(i.to_be_bytes(), &i.to_le_bytes())?;
+    }
+    let mut overlay = SledTreeOverlay::new(&tree);
+    overlay.enable_state_root()?;
+
+    // Perform a deterministic sequence of writes and verify the
+    // incremental root always matches the full one
+    let mut seed: u32 = 7;
+    for _ in 0..256 {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let key = (seed % 96).to_be_bytes();
+        if seed.is_multiple_of(3) && overlay.contains_key(&key)? {
+            overlay.remove(&key)?;
+        } else {
+            overlay.insert(&key, &seed.to_le_bytes())?;
+        }
+        assert_eq!(overlay.state_root().unwrap(), full_root(&overlay)?);
+    }
+
+    // Verify proofs for all possible keys
+    let root = overlay.state_root().unwrap();
+    for i in 0..96u32 {
+        let key = i.to_be_bytes();
+        let proof = overlay.prove(&key).unwrap();
+        let value = overlay.get(&key)?;
+        assert!(proof.verify(&root, &key, value.as_deref()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_merkle() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize overlay
+    let mut overlay = SledDbOverlay::new(&db, vec![]);
+    overlay.open_tree(TREE, false)?;
+    overlay.insert(TREE, b"key_a", b"val_a")?;
+    assert_eq!(overlay.state_root(TREE)?, None);
+
+    // Enable tracking and perform some changes
+    overlay.enable_state_root(TREE)?;
+    overlay.insert(TREE, b"key_b", b"val_b")?;
+    let root = overlay.state_root(TREE)?.unwrap();
+    let proof = overlay.prove(TREE, b"key_b")?.unwrap();
+    assert!(proof.verify(&root, b"key_b", Some(b"val_b")));
+
+    // Adding a diff must update the root accordingly
+    let mut other = SledDbOverlay::new(&db, vec![]);
+    other.open_tree(TREE, false)?;
+    other.insert(TREE, b"key_c", b"val_c")?;
+    overlay.add_diff(&other.diff(&[])?)?;
+    let root = overlay.state_root(TREE)?.unwrap();
+    let proof = overlay.prove(TREE, b"key_c")?.unwrap();
+    assert!(proof.verify(&root, b"key_c", Some(b"val_c")));
+    let expected = SparseMerkleTree::from_records([
+        (b"key_a", b"val_a"),
+        (b"key_b", b"val_b"),
+        (b"key_c", b"val_c"),
+    ]);
+    assert_eq!(root, expected.root());
+
+    Ok(())
+}
+
+/// Auxilliary function to hash provided domain separator and parts.
+fn hash_parts(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = DefaultStateHasher::default();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+#[test]
+fn sparse_merkle_tree_forged_exclusion() {
+    // Build a tree with enough records to have branching nodes
+    let records: Vec<([u8; 4], [u8; 4])> = (0..64u32)
+        .map(|i| (i.to_be_bytes(), i.to_le_bytes()))
+        .collect();
+    let tree = SparseMerkleTree::from_records(records.clone());
+    let root = tree.root();
+
+    // Grab an existing key inclusion proof
+    let (key, value) = records[0];
+    let mut proof = tree.prove(&key);
+    assert!(proof.verify(&root, &key, Some(&value)));
+    assert!(proof.siblings.len() > 1);
+
+    // Cut its path at the node above the leaf, and pass that node
+    // as a leaf occupying a path sharing its prefix
+    let path = hash_parts(&[&key]);
+    let leaf = hash_parts(&[&[0], &path, &hash_parts(&[&value])]);
+    let depth = proof.siblings.len() - 1;
+    let sibling = proof.siblings.pop().unwrap();
+    let node = if (path[depth / 8] >> (7 - depth % 8)) & 1 == 1 {
+        hash_parts(&[&[1], &sibling, &leaf])
+    } else {
+        hash_parts(&[&[1], &leaf, &sibling])
+    };
+    let mut forged_path = path;
+    forged_path[31] ^= 1;
+    proof.leaf = Some((forged_path, node));
+
+    // The forged exclusion proof must be rejected
+    assert!(!proof.verify(&root, &key, None));
+}
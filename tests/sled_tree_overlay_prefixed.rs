@@ -0,0 +1,147 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of a [`SledTreeOverlay`] on top of a
+//! [`sled::Tree`] instance, and emulate multiple logical tables inside
+//! it to verify overlay's prefixed views functionality.
+
+use sled::{Config, IVec};
+
+use sled_overlay::SledTreeOverlay;
+
+const TREE: &[u8] = b"_tree";
+
+/// Auxilliary function to collect provided records iterator.
+fn collect(
+    iter: impl Iterator<Item = Result<(IVec, IVec), sled::Error>>,
+) -> Result<Vec<(IVec, IVec)>, sled::Error> {
+    iter.collect()
+}
+
+/// Auxilliary function to build an expected record.
+fn record(key: &[u8], value: &[u8]) -> (IVec, IVec) {
+    (key.into(), value.into())
+}
+
+#[test]
+fn sled_tree_overlay_prefixed() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+
+    // Initialize tree with records of multiple tables
+    let tree = db.open_tree(TREE)?;
+    tree.insert(b"a/1", b"a1")?;
+    tree.insert(b"a/2", b"a2")?;
+    tree.insert(b"b/1", b"b1")?;
+    tree.insert(b"b/2", b"b2")?;
+    tree.insert(b"a", b"no table")?;
+    tree.insert(b"a0", b"no table")?;
+    tree.insert([0xff, 0xff, 1], b"ff")?;
+
+    // Initialize overlay
+    let mut overlay = SledTreeOverlay::new(&tree);
+
+    // Write through the views
+    let mut table_a = overlay.prefixed(b"a/");
+    assert_eq!(table_a.prefix(), &IVec::from(b"a/"));
+    assert_eq!(table_a.len()?, 2);
+    assert_eq!(table_a.get(b"1")?, Some(b"a1".into()));
+    assert_eq!(table_a.insert(b"3", b"a3")?, None);
+    assert_eq!(table_a.remove(b"1")?, Some(b"a1".into()));
+    assert!(table_a.contains_key(b"3")?);
+    assert!(!table_a.contains_key(b"1")?);
+    assert_eq!(
+        collect(table_a.iter())?,
+        vec![record(b"2", b"a2"), record(b"3", b"a3")]
+    );
+    assert_eq!(table_a.last()?, Some(record(b"3", b"a3")));
+
+    let mut table_b = overlay.prefixed(b"b/");
+    assert_eq!(table_b.insert(b"0", b"b0")?, None);
+    assert_eq!(table_b.len()?, 3);
+
+    // Verify the writes landed on the prefixed keys
+    assert_eq!(overlay.get(b"a/3")?, Some(b"a3".into()));
+    assert_eq!(overlay.get(b"b/0")?, Some(b"b0".into()));
+    assert_eq!(overlay.get(b"a/1")?, None);
+    assert_eq!(tree.get(b"a/1")?, Some(b"a1".into()));
+
+    // Verify range iteration over the overlay
+    assert_eq!(
+        collect(overlay.range::<&[u8], _>(b"a/2".as_slice()..b"b/1".as_slice()))?,
+        vec![
+            record(b"a/2", b"a2"),
+            record(b"a/3", b"a3"),
+            record(b"a0", b"no table"),
+            record(b"b/0", b"b0"),
+        ]
+    );
+    assert_eq!(
+        collect(overlay.scan_prefix(&[0xff]))?,
+        vec![record(&[0xff, 0xff, 1], b"ff")]
+    );
+
+    // Spill the changes and verify the views are unaffected
+    let spill_db = Config::new().temporary(true).open()?;
+    overlay.spill(&spill_db)?;
+    overlay.prefixed(b"a/").insert(b"4", b"a4")?;
+    assert_eq!(
+        collect(overlay.prefixed(b"a/").iter())?,
+        vec![
+            record(b"2", b"a2"),
+            record(b"3", b"a3"),
+            record(b"4", b"a4")
+        ]
+    );
+
+    // Clearing a table leaves the rest of the tree intact
+    let mut table_a = overlay.prefixed(b"a/");
+    table_a.clear()?;
+    assert!(table_a.is_empty()?);
+    assert_eq!(table_a.len()?, 0);
+    assert_eq!(table_a.last()?, None);
+    assert_eq!(
+        collect(overlay.iter())?,
+        vec![
+            record(b"a", b"no table"),
+            record(b"a0", b"no table"),
+            record(b"b/0", b"b0"),
+            record(b"b/1", b"b1"),
+            record(b"b/2", b"b2"),
+            record(&[0xff, 0xff, 1], b"ff"),
+        ]
+    );
+
+    // Apply the changes and verify the tree
+    let batch = overlay.aggregate().unwrap();
+    tree.apply_batch(batch)?;
+    assert_eq!(
+        collect(tree.iter())?,
+        vec![
+            record(b"a", b"no table"),
+            record(b"a0", b"no table"),
+            record(b"b/0", b"b0"),
+            record(b"b/1", b"b1"),
+            record(b"b/2", b"b2"),
+            record(&[0xff, 0xff, 1], b"ff"),
+        ]
+    );
+
+    Ok(())
+}
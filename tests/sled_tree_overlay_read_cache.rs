@@ -0,0 +1,132 @@
+/* This file is part of sled-overlay
+ *
+ * Copyright (C) 2023-2026 Dyne.org foundation
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Simulate the creation of overlays with a main tree values read cache,
+//! and perform reads and writes to verify the cache serves repeated
+//! reads, evicts least recently used keys and gets invalidated on apply.
+
+use sled::{Config, IVec};
+
+use sled_overlay::{SledDbOverlay, SledTreeOverlay};
+
+const TREE: &[u8] = b"_tree";
+
+#[test]
+fn sled_tree_overlay_read_cache() -> Result<(), sled::Error> {
+    // Initialize database and tree
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    let tree = db.open_tree(TREE)?;
+    tree.insert(b"key_a", b"val_a")?;
+    tree.insert(b"key_b", b"val_b")?;
+    tree.insert(b"key_c", b"val_c")?;
+
+    // Initialize overlay with a read cache of two keys
+    let mut overlay = SledTreeOverlay::new(&tree);
+    overlay.set_read_cache_capacity(Some(2));
+    assert_eq!(overlay.read_cache_capacity(), Some(2));
+
+    // Read some keys, including a missing one
+    assert_eq!(overlay.get(b"key_a")?, Some(b"val_a".into()));
+    assert!(!overlay.contains_key(b"key_d")?);
+
+    // Write the tree directly, bypassing the overlay,
+    // so cached reads return the stale values
+    tree.insert(b"key_a", b"val_a2")?;
+    tree.insert(b"key_d", b"val_d")?;
+    assert_eq!(overlay.get(b"key_a")?, Some(b"val_a".into()));
+    assert_eq!(overlay.get(b"key_d")?, None);
+
+    // Overlay changes always take precedence over cached values
+    overlay.insert(b"key_d", b"val_d2")?;
+    assert_eq!(overlay.get(b"key_d")?, Some(b"val_d2".into()));
+    assert_eq!(overlay.remove(b"key_a")?, Some(b"val_a".into()));
+    assert_eq!(overlay.get(b"key_a")?, None);
+
+    // Invalidating the cache returns the fresh values
+    overlay.invalidate_read_cache();
+    overlay.state.removed.clear();
+    overlay.state.cache.clear();
+    assert_eq!(overlay.get(b"key_a")?, Some(b"val_a2".into()));
+    assert_eq!(overlay.get(b"key_d")?, Some(b"val_d".into()));
+
+    // Reading a third key evicts the least recently used one
+    assert_eq!(overlay.get(b"key_b")?, Some(b"val_b".into()));
+    tree.insert(b"key_a", b"val_a3")?;
+    tree.insert(b"key_b", b"val_b2")?;
+    assert_eq!(overlay.get(b"key_a")?, Some(b"val_a3".into()));
+    assert_eq!(overlay.get(b"key_b")?, Some(b"val_b".into()));
+
+    // Iteration always returns the main tree records
+    let records = overlay
+        .iter()
+        .collect::<Result<Vec<(IVec, IVec)>, sled::Error>>()?;
+    assert_eq!(
+        records,
+        vec![
+            (b"key_a".into(), b"val_a3".into()),
+            (b"key_b".into(), b"val_b2".into()),
+            (b"key_c".into(), b"val_c".into()),
+            (b"key_d".into(), b"val_d".into()),
+        ]
+    );
+
+    // Disable the cache
+    overlay.set_read_cache_capacity(None);
+    assert_eq!(overlay.read_cache_capacity(), None);
+    assert_eq!(overlay.get(b"key_b")?, Some(b"val_b2".into()));
+
+    Ok(())
+}
+
+#[test]
+fn sled_db_overlay_read_cache() -> Result<(), sled::Error> {
+    // Initialize database
+    let config = Config::new().temporary(true);
+    let db = config.open()?;
+    db.open_tree(TREE)?.insert(b"key_a", b"val_a")?;
+
+    // Initialize overlay with read caches
+    let mut overlay = SledDbOverlay::new(&db, vec![TREE]);
+    overlay.set_read_cache_capacity(Some(16));
+    overlay.open_tree(TREE, true)?;
+
+    // Cache a value and a missing key
+    assert_eq!(overlay.get(TREE, b"key_a")?, Some(b"val_a".into()));
+    assert_eq!(overlay.get(TREE, b"key_b")?, None);
+
+    // Apply some changes, which invalidates the caches
+    overlay.insert(TREE, b"key_b", b"val_b")?;
+    overlay.remove(TREE, b"key_a")?;
+    let diff = overlay.diff(&[])?;
+    assert_eq!(overlay.apply_diff(&diff), Ok(()));
+
+    // Reads return the applied values
+    assert_eq!(overlay.get(TREE, b"key_a")?, None);
+    assert_eq!(overlay.get(TREE, b"key_b")?, Some(b"val_b".into()));
+
+    // A checkpoint shares the caches, so reverting
+    // to it keeps reading the applied values
+    overlay.checkpoint();
+    overlay.insert(TREE, b"key_c", b"val_c")?;
+    assert_eq!(overlay.apply(), Ok(()));
+    overlay.revert_to_checkpoint();
+    assert_eq!(overlay.get(TREE, b"key_c")?, Some(b"val_c".into()));
+
+    Ok(())
+}